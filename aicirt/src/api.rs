@@ -179,3 +179,32 @@ impl AuthInfo {
         }
     }
 }
+
+/// Mask entries for allowed and forbidden tokens.
+pub const LOGIT_BIAS_ALLOW: f32 = 0.0;
+pub const LOGIT_BIAS_DISALLOW: f32 = -100.0;
+
+/// Add (token, bias) pairs to a mask; returns the number of tokens biased.
+/// Only allowed tokens are touched, so the bias never re-enables a token the mask forbids.
+pub fn add_token_bias(mask: &mut [f32], token_bias: &[(u32, f32)]) -> usize {
+    let mut num_biased = 0;
+    for &(tok, bias) in token_bias {
+        match mask.get_mut(tok as usize) {
+            Some(v) if *v == LOGIT_BIAS_ALLOW => {
+                *v += bias;
+                num_biased += 1;
+            }
+            _ => {}
+        }
+    }
+    num_biased
+}
+
+/// Decode the (token, f32 bias bits) words passed to aici_host_return_token_bias().
+/// Returns the valid pairs, and the ones with an out-of-range token or a non-finite bias.
+pub fn decode_token_bias(words: &[u32], vocab_size: u32) -> (Vec<(u32, f32)>, Vec<(u32, f32)>) {
+    words
+        .chunks_exact(2)
+        .map(|pair| (pair[0], f32::from_bits(pair[1])))
+        .partition(|(tok, val)| *tok < vocab_size && val.is_finite())
+}
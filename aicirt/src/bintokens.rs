@@ -1,5 +1,9 @@
 use crate::HashMap;
-use aici_abi::bytes::TokRxInfo;
+use aici_abi::{
+    bytes::{to_hex_string, TokRxInfo},
+    toktree::{TokTrie, Utf8Class},
+    TokenId,
+};
 use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -146,11 +150,50 @@ fn strip_suffix(sep: &str, s: &mut String) -> Option<String> {
     suff
 }
 
-pub fn test_tokenizers() {
+pub fn test_tokenizers() -> Result<()> {
     for t in tokenizers() {
-        let t = find_tokenizer(t.name).unwrap();
-        println!("tokenizer: {} {}", t.hf_model, t.vocab_size);
+        let t = find_tokenizer(t.name)?;
+        let report = t.verify_against_hf()?;
+        println!("tokenizer: {} {} {}", t.hf_model, t.vocab_size, report);
+        if !report.is_ok() {
+            bail!("token bytes of {} don't match the HF tokenizer", t.hf_model);
+        }
+        check_utf8_classes(&TokTrie::from(&t.tokrx_info(), &t.token_bytes()))?;
+    }
+    Ok(())
+}
+
+/// Check TokTrie::utf8_class() of every token by brute force: a token is a Prefix
+/// if some continuation bytes make it valid UTF-8. Only the first continuation
+/// byte of a character has a restricted range, so trying each value for it, and
+/// 0x80 for the rest, covers all cases.
+fn check_utf8_classes(trie: &TokTrie) -> Result<()> {
+    for tok in 0..trie.vocab_size() as TokenId {
+        let bytes = trie.token(tok);
+        let expected = if std::str::from_utf8(bytes).is_ok() {
+            Utf8Class::Complete
+        } else if (1..=3).any(|len| {
+            (0x80..=0xBF).any(|first| {
+                let mut b = bytes.to_vec();
+                b.push(first);
+                b.resize(bytes.len() + len, 0x80);
+                std::str::from_utf8(&b).is_ok()
+            })
+        }) {
+            Utf8Class::Prefix
+        } else {
+            Utf8Class::Invalid
+        };
+        if trie.utf8_class(tok) != expected {
+            bail!(
+                "token {}: utf8_class() is {:?}, expected {:?}",
+                trie.token_dbg(tok),
+                trie.utf8_class(tok),
+                expected
+            );
+        }
     }
+    Ok(())
 }
 
 pub fn find_tokenizer(mut name: &str) -> Result<ByteTokenizer> {
@@ -313,6 +356,117 @@ impl ByteTokenizer {
     }
 }
 
+pub struct TokenMismatch {
+    pub id: u32,
+    /// What the HF tokenizer decodes the token to.
+    pub expected: Vec<u8>,
+    /// token_bytes() of the token.
+    pub actual: Vec<u8>,
+}
+
+pub struct VerifyReport {
+    pub num_checked: usize,
+    pub mismatches: Vec<TokenMismatch>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checked {} tokens, {} mismatches",
+            self.num_checked,
+            self.mismatches.len()
+        )?;
+        for m in self.mismatches.iter().take(20) {
+            write!(
+                f,
+                "\n  {}: expected {:?} ({}), got {:?} ({})",
+                m.id,
+                String::from_utf8_lossy(&m.expected),
+                to_hex_string(&m.expected),
+                String::from_utf8_lossy(&m.actual),
+                to_hex_string(&m.actual)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl ByteTokenizer {
+    /// Check token_bytes() against what the HF tokenizer itself decodes each
+    /// token to, so that we catch both bugs in from_tokenizer(), and the HF
+    /// tokenizer changing under us.
+    ///
+    /// Tokens are decoded after an "anchor" token, so that the sentencepiece
+    /// decoder doesn't strip the leading space. Tokens that are not valid UTF-8
+    /// (e.g., <0x80>, or parts of multi-byte characters) decode to U+FFFD, so
+    /// for these the bytes are read off the token name instead: <0xNN> pieces
+    /// directly, and byte-level names through the byte decoder.
+    pub fn verify_against_hf(&self) -> Result<VerifyReport> {
+        let anchor = self
+            .token_bytes
+            .iter()
+            .position(|b| b == b"a")
+            .ok_or_else(|| anyhow!("no token for 'a'"))? as u32;
+        let decode = |ids: &[u32]| {
+            self.hf_tokenizer
+                .decode(ids, false)
+                .map_err(|e| anyhow!("can't decode: {}", e))
+        };
+        let prefix = decode(&[anchor])?;
+        let char_map = build_char_map();
+        let added = self.hf_tokenizer.get_added_tokens_decoder();
+
+        let mut report = VerifyReport {
+            num_checked: 0,
+            mismatches: Vec::new(),
+        };
+        for id in 0..self.vocab_size {
+            if self.special.values().any(|s| *s == id) {
+                continue;
+            }
+            let actual = &self.token_bytes[id as usize];
+            let expected = match added.get(&id) {
+                Some(info) => info.content.as_bytes().to_vec(),
+                None => {
+                    let decoded = decode(&[anchor, id])?;
+                    let text = decoded.strip_prefix(&prefix).unwrap_or(&decoded);
+                    if text.contains(char::REPLACEMENT_CHARACTER) {
+                        let name = self.hf_tokenizer.id_to_token(id).unwrap_or_default();
+                        name_to_bytes(&name, &char_map).unwrap_or_else(|| text.into())
+                    } else {
+                        text.into()
+                    }
+                }
+            };
+            if *actual != expected {
+                report.mismatches.push(TokenMismatch {
+                    id,
+                    expected,
+                    actual: actual.clone(),
+                });
+            }
+            report.num_checked += 1;
+        }
+        Ok(report)
+    }
+}
+
+/// Bytes of a token that doesn't decode to valid UTF-8, from its name:
+/// either a <0xNN> sentencepiece piece, or a byte-level encoded name.
+fn name_to_bytes(name: &str, char_map: &HashMap<char, u8>) -> Option<Vec<u8>> {
+    if name.len() == 6 && name.starts_with("<0x") && name.ends_with(">") {
+        return u8::from_str_radix(&name[3..5], 16).ok().map(|b| vec![b]);
+    }
+    name.chars().map(|c| char_map.get(&c).copied()).collect()
+}
+
 impl ByteTokenizer {
     pub fn tokrx_info(&self) -> TokRxInfo {
         TokRxInfo {
@@ -324,3 +478,56 @@ impl ByteTokenizer {
         self.token_bytes.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ByteTokenizer;
+    use tokenizers::Tokenizer;
+
+    /// Byte-level BPE with a token for the lone byte 0xC3 ("\u{00C3}"), which
+    /// the HF tokenizer decodes to U+FFFD.
+    const BYTE_LEVEL_JSON: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [{"id": 3, "content": "<|endoftext|>", "single_word": false,
+            "lstrip": false, "rstrip": false, "normalized": false, "special": true}],
+        "normalizer": null,
+        "pre_tokenizer": null,
+        "post_processor": null,
+        "decoder": {"type": "ByteLevel", "add_prefix_space": false,
+            "trim_offsets": true, "use_regex": true},
+        "model": {"type": "BPE", "dropout": null, "unk_token": null,
+            "continuing_subword_prefix": null, "end_of_word_suffix": null,
+            "fuse_unk": false, "byte_fallback": false,
+            "vocab": {"a": 0, "\u00c3": 1, "\u0120b": 2, "<|endoftext|>": 3},
+            "merges": []}
+    }"#;
+
+    #[test]
+    fn verify_compares_bytes() {
+        let hft: Tokenizer = BYTE_LEVEL_JSON.parse().unwrap();
+        let mut t = ByteTokenizer::from_tokenizer(hft).unwrap();
+        assert_eq!(t.token_bytes[1], vec![0xC3]);
+        let report = t.verify_against_hf().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.num_checked, 3);
+
+        // also decodes to U+FFFD, so a string comparison wouldn't see this
+        t.token_bytes[1] = vec![0xC4];
+        let report = t.verify_against_hf().unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].id, 1);
+        assert_eq!(report.mismatches[0].expected, vec![0xC3]);
+    }
+
+    /// Downloads all the tokenizers, and takes a few seconds each;
+    /// set AICIRT_SKIP_TOKENIZER_TEST=1 to skip.
+    #[test]
+    fn tokenizers_match_hf() {
+        if std::env::var("AICIRT_SKIP_TOKENIZER_TEST").is_ok() {
+            return;
+        }
+        super::test_tokenizers().unwrap();
+    }
+}
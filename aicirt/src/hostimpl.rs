@@ -6,7 +6,10 @@ use aici_abi::{
     bytes::{clone_vec_as_bytes, limit_str, vec_from_bytes, TokRxInfo},
     PostProcessArg, PreProcessArg, StorageCmd,
 };
-use aicirt::user_error;
+use aicirt::{
+    api::{add_token_bias, decode_token_bias, LOGIT_BIAS_ALLOW, LOGIT_BIAS_DISALLOW},
+    user_error,
+};
 use anyhow::{anyhow, Result};
 use std::{
     rc::Rc,
@@ -50,14 +53,13 @@ pub struct ModuleData {
     pub had_error: bool,
     pub storage_log: Vec<StorageCmd>,
     pub start_time: Instant,
+    /// Additive (token, bias) pairs from the last mid_process().
+    pub token_bias: Vec<(u32, f32)>,
     blobs: Vec<Rc<Vec<u8>>>,
 }
 
 const MAXLOG: usize = 64 * 1024;
 
-pub const LOGIT_BIAS_ALLOW: f32 = 0.0;
-pub const LOGIT_BIAS_DISALLOW: f32 = -100.0;
-
 pub struct BlobId(u32);
 
 impl BlobId {
@@ -108,6 +110,7 @@ impl ModuleData {
             had_error: false,
             storage_log: Vec::new(),
             start_time: Instant::now(),
+            token_bias: Vec::new(),
             blobs: vec![Rc::new(Vec::new()); BlobId::MAX_BLOB_ID as usize],
         };
         r.set_blob(BlobId::MODULE_ARG, module_arg.as_bytes().to_vec());
@@ -136,6 +139,12 @@ impl ModuleData {
         self.logit_ptr
             .iter_mut()
             .for_each(|x| *x = LOGIT_BIAS_DISALLOW);
+        self.token_bias.clear();
+    }
+
+    /// Add the token bias to the mask in logit_ptr; returns the number of tokens biased.
+    pub fn apply_token_bias(&mut self) -> usize {
+        add_token_bias(self.logit_ptr, &self.token_bias)
     }
 
     pub fn set_pre_process_data(&mut self, data: &PreProcessArg) {
@@ -487,6 +496,28 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         },
     )?;
 
+    // void aici_host_return_token_bias(const uint32_t *src, uint32_t num_entries);
+    // src holds num_entries pairs of (token, f32 bias bits)
+    linker.func_wrap(
+        "env",
+        "aici_host_return_token_bias",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, num_entries: u32| {
+            let numtok = caller.data().globals.tokrx_info.vocab_size;
+            if num_entries > numtok {
+                return fatal_error(&mut caller, "too many token_bias entries");
+            }
+            let m = read_caller_mem(&caller, src, 8 * num_entries);
+            let words = vec_from_bytes::<u32>(&m);
+            let (bias, invalid) = decode_token_bias(&words, numtok);
+            for (tok, val) in invalid {
+                caller
+                    .data_mut()
+                    .warn(&format!("invalid token_bias entry: {tok} {val}"));
+            }
+            caller.data_mut().token_bias = bias;
+        },
+    )?;
+
     linker.func_wrap(
         "env",
         "aici_host_self_seq_id",
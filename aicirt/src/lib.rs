@@ -28,6 +28,9 @@ pub enum LogMode {
     Normal,
     Test,
     Daemon,
+    /// One JSON object per line; messages that are JSON objects themselves
+    /// (like rllm's request events) are included as objects.
+    Json,
 }
 
 struct LimitedWrite {
@@ -97,6 +100,25 @@ fn daemon_format(
     )
 }
 
+fn json_format(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let msg = args_to_str(5000, record.args());
+    let msg = match serde_json::from_str::<serde_json::Value>(&msg) {
+        Ok(v) if v.is_object() => v,
+        _ => serde_json::Value::String(msg),
+    };
+    let line = serde_json::json!({
+        "time": now.format("%Y-%m-%dT%H:%M:%S%.3f").to_string(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "msg": msg,
+    });
+    write!(w, "{}", line)
+}
+
 pub fn init_log(mode: LogMode) -> Result<()> {
     let logger = match mode {
         LogMode::Normal => Logger::try_with_env_or_str("info")?
@@ -108,6 +130,9 @@ pub fn init_log(mode: LogMode) -> Result<()> {
         LogMode::Daemon => Logger::try_with_env_or_str("info")?
             .format(daemon_format)
             .log_to_stdout(),
+        LogMode::Json => Logger::try_with_env_or_str("info")?
+            .format(json_format)
+            .log_to_stdout(),
     };
 
     logger.start()?;
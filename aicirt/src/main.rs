@@ -1099,6 +1099,10 @@ fn save_tokenizer(cli: &Cli) {
     let tokenizer = find_tokenizer(&cli.tokenizer).unwrap();
     let tokens = tokenizer.token_bytes();
 
+    let report = tokenizer.verify_against_hf().unwrap();
+    log::info!("verify against HF: {}", report);
+    assert!(report.is_ok(), "token bytes don't match the HF tokenizer");
+
     log::info!(
         "TokTrie building: {:?} wl={}",
         tokenizer.tokrx_info(),
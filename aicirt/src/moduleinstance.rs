@@ -1,8 +1,6 @@
 use crate::{
-    api::ModuleInstId,
-    hostimpl::{
-        setup_linker, AiciLimits, GlobalInfo, ModuleData, LOGIT_BIAS_ALLOW, LOGIT_BIAS_DISALLOW,
-    },
+    api::{ModuleInstId, LOGIT_BIAS_ALLOW, LOGIT_BIAS_DISALLOW},
+    hostimpl::{setup_linker, AiciLimits, GlobalInfo, ModuleData},
     shm::Shm,
     worker::{GroupHandle, RtMidProcessArg},
     TimerSet, UserError,
@@ -309,7 +307,13 @@ impl ModuleInstance {
         self.store.data_mut().set_mid_process_data(op, shm);
         self.call_func::<WasmAici, ()>("aici_mid_process", self.handle)?;
         match self.proc_result()? {
-            MidProcessResult::SampleWithBias { .. } => Ok(None),
+            MidProcessResult::SampleWithBias { .. } => {
+                let num_biased = self.store.data_mut().apply_token_bias();
+                if num_biased > 0 {
+                    log::trace!("token_bias applied to {num_biased} tokens");
+                }
+                Ok(None)
+            }
             MidProcessResult::Stop { .. } => {
                 let eos = self.store.data().globals.tokrx_info.tok_eos;
                 self.store
@@ -31,6 +31,9 @@ extern "C" {
     // Set logit bias based on bit-mask in src.
     fn aici_host_return_logit_bias(src: *const u32);
 
+    // Additive bias for allowed tokens; src holds num_entries (token, f32 bits) pairs.
+    fn aici_host_return_token_bias(src: *const u32, num_entries: u32);
+
     fn aici_host_self_seq_id() -> u32;
 
     fn aici_host_return_process_result(res: *const u8, res_size: u32);
@@ -98,6 +101,15 @@ pub fn return_logit_bias(vob: &SimpleVob) {
     }
 }
 
+/// Soft preferences on top of the mask from SampleWithBias; call from mid_process().
+/// The bias is added to the logits of allowed tokens only, and is reset on every step.
+pub fn return_token_bias(bias: &[(TokenId, f32)]) {
+    let words: Vec<u32> = bias.iter().flat_map(|(t, b)| [*t, b.to_bits()]).collect();
+    unsafe {
+        aici_host_return_token_bias(words.as_ptr(), bias.len() as u32);
+    }
+}
+
 pub fn process_arg_bytes() -> Vec<u8> {
     return read_blob(unsafe { aici_host_process_arg() }, 1024);
 }
@@ -21,6 +21,38 @@ pub enum SpecialToken {
     EndOfSentence,
 }
 
+/// How the bytes of a token look as UTF-8, on their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Utf8Class {
+    /// Valid UTF-8 (including tokens with no bytes, like special tokens).
+    Complete = 0,
+    /// Valid UTF-8, except for an incomplete character at the end, which the
+    /// following tokens have to continue.
+    Prefix = 1,
+    /// Not valid UTF-8 on its own, e.g., starting with continuation bytes (common
+    /// in byte-fallback vocabularies); such a token can only follow a Prefix token.
+    Invalid = 2,
+}
+
+impl Utf8Class {
+    pub fn of(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(_) => Utf8Class::Complete,
+            Err(e) if e.error_len().is_none() => Utf8Class::Prefix,
+            Err(_) => Utf8Class::Invalid,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            0 => Utf8Class::Complete,
+            1 => Utf8Class::Prefix,
+            _ => Utf8Class::Invalid,
+        }
+    }
+}
+
 pub trait Recognizer {
     /// If `stack.top()` transitions via `byte` to `X`, execute `stack.push(X)`.
     fn push_byte(&mut self, byte: u8) {
@@ -59,6 +91,8 @@ pub struct TokTrie {
     nodes: Vec<TrieNode>,
     max_token_len: usize,
     token_duplicates: FxHashMap<TokenId, Vec<TokenId>>,
+    /// Utf8Class of each token, as u8.
+    utf8_classes: Vec<u8>,
 }
 
 #[repr(C)]
@@ -148,12 +182,16 @@ impl TokTrie {
             nodes,
             max_token_len: 0,
             token_duplicates: FxHashMap::default(),
+            utf8_classes: Vec::new(),
         };
         r.finalize_ctor();
         r
     }
 
     fn finalize_ctor(&mut self) {
+        self.utf8_classes = (0..self.info.vocab_size)
+            .map(|tok_id| Utf8Class::of(self.token(tok_id)) as u8)
+            .collect();
         for tok_id in 0..self.info.vocab_size {
             let bytes = self.token(tok_id);
             let tok_ids = self.greedy_tokenize(bytes);
@@ -290,6 +328,16 @@ impl TokTrie {
         &self.token_data[off..(off + len as usize)]
     }
 
+    pub fn utf8_class(&self, tok: TokenId) -> Utf8Class {
+        Utf8Class::from_u8(self.utf8_classes[tok as usize])
+    }
+
+    /// utf8_class() of every token, as u8; e.g., to mask all Invalid tokens at once
+    /// when the text so far ends at a character boundary.
+    pub fn utf8_classes(&self) -> &[u8] {
+        &self.utf8_classes
+    }
+
     pub fn decode(&self, tokens: &[TokenId]) -> Vec<u8> {
         tokens
             .iter()
@@ -384,6 +432,7 @@ impl TokTrie {
             nodes,
             max_token_len: 0,
             token_duplicates: FxHashMap::default(),
+            utf8_classes: Vec::new(),
         };
         r.finalize_ctor();
         r
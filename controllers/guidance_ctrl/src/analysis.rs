@@ -0,0 +1,135 @@
+// Structure of a grammar, for quick feedback to its authors; no model is involved.
+//
+// Byte lengths are exact; token counts are bounds computed from the tokenizer
+// (greedy tokenization of literals, or max_token_len for free text).
+
+use crate::earley::{Grammar, Parser, SymbolStructure};
+use crate::progress::Warning;
+use aici_abi::toktree::TokTrie;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GrammarReport {
+    /// False if the grammar can't produce any text; see `unproductive`.
+    pub satisfiable: bool,
+    #[serde(flatten)]
+    pub size: SizeReport,
+    /// Text forced at the start of the output, before the model samples anything.
+    pub forced_prefix: ForcedText,
+    /// Parts of the grammar that always produce the same text.
+    pub literals: Vec<ForcedText>,
+    pub captures: Vec<CaptureReport>,
+    /// Names of the rules that can't produce any text.
+    pub unproductive: Vec<String>,
+    pub warnings: Vec<Warning>,
+}
+
+/// Missing max_* means unbounded (recursive rules); missing min_* means
+/// nothing can be produced. Model variables count as no bytes or tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SizeReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ForcedText {
+    /// Grammar symbol producing the text; missing for forced_prefix.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    pub str: String,
+    /// Greedy tokenization; the model may use a different one.
+    pub num_tokens: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CaptureReport {
+    pub name: String,
+    #[serde(flatten)]
+    pub size: SizeReport,
+    /// Set when the capture always holds the same text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub literal: Option<String>,
+}
+
+/// Analyze `grammar` (as built from the guidance protobuf, before optimize()).
+pub fn analyze_grammar(grammar: &Grammar, trie: &TokTrie) -> GrammarReport {
+    let structure = grammar.analyze();
+    let satisfiable = structure.start.min_bytes.is_some();
+
+    let forced_prefix = if satisfiable {
+        let mut parser = Parser::new(grammar.optimize().compile());
+        parser.force_bytes()
+    } else {
+        Vec::new()
+    };
+
+    GrammarReport {
+        satisfiable,
+        size: size_report(&structure.start, trie),
+        forced_prefix: forced_text(None, &forced_prefix, trie),
+        literals: structure
+            .literals
+            .iter()
+            .map(|(name, text)| forced_text(Some(name.clone()), text, trie))
+            .collect(),
+        captures: structure
+            .captures
+            .iter()
+            .map(|(name, s)| CaptureReport {
+                name: name.clone(),
+                size: size_report(s, trie),
+                literal: s
+                    .literal
+                    .as_ref()
+                    .map(|t| String::from_utf8_lossy(t).to_string()),
+            })
+            .collect(),
+        unproductive: structure.unproductive,
+        warnings: grammar
+            .warnings()
+            .into_iter()
+            .map(|w| Warning {
+                code: w.code.to_string(),
+                message: w.message,
+            })
+            .collect(),
+    }
+}
+
+fn size_report(s: &SymbolStructure, trie: &TokTrie) -> SizeReport {
+    let (min_tokens, max_tokens) = match &s.literal {
+        Some(text) => {
+            let n = trie.greedy_tokenize(text).len();
+            (Some(n), Some(n))
+        }
+        None => {
+            let max_len = std::cmp::max(trie.max_token_len(), 1);
+            // every byte is at least a (byte) token
+            (
+                s.min_bytes.map(|n| (n + max_len - 1) / max_len),
+                s.max_bytes,
+            )
+        }
+    };
+    SizeReport {
+        min_bytes: s.min_bytes,
+        max_bytes: s.max_bytes,
+        min_tokens,
+        max_tokens,
+    }
+}
+
+fn forced_text(symbol: Option<String>, text: &[u8], trie: &TokTrie) -> ForcedText {
+    ForcedText {
+        symbol,
+        str: String::from_utf8_lossy(text).to_string(),
+        num_tokens: trie.greedy_tokenize(text).len(),
+    }
+}
@@ -0,0 +1,65 @@
+use aici_abi::toktree::{Recognizer, SpecialToken};
+
+/// Runs a Recognizer for at most `budget` bytes of the token trie walk. After
+/// that, every byte is accepted without asking the recognizer, so the rest of
+/// the trie is allowed: the mask is a superset of the exact one (nothing that
+/// was already rejected is allowed), and sampled tokens have to be checked.
+pub struct MaskBudget<'a, R: Recognizer> {
+    inner: &'a mut R,
+    budget: usize,
+    used: usize,
+    /// Bytes accepted without asking `inner`, on top of its stack.
+    unchecked_depth: usize,
+}
+
+impl<'a, R: Recognizer> MaskBudget<'a, R> {
+    pub fn new(inner: &'a mut R, budget: usize) -> Self {
+        MaskBudget {
+            inner,
+            budget,
+            used: 0,
+            unchecked_depth: 0,
+        }
+    }
+
+    /// Whether the budget ran out, so that the mask is only a superset.
+    pub fn exhausted(&self) -> bool {
+        self.used >= self.budget
+    }
+}
+
+impl<'a, R: Recognizer> Recognizer for MaskBudget<'a, R> {
+    fn pop_bytes(&mut self, num: usize) {
+        let unchecked = std::cmp::min(num, self.unchecked_depth);
+        self.unchecked_depth -= unchecked;
+        self.inner.pop_bytes(num - unchecked);
+    }
+
+    fn collapse(&mut self) {
+        assert!(self.unchecked_depth == 0);
+        self.inner.collapse()
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.inner.special_allowed(tok)
+    }
+
+    fn trie_started(&mut self) {
+        self.inner.trie_started()
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.unchecked_depth == 0);
+        self.inner.trie_finished()
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.exhausted() {
+            self.unchecked_depth += 1;
+            true
+        } else {
+            self.used += 1;
+            self.inner.try_push_byte(byte)
+        }
+    }
+}
@@ -65,8 +65,9 @@ impl NodeProps {
                 panic!("None function type in guidance::Grammar")
             }
         };
-        if r.max_tokens >= 1_000_000 {
+        if r.max_tokens >= 1_000_000 || r.max_tokens <= 0 {
             // guidance is very liberal with unspecified max_tokens, sometimes it's 10m, sometimes it's 100m
+            // (and 0 is the protobuf default)
             r.max_tokens = i32::MAX;
         }
         r
@@ -113,6 +114,15 @@ pub fn earley_grm_from_guidance(bytes: &[u8]) -> Result<Grammar> {
                 _ => None,
             };
             let props = NodeProps::from_grammar_function(&n.function_type);
+            if props.hidden && !props.commit_point {
+                grm.add_warning(
+                    "hidden_without_commit",
+                    format!(
+                        "hidden is only supported on commit points; ignored on {:?}",
+                        props.name
+                    ),
+                );
+            }
             let sym_props = props.to_symbol_props();
             let name = sym_props.capture_name.as_ref().unwrap_or(&props.name);
             // println!("props: {:?}", props);
@@ -117,11 +117,43 @@ impl SymName {
     }
 }
 
+/// Non-fatal problem with a grammar; `code` is a stable identifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarWarning {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// What Grammar::analyze() finds.
+#[derive(Debug, Clone)]
+pub struct GrammarStructure {
+    pub start: SymbolStructure,
+    /// Symbols, reachable from the start, that can't produce any text
+    /// (empty byte sets, no rules, or only rules using such symbols).
+    pub unproductive: Vec<String>,
+    /// (capture name, structure); a name may repeat.
+    pub captures: Vec<(String, SymbolStructure)>,
+    /// (symbol name, text) of symbols that always produce the same text,
+    /// and aren't part of a longer such symbol.
+    pub literals: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SymbolStructure {
+    /// Shortest text in bytes; None if the symbol can't produce any text.
+    pub min_bytes: Option<usize>,
+    /// Longest text in bytes; None if unbounded (the symbol is recursive).
+    pub max_bytes: Option<usize>,
+    /// The only text the symbol produces, if so.
+    pub literal: Option<Vec<u8>>,
+}
+
 pub struct Grammar {
     symbols: Vec<Symbol>,
     symbol_by_name: FxHashMap<String, SymIdx>,
     byte_terminals: FxHashMap<ByteSet, SymIdx>,
     model_variables: FxHashMap<String, SymIdx>,
+    warnings: Vec<GrammarWarning>,
 }
 
 impl Grammar {
@@ -131,11 +163,289 @@ impl Grammar {
             symbol_by_name: FxHashMap::default(),
             byte_terminals: FxHashMap::default(),
             model_variables: FxHashMap::default(),
+            warnings: vec![],
         };
         let _ = r.symbol("_start");
         r
     }
 
+    /// Record a warning found while building the grammar; duplicates are dropped.
+    pub fn add_warning(&mut self, code: &'static str, message: String) {
+        let w = GrammarWarning { code, message };
+        if !self.warnings.contains(&w) {
+            self.warnings.push(w);
+        }
+    }
+
+    /// Warnings recorded while building the grammar, and ones found by looking at it.
+    /// Call before optimize(), which drops unreachable symbols.
+    pub fn warnings(&self) -> Vec<GrammarWarning> {
+        let mut r = self.warnings.clone();
+
+        let reachable = self.reachable();
+        let unreachable = self
+            .symbols
+            .iter()
+            .filter(|s| !s.is_terminal() && !reachable[s.idx.0 as usize])
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        if !unreachable.is_empty() {
+            r.push(GrammarWarning {
+                code: "unreachable_symbol",
+                message: format!(
+                    "{} symbol(s) can't be reached from the start symbol: {}",
+                    unreachable.len(),
+                    unreachable
+                        .iter()
+                        .take(10)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            });
+        }
+
+        let mut captures = FxHashMap::default();
+        for sym in &self.symbols {
+            if let Some(name) = &sym.props.capture_name {
+                *captures.entry(name.as_str()).or_insert(0) += 1;
+            }
+        }
+        let mut dups = captures
+            .into_iter()
+            .filter(|(_, n)| *n > 1)
+            .collect::<Vec<_>>();
+        dups.sort();
+        for (name, n) in dups {
+            r.push(GrammarWarning {
+                code: "duplicate_capture",
+                message: format!("capture name {name:?} is used by {n} different symbols"),
+            });
+        }
+
+        r
+    }
+
+    fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.symbols.len()];
+        let mut todo = vec![self.start()];
+        reachable[self.start().0 as usize] = true;
+        while let Some(sym) = todo.pop() {
+            for rule in &self.sym_data(sym).rules {
+                for s in &rule.rhs {
+                    if !reachable[s.0 as usize] {
+                        reachable[s.0 as usize] = true;
+                        todo.push(*s);
+                    }
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Lengths and literal text of the start symbol and captures, and the symbols
+    /// that can't produce any text. Like warnings(), call before optimize().
+    pub fn analyze(&self) -> GrammarStructure {
+        let n = self.symbols.len();
+        let reachable = self.reachable();
+
+        // shortest text of each symbol; None if it can't produce any
+        let mut min_bytes: Vec<Option<usize>> = self
+            .symbols
+            .iter()
+            .map(|s| match &s.bytes {
+                Some(b) if b.num_bytes() > 0 => Some(1),
+                Some(_) => None,
+                None if s.is_model_variable() => Some(0),
+                None => None,
+            })
+            .collect();
+        loop {
+            let mut changed = false;
+            for sym in &self.symbols {
+                for rule in &sym.rules {
+                    let len = rule
+                        .rhs
+                        .iter()
+                        .map(|s| min_bytes[s.0 as usize])
+                        .sum::<Option<usize>>();
+                    let curr = &mut min_bytes[sym.idx.0 as usize];
+                    if let Some(len) = len {
+                        if curr.map_or(true, |m| len < m) {
+                            *curr = Some(len);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        let productive = |r: &Rule| r.rhs.iter().all(|s| min_bytes[s.0 as usize].is_some());
+
+        // longest text; symbols that can reach themselves (via productive rules) are unbounded
+        let mut max_bytes: Vec<Option<usize>> = vec![None; n];
+        let mut unbounded = vec![false; n];
+        let mut state = vec![0u8; n]; // 0 - not visited, 1 - on the stack, 2 - done
+        let children = |i: usize| {
+            let mut ch = self.symbols[i]
+                .rules
+                .iter()
+                .filter(|r| productive(r))
+                .flat_map(|r| r.rhs.iter().map(|s| s.0 as usize))
+                .collect::<Vec<_>>();
+            ch.sort();
+            ch.dedup();
+            ch
+        };
+        for root in 0..n {
+            if state[root] != 0 || min_bytes[root].is_none() {
+                continue;
+            }
+            state[root] = 1;
+            let mut stack = vec![(root, children(root), 0)];
+            while !stack.is_empty() {
+                let top = stack.len() - 1;
+                let (sym, pos) = (stack[top].0, stack[top].2);
+                if pos < stack[top].1.len() {
+                    let child = stack[top].1[pos];
+                    stack[top].2 += 1;
+                    match state[child] {
+                        0 => {
+                            state[child] = 1;
+                            stack.push((child, children(child), 0));
+                        }
+                        1 => {
+                            let from = stack.iter().position(|e| e.0 == child).unwrap();
+                            for e in &stack[from..] {
+                                unbounded[e.0] = true;
+                            }
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                stack.pop();
+                state[sym] = 2;
+                let s = &self.symbols[sym];
+                if s.is_terminal() {
+                    max_bytes[sym] = min_bytes[sym];
+                    continue;
+                }
+                if unbounded[sym] {
+                    continue;
+                }
+                let mut max = 0;
+                for rule in s.rules.iter().filter(|r| productive(r)) {
+                    let len = rule
+                        .rhs
+                        .iter()
+                        .map(|s| max_bytes[s.0 as usize])
+                        .sum::<Option<usize>>();
+                    match len {
+                        Some(len) => max = std::cmp::max(max, len),
+                        None => unbounded[sym] = true,
+                    }
+                }
+                if !unbounded[sym] {
+                    max_bytes[sym] = Some(max);
+                }
+            }
+        }
+
+        // text of symbols that always produce the same bytes
+        let mut literal: Vec<Option<Vec<u8>>> = self
+            .symbols
+            .iter()
+            .map(|s| {
+                s.bytes
+                    .as_ref()
+                    .and_then(|b| b.single_byte())
+                    .map(|b| vec![b])
+            })
+            .collect();
+        loop {
+            let mut changed = false;
+            for sym in &self.symbols {
+                if literal[sym.idx.0 as usize].is_some() || sym.is_terminal() {
+                    continue;
+                }
+                let rules = sym
+                    .rules
+                    .iter()
+                    .filter(|r| productive(r))
+                    .collect::<Vec<_>>();
+                if rules.len() != 1 || rules[0].rhs.is_empty() {
+                    continue;
+                }
+                let text = rules[0]
+                    .rhs
+                    .iter()
+                    .map(|s| literal[s.0 as usize].clone())
+                    .collect::<Option<Vec<_>>>();
+                if let Some(text) = text {
+                    literal[sym.idx.0 as usize] = Some(text.concat());
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let structure = |i: usize| SymbolStructure {
+            min_bytes: min_bytes[i],
+            max_bytes: max_bytes[i],
+            literal: literal[i].clone(),
+        };
+
+        // literals that aren't part of a longer one
+        let mut in_larger = vec![false; n];
+        for sym in &self.symbols {
+            if literal[sym.idx.0 as usize].is_some() {
+                for rule in &sym.rules {
+                    for s in &rule.rhs {
+                        in_larger[s.0 as usize] = true;
+                    }
+                }
+            }
+        }
+        let literals = self
+            .symbols
+            .iter()
+            .filter(|s| {
+                let i = s.idx.0 as usize;
+                reachable[i] && !s.is_terminal() && !in_larger[i] && literal[i].is_some()
+            })
+            .map(|s| (s.name.clone(), literal[s.idx.0 as usize].clone().unwrap()))
+            .collect();
+
+        let captures = self
+            .symbols
+            .iter()
+            .filter(|s| reachable[s.idx.0 as usize])
+            .filter_map(|s| {
+                let name = s.props.capture_name.as_ref()?;
+                Some((name.clone(), structure(s.idx.0 as usize)))
+            })
+            .collect();
+
+        let unproductive = self
+            .symbols
+            .iter()
+            .filter(|s| reachable[s.idx.0 as usize] && min_bytes[s.idx.0 as usize].is_none())
+            .map(|s| s.name.clone())
+            .collect();
+
+        GrammarStructure {
+            start: structure(self.start().0 as usize),
+            unproductive,
+            captures,
+            literals,
+        }
+    }
+
     pub fn start(&self) -> SymIdx {
         self.symbols[0].idx
     }
@@ -167,6 +477,24 @@ impl Grammar {
         }
     }
 
+    /// Names of the model variables used in the grammar, sorted.
+    pub fn model_variable_names(&self) -> Vec<String> {
+        let mut names = self.model_variables.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn capture_names(&self) -> Vec<String> {
+        let mut names = self
+            .symbols
+            .iter()
+            .filter_map(|s| s.props.capture_name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+
     pub fn terminal(&mut self, bytes: &ByteSet) -> SymIdx {
         match self.byte_terminals.get(bytes) {
             Some(sym) => *sym,
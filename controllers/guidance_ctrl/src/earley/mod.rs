@@ -1,13 +1,17 @@
+mod budget;
 mod byteset;
 mod from_guidance;
 mod grammar;
 mod parser;
 
+pub use budget::MaskBudget;
 pub use byteset::ByteSet;
 pub use from_guidance::earley_grm_from_guidance;
 #[allow(unused_imports)]
-pub use grammar::{Grammar, ModelVariable};
-pub use parser::{Parser, ParseResult};
+pub use grammar::{
+    Grammar, GrammarStructure, GrammarWarning, ModelVariable, SymIdx, SymbolProps, SymbolStructure,
+};
+pub use parser::{Parser, ParseResult, Stats};
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod bench;
@@ -1,6 +1,8 @@
 use std::{fmt::Debug, hash::Hash, ops::Range, vec};
 
 use aici_abi::toktree::{Recognizer, SpecialToken};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
 
 use super::grammar::{CGrammar, CSymIdx, ModelVariable, RuleIdx, SimpleHash};
 
@@ -31,13 +33,78 @@ struct Item {
     data: u64,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Stats {
     pub rows: usize,
     pub empty_rows: usize,
     pub nontrivial_scans: usize,
     pub scan_items: usize,
     pub all_items: usize,
+    /// High-water marks: Earley items in one row, and bytes held by the item and row arrays.
+    pub max_items_per_row: usize,
+    pub arena_bytes: usize,
+
+    /// Number of token masks computed, and the smallest/total number of allowed tokens in them.
+    pub masks: usize,
+    pub allowed_tokens_min: usize,
+    pub allowed_tokens_sum: usize,
+    /// Tokens forced by the grammar vs. sampled by the model.
+    pub forced_tokens: usize,
+    pub sampled_tokens: usize,
+    /// Masks that ran out of mask_budget, and sampled tokens these let through
+    /// that turned out not to match the grammar (and were sampled again).
+    pub mask_fallbacks: usize,
+    pub rejected_tokens: usize,
+}
+
+impl Stats {
+    pub fn allowed_tokens_avg(&self) -> f64 {
+        if self.masks == 0 {
+            0.0
+        } else {
+            self.allowed_tokens_sum as f64 / self.masks as f64
+        }
+    }
+
+    /// One-line summary, for logging.
+    pub fn summary(&self) -> String {
+        format!(
+            "rows: {}, items: {} (max {}/row), arena: {}k, masks: {} (avg {:.1} allowed), \
+             tokens: {} forced + {} sampled",
+            self.rows,
+            self.all_items,
+            self.max_items_per_row,
+            self.arena_bytes / 1024,
+            self.masks,
+            self.allowed_tokens_avg(),
+            self.forced_tokens,
+            self.sampled_tokens
+        )
+    }
+
+    /// Counters accumulated since `prev`; saturates if they were reset in between.
+    pub fn delta(&self, prev: &Stats) -> Stats {
+        Stats {
+            rows: self.rows.saturating_sub(prev.rows),
+            empty_rows: self.empty_rows.saturating_sub(prev.empty_rows),
+            nontrivial_scans: self.nontrivial_scans.saturating_sub(prev.nontrivial_scans),
+            scan_items: self.scan_items.saturating_sub(prev.scan_items),
+            all_items: self.all_items.saturating_sub(prev.all_items),
+            // high-water marks aren't counters either
+            max_items_per_row: self.max_items_per_row,
+            arena_bytes: self.arena_bytes,
+            masks: self.masks.saturating_sub(prev.masks),
+            // the minimum isn't a counter; keep the current one
+            allowed_tokens_min: self.allowed_tokens_min,
+            allowed_tokens_sum: self
+                .allowed_tokens_sum
+                .saturating_sub(prev.allowed_tokens_sum),
+            forced_tokens: self.forced_tokens.saturating_sub(prev.forced_tokens),
+            sampled_tokens: self.sampled_tokens.saturating_sub(prev.sampled_tokens),
+            mask_fallbacks: self.mask_fallbacks.saturating_sub(prev.mask_fallbacks),
+            rejected_tokens: self.rejected_tokens.saturating_sub(prev.rejected_tokens),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -157,16 +224,38 @@ struct RowInfo {
     commit_item: Item,
 }
 
+pub struct Capture {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    /// Position of `bytes` in get_bytes(); None if they were since hidden.
+    pub span: Option<Range<usize>>,
+}
+
+/// A symbol with max_tokens that is being parsed at the current position.
+pub struct LimitedRegion {
+    pub sym: CSymIdx,
+    pub name: String,
+    /// Row where the symbol started, i.e., its offset in get_bytes().
+    pub start: usize,
+    pub max_tokens: usize,
+}
+
 pub struct Parser {
     grammar: CGrammar,
     scratch: Scratch,
-    captures: Vec<(String, Vec<u8>)>,
+    captures: Vec<Capture>,
+    /// capture name -> indices into `captures`, in order
+    capture_idxs: FxHashMap<String, Vec<usize>>,
+    /// (offset in get_bytes(), bytes) removed by hide_item(), oldest first
+    hidden_spans: Vec<(usize, Vec<u8>)>,
     rows: Vec<Row>,
     row_infos: Vec<RowInfo>,
     stats: Stats,
     is_accepting: bool,
     last_collapse: usize,
     speculative: bool,
+    /// Model variables that are single tokens, advanced over with scan_model_variable().
+    token_variables: Vec<ModelVariable>,
 }
 
 impl Scratch {
@@ -216,11 +305,14 @@ impl Parser {
             rows: vec![],
             row_infos: vec![],
             captures: vec![],
+            capture_idxs: FxHashMap::default(),
+            hidden_spans: vec![],
             scratch: Scratch::default(),
             stats: Stats::default(),
             is_accepting: false,
             last_collapse: 0,
             speculative: false,
+            token_variables: vec![],
         };
         for rule in r.grammar.rules_of(start).to_vec() {
             r.scratch.add_unique(Item::new(rule, 0), &r.grammar, "init");
@@ -234,6 +326,27 @@ impl Parser {
         self.is_accepting
     }
 
+    /// True when the input is accepted and nothing can follow it: no terminal
+    /// and no model variable other than EOS is expected in the current row.
+    pub fn only_termination_viable(&self) -> bool {
+        if !self.is_accepting {
+            return false;
+        }
+        let eos = ModelVariable::SpecialToken(SpecialToken::EndOfSentence);
+        for i in self.curr_row().item_indices() {
+            let item = self.scratch.items[i];
+            let sym = self.grammar.sym_idx_at(item.rule_idx());
+            if self.grammar.is_terminal(sym) {
+                return false;
+            }
+            match &self.grammar.sym_data(sym).props.model_variable {
+                Some(mv) if *mv != eos => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+
     fn item_to_string(&self, item: &Item) -> String {
         item_to_string(&self.grammar, item)
     }
@@ -255,6 +368,35 @@ impl Parser {
         // self.rows.drain(self.rows.len() - n..);
     }
 
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Record a token mask with `num_allowed` tokens computed for the current position.
+    pub fn record_mask(&mut self, num_allowed: usize) {
+        let st = &mut self.stats;
+        st.allowed_tokens_min = if st.masks == 0 {
+            num_allowed
+        } else {
+            std::cmp::min(st.allowed_tokens_min, num_allowed)
+        };
+        st.masks += 1;
+        st.allowed_tokens_sum += num_allowed;
+    }
+
+    pub fn record_tokens(&mut self, forced: usize, sampled: usize) {
+        self.stats.forced_tokens += forced;
+        self.stats.sampled_tokens += sampled;
+    }
+
+    pub fn record_mask_fallback(&mut self) {
+        self.stats.mask_fallbacks += 1;
+    }
+
+    pub fn record_rejected_token(&mut self) {
+        self.stats.rejected_tokens += 1;
+    }
+
     #[allow(dead_code)]
     pub fn print_stats(&mut self) {
         println!("stats: {:?}", self.stats);
@@ -267,6 +409,16 @@ impl Parser {
         self.row_infos.iter().skip(1).map(|ri| ri.byte).collect()
     }
 
+    /// get_bytes() with the hidden bytes put back.
+    pub fn get_raw_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.get_bytes();
+        // undo the hides, most recent first
+        for (pos, hidden) in self.hidden_spans.iter().rev() {
+            bytes.splice(*pos..*pos, hidden.iter().cloned());
+        }
+        bytes
+    }
+
     pub fn force_bytes(&mut self) -> Vec<u8> {
         assert!(!self.speculative);
         let mut bytes = vec![];
@@ -281,6 +433,22 @@ impl Parser {
         bytes
     }
 
+    /// Length of the longest prefix of `bytes` that the parser accepts, without advancing it.
+    pub fn valid_prefix_len(&mut self, bytes: &[u8]) -> usize {
+        assert!(!self.speculative);
+        self.speculative = true;
+        let mut n = 0;
+        for b in bytes {
+            if self.scan(*b) == ParseResult::Reject {
+                break;
+            }
+            n += 1;
+        }
+        self.pop_rows(n);
+        self.speculative = false;
+        n
+    }
+
     fn curr_row(&self) -> &Row {
         &self.rows[self.rows.len() - 1]
     }
@@ -300,6 +468,37 @@ impl Parser {
         vars
     }
 
+    /// Descriptions of what can come next: byte classes of the expected terminals,
+    /// and model variables (as `M:name`). At most `limit` entries, plus "..." if truncated.
+    pub fn expected_terminals(&self, limit: usize) -> Vec<String> {
+        let mut r = vec![];
+        for i in self.curr_row().item_indices() {
+            let item = self.scratch.items[i];
+            let sym = self.grammar.sym_idx_at(item.rule_idx());
+            let desc = if self.grammar.is_terminal(sym) {
+                format!("{}", self.grammar.terminal_byteset(sym))
+            } else if let Some(mv) = &self.grammar.sym_data(sym).props.model_variable {
+                format!("M:{}", mv.to_string())
+            } else {
+                continue;
+            };
+            if !r.contains(&desc) {
+                if r.len() >= limit {
+                    r.push("...".to_string());
+                    break;
+                }
+                r.push(desc);
+            }
+        }
+        r
+    }
+
+    /// Model variables that stand for a single token the model can sample;
+    /// bytes are not forced where one of them can come next.
+    pub fn set_token_variables(&mut self, vars: Vec<ModelVariable>) {
+        self.token_variables = vars;
+    }
+
     fn forced_byte(&self) -> Option<u8> {
         if self.is_accepting {
             // we're not forced when in accepting state
@@ -310,6 +509,11 @@ impl Parser {
         for i in self.curr_row().item_indices() {
             let item = self.scratch.items[i];
             let sym = self.grammar.sym_idx_at(item.rule_idx());
+            if let Some(mv) = &self.grammar.sym_data(sym).props.model_variable {
+                if self.token_variables.contains(mv) {
+                    return None;
+                }
+            }
             if self.grammar.is_terminal(sym) {
                 if self.grammar.is_single_byte_terminal(sym) {
                     if byte_sym == None || byte_sym == Some(sym) {
@@ -332,14 +536,164 @@ impl Parser {
         }
     }
 
-    pub fn hide_item(&mut self, sym: CSymIdx, row_idx: usize) -> ParseResult {
+    /// Regions with max_tokens still open at the current position, outermost first.
+    pub fn limited_regions(&self) -> Vec<LimitedRegion> {
+        let mut r: Vec<LimitedRegion> = vec![];
+        for i in self.curr_row().item_indices() {
+            let item = self.scratch.items[i];
+            if self.grammar.sym_idx_at(item.rule_idx()) == CSymIdx::NULL {
+                continue;
+            }
+            let sym = self.grammar.sym_idx_of(item.rule_idx());
+            let sym_data = self.grammar.sym_data(sym);
+            if sym_data.props.max_tokens == usize::MAX
+                || r.iter()
+                    .any(|lr| lr.sym == sym && lr.start == item.start_pos())
+            {
+                continue;
+            }
+            r.push(LimitedRegion {
+                sym,
+                name: sym_data.name.clone(),
+                start: item.start_pos(),
+                max_tokens: sym_data.props.max_tokens,
+            });
+        }
+        r.sort_by_key(|lr| lr.start);
+        r
+    }
+
+    /// End `region` at the current position, as if its rules allowed it: continue with
+    /// what follows the region, and drop the parses still inside it.
+    /// Commit points and hiding don't apply to a region ended this way.
+    pub fn close_region(&mut self, region: &LimitedRegion) -> ParseResult {
+        assert!(!self.speculative);
+        let curr_idx = self.num_rows() - 1;
+        info!(
+            "close_region: {} {}..{}",
+            region.name, region.start, curr_idx
+        );
+
+        let mut items_to_add = vec![];
+        for i in self.rows[region.start].item_indices() {
+            let item = self.scratch.items[i];
+            if self.grammar.sym_idx_at(item.rule_idx()) == region.sym {
+                items_to_add.push(item.advance_dot());
+            }
+        }
+        // parses that started before the region and don't complete here (completions
+        // were already handled when the row was built)
+        for i in self.curr_row().item_indices() {
+            let item = self.scratch.items[i];
+            if item.start_pos() < region.start
+                && self.grammar.sym_idx_at(item.rule_idx()) != CSymIdx::NULL
+            {
+                items_to_add.push(item);
+            }
+        }
+
+        if let Some(name) = &self.grammar.sym_data(region.sym).props.capture_name {
+            let bytes = self.row_infos[region.start + 1..=curr_idx]
+                .iter()
+                .map(|ri| ri.byte)
+                .collect::<Vec<_>>();
+            self.capture_idxs
+                .entry(name.clone())
+                .or_default()
+                .push(self.captures.len());
+            self.captures.push(Capture {
+                name: name.clone(),
+                bytes,
+                span: Some(region.start..curr_idx),
+            });
+        }
+
+        let agenda_ptr = self.curr_row().first_item;
+        let byte = self.row_infos[curr_idx].byte;
+        self.pop_rows(1);
+        self.scratch.new_row(agenda_ptr);
+        for item in items_to_add {
+            self.scratch.add_unique(item, &self.grammar, "close");
+        }
+        self.push_row(agenda_ptr, byte)
+    }
+
+    /// Advance over model variable `mv` (a token with no bytes in the grammar) at the
+    /// current position; parses where it can't come next are dropped.
+    pub fn scan_model_variable(&mut self, mv: &ModelVariable) -> ParseResult {
+        assert!(!self.speculative);
+        let curr_idx = self.num_rows() - 1;
+        let row_items = self
+            .curr_row()
+            .item_indices()
+            .map(|i| self.scratch.items[i])
+            .collect::<Vec<_>>();
+        let mut items_to_add = vec![];
+        for item in &row_items {
+            let sym = self.grammar.sym_idx_at(item.rule_idx());
+            if self.grammar.sym_data(sym).props.model_variable.as_ref() == Some(mv) {
+                items_to_add.push(item.advance_dot());
+            }
+        }
+        if items_to_add.is_empty() {
+            return ParseResult::Reject;
+        }
+        // push_row() only completes items that started in earlier rows;
+        // the ones predicted in the replaced row are completed here
+        let mut i = 0;
+        while i < items_to_add.len() {
+            let item = items_to_add[i];
+            i += 1;
+            if item.start_pos() != curr_idx
+                || self.grammar.sym_idx_at(item.rule_idx()) != CSymIdx::NULL
+            {
+                continue;
+            }
+            let lhs = self.grammar.sym_idx_of(item.rule_idx());
+            for other in &row_items {
+                let next = other.advance_dot();
+                if self.grammar.sym_idx_at(other.rule_idx()) == lhs && !items_to_add.contains(&next)
+                {
+                    items_to_add.push(next);
+                }
+            }
+        }
+        info!("scan_model_variable: {} at {}", mv.to_string(), curr_idx);
+
+        // the current row is replaced, so that no byte is added
+        let agenda_ptr = self.curr_row().first_item;
+        let byte = self.row_infos[curr_idx].byte;
+        self.pop_rows(1);
+        self.scratch.new_row(agenda_ptr);
+        for item in items_to_add {
+            self.scratch
+                .add_unique(item, &self.grammar, "model_variable");
+        }
+        self.push_row(agenda_ptr, byte)
+    }
+
+    /// `byte` is the one being scanned, which completed the hidden item.
+    pub fn hide_item(&mut self, sym: CSymIdx, row_idx: usize, byte: u8) -> ParseResult {
         info!("hide_item: {} {}", self.grammar.sym_data(sym).name, row_idx);
 
         let row_range = self.rows[row_idx].item_indices();
         let agenda_ptr = row_range.start;
+        let mut hidden = self.row_infos[row_idx + 1..self.num_rows()]
+            .iter()
+            .map(|ri| ri.byte)
+            .collect::<Vec<_>>();
+        hidden.push(byte);
+        self.hidden_spans.push((row_idx, hidden));
         self.pop_rows(self.num_rows() - row_idx);
         assert!(self.num_rows() == row_idx);
 
+        // bytes past row_idx are no longer part of get_bytes()
+        for cap in self.captures.iter_mut() {
+            if cap.span.as_ref().map_or(false, |s| s.end > row_idx) {
+                cap.span = None;
+            }
+        }
+
         let mut items_to_add = vec![];
         for idx in row_range {
             let item = self.scratch.items[idx];
@@ -386,10 +740,64 @@ impl Parser {
         self.push_row(self.scratch.row_start, b)
     }
 
-    pub fn captures(&self) -> &[(String, Vec<u8>)] {
+    pub fn captures(&self) -> &[Capture] {
         &self.captures
     }
 
+    /// Whether `cap` may still be extended: the parser is inside a parse of a capture
+    /// symbol with the same name, which started where `cap` did. False for captures
+    /// in hidden text, which was committed.
+    pub fn capture_in_progress(&self, cap: &Capture) -> bool {
+        let start = match &cap.span {
+            Some(span) => span.start,
+            None => return false,
+        };
+        // (symbol, start row) of the parses in progress, from the current row outwards
+        let mut todo = vec![];
+        for i in self.curr_row().item_indices() {
+            let item = self.scratch.items[i];
+            if self.grammar.sym_idx_at(item.rule_idx()) != CSymIdx::NULL {
+                todo.push((self.grammar.sym_idx_of(item.rule_idx()), item.start_pos()));
+            }
+        }
+        let mut seen = vec![];
+        while let Some((sym, pos)) = todo.pop() {
+            // parses that started earlier can't be inside the capture
+            if pos < start || seen.contains(&(sym, pos)) {
+                continue;
+            }
+            seen.push((sym, pos));
+            if pos == start
+                && self.grammar.sym_data(sym).props.capture_name.as_ref() == Some(&cap.name)
+            {
+                return true;
+            }
+            for i in self.rows[pos].item_indices() {
+                let item = self.scratch.items[i];
+                if self.grammar.sym_idx_at(item.rule_idx()) == sym {
+                    todo.push((self.grammar.sym_idx_of(item.rule_idx()), item.start_pos()));
+                }
+            }
+        }
+        false
+    }
+
+    /// Indices into captures() of all values captured under `name`, oldest first.
+    pub fn capture_indices(&self, name: &str) -> &[usize] {
+        self.capture_idxs.get(name).map_or(&[], |v| v.as_slice())
+    }
+
+    /// Latest value for every capture name.
+    pub fn captures_map(&self) -> FxHashMap<&str, &[u8]> {
+        self.capture_idxs
+            .iter()
+            .map(|(name, idxs)| {
+                let idx = *idxs.last().unwrap();
+                (name.as_str(), self.captures[idx].bytes.as_slice())
+            })
+            .collect()
+    }
+
     #[inline(always)]
     fn push_row(&mut self, mut agenda_ptr: usize, byte: u8) -> ParseResult {
         let curr_idx = self.rows.len();
@@ -427,7 +835,16 @@ impl Parser {
                         .map(|ri| ri.byte)
                         .collect::<Vec<_>>();
                     bytes.push(byte);
-                    self.captures.push((var_name.clone(), bytes));
+                    // row N is reached after scanning byte N-1 of get_bytes()
+                    self.capture_idxs
+                        .entry(var_name.clone())
+                        .or_default()
+                        .push(self.captures.len());
+                    self.captures.push(Capture {
+                        name: var_name.clone(),
+                        bytes,
+                        span: Some(item.start_pos()..curr_idx),
+                    });
                 }
 
                 if flags.commit_point() {
@@ -449,7 +866,7 @@ impl Parser {
                     commit_item = item;
                     debug!("commit point: {}", self.item_to_string(&item));
                     if !self.speculative && flags.hidden() {
-                        return self.hide_item(lhs, item.start_pos());
+                        return self.hide_item(lhs, item.start_pos(), byte);
                     }
                 }
 
@@ -480,6 +897,13 @@ impl Parser {
 
         let row_len = self.scratch.row_len();
         self.stats.all_items += row_len;
+        self.stats.max_items_per_row = std::cmp::max(self.stats.max_items_per_row, row_len);
+        self.stats.arena_bytes = std::cmp::max(
+            self.stats.arena_bytes,
+            self.scratch.items.capacity() * std::mem::size_of::<Item>()
+                + self.rows.capacity() * std::mem::size_of::<Row>()
+                + self.row_infos.capacity() * std::mem::size_of::<RowInfo>(),
+        );
 
         if row_len == 0 {
             assert!(!self.is_accepting);
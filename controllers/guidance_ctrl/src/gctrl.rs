@@ -1,16 +1,54 @@
 use aici_abi::{
-    arg_bytes, bytes::to_hex_string, tokenize_bytes, toktree::TokTrie, AiciCtrl, MidProcessArg,
-    MidProcessResult, PostProcessArg, PostProcessResult, PreProcessArg, PreProcessResult, TokenId,
+    arg_bytes,
+    bytes::to_hex_string,
+    svob::SimpleVob,
+    toktree::{Recognizer, SpecialToken, TokTrie},
+    AiciCtrl, MidProcessArg, MidProcessResult, PostProcessArg, PostProcessResult, PreProcessArg,
+    PreProcessResult, TokenId,
 };
+use analysis::{analyze_grammar, GrammarReport};
+use anyhow::{bail, Result};
 use base64::{self, Engine as _};
-use earley::{earley_grm_from_guidance, Parser};
+use earley::{
+    earley_grm_from_guidance, Grammar, GrammarWarning, MaskBudget, ModelVariable, Parser, Stats,
+};
+use progress::{
+    serialize_ndjson, AllCaptures, Capture, CaptureItem, FfTokens, FinalText, Limit, Mask,
+    ParserError, Progress, ProgressItem, Retract, StatsReport, StopReason, StopReasonKind, Usage,
+    Warning,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    time::{Duration, Instant},
+};
 
 use crate::earley::ParseResult;
 
+mod analysis;
 mod earley;
+mod progress;
 mod serialization;
+#[cfg(test)]
+mod tests;
+
+/// Calls into the host; the tests run without one, and use tests::host instead.
+#[cfg(not(test))]
+mod host {
+    pub use aici_abi::{return_token_bias, tokenize, tokenize_bytes};
+
+    pub fn print_progress(line: &str) {
+        print!("JSON-OUT: {}", line);
+    }
+
+    pub fn read_var(name: &str) -> Option<Vec<u8>> {
+        aici_abi::VariableStorage::new().get(name)
+    }
+}
+#[cfg(test)]
+use tests::host;
 
 const INFO: bool = true;
 
@@ -26,13 +64,227 @@ pub struct Runner {
     toktrie: TokTrie,
     parser: Parser,
     llm_tokens: Vec<TokenId>,
+    /// For each of llm_tokens, whether the model sampled it (or the grammar forced it).
+    is_sampled: Vec<bool>,
     is_ff: bool,
     reported_captures: usize,
+    capture_mode: CaptureMode,
+    stats_mode: StatsMode,
+    prev_stats: Stats,
+    stop_reason: Option<StopReasonKind>,
+    json_captures: Vec<String>,
+    pending_warnings: Vec<GrammarWarning>,
+    /// Printed on the first step (report.grammar).
+    grammar_report: Option<GrammarReport>,
+    raw_text: bool,
+    report: ReportConfig,
+    /// Tokens of llm_tokens forced before the first sampled token.
+    prompt_tokens: usize,
+    token_bias: Vec<(TokenId, f32)>,
+    /// Biased tokens that were allowed in the last mask.
+    num_biased: usize,
+    token_healing: bool,
+    /// Model variables that stand for a single token, and the tokens.
+    token_vars: Vec<(ModelVariable, TokenId)>,
+    /// (offset in the parser's bytes, token) for each of token_vars sampled so far.
+    sampled_token_vars: Vec<(usize, TokenId)>,
+    /// Masks printed so far (report.mask).
+    reported_masks: usize,
+    mask_budget: Option<usize>,
+    /// The last mask ran out of mask_budget, so the sampled token needs checking.
+    mask_fallback: bool,
+    /// (offset in the parser's bytes, token) for sampled tokens that didn't match
+    /// the grammar there; these are left out of later masks at that offset.
+    rejected_tokens: Vec<(usize, TokenId)>,
+    milestones: Vec<String>,
+    /// Captures (indices into the parser's captures()) already checked for milestones.
+    checked_captures: usize,
+    /// Milestone captures (indices as above) that may still be extended.
+    pending_milestones: Vec<usize>,
+    /// When to abort(), from time_limit_ms.
+    deadline: Option<Instant>,
+    append_var: Option<String>,
+    /// Bytes of append_var already in the sequence.
+    appended_len: usize,
 }
 
 #[derive(Serialize, Deserialize)]
 struct RunnerArg {
     guidance_b64: String,
+    #[serde(default)]
+    capture_mode: CaptureMode,
+    #[serde(default)]
+    stats: StatsMode,
+    /// Names of captures that hold JSON; these are reported with the parsed `value`.
+    #[serde(default)]
+    json_captures: Vec<String>,
+    /// Also report the text including hidden parts in final_text.
+    #[serde(default)]
+    raw_text: bool,
+    #[serde(default)]
+    report: ReportConfig,
+    /// Added to the logits of the listed tokens, when the grammar allows them;
+    /// negative values discourage a token without forbidding it.
+    /// Keys are strings that tokenize to a single token, or token ids as "[123]".
+    #[serde(default)]
+    token_bias: HashMap<String, f32>,
+    /// Force the exact tokenization of forced text, instead of leaving its last
+    /// token(s) for the model to generate when they could be extended (token healing).
+    #[serde(default)]
+    disable_token_healing: bool,
+    /// Model variables of the grammar that stand for a single (special) token, which
+    /// the model may sample where the grammar has them, e.g. {"tool_call": "<|python_tag|>"}.
+    /// Values are token text, or token ids as "[123]". Model variables named like
+    /// special tokens ("<|python_tag|>") are resolved without being listed here.
+    #[serde(default)]
+    special_tokens: HashMap<String, String>,
+    /// Bytes of the token trie to check against the grammar when computing a mask.
+    /// When exceeded, the rest of the trie is allowed unchecked, and a sampled token
+    /// that doesn't match the grammar is taken back and sampled again.
+    #[serde(default)]
+    mask_budget: Option<usize>,
+    /// Names of captures that end the generation as soon as they complete and can't be
+    /// extended anymore (e.g., digits once a non-digit follows), with stop reason
+    /// "milestone", even if the grammar could go on. The grammar has
+    /// no nested scopes, so a milestone always ends the whole sequence, also when
+    /// the capture is in a hidden region (whose text stays out of final_text).
+    #[serde(default)]
+    milestones: Vec<String>,
+    /// Stop the sequence with stop reason "aborted" at the first step after this many
+    /// milliseconds (counted from when the controller starts), with the text so far.
+    #[serde(default)]
+    time_limit_ms: Option<u64>,
+    /// Name of a variable (in the host's variable storage) that others append text to,
+    /// e.g. tool output; what was appended since the last step goes into the sequence
+    /// as forced text. Text the grammar doesn't allow stops the sequence with parser_error.
+    #[serde(default)]
+    append_var: Option<String>,
+}
+
+/// Which text fields go into the progress objects; the defaults include all of them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+struct ReportConfig {
+    include_str: bool,
+    /// `hex` is still included when `str` is left out or can't represent the bytes exactly.
+    include_hex: bool,
+    /// Truncate the text of longer captures to this many bytes.
+    max_capture_bytes: Option<usize>,
+    /// List the token ids in ff_tokens.
+    include_tokens: bool,
+    /// Only stream the captures with these names; a name ending in `*` matches
+    /// as a prefix. Other captures are still recorded, and keep their ids.
+    capture_filter: Option<Vec<String>>,
+    /// Print the latest value of every capture (filtered or not) when the
+    /// sequence stops.
+    all_captures: bool,
+    /// Print the token mask of each step; for offline analysis of the grammar.
+    mask: Option<MaskReportConfig>,
+    /// Print a grammar_report object (see analysis.rs) on the first step.
+    grammar: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+struct MaskReportConfig {
+    /// List at most this many of the allowed token ids, lowest first.
+    max_ids: usize,
+    /// Include the whole mask as a bitmap instead of the ids.
+    bitmap: bool,
+    /// Stop after this many masks, to bound the output of long sequences.
+    max_masks: usize,
+}
+
+impl Default for MaskReportConfig {
+    fn default() -> Self {
+        MaskReportConfig {
+            max_ids: 64,
+            bitmap: false,
+            max_masks: 256,
+        }
+    }
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        ReportConfig {
+            include_str: true,
+            include_hex: true,
+            max_capture_bytes: None,
+            include_tokens: false,
+            capture_filter: None,
+            all_captures: false,
+            mask: None,
+            grammar: false,
+        }
+    }
+}
+
+impl ReportConfig {
+    /// The `str` and `hex` fields for `bytes`.
+    fn text(&self, bytes: &[u8]) -> (Option<String>, Option<String>) {
+        let str = if self.include_str {
+            Some(String::from_utf8_lossy(bytes).to_string())
+        } else {
+            None
+        };
+        let hex = if self.include_hex || str.is_none() || std::str::from_utf8(bytes).is_err() {
+            Some(to_hex_string(bytes))
+        } else {
+            None
+        };
+        (str, hex)
+    }
+
+    fn streams_capture(&self, name: &str) -> bool {
+        match &self.capture_filter {
+            Some(filter) => filter.iter().any(|f| match f.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == f,
+            }),
+            None => true,
+        }
+    }
+
+    fn capture_item(&self, bytes: &[u8]) -> CaptureItem {
+        let (bytes, full_len) = match self.max_capture_bytes {
+            Some(max) if bytes.len() > max => (&bytes[..max], Some(bytes.len())),
+            _ => (bytes, None),
+        };
+        let (str, hex) = self.text(bytes);
+        CaptureItem {
+            str,
+            hex,
+            truncated: full_len.is_some(),
+            full_len,
+        }
+    }
+}
+
+/// How to report a capture name that matches more than once (e.g., inside a repetition).
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum CaptureMode {
+    /// Report each capture when it completes; the latest one for a name wins.
+    #[default]
+    Latest,
+    /// Like Latest, but number the reports with `seq`, so clients can keep all of them, in order.
+    All,
+    /// Report all values captured so far under the name in `list`.
+    List,
+}
+
+/// When to print parser stats; unless Off, the cumulative stats are also printed when
+/// the sequence stops.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum StatsMode {
+    #[default]
+    Off,
+    /// Stats accumulated since the previous step, on every step.
+    DeltaEveryCall,
+    /// Only the cumulative stats, when the sequence stops.
+    FinalCumulative,
 }
 
 impl Runner {
@@ -42,41 +294,543 @@ impl Runner {
             .decode(arg.guidance_b64)
             .expect("invalid base64");
         let grm = earley_grm_from_guidance(&guidance).expect("invalid guidance protobuf");
+        Self::from_grammar(arg, grm, TokTrie::from_host())
+    }
+
+    /// The controller for `grm`, as built from arg.guidance_b64 (before optimize()).
+    fn from_grammar(arg: RunnerArg, grm: Grammar, toktrie: TokTrie) -> Self {
         infoln!("original: {:?}", grm);
+        let mut pending_warnings = grm.warnings();
+        let token_vars = resolve_token_vars(
+            &toktrie,
+            &grm.model_variable_names(),
+            &arg.special_tokens,
+            &mut pending_warnings,
+        );
+        let capture_names = grm.capture_names();
+        for name in &arg.milestones {
+            if !capture_names.contains(name) {
+                pending_warnings.push(GrammarWarning {
+                    code: "milestone",
+                    message: format!("milestones: no capture named {:?}", name),
+                });
+            }
+        }
+        let grammar_report = if arg.report.grammar {
+            Some(analyze_grammar(&grm, &toktrie))
+        } else {
+            None
+        };
         let grm = grm.optimize();
         infoln!("optimized: {:?}", grm);
         let cgrm = grm.compile();
-        let parser = Parser::new(cgrm);
+        let mut parser = Parser::new(cgrm);
+        parser.set_token_variables(token_vars.iter().map(|(mv, _)| mv.clone()).collect());
+        let token_bias = resolve_token_bias(&toktrie, &arg.token_bias, &mut pending_warnings);
         Runner {
-            toktrie: TokTrie::from_host(),
+            toktrie,
             parser,
             llm_tokens: Vec::new(),
+            is_sampled: Vec::new(),
             is_ff: false,
             reported_captures: 0,
+            capture_mode: arg.capture_mode,
+            stats_mode: arg.stats,
+            prev_stats: Stats::default(),
+            stop_reason: None,
+            json_captures: arg.json_captures,
+            pending_warnings,
+            grammar_report,
+            raw_text: arg.raw_text,
+            report: arg.report,
+            prompt_tokens: 0,
+            token_bias,
+            num_biased: 0,
+            token_healing: !arg.disable_token_healing,
+            token_vars,
+            sampled_token_vars: Vec::new(),
+            reported_masks: 0,
+            mask_budget: arg.mask_budget,
+            mask_fallback: false,
+            rejected_tokens: Vec::new(),
+            milestones: arg.milestones,
+            checked_captures: 0,
+            pending_milestones: Vec::new(),
+            deadline: arg
+                .time_limit_ms
+                .map(|ms| Instant::now() + Duration::from_millis(ms)),
+            append_var: arg.append_var,
+            appended_len: 0,
+        }
+    }
+
+    fn set_stop_reason(&mut self, reason: StopReasonKind, detail: serde_json::Value) {
+        if self.stop_reason.is_some() {
+            return;
+        }
+        self.stop_reason = Some(reason);
+        infoln!("parser stats: {}", self.parser.stats().summary());
+        let mut items = vec![ProgressItem::FinalText(self.final_text())];
+        if self.report.all_captures {
+            items.push(ProgressItem::AllCaptures(self.all_captures()));
+        }
+        if self.stats_mode != StatsMode::Off {
+            let stats = self.stats_report(self.parser.stats().clone(), true);
+            items.push(ProgressItem::Stats(stats));
+        }
+        items.extend([
+            ProgressItem::Usage(self.usage()),
+            ProgressItem::StopReason(StopReason { reason, detail }),
+        ]);
+        for item in items {
+            self.emit(item);
+        }
+    }
+
+    /// Stop now (e.g., on an upstream timeout), and report the text and captures so far.
+    /// Nothing is forced to complete the grammar, so the text may be cut anywhere.
+    pub fn abort(&mut self, reason: &str) -> MidProcessResult {
+        let _ = self.scan_sampled();
+        self.stop(StopReasonKind::Aborted, json!({ "message": reason }))
+    }
+
+    /// Advance the parser over `bytes` that come from outside the model (e.g., a tool
+    /// output appended to append_var mid-run). The next mid_process splices them in,
+    /// and reports them as ff_tokens (and in usage.ff_tokens), like text forced by the grammar.
+    /// If the grammar doesn't allow them, the sequence is stopped with parser_error
+    /// pointing at the first rejected byte.
+    pub fn append_forced_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.stop_reason.is_some() {
+            bail!("sequence already stopped");
+        }
+        if let Some(b) = self.scan_sampled() {
+            let _ = self.parser_error(&[b]);
+            bail!("sampled byte {:?} doesn't match the grammar", b as char);
+        }
+        let valid = self.parser.valid_prefix_len(bytes);
+        if valid < bytes.len() {
+            for b in &bytes[..valid] {
+                let _ = self.parser.scan(*b);
+            }
+            let _ = self.parser_error(&bytes[valid..]);
+            bail!(
+                "appended bytes don't match the grammar after {} bytes",
+                valid
+            );
+        }
+        for b in bytes {
+            let _ = self.parser.scan(*b);
+        }
+        Ok(())
+    }
+
+    /// What was appended to append_var since the last call, if anything.
+    fn read_appended(&mut self) -> Option<Vec<u8>> {
+        let value = host::read_var(self.append_var.as_ref()?)?;
+        if value.len() <= self.appended_len {
+            return None;
+        }
+        let bytes = value[self.appended_len..].to_vec();
+        self.appended_len = value.len();
+        Some(bytes)
+    }
+
+    /// Scan the bytes sampled since the last mid_process; returns the first byte the
+    /// parser rejects, if any.
+    fn scan_sampled(&mut self) -> Option<u8> {
+        let llm_bytes = self.toktrie.decode(&self.llm_tokens);
+        let parsed = self.parser.get_bytes();
+        if llm_bytes.starts_with(&parsed) {
+            for b in &llm_bytes[parsed.len()..] {
+                if self.parser.scan(*b) == ParseResult::Reject {
+                    return Some(*b);
+                }
+            }
+        }
+        None
+    }
+
+    /// Stop with StopReasonKind::ParserError, after reporting where and why the parse failed.
+    fn parser_error(&mut self, rejected: &[u8]) -> MidProcessResult {
+        let bytes = self.parser.get_bytes();
+        let offset = bytes.len();
+        let context = &bytes[offset.saturating_sub(32)..];
+        let err = ParserError {
+            offset,
+            context_hex: to_hex_string(context),
+            rejected_hex: to_hex_string(rejected),
+            expected: self.parser.expected_terminals(20),
+        };
+        self.emit(ProgressItem::ParserError(err));
+        self.stop(
+            StopReasonKind::ParserError,
+            json!({ "rejected_hex": to_hex_string(rejected), "byte_offset": offset }),
+        )
+    }
+
+    /// The last sampled token was let through by a fallback mask, and the grammar
+    /// rejected it after `num_scanned` of its bytes; take it back and sample again.
+    fn reject_sampled(&mut self, num_scanned: usize) -> MidProcessResult {
+        self.parser.pop_bytes(num_scanned);
+        self.report_retract(self.llm_tokens.len() - 1);
+        let token = self.llm_tokens.pop().unwrap();
+        self.is_sampled.pop();
+        infoln!("rejected sampled token: {}", self.toktrie.token_dbg(token));
+        let offset = self.parser.get_bytes().len();
+        self.rejected_tokens.retain(|(off, _)| *off == offset);
+        self.rejected_tokens.push((offset, token));
+        self.parser.record_rejected_token();
+        self.is_ff = true;
+        self.report_step_stats();
+        MidProcessResult::Splice {
+            backtrack: 1,
+            ff_tokens: vec![],
+        }
+    }
+
+    /// The first milestone capture that completed and can't be extended anymore, if any.
+    fn reached_milestone(&mut self) -> Option<String> {
+        let captures = self.parser.captures();
+        for idx in self.checked_captures..captures.len() {
+            if self.milestones.contains(&captures[idx].name) {
+                self.pending_milestones.push(idx);
+            }
+        }
+        self.checked_captures = captures.len();
+        // captures of bytes since taken back (e.g., by reject_sampled()) don't count
+        let bytes = self.parser.get_bytes();
+        self.pending_milestones.retain(|idx| {
+            let c = &captures[*idx];
+            c.span.as_ref().map_or(true, |s| {
+                s.end <= bytes.len() && bytes[s.clone()] == c.bytes[..]
+            })
+        });
+        let idx = self
+            .pending_milestones
+            .iter()
+            .find(|idx| !self.parser.capture_in_progress(&captures[**idx]))?;
+        Some(captures[*idx].name.clone())
+    }
+
+    fn stop(&mut self, reason: StopReasonKind, detail: serde_json::Value) -> MidProcessResult {
+        self.report_captures();
+        self.set_stop_reason(reason, detail);
+        MidProcessResult::Stop
+    }
+
+    /// Tokenize `bytes` (a prefix of the parser's bytes), with the tokens of sampled
+    /// token_vars put back where they were sampled.
+    fn tokenize_fixed(&self, bytes: &[u8]) -> Vec<TokenId> {
+        let mut tokens = Vec::new();
+        let mut prev = 0;
+        for (off, t) in &self.sampled_token_vars {
+            if *off > bytes.len() {
+                break;
+            }
+            if *off > prev {
+                tokens.extend(host::tokenize_bytes(&bytes[prev..*off]));
+            }
+            tokens.push(*t);
+            prev = *off;
+        }
+        if prev < bytes.len() {
+            tokens.extend(host::tokenize_bytes(&bytes[prev..]));
+        }
+        tokens
+    }
+
+    /// The model sampled `token`; advance the parser if it's one of token_vars.
+    fn scan_token_var(&mut self, token: TokenId) {
+        let mv = match self.token_vars.iter().find(|(_, t)| *t == token) {
+            Some((mv, _)) => mv.clone(),
+            None => return,
+        };
+        let _ = self.scan_sampled();
+        if self.parser.scan_model_variable(&mv) == ParseResult::Reject {
+            infoln!("token {} not allowed here", self.toktrie.token_dbg(token));
+            return;
+        }
+        self.sampled_token_vars
+            .push((self.parser.get_bytes().len(), token));
+    }
+
+    /// Range of indices into `llm_tokens` of the tokens covering `span` of parser bytes,
+    /// if they are all there yet.
+    fn token_span(&self, span: &Range<usize>) -> Option<Range<usize>> {
+        let mut start = None;
+        let mut off = 0;
+        for (idx, t) in self.llm_tokens.iter().enumerate() {
+            let end = off + self.toktrie.token(*t).len();
+            if start.is_none() && end > span.start {
+                start = Some(idx);
+            }
+            if end >= span.end {
+                let start = start.unwrap_or(idx);
+                return Some(start..std::cmp::max(start, idx + 1));
+            }
+            off = end;
+        }
+        None
+    }
+
+    /// Number of tokens with bytes at or past `pos` in the parser's bytes.
+    fn tokens_since(&self, pos: usize) -> usize {
+        let mut off = 0;
+        let mut n = 0;
+        for t in &self.llm_tokens {
+            off += self.toktrie.token(*t).len();
+            if off > pos {
+                n += 1;
+            }
+        }
+        n
+    }
+
+    /// End the grammar regions that used up their max_tokens; true if any was ended
+    /// (or the parser failed doing so, and the sequence was stopped).
+    fn close_spent_regions(&mut self) -> bool {
+        let mut closed = false;
+        loop {
+            let spent = self.parser.limited_regions().into_iter().find_map(|r| {
+                let consumed = self.tokens_since(r.start);
+                if consumed >= r.max_tokens {
+                    Some((r, consumed))
+                } else {
+                    None
+                }
+            });
+            let (region, consumed) = match spent {
+                Some(s) => s,
+                None => return closed,
+            };
+            closed = true;
+            self.emit(ProgressItem::Limit(Limit {
+                scope: region.name.clone(),
+                limit: region.max_tokens,
+                consumed,
+            }));
+            if self.parser.close_region(&region) == ParseResult::Reject {
+                let message = format!("nothing can follow {} after max_tokens", region.name);
+                let _ = self.stop(StopReasonKind::ParserError, json!({ "message": message }));
+                return true;
+            }
+        }
+    }
+
+    /// Report removal of `llm_tokens[keep..]`, if any.
+    fn report_retract(&self, keep: usize) {
+        let removed = &self.llm_tokens[keep..];
+        if removed.is_empty() {
+            return;
+        }
+        let r = Retract {
+            num_tokens: removed.len(),
+            num_bytes: self.toktrie.decode(removed).len(),
+        };
+        self.emit(ProgressItem::Retract(r));
+    }
+
+    fn final_text(&self) -> FinalText {
+        let bytes = self.parser.get_bytes();
+        let (str, hex) = self.report.text(&bytes);
+        let usage = self.usage();
+        FinalText {
+            str,
+            hex,
+            raw_hex: if self.raw_text {
+                Some(to_hex_string(&self.parser.get_raw_bytes()))
+            } else {
+                None
+            },
+            num_tokens: usage.total_tokens,
+            num_sampled_tokens: usage.sampled_tokens,
+            num_ff_tokens: usage.prompt_tokens + usage.ff_tokens,
+        }
+    }
+
+    fn emit(&self, item: ProgressItem) {
+        let line = serialize_ndjson(&[Progress::from(item)]);
+        host::print_progress(&line);
+    }
+
+    fn report_warnings(&mut self) {
+        for w in std::mem::take(&mut self.pending_warnings) {
+            let w = Warning {
+                code: w.code.to_string(),
+                message: w.message,
+            };
+            self.emit(ProgressItem::Warning(w));
+        }
+    }
+
+    /// `healed_bytes` of the forced text were left for the model to generate.
+    fn report_ff_tokens(&self, ff_tokens: &[TokenId], healed_bytes: usize) {
+        let bytes = self.toktrie.decode(ff_tokens);
+        let (str, hex) = self.report.text(&bytes);
+        let ff = FfTokens {
+            str,
+            hex,
+            num_tokens: ff_tokens.len(),
+            tokens: if self.report.include_tokens {
+                Some(ff_tokens.to_vec())
+            } else {
+                None
+            },
+            healed_bytes: if healed_bytes > 0 {
+                Some(healed_bytes)
+            } else {
+                None
+            },
+        };
+        self.emit(ProgressItem::FfTokens(ff));
+    }
+
+    fn report_mask(&mut self, set: &SimpleVob) {
+        let cfg = self.report.mask.as_ref().unwrap();
+        if self.reported_masks >= cfg.max_masks {
+            return;
+        }
+        self.reported_masks += 1;
+        let vocab_size = self.toktrie.vocab_size();
+        let allowed = (0..vocab_size as TokenId).filter(|t| set.is_allowed(*t));
+        let step = self.parser.stats().masks - 1;
+        let allowed_count = set.num_set();
+        let mask = if cfg.bitmap {
+            let mut bits = vec![0u8; (vocab_size + 7) / 8];
+            for t in allowed {
+                bits[t as usize / 8] |= 1 << (t % 8);
+            }
+            Mask {
+                step,
+                allowed_count,
+                ids: None,
+                bitmap: Some(base64::engine::general_purpose::STANDARD.encode(&bits)),
+                vocab_size: Some(vocab_size),
+            }
+        } else {
+            let ids: Vec<TokenId> = allowed.take(cfg.max_ids).collect();
+            Mask {
+                step,
+                allowed_count,
+                ids: Some(ids),
+                bitmap: None,
+                vocab_size: None,
+            }
+        };
+        self.emit(ProgressItem::Mask(mask));
+    }
+
+    /// Report stats accumulated since the last call, in StatsMode::DeltaEveryCall.
+    fn report_step_stats(&mut self) {
+        if self.stats_mode != StatsMode::DeltaEveryCall {
+            return;
+        }
+        let stats = self.parser.stats().clone();
+        let delta = stats.delta(&self.prev_stats);
+        self.prev_stats = stats;
+        self.emit(ProgressItem::Stats(self.stats_report(delta, false)));
+    }
+
+    fn stats_report(&self, stats: Stats, cumulative: bool) -> StatsReport {
+        StatsReport {
+            cumulative,
+            allowed_tokens_avg: stats.allowed_tokens_avg(),
+            stats,
+            biased_tokens: if cumulative || self.token_bias.is_empty() {
+                None
+            } else {
+                Some(self.num_biased)
+            },
+        }
+    }
+
+    fn usage(&self) -> Usage {
+        let sampled_tokens = self.is_sampled.iter().filter(|s| **s).count();
+        let total_tokens = self.llm_tokens.len();
+        Usage {
+            prompt_tokens: self.prompt_tokens,
+            sampled_tokens,
+            ff_tokens: total_tokens - sampled_tokens - self.prompt_tokens,
+            total_tokens,
         }
     }
 
     fn report_captures(&mut self) {
-        let captures = &self.parser.captures()[self.reported_captures..];
-        for (name, val) in captures {
+        while self.reported_captures < self.parser.captures().len() {
+            let idx = self.reported_captures;
             self.reported_captures += 1;
-            let cap = Capture {
-                object: "capture",
-                name: name.clone(),
-                str: String::from_utf8_lossy(val).to_string(),
-                hex: to_hex_string(val),
-            };
-            println!("JSON-OUT: {}", serde_json::to_string(&cap).unwrap());
+            if self.is_streamed(idx) {
+                let cap = self.capture(idx);
+                self.emit(ProgressItem::Capture(cap));
+            }
         }
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct Capture {
-    object: &'static str, // "capture"
-    name: String,
-    str: String,
-    hex: String,
+    /// Whether the parser's capture number `seq` passes the capture_filter.
+    fn is_streamed(&self, seq: usize) -> bool {
+        self.report
+            .streams_capture(&self.parser.captures()[seq].name)
+    }
+
+    /// The latest value of every capture name, streamed or not.
+    pub fn all_captures(&self) -> AllCaptures {
+        AllCaptures {
+            captures: self
+                .parser
+                .captures_map()
+                .into_iter()
+                .map(|(name, bytes)| (name.to_string(), self.report.capture_item(bytes)))
+                .collect(),
+        }
+    }
+
+    /// The report of the parser's capture number `seq`, which is also its id.
+    fn capture(&self, seq: usize) -> Capture {
+        let c = &self.parser.captures()[seq];
+        let item = self.report.capture_item(&c.bytes);
+        let list = if self.capture_mode == CaptureMode::List {
+            let all = self.parser.captures();
+            Some(
+                self.parser
+                    .capture_indices(&c.name)
+                    .iter()
+                    .take_while(|idx| **idx <= seq)
+                    .map(|idx| self.report.capture_item(&all[*idx].bytes))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let tokens = c.span.as_ref().and_then(|s| self.token_span(s));
+        let (value, parse_error) = if self.json_captures.contains(&c.name) {
+            match serde_json::from_slice::<serde_json::Value>(&c.bytes) {
+                Ok(v) => (Some(v), None),
+                Err(e) => (None, Some(e.to_string())),
+            }
+        } else {
+            (None, None)
+        };
+        Capture {
+            id: seq,
+            name: c.name.clone(),
+            str: item.str,
+            hex: item.hex,
+            truncated: item.truncated,
+            full_len: item.full_len,
+            seq: if self.capture_mode == CaptureMode::All {
+                Some(seq)
+            } else {
+                None
+            },
+            list,
+            start_byte: c.span.as_ref().map(|s| s.start),
+            end_byte: c.span.as_ref().map(|s| s.end),
+            start_token: tokens.as_ref().map(|t| t.start),
+            end_token: tokens.as_ref().map(|t| t.end),
+            value,
+            parse_error,
+        }
+    }
 }
 
 impl AiciCtrl for Runner {
@@ -84,15 +838,40 @@ impl AiciCtrl for Runner {
         PreProcessResult::continue_()
     }
 
-    fn mid_process(&mut self, _arg: MidProcessArg) -> MidProcessResult {
-        let start_time = std::time::Instant::now();
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        let start_time = Instant::now();
+        self.report_warnings();
+        if let Some(report) = self.grammar_report.take() {
+            self.emit(ProgressItem::GrammarReport(report));
+        }
+        if self.deadline.is_some_and(|d| start_time >= d) {
+            return self.abort("time_limit_ms exceeded");
+        }
+        if let Some(bytes) = self.read_appended() {
+            if let Err(e) = self.append_forced_bytes(&bytes) {
+                infoln!("append_var: {}", e);
+                return MidProcessResult::Stop;
+            }
+        }
         let _ = self.parser.force_bytes();
         let fixed_bytes = self.parser.get_bytes();
-        let mut fixed_tokens = tokenize_bytes(&fixed_bytes);
+        let mut fixed_tokens = self.tokenize_fixed(&fixed_bytes);
+        let eos = self.toktrie.special_token(SpecialToken::EndOfSentence);
         let mut suff = Vec::new();
         let mut chop_tokens = 0;
         let mut chop_bytes = 0;
-        for (idx, t) in fixed_tokens.iter().rev().enumerate() {
+        // token healing: drop trailing tokens whose bytes could be part of a longer
+        // token, and let the model generate these bytes (and possibly more)
+        let max_heal = if self.token_healing {
+            fixed_tokens.len()
+        } else {
+            0
+        };
+        for (idx, t) in fixed_tokens.iter().rev().take(max_heal).enumerate() {
+            // never heal across special tokens
+            if *t == eos || self.toktrie.token(*t).is_empty() {
+                break;
+            }
             suff.splice(0..0, self.toktrie.token(*t).iter().cloned());
             if suff.len() > self.toktrie.max_token_len() {
                 break;
@@ -114,9 +893,19 @@ impl AiciCtrl for Runner {
                     self.toktrie.tokens_dbg(&ff_tokens),
                 );
                 infoln!("fixed_tokens: {:?}", self.toktrie.tokens_dbg(&fixed_tokens));
+                self.report_retract(idx);
+                self.report_ff_tokens(&ff_tokens, chop_bytes);
+                self.prompt_tokens = self.prompt_tokens.min(idx);
+                if self.parser.stats().sampled_tokens == 0 {
+                    self.prompt_tokens += ff_tokens.len();
+                }
+                self.parser.record_tokens(ff_tokens.len(), 0);
+                self.is_sampled.truncate(idx);
+                self.is_sampled.resize(fixed_tokens.len(), false);
                 self.llm_tokens = fixed_tokens;
                 self.is_ff = true;
                 self.report_captures();
+                self.report_step_stats();
                 return MidProcessResult::Splice {
                     backtrack,
                     ff_tokens,
@@ -127,15 +916,19 @@ impl AiciCtrl for Runner {
         let llm_bytes = self.toktrie.decode(&self.llm_tokens[fixed_tokens.len()..]);
         let byte_suffix = fixed_bytes[fixed_bytes.len() - chop_bytes..].to_vec();
 
+        let mask_fallback = std::mem::take(&mut self.mask_fallback);
         let byte_suffix = if byte_suffix.len() <= llm_bytes.len() {
             if !llm_bytes.starts_with(&byte_suffix) {
                 panic!("llm_bytes: {:?}, byte_suffix: {:?}", llm_bytes, byte_suffix);
             }
 
-            for b in &llm_bytes[byte_suffix.len()..] {
+            for (idx, b) in llm_bytes[byte_suffix.len()..].iter().enumerate() {
                 let r = self.parser.scan(*b);
                 if r == ParseResult::Reject {
-                    panic!("rejected byte: {}", b);
+                    if mask_fallback {
+                        return self.reject_sampled(idx);
+                    }
+                    return self.parser_error(&[*b]);
                 }
             }
             vec![]
@@ -146,13 +939,61 @@ impl AiciCtrl for Runner {
             byte_suffix[llm_bytes.len()..].to_vec()
         };
 
+        if let Some(name) = self.reached_milestone() {
+            infoln!("milestone: {}", name);
+            return self.stop(StopReasonKind::Milestone, json!({ "capture": name }));
+        }
+
+        if byte_suffix.is_empty() && self.close_spent_regions() {
+            if self.stop_reason.is_some() {
+                return MidProcessResult::Stop;
+            }
+            // what follows the closed region may be forced; start over
+            return self.mid_process(arg);
+        }
+
+        // no need to compute the mask when it could only contain EOS
+        if byte_suffix.is_empty() && self.parser.only_termination_viable() {
+            infoln!("only termination viable");
+            return self.stop(StopReasonKind::ParserAccepted, json!(null));
+        }
+
         // self.parser.print_row(self.parser.num_rows() - 1);
 
         self.is_ff = false;
 
         let mut set = self.toktrie.alloc_token_set();
-        self.toktrie
-            .compute_bias_ext(&mut self.parser, &mut set, &byte_suffix);
+        if let Some(budget) = self.mask_budget {
+            let mut r = MaskBudget::new(&mut self.parser, budget);
+            self.toktrie
+                .compute_bias_ext(&mut r, &mut set, &byte_suffix);
+            self.mask_fallback = r.exhausted();
+        } else {
+            self.toktrie
+                .compute_bias_ext(&mut self.parser, &mut set, &byte_suffix);
+        }
+        if self.mask_fallback {
+            infoln!("mask budget exhausted; sampled token will be checked");
+            self.parser.record_mask_fallback();
+        }
+        for (off, t) in &self.rejected_tokens {
+            if *off == fixed_bytes.len() {
+                set.disallow_token(*t);
+            }
+        }
+        // token_vars have no bytes, so the trie never allows them
+        if byte_suffix.is_empty() && !self.token_vars.is_empty() {
+            let vars = self.parser.model_variables();
+            for (mv, t) in &self.token_vars {
+                if vars.contains(mv) {
+                    set.allow_token(*t);
+                }
+            }
+        }
+        self.parser.record_mask(set.num_set());
+        if self.report.mask.is_some() {
+            self.report_mask(&set);
+        }
         infoln!(
             "bias: (pref: {:?}) {:?} {}",
             String::from_utf8_lossy(&byte_suffix),
@@ -160,7 +1001,26 @@ impl AiciCtrl for Runner {
             self.toktrie.token_set_dbg(&set)
         );
 
+        if set.num_set() == 0 {
+            // no token can continue the text, possibly including byte_suffix
+            return self.parser_error(&[]);
+        } else if set.num_set() == 1 && set.is_allowed(eos) && self.parser.is_accepting() {
+            return self.stop(StopReasonKind::ParserAccepted, json!(null));
+        }
+
+        if !self.token_bias.is_empty() {
+            let bias: Vec<_> = self
+                .token_bias
+                .iter()
+                .filter(|(t, _)| set.is_allowed(*t))
+                .cloned()
+                .collect();
+            self.num_biased = bias.len();
+            host::return_token_bias(&bias);
+        }
+
         self.report_captures();
+        self.report_step_stats();
 
         MidProcessResult::SampleWithBias {
             allowed_tokens: set,
@@ -174,11 +1034,95 @@ impl AiciCtrl for Runner {
             self.toktrie.tokens_dbg(&arg.tokens)
         );
         if !self.is_ff {
-            self.llm_tokens.extend(&arg.tokens);
+            for t in &arg.tokens {
+                self.llm_tokens.push(*t);
+                self.is_sampled.push(true);
+                self.scan_token_var(*t);
+            }
+            self.parser.record_tokens(0, arg.tokens.len());
+        }
+        let eos = self.toktrie.special_token(SpecialToken::EndOfSentence);
+        let res = PostProcessResult {
+            stop: arg.tokens.contains(&eos),
+        };
+        if res.stop {
+            self.set_stop_reason(StopReasonKind::EosToken, json!(null));
+        }
+        res
+    }
+}
+
+/// A string that tokenizes to a single token, or a token id as "[123]".
+fn resolve_token(toktrie: &TokTrie, key: &str) -> Option<TokenId> {
+    let tok = match key.strip_prefix('[').and_then(|k| k.strip_suffix(']')) {
+        Some(id) => id.parse::<TokenId>().ok(),
+        None => match host::tokenize(key).as_slice() {
+            [t] => Some(*t),
+            _ => None,
+        },
+    };
+    tok.filter(|t| (*t as usize) < toktrie.vocab_size())
+}
+
+fn resolve_token_vars(
+    toktrie: &TokTrie,
+    names: &[String],
+    special_tokens: &HashMap<String, String>,
+    warnings: &mut Vec<GrammarWarning>,
+) -> Vec<(ModelVariable, TokenId)> {
+    let mut res = Vec::new();
+    for name in names {
+        let mv = ModelVariable::from_string(name);
+        if !matches!(mv, ModelVariable::Other(_)) {
+            continue;
+        }
+        let key = match special_tokens.get(name) {
+            Some(key) => key.as_str(),
+            None if name.starts_with('<') && name.ends_with('>') => name.as_str(),
+            None => continue,
+        };
+        match resolve_token(toktrie, key) {
+            Some(t) => res.push((mv, t)),
+            None => warnings.push(GrammarWarning {
+                code: "special_token",
+                message: format!("special_tokens: {:?} is not a single token", key),
+            }),
+        }
+    }
+    let mut unused = special_tokens
+        .keys()
+        .filter(|k| !names.contains(k))
+        .collect::<Vec<_>>();
+    unused.sort();
+    for name in unused {
+        warnings.push(GrammarWarning {
+            code: "special_token",
+            message: format!(
+                "special_tokens: no model variable {:?} in the grammar",
+                name
+            ),
+        });
+    }
+    res
+}
+
+fn resolve_token_bias(
+    toktrie: &TokTrie,
+    token_bias: &HashMap<String, f32>,
+    warnings: &mut Vec<GrammarWarning>,
+) -> Vec<(TokenId, f32)> {
+    let mut res = Vec::new();
+    for (key, bias) in token_bias {
+        match resolve_token(toktrie, key) {
+            Some(t) if bias.is_finite() => res.push((t, *bias)),
+            _ => warnings.push(GrammarWarning {
+                code: "token_bias",
+                message: format!("token_bias: can't use {:?}: {}", key, bias),
+            }),
         }
-        // TODO EOS!
-        PostProcessResult::from_arg(&arg)
     }
+    res.sort_by_key(|(t, _)| *t);
+    res
 }
 
 fn main() {
@@ -188,4 +1132,5 @@ fn main() {
     }
 }
 
+#[cfg(not(test))]
 aici_abi::aici_expose_all!(Runner, Runner::new());
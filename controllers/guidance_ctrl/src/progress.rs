@@ -0,0 +1,247 @@
+// Objects printed by the controller as `JSON-OUT: {...}` lines.
+//
+// Every object has an "object" field naming its kind, and "v", the version
+// of the format; bump PROGRESS_VERSION when the meaning of a field changes.
+
+use crate::{analysis::GrammarReport, earley::Stats};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const PROGRESS_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "object", rename_all = "snake_case")]
+pub enum ProgressItem {
+    Capture(Capture),
+    FfTokens(FfTokens),
+    Retract(Retract),
+    Warning(Warning),
+    Limit(Limit),
+    FinalText(FinalText),
+    AllCaptures(AllCaptures),
+    Stats(StatsReport),
+    Mask(Mask),
+    Usage(Usage),
+    StopReason(StopReason),
+    ParserError(ParserError),
+    GrammarReport(GrammarReport),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub v: u32,
+    #[serde(flatten)]
+    pub item: ProgressItem,
+}
+
+impl From<ProgressItem> for Progress {
+    fn from(item: ProgressItem) -> Self {
+        Progress {
+            v: PROGRESS_VERSION,
+            item,
+        }
+    }
+}
+
+/// One compact JSON object per line.
+pub fn serialize_ndjson(items: &[Progress]) -> String {
+    let mut r = String::new();
+    for item in items {
+        r.push_str(&serde_json::to_string(item).unwrap());
+        r.push('\n');
+    }
+    r
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Capture {
+    /// Increasing, in the order the captures of the sequence are reported, from 0.
+    pub id: usize,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub str: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+    /// Set when `str`/`hex` hold only the first max_capture_bytes of the capture,
+    /// which is `full_len` bytes long.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_len: Option<usize>,
+    /// Position of this capture among all captures of the sequence (capture_mode "all").
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<usize>,
+    /// All values captured under `name` so far, oldest first (capture_mode "list").
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub list: Option<Vec<CaptureItem>>,
+    /// Byte range of the capture in the text generated so far (including forced text);
+    /// missing if the bytes were hidden.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_byte: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_byte: Option<usize>,
+    /// Range of tokens covering the bytes above, as tokenized when reported;
+    /// missing if not all of them were tokenized yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_token: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_token: Option<usize>,
+    /// Parsed value of a capture listed in the json_captures controller arg.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    /// Set instead of `value` when the capture is not valid JSON.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_error: Option<String>,
+}
+
+/// Text matched by the grammar, without hidden parts; printed when the controller stops.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FinalText {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub str: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+    /// Including the hidden parts (raw_text controller arg).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_hex: Option<String>,
+    /// The same counts as in the usage object; num_ff_tokens includes its prompt_tokens.
+    pub num_tokens: usize,
+    pub num_sampled_tokens: usize,
+    pub num_ff_tokens: usize,
+}
+
+/// The latest value of every capture, including those left out by capture_filter;
+/// printed after final_text when the `all_captures` report option is set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AllCaptures {
+    pub captures: BTreeMap<String, CaptureItem>,
+}
+
+/// Parser stats, either for the last step or (`cumulative`) for the whole sequence.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatsReport {
+    pub cumulative: bool,
+    #[serde(flatten)]
+    pub stats: Stats,
+    pub allowed_tokens_avg: f64,
+    /// Tokens in the last mask that had a token_bias; only when token_bias is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub biased_tokens: Option<usize>,
+}
+
+/// The allowed tokens of a step, printed when the `mask` report option is set.
+/// `step` counts the masks computed for the sequence, from 0.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Mask {
+    pub step: usize,
+    pub allowed_count: usize,
+    /// The first max_ids allowed tokens; there are more if allowed_count is larger.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ids: Option<Vec<u32>>,
+    /// Base64 of vocab_size bits, token `t` being bit `t % 8` of byte `t / 8`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitmap: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vocab_size: Option<usize>,
+}
+
+/// Token counts for the sequence; printed when the controller stops.
+/// Only tokens still in the sequence are counted, not those retracted (see Retract).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Usage {
+    /// Tokens forced by the grammar before the first sampled token (and not re-tokenized
+    /// with it since).
+    pub prompt_tokens: usize,
+    pub sampled_tokens: usize,
+    /// Tokens forced by the grammar after that.
+    pub ff_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// Non-fatal grammar problem; each is reported once.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub code: String,
+    pub message: String,
+}
+
+/// A grammar region (`scope` is its name) used up its max_tokens and was ended early.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Limit {
+    pub scope: String,
+    pub limit: usize,
+    pub consumed: usize,
+}
+
+/// Tokens forced by the grammar, rather than sampled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FfTokens {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub str: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+    pub num_tokens: usize,
+    /// The forced token ids (report.include_tokens).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Vec<u32>>,
+    /// Token healing: this many bytes of forced text follow `str`, but are
+    /// left for the model to generate, as part of a possibly longer token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub healed_bytes: Option<usize>,
+}
+
+/// Previously generated tokens were removed (backtracking); drop the last
+/// `num_bytes` of the text seen so far.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Retract {
+    pub num_tokens: usize,
+    pub num_bytes: usize,
+}
+
+/// The text stopped matching the grammar at `offset` (in the bytes matched so far).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ParserError {
+    pub offset: usize,
+    /// Up to the last 32 bytes before `offset`.
+    pub context_hex: String,
+    /// The rejected bytes, if any.
+    pub rejected_hex: String,
+    /// What the grammar allowed at `offset`; see Parser::expected_terminals().
+    pub expected: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReasonKind {
+    /// The grammar is complete, and can't be extended.
+    ParserAccepted,
+    /// The model sampled EOS (which the grammar allowed).
+    EosToken,
+    /// The text doesn't match the grammar, or the grammar allows nothing further.
+    ParserError,
+    /// The time_limit_ms of the controller arg ran out (or Runner::abort() was called);
+    /// the text may not be complete.
+    Aborted,
+    /// One of the milestone captures completed; `detail` has its name.
+    Milestone,
+}
+
+/// Printed once, when the controller stops the sequence.
+/// Limits enforced by the host (max_tokens, host-side aborts) don't go through the controller.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StopReason {
+    pub reason: StopReasonKind,
+    pub detail: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CaptureItem {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub str: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_len: Option<usize>,
+}
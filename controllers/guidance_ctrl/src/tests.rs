@@ -0,0 +1,1069 @@
+// Runner and parser tests, on small hand-built grammars and a byte-level token trie.
+
+use crate::{
+    analysis::analyze_grammar,
+    earley::{ByteSet, Grammar, ModelVariable, ParseResult, Parser, SymIdx, SymbolProps},
+    progress::{
+        serialize_ndjson, FfTokens, Limit, Progress, ProgressItem, Retract, StopReasonKind, Usage,
+        PROGRESS_VERSION,
+    },
+    Runner, RunnerArg,
+};
+use aici_abi::{
+    bytes::TokRxInfo, svob::SimpleVob, toktree::TokTrie, MidProcessArg, MidProcessResult,
+    PostProcessArg, TokenId,
+};
+use anyhow::Result;
+use serde_json::json;
+use std::{cell::RefCell, collections::HashMap};
+
+/// Stands in for the host: tokenizes with test_trie(), and keeps what the
+/// controller prints and returns, for take_progress() and friends.
+pub mod host {
+    use super::*;
+
+    thread_local! {
+        static TRIE: TokTrie = test_trie();
+        static PROGRESS: RefCell<String> = RefCell::new(String::new());
+        static TOKEN_BIAS: RefCell<Vec<(TokenId, f32)>> = RefCell::new(Vec::new());
+        static VARS: RefCell<HashMap<String, Vec<u8>>> = RefCell::new(HashMap::new());
+    }
+
+    pub fn tokenize_bytes(s: &[u8]) -> Vec<TokenId> {
+        TRIE.with(|t| t.greedy_tokenize(s))
+    }
+
+    pub fn tokenize(s: &str) -> Vec<TokenId> {
+        tokenize_bytes(s.as_bytes())
+    }
+
+    pub fn return_token_bias(bias: &[(TokenId, f32)]) {
+        TOKEN_BIAS.with(|b| *b.borrow_mut() = bias.to_vec());
+    }
+
+    pub fn print_progress(line: &str) {
+        PROGRESS.with(|p| p.borrow_mut().push_str(line));
+    }
+
+    pub fn take_progress_lines() -> String {
+        PROGRESS.with(|p| std::mem::take(&mut *p.borrow_mut()))
+    }
+
+    pub fn read_var(name: &str) -> Option<Vec<u8>> {
+        VARS.with(|v| v.borrow().get(name).cloned())
+    }
+
+    pub fn append_var(name: &str, bytes: &[u8]) {
+        VARS.with(|v| {
+            let mut vars = v.borrow_mut();
+            vars.entry(name.to_string())
+                .or_default()
+                .extend_from_slice(bytes)
+        })
+    }
+
+    pub fn clear_vars() {
+        VARS.with(|v| v.borrow_mut().clear())
+    }
+}
+
+const EOS: TokenId = 256;
+/// Multi-byte tokens, with ids from 257 on.
+const WORDS: &[&str] = &["ab", "abc", "12", "123", "foo", "foobar"];
+/// A special token with no bytes, after WORDS.
+const TAG: TokenId = 257 + WORDS.len() as TokenId;
+
+/// Tokens 0..=255 are the single bytes, then EOS, WORDS and TAG.
+pub fn test_trie() -> TokTrie {
+    let mut words: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+    words.push(vec![]);
+    words.extend(WORDS.iter().map(|w| w.as_bytes().to_vec()));
+    words.push(vec![]);
+    let info = TokRxInfo {
+        vocab_size: words.len() as u32,
+        tok_eos: EOS,
+    };
+    TokTrie::from(&info, &words)
+}
+
+fn token(s: &str) -> TokenId {
+    match s.as_bytes() {
+        [b] => *b as TokenId,
+        _ => 257 + WORDS.iter().position(|w| *w == s).unwrap() as TokenId,
+    }
+}
+
+/// A symbol for the literal text `s`.
+fn lit(g: &mut Grammar, s: &str) -> SymIdx {
+    let sym = g.fresh_symbol(&format!("lit_{s}"));
+    let rhs = s
+        .bytes()
+        .map(|b| g.terminal(&ByteSet::from_range(b, b)))
+        .collect();
+    g.add_rule(sym, rhs);
+    sym
+}
+
+fn byte_range(g: &mut Grammar, start: u8, end: u8) -> SymIdx {
+    g.terminal(&ByteSet::from_range(start, end))
+}
+
+/// A symbol for `rhs` with `props`.
+fn with_props(g: &mut Grammar, name: &str, rhs: Vec<SymIdx>, props: SymbolProps) -> SymIdx {
+    let sym = g.fresh_symbol(name);
+    g.add_rule(sym, rhs);
+    g.apply_props(sym, props);
+    sym
+}
+
+/// One or more of `item`.
+fn plus(g: &mut Grammar, name: &str, item: SymIdx) -> SymIdx {
+    let sym = g.fresh_symbol(name);
+    g.add_rule(sym, vec![item]);
+    g.add_rule(sym, vec![sym, item]);
+    sym
+}
+
+fn capture(g: &mut Grammar, name: &str, rhs: Vec<SymIdx>) -> SymIdx {
+    let props = SymbolProps {
+        capture_name: Some(name.to_string()),
+        ..SymbolProps::default()
+    };
+    with_props(g, name, rhs, props)
+}
+
+/// `[` item (`,` item)* `]`, with each item a digit captured as "item".
+fn list_grammar() -> Grammar {
+    let mut g = Grammar::new();
+    let digit = byte_range(&mut g, b'0', b'9');
+    let item = capture(&mut g, "item", vec![digit]);
+    let comma = lit(&mut g, ",");
+    let items = g.fresh_symbol("items");
+    g.add_rule(items, vec![item]);
+    g.add_rule(items, vec![items, comma, item]);
+    let open = lit(&mut g, "[");
+    let close = lit(&mut g, "]");
+    let start = g.start();
+    g.add_rule(start, vec![open, items, close]);
+    g
+}
+
+/// One or more digits, captured as "num".
+fn digits_grammar() -> Grammar {
+    let mut g = Grammar::new();
+    let digit = byte_range(&mut g, b'0', b'9');
+    let digits = plus(&mut g, "digits", digit);
+    let num = capture(&mut g, "num", vec![digits]);
+    let start = g.start();
+    g.add_rule(start, vec![num]);
+    g
+}
+
+fn parser(g: Grammar) -> Parser {
+    Parser::new(g.optimize().compile())
+}
+
+fn scan_all(p: &mut Parser, bytes: &[u8]) {
+    for b in bytes {
+        assert!(
+            p.scan(*b) != ParseResult::Reject,
+            "rejected {:?}",
+            *b as char
+        );
+    }
+}
+
+/// `[` digits `]`, with at most `max_tokens` tokens of digits, captured as "num".
+fn limited_grammar(max_tokens: usize) -> Grammar {
+    let mut g = Grammar::new();
+    let digit = byte_range(&mut g, b'0', b'9');
+    let digits = plus(&mut g, "digits", digit);
+    let props = SymbolProps {
+        max_tokens,
+        capture_name: Some("num".to_string()),
+        ..SymbolProps::default()
+    };
+    let num = with_props(&mut g, "num", vec![digits], props);
+    let open = lit(&mut g, "[");
+    let close = lit(&mut g, "]");
+    let start = g.start();
+    g.add_rule(start, vec![open, num, close]);
+    g
+}
+
+/// "12ab" followed by "c" or "1".
+fn healing_grammar() -> Grammar {
+    let mut g = Grammar::new();
+    let prefix = lit(&mut g, "12ab");
+    let c = lit(&mut g, "c");
+    let one = lit(&mut g, "1");
+    let end = g.fresh_symbol("end");
+    g.add_rule(end, vec![c]);
+    g.add_rule(end, vec![one]);
+    let start = g.start();
+    g.add_rule(start, vec![prefix, end]);
+    g
+}
+
+/// "1", model variable "tag" (wrapped in a capture, which it completes), then "2".
+fn tag_grammar() -> Grammar {
+    let mut g = Grammar::new();
+    let one = lit(&mut g, "1");
+    let tag = g.model_variable("tag");
+    let tagged = capture(&mut g, "tagged", vec![tag]);
+    let two = lit(&mut g, "2");
+    let start = g.start();
+    g.add_rule(start, vec![one, tagged, two]);
+    g
+}
+
+/// Two hidden digits (captured as "hid"), then "x".
+fn hidden_grammar() -> Grammar {
+    let mut g = Grammar::new();
+    let digit = byte_range(&mut g, b'0', b'9');
+    let props = SymbolProps {
+        hidden: true,
+        commit_point: true,
+        capture_name: Some("hid".to_string()),
+        ..SymbolProps::default()
+    };
+    let hid = with_props(&mut g, "hid", vec![digit, digit], props);
+    let x = lit(&mut g, "x");
+    let start = g.start();
+    g.add_rule(start, vec![hid, x]);
+    g
+}
+
+fn runner(g: Grammar, arg: serde_json::Value) -> Runner {
+    let mut arg = arg;
+    arg["guidance_b64"] = json!("");
+    let arg: RunnerArg = serde_json::from_value(arg).unwrap();
+    // drop what other tests on this thread left behind
+    host::take_progress_lines();
+    host::clear_vars();
+    Runner::from_grammar(arg, g, test_trie())
+}
+
+/// Inverse of serialize_ndjson(); empty lines are skipped.
+fn parse_ndjson(s: &str) -> Result<Vec<Progress>> {
+    s.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| Ok(serde_json::from_str(l)?))
+        .collect()
+}
+
+/// What the controller printed since the last call.
+fn take_progress() -> Vec<ProgressItem> {
+    let progress = parse_ndjson(&host::take_progress_lines()).unwrap();
+    assert!(progress.iter().all(|p| p.v == PROGRESS_VERSION));
+    progress.into_iter().map(|p| p.item).collect()
+}
+
+/// Runs the sequence like the host would, with `sample` picking each sampled token
+/// from the allowed ones, until the controller (or EOS) stops it; returns the tokens.
+fn run(
+    runner: &mut Runner,
+    mut sample: impl FnMut(&[TokenId], &SimpleVob) -> TokenId,
+) -> Vec<TokenId> {
+    let mut tokens = Vec::new();
+    for _ in 0..1000 {
+        match runner.mid_process(MidProcessArg { fork_group: vec![] }) {
+            MidProcessResult::Stop => return tokens,
+            MidProcessResult::Splice {
+                backtrack,
+                ff_tokens,
+            } => {
+                tokens.truncate(tokens.len() - backtrack as usize);
+                tokens.extend_from_slice(&ff_tokens);
+                runner.post_process(PostProcessArg {
+                    tokens: ff_tokens,
+                    backtrack,
+                });
+            }
+            MidProcessResult::SampleWithBias { allowed_tokens } => {
+                let t = sample(&tokens, &allowed_tokens);
+                tokens.push(t);
+                let res = runner.post_process(PostProcessArg {
+                    tokens: vec![t],
+                    backtrack: 0,
+                });
+                if res.stop {
+                    return tokens;
+                }
+            }
+        }
+    }
+    panic!("sequence didn't stop");
+}
+
+/// A sampler that generates `text`, with the longest allowed token that fits,
+/// and then EOS.
+fn generate(text: &str) -> impl FnMut(&[TokenId], &SimpleVob) -> TokenId {
+    let trie = test_trie();
+    let text = text.as_bytes().to_vec();
+    move |tokens, allowed| {
+        let done = trie.decode(tokens).len();
+        let rest = &text[done..];
+        (0..trie.vocab_size() as TokenId)
+            .filter(|t| allowed.is_allowed(*t) && !trie.token(*t).is_empty())
+            .filter(|t| rest.starts_with(trie.token(*t)))
+            .max_by_key(|t| trie.token(*t).len())
+            .unwrap_or_else(|| {
+                assert!(rest.is_empty(), "can't generate {:?}", rest);
+                EOS
+            })
+    }
+}
+
+/// A sampler that generates `text` one byte (token) at a time, and then EOS.
+fn bytewise(text: &str) -> impl FnMut(&[TokenId], &SimpleVob) -> TokenId {
+    let trie = test_trie();
+    let text = text.as_bytes().to_vec();
+    move |tokens, allowed| {
+        let done = trie.decode(tokens).len();
+        let t = text.get(done).map_or(EOS, |b| *b as TokenId);
+        assert!(allowed.is_allowed(t), "{:?} not allowed", t);
+        t
+    }
+}
+
+fn final_text(progress: &[ProgressItem]) -> String {
+    progress
+        .iter()
+        .find_map(|p| match p {
+            ProgressItem::FinalText(t) => t.str.clone(),
+            _ => None,
+        })
+        .unwrap()
+}
+
+fn stop_reason(progress: &[ProgressItem]) -> (StopReasonKind, serde_json::Value) {
+    let reasons = progress
+        .iter()
+        .filter_map(|p| match p {
+            ProgressItem::StopReason(r) => Some((r.reason, r.detail.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert!(reasons.len() == 1, "{reasons:?}");
+    reasons[0].clone()
+}
+
+fn usage(progress: &[ProgressItem]) -> Usage {
+    progress
+        .iter()
+        .find_map(|p| match p {
+            ProgressItem::Usage(u) => Some(u.clone()),
+            _ => None,
+        })
+        .unwrap()
+}
+
+fn captures(progress: &[ProgressItem]) -> Vec<crate::progress::Capture> {
+    progress
+        .iter()
+        .filter_map(|p| match p {
+            ProgressItem::Capture(c) => Some(c.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_trie_tokens() {
+    let trie = test_trie();
+    assert!(trie.token(token("foobar")) == b"foobar");
+    assert!(trie.token(TAG).is_empty());
+    assert!(trie.greedy_tokenize(b"abcab") == vec![token("abc"), token("ab")]);
+}
+
+#[test]
+fn capture_mode_all_and_list() {
+    for mode in ["latest", "all", "list"] {
+        let mut r = runner(list_grammar(), json!({ "capture_mode": mode }));
+        run(&mut r, generate("[1,2,3]"));
+        let progress = take_progress();
+        assert!(final_text(&progress) == "[1,2,3]");
+
+        let caps = captures(&progress);
+        let values = caps
+            .iter()
+            .map(|c| c.str.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert!(values == ["1", "2", "3"], "{mode}: {values:?}");
+        assert!(caps.iter().map(|c| c.id).eq(0..3));
+        let seqs = caps.iter().map(|c| c.seq).collect::<Vec<_>>();
+        let lists = caps
+            .iter()
+            .map(|c| c.list.as_ref().map(|l| l.len()))
+            .collect::<Vec<_>>();
+        match mode {
+            "all" => assert!(seqs == [Some(0), Some(1), Some(2)]),
+            "list" => assert!(lists == [Some(1), Some(2), Some(3)]),
+            _ => assert!(seqs == [None; 3] && lists == [None; 3]),
+        }
+    }
+}
+
+#[test]
+fn stop_reason_accepted() {
+    let mut r = runner(list_grammar(), json!({}));
+    let tokens = run(&mut r, generate("[1]"));
+    // nothing can follow the "]", so the controller stops before EOS
+    assert!(!tokens.contains(&EOS));
+    let progress = take_progress();
+    assert!(stop_reason(&progress) == (StopReasonKind::ParserAccepted, json!(null)));
+}
+
+#[test]
+fn stop_reason_eos() {
+    let mut r = runner(digits_grammar(), json!({}));
+    let tokens = run(&mut r, generate("123"));
+    assert!(tokens == [token("123"), EOS]);
+    let progress = take_progress();
+    assert!(final_text(&progress) == "123");
+    assert!(stop_reason(&progress) == (StopReasonKind::EosToken, json!(null)));
+}
+
+#[test]
+fn stop_reason_parser_error() {
+    let mut r = runner(digits_grammar(), json!({}));
+    // a host that doesn't apply the mask
+    run(&mut r, |tokens, _| {
+        if tokens.is_empty() {
+            token("1")
+        } else {
+            b'x' as TokenId
+        }
+    });
+    let progress = take_progress();
+    let (reason, detail) = stop_reason(&progress);
+    assert!(reason == StopReasonKind::ParserError);
+    assert!(detail == json!({ "rejected_hex": "78", "byte_offset": 1 }));
+    assert!(final_text(&progress) == "1");
+
+    let err = progress
+        .iter()
+        .find_map(|p| match p {
+            ProgressItem::ParserError(e) => Some(e.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert!(err.offset == 1 && err.context_hex == "31" && err.rejected_hex == "78");
+    assert!(err.expected == [ByteSet::from_range(b'0', b'9').to_string()]);
+}
+
+#[test]
+fn ndjson_round_trip() {
+    let mut r = runner(list_grammar(), json!({ "capture_mode": "list" }));
+    run(&mut r, generate("[1,2]"));
+    let progress = take_progress()
+        .into_iter()
+        .map(Progress::from)
+        .collect::<Vec<_>>();
+    let s = serialize_ndjson(&progress);
+    assert!(s.lines().count() == progress.len());
+    assert!(parse_ndjson(&format!("\n{s}\n")).unwrap() == progress);
+}
+
+#[test]
+fn parser_capture_index() {
+    let mut p = parser(list_grammar());
+    scan_all(&mut p, b"[4,5");
+    assert!(p.capture_indices("item") == [0, 1]);
+    assert!(p.capture_indices("nope").is_empty());
+    scan_all(&mut p, b",6]");
+    assert!(p.capture_indices("item") == [0, 1, 2]);
+    let values = p
+        .capture_indices("item")
+        .iter()
+        .map(|i| p.captures()[*i].bytes.clone())
+        .collect::<Vec<_>>();
+    assert!(values == [b"4", b"5", b"6"]);
+    let map = p.captures_map();
+    assert!(map.len() == 1 && map["item"] == b"6");
+}
+
+#[test]
+fn usage_after_retract() {
+    // two digits, then "3x"
+    let mut g = Grammar::new();
+    let d = byte_range(&mut g, b'0', b'9');
+    let rest = lit(&mut g, "3x");
+    let start = g.start();
+    g.add_rule(start, vec![d, d, rest]);
+    let mut r = runner(g, json!({}));
+
+    // the model samples "12" and "3" (the only token the mask allows there), which
+    // are re-tokenized with the forced "x" as "123" "x"
+    let mut picks = [token("12"), token("3")].into_iter();
+    let tokens = run(&mut r, |_, allowed| {
+        let t = picks.next().unwrap();
+        assert!(allowed.is_allowed(t));
+        t
+    });
+    assert!(tokens == [token("123"), token("x")]);
+    let progress = take_progress();
+    assert!(progress.contains(&ProgressItem::Retract(Retract {
+        num_tokens: 2,
+        num_bytes: 3,
+    })));
+    let u = usage(&progress);
+    assert!(u.prompt_tokens == 0 && u.sampled_tokens == 0);
+    assert!(u.ff_tokens == 2 && u.total_tokens == 2);
+    assert!(final_text(&progress) == "123x");
+}
+
+#[test]
+fn parser_close_region() {
+    let mut p = parser(limited_grammar(2));
+    scan_all(&mut p, b"[12");
+    let regions = p.limited_regions();
+    assert!(regions.len() == 1);
+    assert!(regions[0].name == "num" && regions[0].start == 1 && regions[0].max_tokens == 2);
+    assert!(p.close_region(&regions[0]) != ParseResult::Reject);
+    assert!(p.limited_regions().is_empty());
+    // the region can't be extended anymore
+    assert!(p.valid_prefix_len(b"3") == 0);
+    scan_all(&mut p, b"]");
+    assert!(p.is_accepting());
+    let caps = p.captures();
+    assert!(caps.last().unwrap().name == "num" && caps.last().unwrap().bytes == b"12");
+}
+
+#[test]
+fn max_tokens_closes_region() {
+    let mut r = runner(limited_grammar(2), json!({}));
+    let tokens = run(&mut r, |_, allowed| {
+        assert!(allowed.is_allowed(token("1")));
+        token("1")
+    });
+    assert!(tokens == [token("["), token("1"), token("1"), token("]")]);
+    let progress = take_progress();
+    assert!(progress.contains(&ProgressItem::Limit(Limit {
+        scope: "num".to_string(),
+        limit: 2,
+        consumed: 2,
+    })));
+    assert!(final_text(&progress) == "[11]");
+    assert!(captures(&progress).last().unwrap().str.as_deref() == Some("11"));
+    assert!(stop_reason(&progress).0 == StopReasonKind::ParserAccepted);
+}
+
+#[test]
+fn time_limit_aborts() {
+    let mut r = runner(list_grammar(), json!({ "time_limit_ms": 0 }));
+    let tokens = run(&mut r, generate("[1]"));
+    assert!(tokens.is_empty());
+    let progress = take_progress();
+    let (reason, detail) = stop_reason(&progress);
+    assert!(reason == StopReasonKind::Aborted);
+    assert!(detail == json!({ "message": "time_limit_ms exceeded" }));
+    assert!(final_text(&progress) == "");
+}
+
+#[test]
+fn abort_scans_sampled_tokens() {
+    let mut r = runner(list_grammar(), json!({ "time_limit_ms": 60_000 }));
+    let mut sample = generate("[1,2]");
+    let mut tokens = vec![];
+    // "[" is forced, then "1", "," and "2" are sampled
+    while tokens.len() < 4 {
+        match r.mid_process(MidProcessArg { fork_group: vec![] }) {
+            MidProcessResult::Splice { ff_tokens, .. } => {
+                tokens.extend_from_slice(&ff_tokens);
+                r.post_process(PostProcessArg {
+                    tokens: ff_tokens,
+                    backtrack: 0,
+                });
+            }
+            MidProcessResult::SampleWithBias { allowed_tokens } => {
+                let t = sample(&tokens, &allowed_tokens);
+                tokens.push(t);
+                r.post_process(PostProcessArg {
+                    tokens: vec![t],
+                    backtrack: 0,
+                });
+            }
+            MidProcessResult::Stop => panic!("stopped early"),
+        }
+    }
+    assert!(matches!(
+        r.abort("upstream timeout"),
+        MidProcessResult::Stop
+    ));
+    let progress = take_progress();
+    assert!(final_text(&progress) == "[1,2");
+    let values = captures(&progress)
+        .into_iter()
+        .map(|c| c.str.unwrap())
+        .collect::<Vec<_>>();
+    assert!(values == ["1", "2"]);
+    let (reason, detail) = stop_reason(&progress);
+    assert!(reason == StopReasonKind::Aborted);
+    assert!(detail == json!({ "message": "upstream timeout" }));
+}
+
+#[test]
+fn token_healing() {
+    let mut r = runner(
+        healing_grammar(),
+        json!({ "report": { "include_tokens": true } }),
+    );
+    let tokens = run(&mut r, generate("12abc"));
+    // "ab" is left for the model, which can then sample "abc"
+    assert!(tokens == [token("12"), token("abc")]);
+    let progress = take_progress();
+    assert!(progress.contains(&ProgressItem::FfTokens(FfTokens {
+        str: Some("12".to_string()),
+        hex: Some("3132".to_string()),
+        num_tokens: 1,
+        tokens: Some(vec![token("12")]),
+        healed_bytes: Some(2),
+    })));
+    assert!(final_text(&progress) == "12abc");
+    let u = usage(&progress);
+    assert!(u.prompt_tokens == 1 && u.sampled_tokens == 1 && u.total_tokens == 2);
+
+    let mut r = runner(healing_grammar(), json!({ "disable_token_healing": true }));
+    let tokens = run(&mut r, generate("12abc"));
+    assert!(tokens == [token("12"), token("ab"), token("c")]);
+    assert!(final_text(&take_progress()) == "12abc");
+}
+
+#[test]
+fn parser_valid_prefix_len() {
+    let mut p = parser(list_grammar());
+    scan_all(&mut p, b"[1");
+    assert!(p.valid_prefix_len(b",2]x") == 3);
+    assert!(p.valid_prefix_len(b"2") == 0);
+    assert!(p.valid_prefix_len(b"") == 0);
+    // nothing was scanned or captured
+    assert!(p.get_bytes() == b"[1");
+    assert!(p.captures().len() == 1);
+    scan_all(&mut p, b",2]");
+    assert!(p.is_accepting());
+}
+
+#[test]
+fn append_var_forces_text() {
+    let mut r = runner(list_grammar(), json!({ "append_var": "tool" }));
+    let mut step = 0;
+    let tokens = run(&mut r, |_, allowed| {
+        step += 1;
+        if step == 1 {
+            host::append_var("tool", b",2");
+            token("1")
+        } else {
+            assert!(allowed.is_allowed(token("]")));
+            token("]")
+        }
+    });
+    assert!(tokens == [token("["), token("1"), token(","), token("2"), token("]")]);
+    let progress = take_progress();
+    assert!(final_text(&progress) == "[1,2]");
+    let values = captures(&progress)
+        .into_iter()
+        .map(|c| c.str.unwrap())
+        .collect::<Vec<_>>();
+    assert!(values == ["1", "2"]);
+    let u = usage(&progress);
+    assert!(u.prompt_tokens == 1 && u.sampled_tokens == 2 && u.ff_tokens == 2);
+}
+
+#[test]
+fn append_var_rejected() {
+    let mut r = runner(list_grammar(), json!({ "append_var": "tool" }));
+    let tokens = run(&mut r, |_, _| {
+        host::append_var("tool", b"x");
+        token("1")
+    });
+    assert!(tokens == [token("["), token("1")]);
+    let progress = take_progress();
+    let (reason, detail) = stop_reason(&progress);
+    assert!(reason == StopReasonKind::ParserError);
+    assert!(detail == json!({ "rejected_hex": "78", "byte_offset": 2 }));
+    assert!(final_text(&progress) == "[1");
+}
+
+#[test]
+fn parser_scan_model_variable() {
+    let tag = ModelVariable::Other("tag".to_string());
+    let mut p = parser(tag_grammar());
+    assert!(p.model_variables().is_empty());
+    scan_all(&mut p, b"1");
+    assert!(p.model_variables() == [tag.clone()]);
+    assert!(p.valid_prefix_len(b"2") == 0);
+    let other = ModelVariable::Other("other".to_string());
+    assert!(p.scan_model_variable(&other) == ParseResult::Reject);
+
+    let num_rows = p.num_rows();
+    assert!(p.scan_model_variable(&tag) != ParseResult::Reject);
+    // the row is replaced, not added, and no byte is added
+    assert!(p.num_rows() == num_rows);
+    assert!(p.get_bytes() == b"1");
+    assert!(p.model_variables().is_empty());
+    // the completed "tagged" lets "2" follow
+    scan_all(&mut p, b"2");
+    assert!(p.is_accepting());
+}
+
+#[test]
+fn special_token_sampled() {
+    let arg = json!({ "special_tokens": { "tag": format!("[{TAG}]") } });
+    let mut r = runner(tag_grammar(), arg);
+    let tokens = run(&mut r, |tokens, allowed| {
+        if tokens.is_empty() {
+            token("1")
+        } else {
+            // no bytes can follow "1", only the tag
+            assert!(allowed.num_set() == 1 && allowed.is_allowed(TAG));
+            TAG
+        }
+    });
+    assert!(tokens == [token("1"), TAG, token("2")]);
+    let progress = take_progress();
+    assert!(final_text(&progress) == "12");
+    assert!(usage(&progress).sampled_tokens == 2);
+}
+
+#[test]
+fn grammar_report() {
+    let mut r = runner(list_grammar(), json!({ "report": { "grammar": true } }));
+    run(&mut r, generate("[1]"));
+    let reports = take_progress()
+        .into_iter()
+        .filter_map(|p| match p {
+            ProgressItem::GrammarReport(r) => Some(r),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert!(reports.len() == 1);
+    let report = &reports[0];
+    assert!(report.satisfiable && report.unproductive.is_empty());
+    assert!(report.forced_prefix.str == "[" && report.forced_prefix.num_tokens == 1);
+    assert!(report.size.min_bytes == Some(3) && report.size.max_bytes.is_none());
+    assert!(report.captures.len() == 1);
+    let item = &report.captures[0];
+    assert!(item.name == "item" && item.literal.is_none());
+    assert!(item.size.min_bytes == Some(1) && item.size.max_bytes == Some(1));
+}
+
+#[test]
+fn grammar_report_unsatisfiable() {
+    let mut g = Grammar::new();
+    let undefined = g.fresh_symbol("undefined");
+    let open = lit(&mut g, "[");
+    let start = g.start();
+    g.add_rule(start, vec![open, undefined]);
+    let report = analyze_grammar(&g, &test_trie());
+    assert!(!report.satisfiable);
+    assert!(report.size.min_bytes.is_none());
+    assert!(report.forced_prefix.str.is_empty());
+    assert!(report.unproductive.contains(&"undefined".to_string()));
+}
+
+#[test]
+fn mask_budget_rejects_sampled() {
+    let arg = json!({ "mask_budget": 1, "stats": "final_cumulative" });
+    let mut r = runner(list_grammar(), arg);
+    let mut picks = vec![token("ab"), token("1"), token("]")].into_iter();
+    let mut num_sampled = 0;
+    let tokens = run(&mut r, |tokens, allowed| {
+        num_sampled += 1;
+        if num_sampled == 2 {
+            // "ab" was taken back, and is left out at this position
+            assert!(tokens == [token("[")]);
+            assert!(!allowed.is_allowed(token("ab")));
+        }
+        let t = picks.next().unwrap();
+        // the mask is only a superset, so it lets "ab" through
+        assert!(allowed.is_allowed(t));
+        t
+    });
+    assert!(num_sampled == 3);
+    assert!(tokens == [token("["), token("1"), token("]")]);
+
+    let progress = take_progress();
+    assert!(progress.contains(&ProgressItem::Retract(Retract {
+        num_tokens: 1,
+        num_bytes: 2,
+    })));
+    let stats = progress
+        .iter()
+        .find_map(|p| match p {
+            ProgressItem::Stats(s) => Some(s.stats.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert!(stats.mask_fallbacks == 3 && stats.rejected_tokens == 1);
+    assert!(final_text(&progress) == "[1]");
+    let u = usage(&progress);
+    assert!(u.prompt_tokens == 1 && u.sampled_tokens == 2 && u.total_tokens == 3);
+    assert!(stop_reason(&progress).0 == StopReasonKind::ParserAccepted);
+}
+
+#[test]
+fn milestone_after_capture_ends() {
+    // "(" digits ")" digits, with the first digits captured as "num"
+    let mut g = Grammar::new();
+    let digit = byte_range(&mut g, b'0', b'9');
+    let digits = plus(&mut g, "digits", digit);
+    let num = capture(&mut g, "num", vec![digits]);
+    let open = lit(&mut g, "(");
+    let close = lit(&mut g, ")");
+    let rest = plus(&mut g, "rest", digit);
+    let start = g.start();
+    g.add_rule(start, vec![open, num, close, rest]);
+
+    let mut r = runner(g, json!({ "milestones": ["num"] }));
+    let tokens = run(&mut r, bytewise("(123)45"));
+    // "1" and "12" are captured too, but the digits could go on
+    assert!(tokens == "(123)".bytes().map(|b| b as TokenId).collect::<Vec<_>>());
+    let progress = take_progress();
+    assert!(final_text(&progress) == "(123)");
+    assert!(captures(&progress).last().unwrap().str.as_deref() == Some("123"));
+    assert!(stop_reason(&progress) == (StopReasonKind::Milestone, json!({ "capture": "num" })));
+}
+
+#[test]
+fn milestone_ignores_retracted_capture() {
+    // "(" "1" ")", with the "1" captured as "num"
+    let mut g = Grammar::new();
+    let one = lit(&mut g, "1");
+    let num = capture(&mut g, "num", vec![one]);
+    let open = lit(&mut g, "(");
+    let close = lit(&mut g, ")");
+    let start = g.start();
+    g.add_rule(start, vec![open, num, close]);
+
+    let arg = json!({ "milestones": ["num"], "mask_budget": 1 });
+    let mut r = runner(g, arg);
+    let mut num_sampled = 0;
+    let tokens = run(&mut r, |_, allowed| {
+        num_sampled += 1;
+        // "12" completes "num" with its first byte, and is then taken back
+        let t = if num_sampled == 1 {
+            token("12")
+        } else {
+            token("1")
+        };
+        assert!(allowed.is_allowed(t));
+        t
+    });
+    assert!(num_sampled == 2);
+    assert!(tokens == [token("("), token("1")]);
+    let progress = take_progress();
+    assert!(final_text(&progress) == "(1");
+    assert!(stop_reason(&progress) == (StopReasonKind::Milestone, json!({ "capture": "num" })));
+}
+
+#[test]
+fn capture_filter_and_all_captures() {
+    // three digits separated by ",", captured as "x_a", "y" and "x_b"
+    let mut g = Grammar::new();
+    let mut rhs = vec![];
+    for name in ["x_a", "y", "x_b"] {
+        if !rhs.is_empty() {
+            rhs.push(lit(&mut g, ","));
+        }
+        let digit = byte_range(&mut g, b'0', b'9');
+        rhs.push(capture(&mut g, name, vec![digit]));
+    }
+    let start = g.start();
+    g.add_rule(start, rhs);
+
+    let report = json!({ "capture_filter": ["x_*"], "all_captures": true });
+    let mut r = runner(g, json!({ "report": report }));
+    run(&mut r, generate("1,2,3"));
+    let progress = take_progress();
+
+    // "y" isn't streamed, but keeps its id
+    let streamed = captures(&progress)
+        .into_iter()
+        .map(|c| format!("{}:{}={}", c.id, c.name, c.str.unwrap()))
+        .collect::<Vec<_>>();
+    assert!(streamed == ["0:x_a=1", "2:x_b=3"], "{streamed:?}");
+
+    let all = progress
+        .iter()
+        .find_map(|p| match p {
+            ProgressItem::AllCaptures(a) => Some(a.clone()),
+            _ => None,
+        })
+        .unwrap();
+    let all = all
+        .captures
+        .into_iter()
+        .map(|(name, item)| format!("{}={}", name, item.str.unwrap()))
+        .collect::<Vec<_>>();
+    assert!(all == ["x_a=1", "x_b=3", "y=2"], "{all:?}");
+}
+
+#[test]
+fn mask_report_matches_applied_mask() {
+    use base64::Engine as _;
+
+    for bitmap in [true, false] {
+        let report = json!({ "mask": { "bitmap": bitmap, "max_ids": 4 } });
+        let mut r = runner(list_grammar(), json!({ "report": report }));
+        let mut sample = generate("[1,2]");
+        let mut applied = vec![];
+        run(&mut r, |tokens, allowed| {
+            applied.push(
+                (0..test_trie().vocab_size() as TokenId)
+                    .filter(|t| allowed.is_allowed(*t))
+                    .collect::<Vec<_>>(),
+            );
+            sample(tokens, allowed)
+        });
+        let masks = take_progress()
+            .into_iter()
+            .filter_map(|p| match p {
+                ProgressItem::Mask(m) => Some(m),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(masks.len() == applied.len());
+        for (step, (mask, applied)) in masks.iter().zip(applied.iter()).enumerate() {
+            assert!(mask.step == step);
+            assert!(mask.allowed_count == applied.len());
+            if bitmap {
+                let vocab_size = mask.vocab_size.unwrap();
+                assert!(vocab_size == test_trie().vocab_size());
+                let bits = base64::engine::general_purpose::STANDARD
+                    .decode(mask.bitmap.as_ref().unwrap())
+                    .unwrap();
+                assert!(bits.len() == (vocab_size + 7) / 8);
+                let decoded = (0..vocab_size as TokenId)
+                    .filter(|t| bits[*t as usize / 8] & (1 << (t % 8)) != 0)
+                    .collect::<Vec<_>>();
+                assert!(
+                    decoded == *applied,
+                    "step {step}: {decoded:?} vs {applied:?}"
+                );
+                assert!(mask.ids.is_none());
+            } else {
+                let n = std::cmp::min(4, applied.len());
+                assert!(mask.ids.as_ref().unwrap()[..] == applied[..n]);
+                assert!(mask.bitmap.is_none() && mask.vocab_size.is_none());
+            }
+        }
+    }
+}
+
+#[test]
+fn capture_spans() {
+    let mut r = runner(list_grammar(), json!({}));
+    let tokens = run(&mut r, generate("[1,2]"));
+    assert!(tokens.len() == 5);
+    let spans = captures(&take_progress())
+        .into_iter()
+        .map(|c| {
+            (
+                c.start_byte.unwrap(),
+                c.end_byte.unwrap(),
+                c.start_token.unwrap(),
+                c.end_token.unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    // "[" "1" "," "2" "]", one byte per token
+    assert!(spans == [(1, 2, 1, 2), (3, 4, 3, 4)], "{spans:?}");
+}
+
+#[test]
+fn json_captures() {
+    let mut g = Grammar::new();
+    let good_text = lit(&mut g, "[1]");
+    let good = capture(&mut g, "good", vec![good_text]);
+    let bad_text = lit(&mut g, "[x");
+    let bad = capture(&mut g, "bad", vec![bad_text]);
+    let other_text = lit(&mut g, "2");
+    let other = capture(&mut g, "other", vec![other_text]);
+    let start = g.start();
+    g.add_rule(start, vec![good, bad, other]);
+
+    let mut r = runner(g, json!({ "json_captures": ["good", "bad"] }));
+    run(&mut r, |_, _| panic!("all of the text is forced"));
+    let caps = captures(&take_progress());
+    assert!(caps.len() == 3);
+    assert!(caps[0].value == Some(json!([1])) && caps[0].parse_error.is_none());
+    assert!(caps[1].value.is_none() && caps[1].parse_error.is_some());
+    // not listed in json_captures
+    assert!(caps[2].value.is_none() && caps[2].parse_error.is_none());
+}
+
+#[test]
+fn grammar_warnings() {
+    let mut g = list_grammar();
+    let _ = lit(&mut g, "unused");
+    let mut r = runner(g, json!({ "milestones": ["nope"] }));
+    run(&mut r, generate("[1]"));
+    let codes = take_progress()
+        .into_iter()
+        .filter_map(|p| match p {
+            ProgressItem::Warning(w) => Some(w.code),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    // each is reported once
+    assert!(codes == ["unreachable_symbol", "milestone"], "{codes:?}");
+}
+
+#[test]
+fn parser_hidden_text() {
+    let mut p = parser(hidden_grammar());
+    scan_all(&mut p, b"12x");
+    assert!(p.is_accepting());
+    assert!(p.get_bytes() == b"x");
+    assert!(p.get_raw_bytes() == b"12x");
+    let cap = p.captures().last().unwrap();
+    assert!(cap.name == "hid" && cap.bytes == b"12" && cap.span.is_none());
+}
+
+#[test]
+fn final_text_without_hidden() {
+    let mut r = runner(hidden_grammar(), json!({ "raw_text": true }));
+    run(&mut r, |tokens, allowed| {
+        if tokens.is_empty() {
+            token("12")
+        } else {
+            assert!(allowed.num_set() == 1 && allowed.is_allowed(token("x")));
+            token("x")
+        }
+    });
+    let progress = take_progress();
+    let text = progress
+        .iter()
+        .find_map(|p| match p {
+            ProgressItem::FinalText(t) => Some(t.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert!(text.str.as_deref() == Some("x"));
+    assert!(text.raw_hex.as_deref() == Some("313278"));
+    let caps = captures(&progress);
+    assert!(caps[0].name == "hid" && caps[0].str.as_deref() == Some("12"));
+    assert!(caps[0].start_byte.is_none());
+}
+
+#[test]
+fn report_hex_and_truncated_captures() {
+    let report = json!({ "include_str": false, "max_capture_bytes": 2 });
+    let mut r = runner(digits_grammar(), json!({ "report": report }));
+    run(&mut r, generate("123"));
+    let progress = take_progress();
+    let text = progress
+        .iter()
+        .find_map(|p| match p {
+            ProgressItem::FinalText(t) => Some(t.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert!(text.str.is_none() && text.hex.as_deref() == Some("313233"));
+
+    let cap = captures(&progress).pop().unwrap();
+    assert!(cap.str.is_none() && cap.hex.as_deref() == Some("3132"));
+    assert!(cap.truncated && cap.full_len == Some(3));
+}
@@ -1,9 +1,10 @@
 // based on https://github.com/vllm-project/vllm/blob/b9fe4616f98b77b4b9458bce203aa6544cb31ef2/vllm/config.py
 
-use crate::ModelExec;
+use crate::{HashMap, ModelExec};
 use aicirt::{bail_user, valid_module_or_tag};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::{fmt::Display, time::Duration};
 
 #[derive(Debug)]
 pub struct RllmConfig<ME: ModelExec> {
@@ -14,6 +15,125 @@ pub struct RllmConfig<ME: ModelExec> {
     pub aici: AiciConfig,
 }
 
+impl<ME: ModelExec> RllmConfig<ME> {
+    /// Check constraints between fields, returning all violations at once.
+    /// Backend-specific checks are done by ModelExec::validate_config().
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut err = |field: &'static str, message: String, fix: String| {
+            errors.push(ConfigError {
+                field,
+                message,
+                fix,
+            })
+        };
+
+        let sched = &self.scheduler;
+        let par = &self.parallel;
+
+        if par.pipeline_parallel_size == 0 {
+            err(
+                "parallel.pipeline_parallel_size",
+                "must be at least 1".to_string(),
+                "set it to 1".to_string(),
+            );
+        }
+        if par.tensor_parallel_size == 0 {
+            err(
+                "parallel.tensor_parallel_size",
+                "must be at least 1".to_string(),
+                "set it to 1".to_string(),
+            );
+        }
+        if sched.max_num_seqs == 0 {
+            err(
+                "scheduler.max_num_seqs",
+                "must be at least 1".to_string(),
+                "set it to 1 or more".to_string(),
+            );
+        }
+        if sched.max_model_len == 0 || sched.max_model_len > self.meta.max_sequence_length {
+            err(
+                "scheduler.max_model_len",
+                format!(
+                    "{} is not in [1, {}] (the model's max_sequence_length)",
+                    sched.max_model_len, self.meta.max_sequence_length
+                ),
+                format!("set it to at most {}", self.meta.max_sequence_length),
+            );
+        }
+        if sched.max_num_batched_tokens < sched.max_num_seqs {
+            err(
+                "scheduler.max_num_batched_tokens",
+                format!(
+                    "{} is less than max_num_seqs ({}), so a full batch can't be decoded in one step",
+                    sched.max_num_batched_tokens, sched.max_num_seqs
+                ),
+                format!(
+                    "raise it to at least {} or lower max_num_seqs",
+                    sched.max_num_seqs
+                ),
+            );
+        }
+        if sched.max_num_kv_tokens < sched.max_model_len {
+            err(
+                "scheduler.max_num_kv_tokens",
+                format!(
+                    "{} is less than max_model_len ({}), so a sequence of max length never fits",
+                    sched.max_num_kv_tokens, sched.max_model_len
+                ),
+                format!(
+                    "raise it to at least {} or lower max_model_len",
+                    sched.max_model_len
+                ),
+            );
+        }
+        if let Some(sink) = sched.kv_truncation_sink {
+            if sink >= sched.max_model_len {
+                err(
+                    "scheduler.kv_truncation_sink",
+                    format!(
+                        "{} must be less than max_model_len ({})",
+                        sink, sched.max_model_len
+                    ),
+                    "use a few tokens (e.g. 4), or leave it unset".to_string(),
+                );
+            }
+        }
+        if self.aici.max_fuel < 100 {
+            err(
+                "aici.max_fuel",
+                format!("{} is less than 100", self.aici.max_fuel),
+                "leave it at 0 to derive it from max_sequence_length".to_string(),
+            );
+        }
+
+        ME::validate_config(self, &mut errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// A violated constraint in RllmConfig, see RllmConfig::validate().
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    /// Path of the offending field, e.g. "scheduler.max_num_seqs".
+    pub field: &'static str,
+    pub message: String,
+    /// Suggested way to fix it.
+    pub fix: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}; {}", self.field, self.message, self.fix)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ModelMeta {
     pub id: String,
@@ -47,6 +167,45 @@ pub struct SchedulerConfig {
     pub max_num_seqs: usize,
     /// Maximum length of a sequence (including prompt and generated text).
     pub max_model_len: usize,
+    /// By default sequences are finished (with FinishReason::MaxModelLenReached)
+    /// once they reach max_model_len.
+    /// If set, for models that tolerate it, they keep going, but attention only sees
+    /// the first `kv_truncation_sink` tokens ("attention sinks") and the most recent
+    /// tokens, so that at most max_model_len KV entries are used.
+    /// Query positions are shifted to stay below max_model_len; keys already in the cache
+    /// keep their original positions, so this is an approximation (StreamingLLM-style).
+    /// The tokens after the sink are dropped in whole KV blocks, so attention may see
+    /// slightly fewer than max_model_len entries.
+    /// KV blocks outside of the window are not freed.
+    pub kv_truncation_sink: Option<usize>,
+    /// If set, every sampler is seeded from this and the request id, and requests
+    /// are prioritized by arrival order rather than arrival time, so that
+    /// the same requests give the same output when run again.
+    /// GPU kernels (atomics in reductions, split-K matmuls) can still differ between runs;
+    /// the CPU backend is fully deterministic.
+    pub deterministic_seed: Option<u64>,
+    /// Identical prompts waiting at the same time are prefilled once, with the
+    /// KV blocks shared by all the requests; see Scheduler::add_seq_group().
+    pub coalesce_prompts: bool,
+    /// Whether requests coalesced into another's prefill are billed for their
+    /// prompt tokens in TokenUsage (as if they were prefilled on their own).
+    pub bill_coalesced_prompts: bool,
+    /// Requests not scheduled within this time of arrival are finished with
+    /// FinishReason::QueueTimeout, without running; an earlier per-request
+    /// deadline takes precedence. Requests that already ran are never shed.
+    pub queue_timeout: Option<Duration>,
+    /// Build the RequestOutputs of a step at the start of the next one, while its
+    /// forward pass runs, rather than between sampling and the next forward pass.
+    /// Sampling is unchanged; every output is streamed one step later.
+    pub pipeline_outputs: bool,
+}
+
+impl SchedulerConfig {
+    /// Sampler seed for sequence `idx` of `request_id`, when deterministic_seed is set.
+    pub fn request_seed(&self, request_id: &str, idx: usize) -> Option<u64> {
+        self.deterministic_seed
+            .map(|seed| fxhash::hash64(&(seed, request_id, idx)))
+    }
 }
 
 pub const SAMPLING_EPS: f32 = 1e-5;
@@ -58,6 +217,30 @@ pub enum EarlyStopping {
     Never,
 }
 
+/// What a request computes; everything but Generate is answered from the
+/// prefill alone, and finishes with FinishReason::PrefillOnly.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestKind {
+    #[default]
+    Generate,
+    /// Log-probabilities of the last `continuation_len` prompt tokens, each
+    /// given the tokens before it; see PrefillOutput::Scores.
+    Score { continuation_len: usize },
+    /// The final hidden state (after the last norm), pooled over the prompt;
+    /// see PrefillOutput::Embedding.
+    Embed { pooling: Pooling },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    /// The hidden state of the last prompt token.
+    Last,
+    /// The mean over all prompt tokens.
+    Mean,
+}
+
 /// Sampling parameters for text generation.
 ///
 /// Overall, we follow the sampling parameters from the OpenAI text completion
@@ -115,6 +298,29 @@ pub struct SamplingParams {
 
     /// Number of log probabilities to return per output token.
     pub logprobs: Option<i32>,
+
+    /// Added to the logits of the given tokens before sampling, in [-100, 100].
+    pub logit_bias: HashMap<u32, f32>,
+
+    /// Phrases that are never generated, with or without a leading space.
+    pub bad_words: Vec<String>,
+
+    /// Tokenizations of bad_words; filled in by the engine.
+    #[serde(skip)]
+    pub bad_words_tokens: Vec<Vec<u32>>,
+
+    /// Tokens that are never sampled. None means the tokenizer's special added
+    /// tokens other than EOS, unless a controller is set (it decides what's allowed then).
+    pub suppress_tokens: Option<Vec<u32>>,
+
+    /// Tokens left out of the output text, even when generated; they are still
+    /// in the output token ids. None means the tokenizer's special added tokens
+    /// other than EOS.
+    pub hide_tokens: Option<Vec<u32>>,
+
+    /// Generate (the default), or only score or embed the prompt.
+    #[serde(default)]
+    pub kind: RequestKind,
 }
 
 impl SamplingParams {
@@ -137,6 +343,12 @@ impl SamplingParams {
             ignore_eos: false,
             max_tokens: 16,
             logprobs: None,
+            logit_bias: HashMap::default(),
+            bad_words: Vec::new(),
+            bad_words_tokens: Vec::new(),
+            suppress_tokens: None,
+            hide_tokens: None,
+            kind: RequestKind::Generate,
         };
         r.verify_args().unwrap();
         r
@@ -211,6 +423,33 @@ impl SamplingParams {
                 bail_user!("logprobs must be non-negative, got {}.", logprobs);
             }
         }
+        for (tok, bias) in self.logit_bias.iter() {
+            if !(*bias >= -100.0 && *bias <= 100.0) {
+                bail_user!(
+                    "logit_bias must be in [-100, 100], got {} for {}.",
+                    bias,
+                    tok
+                );
+            }
+        }
+        if self.bad_words.iter().any(|w| w.is_empty()) {
+            bail_user!("bad_words can't be empty strings.");
+        }
+        if self.kind != RequestKind::Generate {
+            if self.best_of != 1 || self.use_beam_search || self.controller.is_some() {
+                bail_user!(
+                    "score and embed requests can't use n, best_of, beam search or a controller."
+                );
+            }
+            if matches!(
+                self.kind,
+                RequestKind::Score {
+                    continuation_len: 0
+                }
+            ) {
+                bail_user!("continuation_len must be at least 1.");
+            }
+        }
         Ok(())
     }
 
@@ -0,0 +1,113 @@
+// Checks of weight files fetched from the hub (or given locally), so that a
+// truncated download is fetched again, instead of failing later with an mmap
+// or header error on every start.
+
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+use std::{fs::File, io::Read, path::Path};
+
+/// Downloads of a file before giving up on it passing check_file().
+pub const DOWNLOAD_ATTEMPTS: usize = 3;
+
+// same limit as the safetensors crate
+const MAX_HEADER_LEN: u64 = 100_000_000;
+
+pub fn is_safetensors(filename: &str) -> bool {
+    matches!(
+        Path::new(filename).extension().and_then(|e| e.to_str()),
+        Some("safetensors" | "safetensors-rust")
+    )
+}
+
+/// The size of the file according to its safetensors header: the header and
+/// the end of the last tensor.
+pub fn safetensors_expected_size(path: &Path) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    if size < 8 {
+        bail!(
+            "{}: {size} bytes, too short for a safetensors header",
+            path.display()
+        );
+    }
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    let header_len = u64::from_le_bytes(len);
+    if header_len > MAX_HEADER_LEN || 8 + header_len > size {
+        bail!(
+            "{}: {size} bytes, with a safetensors header of {header_len}",
+            path.display()
+        );
+    }
+    let mut header = vec![0u8; header_len as usize];
+    file.read_exact(&mut header)?;
+    let header: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&header)
+        .map_err(|e| anyhow!("{}: invalid safetensors header: {e}", path.display()))?;
+    let data_len = header
+        .iter()
+        .filter(|(name, _)| *name != "__metadata__")
+        .map(|(name, info)| match info["data_offsets"][1].as_u64() {
+            Some(end) => Ok(end),
+            None => Err(anyhow!("{}: no data_offsets for {name}", path.display())),
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .max()
+        .unwrap_or(0);
+    Ok(8 + header_len + data_len)
+}
+
+/// Check that `path` (fetched as `filename`) is complete: not empty, of the size
+/// its header says for safetensors files, and with the given sha256, if any.
+pub fn check_file(path: &Path, filename: &str, sha256: Option<&str>) -> Result<()> {
+    let size = std::fs::metadata(path)?.len();
+    if size == 0 {
+        bail!("{}: empty file", path.display());
+    }
+    if is_safetensors(filename) {
+        let expected = safetensors_expected_size(path)?;
+        if size != expected {
+            bail!("{}: {size} bytes, expected {expected}", path.display());
+        }
+    }
+    if let Some(expected) = sha256 {
+        let actual = file_sha256(path)?;
+        if actual != expected {
+            bail!("{}: sha256 {actual}, expected {expected}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// The sha256 of a file in the hub cache, if the hub gave one: blobs of LFS
+/// files are named after it.
+pub fn hub_sha256(path: &Path) -> Option<String> {
+    let blob = std::fs::canonicalize(path).ok()?;
+    let name = blob.file_name()?.to_str()?;
+    if name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(name.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Remove a file from the hub cache (the blob, and the snapshot link to it),
+/// so that it's downloaded again.
+pub fn remove_cached(path: &Path) -> Result<()> {
+    let blob = std::fs::canonicalize(path)?;
+    if blob != path {
+        std::fs::remove_file(&blob)?;
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
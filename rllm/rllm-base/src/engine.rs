@@ -1,13 +1,16 @@
 use crate::{
-    config::{ParallelConfig, RllmConfig, SamplingParams, SchedulerConfig},
+    config::{ParallelConfig, RequestKind, RllmConfig, SamplingParams, SchedulerConfig},
+    download::{check_file, hub_sha256, remove_cached, DOWNLOAD_ATTEMPTS},
     iface::AiciRtIface,
+    metrics::{EngineLatency, LatencySummary, SeqGroupTiming},
     seq::{
-        AiciSampling, FinishReason, RequestOutput, SchedulingPhase, SeqOutput, Sequence,
-        SequenceGroup, Token, TokenUsage,
+        AiciSampling, FinishReason, KvSnapshot, PrefillOutput, RequestOutput, SchedulingPhase,
+        SeqOutput, Sequence, SequenceGroup, Token, TokenUsage,
     },
     util::get_setting,
-    AiciBias as _, HashMap, LoaderArgs, LogitsProcessor, ModelExec, Scheduler, SchedulerOutputs,
-    SequenceManager, TBlockSpaceManager as _,
+    AdmissionStats, AiciBias as _, BlockManagerView, CoalescingStats, ControllerSpec, HashMap,
+    LoaderArgs, LogitsProcessor, ModelExec, NoStepHooks, SampledToken, Scheduler, SchedulerOutputs,
+    SeqId, SequenceManager, StepHooks, TBlockSpaceManager as _,
 };
 use aici_abi::toktree::TokTrie;
 use aicirt::{
@@ -15,15 +18,21 @@ use aicirt::{
         AiciMidOp, AiciMidProcessReq, AiciPostOp, AiciPostPreProcessReq, AiciPreOp, ModuleInstId,
         SequenceResult,
     },
-    with_timer, TimerRef, TimerSet,
+    bail_user, with_timer, TimerRef, TimerSet,
 };
 use anyhow::{bail, Error as E, Result};
 use hf_hub::{
     api::sync::{Api, ApiRepo},
-    RepoType,
+    Cache, CacheRepo, RepoType,
 };
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, ops::Deref, path::PathBuf, sync::Arc, time::Instant};
+use std::{
+    fmt::Display,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 use tokenizers::Tokenizer;
 
 #[derive(Clone)]
@@ -40,16 +49,75 @@ pub struct ExpectedGeneration {
     pub output: Vec<ExpectedToken>,
 }
 
+/// A prompt as text, or already tokenized (e.g., by a previous call),
+/// which skips re-encoding the text.
+pub enum PromptInput<'a> {
+    Text(&'a str),
+    Tokens(Vec<Token>),
+}
+
+impl<'a> From<&'a str> for PromptInput<'a> {
+    fn from(text: &'a str) -> Self {
+        PromptInput::Text(text)
+    }
+}
+
+impl From<Vec<Token>> for PromptInput<'_> {
+    fn from(tokens: Vec<Token>) -> Self {
+        PromptInput::Tokens(tokens)
+    }
+}
+
+pub fn check_prompt_tokens(tokens: &[Token], vocab_size: usize) -> Result<()> {
+    if tokens.is_empty() {
+        bail!("empty prompt");
+    }
+    if let Some((idx, t)) = tokens
+        .iter()
+        .enumerate()
+        .find(|(_, t)| **t as usize >= vocab_size)
+    {
+        bail!("prompt token out of range ({t} >= {vocab_size} at {idx})");
+    }
+    Ok(())
+}
+
+/// Check the token ids in `params` (logit_bias, suppress_tokens and hide_tokens)
+/// against the vocabulary.
+pub fn check_sampling_tokens(params: &SamplingParams, vocab_size: usize) -> Result<()> {
+    let out_of_range = |t: &&u32| **t as usize >= vocab_size;
+    if let Some(t) = params.logit_bias.keys().find(out_of_range) {
+        bail_user!("logit_bias token out of range ({t} >= {vocab_size})");
+    }
+    let lists = [
+        ("suppress_tokens", &params.suppress_tokens),
+        ("hide_tokens", &params.hide_tokens),
+    ];
+    for (name, tokens) in lists {
+        if let Some(t) = tokens.iter().flatten().find(out_of_range) {
+            bail_user!("{name} token out of range ({t} >= {vocab_size})");
+        }
+    }
+    Ok(())
+}
+
 pub struct AddRequest {
     pub request_id: String,
     pub prompt: Vec<Token>,
     pub sampling_params: SamplingParams,
     pub expected: Option<ExpectedGeneration>,
     pub init_result: Option<SequenceResult>,
+    /// Shed the request if it isn't scheduled by then; see SequenceGroup::deadline.
+    pub deadline: Option<Instant>,
+    /// When the request arrived, if before queue_request(); defaults to now.
+    pub arrival_time: Option<Instant>,
+    /// Controller to run through the StepHooks; see StepHooks::attach().
+    pub controller: Option<ControllerSpec>,
 }
 
 pub enum Repo {
-    Api(ApiRepo),
+    /// With the cache the API downloads to.
+    Api(ApiRepo, CacheRepo),
     Local(String),
 }
 
@@ -61,12 +129,9 @@ impl Repo {
                 let api = Api::new()?;
                 let model_id = args.model_id.clone();
                 let revision = args.revision.clone().unwrap_or("main".to_string());
-                let api = api.repo(hf_hub::Repo::with_revision(
-                    model_id,
-                    RepoType::Model,
-                    revision,
-                ));
-                Ok(Repo::Api(api))
+                let repo = hf_hub::Repo::with_revision(model_id, RepoType::Model, revision);
+                let cache = Cache::default().repo(repo.clone());
+                Ok(Repo::Api(api.repo(repo), cache))
             }
         }
     }
@@ -74,17 +139,40 @@ impl Repo {
     #[allow(dead_code)]
     pub fn is_local(&self) -> bool {
         match self {
-            Repo::Api(_) => false,
+            Repo::Api(..) => false,
             Repo::Local(_) => true,
         }
     }
 
+    /// Path of `filename`, downloading it if needed. Files that fail
+    /// download::check_file() are downloaded again, up to DOWNLOAD_ATTEMPTS times.
     pub fn get(&self, filename: &str) -> Result<PathBuf> {
         match self {
-            Repo::Api(api) => api.get(filename).map_err(E::msg),
+            Repo::Api(api, cache) => {
+                // files already in the cache are only checked by size; hashing
+                // all the weights on every start would take too long
+                let mut cached = cache.get(filename).is_some();
+                let mut attempt = 1;
+                loop {
+                    let path = api.get(filename).map_err(E::msg)?;
+                    let sha256 = if cached { None } else { hub_sha256(&path) };
+                    let err = match check_file(&path, filename, sha256.as_deref()) {
+                        Ok(()) => return Ok(path),
+                        Err(e) => e,
+                    };
+                    if attempt >= DOWNLOAD_ATTEMPTS {
+                        bail!("{filename} is still broken after {attempt} attempts: {err}");
+                    }
+                    log::warn!("{err}; downloading {filename} again");
+                    remove_cached(&path)?;
+                    cached = false;
+                    attempt += 1;
+                }
+            }
             Repo::Local(path) => {
                 let p: PathBuf = (path.to_owned() + filename).into();
                 if p.exists() {
+                    check_file(&p, filename, None)?;
                     Ok(p)
                 } else {
                     bail!("file {p:?} doesn't exists")
@@ -102,7 +190,7 @@ impl Repo {
 impl Display for Repo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Repo::Api(api) => write!(f, "{}", api.url("")),
+            Repo::Api(api, _) => write!(f, "{}", api.url("")),
             Repo::Local(path) => write!(f, "{}", path),
         }
     }
@@ -112,6 +200,14 @@ impl Display for Repo {
 pub struct Stats {
     pub free_gpu_blocks: usize,
     pub free_cpu_blocks: usize,
+    /// Time to first token, over finished requests.
+    pub ttft: LatencySummary,
+    /// Inter-token latency, over finished requests.
+    pub inter_token: LatencySummary,
+    /// Block usage, without the per-sequence lists.
+    pub blocks: Option<BlockManagerView>,
+    pub coalescing: CoalescingStats,
+    pub admission: AdmissionStats,
 }
 
 impl Stats {
@@ -133,11 +229,18 @@ pub struct RllmEngine<ME: ModelExec> {
     pub alt: usize,
     pub eos_token_id: Token,
     pub space_token_id: Token,
+    /// The tokenizer's special added tokens other than EOS; the default
+    /// suppress_tokens and hide_tokens.
+    pub added_tokens: Vec<Token>,
     pub num_errors: usize,
 
     post_ops: Vec<AiciPostOp>,
+    /// Requests sampled in the last step, whose outputs are built in the next one;
+    /// see SchedulerConfig::pipeline_outputs.
+    deferred_outputs: Vec<String>,
 
     pub timers: TimerSet,
+    pub latency: EngineLatency,
 
     tim_step: TimerRef,
 
@@ -153,6 +256,7 @@ pub struct RllmEngine<ME: ModelExec> {
     tim_aici_post: TimerRef,
 
     aicirt: Option<AiciRtIface>,
+    hooks: Box<dyn StepHooks<ME>>,
 
     scheduler: Scheduler<ME>,
     seq_mgr: Arc<ME::SequenceManager>,
@@ -180,11 +284,24 @@ impl<ME: ModelExec> RllmEngine<ME> {
                 max_num_kv_tokens: model_len * 10,
                 max_num_seqs: 100,
                 max_model_len: model_len,
+                kv_truncation_sink: args.kv_truncation_sink,
+                deterministic_seed: args.deterministic_seed,
+                coalesce_prompts: args.coalesce_prompts,
+                bill_coalesced_prompts: args.bill_coalesced_prompts,
+                queue_timeout: args.queue_timeout,
+                pipeline_outputs: args.pipeline_outputs,
             },
             aici,
         };
 
-        ME::verify_args(&rllm_config)?;
+        if let Err(errors) = rllm_config.validate() {
+            let lines = errors
+                .iter()
+                .map(|e| format!("  {e}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            bail_user!("invalid configuration:\n{}", lines);
+        }
 
         Ok(rllm_config)
     }
@@ -196,17 +313,8 @@ impl<ME: ModelExec> RllmEngine<ME> {
         rllm_config: Arc<RllmConfig<ME>>,
     ) -> Result<Self> {
         let (tokenizer, tok_trie) = RllmEngine::<ME>::load_tokenizer(&mut args)?;
-        let eos_token_id = tok_trie.info().tok_eos;
-        let space_token_id = tok_trie.greedy_tokenize(b" ")[0];
         let repo = Repo::from(&args)?;
 
-        let scheduler = Scheduler::new(
-            tmodel.sequence_manager(),
-            block_space_manager,
-            rllm_config.clone(),
-        );
-
-        let timers = TimerSet::new();
         let mut model_id = format!("{}", repo);
         match &args.revision {
             Some(r) => model_id += &format!("@{}", r),
@@ -217,7 +325,46 @@ impl<ME: ModelExec> RllmEngine<ME> {
             None => {}
         }
 
-        Ok(RllmEngine {
+        let mut engine = Self::from_parts(
+            model_id,
+            tokenizer,
+            tok_trie,
+            tmodel,
+            block_space_manager,
+            rllm_config,
+        );
+        engine.alt = args.alt;
+        Ok(engine)
+    }
+
+    /// build(), with the tokenizer already loaded; `tok_trie` has to match it.
+    pub fn from_parts(
+        model_id: String,
+        tokenizer: Tokenizer,
+        tok_trie: TokTrie,
+        tmodel: ME,
+        block_space_manager: ME::BlockSpaceManager,
+        rllm_config: Arc<RllmConfig<ME>>,
+    ) -> Self {
+        let eos_token_id = tok_trie.info().tok_eos;
+        let space_token_id = tok_trie.greedy_tokenize(b" ")[0];
+        let mut added_tokens = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(t, added)| added.special && *t != eos_token_id)
+            .map(|(t, _)| t)
+            .collect::<Vec<_>>();
+        added_tokens.sort();
+
+        let scheduler = Scheduler::new(
+            tmodel.sequence_manager(),
+            block_space_manager,
+            rllm_config.clone(),
+        );
+
+        let timers = TimerSet::new();
+
+        RllmEngine {
             config: rllm_config,
             tokenizer: Arc::new(tokenizer),
             tok_trie: Arc::new(tok_trie),
@@ -229,10 +376,14 @@ impl<ME: ModelExec> RllmEngine<ME> {
             num_errors: 0,
             eos_token_id,
             space_token_id,
-            alt: args.alt,
+            added_tokens,
+            alt: 0,
             scheduler,
             aicirt: None,
+            hooks: Box::new(NoStepHooks),
             post_ops: Vec::new(),
+            deferred_outputs: Vec::new(),
+            latency: EngineLatency::default(),
             tim_step: timers.new_timer("step"),
             tim_schedule: timers.new_timer("step.schedule"),
             tim_aici_mid: timers.new_timer("step.aici_mid"),
@@ -243,7 +394,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
             tim_logit_sample: timers.new_timer("step.run_model.sample.sample"),
             tim_aici_post: timers.new_timer("step.run_model.sample.aici_post"),
             timers,
-        })
+        }
     }
 
     pub fn load_tokenizer(args: &mut LoaderArgs) -> Result<(Tokenizer, TokTrie)> {
@@ -263,15 +414,172 @@ impl<ME: ModelExec> RllmEngine<ME> {
         self.aicirt = Some(aicirt);
     }
 
+    pub fn set_step_hooks(&mut self, hooks: Box<dyn StepHooks<ME>>) {
+        self.hooks = hooks;
+    }
+
     pub fn gen_req_id(&mut self) -> String {
         self.req_id_cnt += 1;
         format!("_{}", self.req_id_cnt)
     }
 
+    /// Fork a running sequence into `n` new sequences of the same request,
+    /// sharing its KV cache. See Scheduler::fork_seq().
+    pub fn fork_sequence(&mut self, seq_id: SeqId, n: usize) -> Result<Vec<SeqId>> {
+        let ids = self.scheduler.fork_seq(seq_id, n)?;
+        for id in &ids {
+            self.hooks.fork(seq_id, *id);
+        }
+        Ok(ids)
+    }
+
+    pub fn set_seq_sampling_params(
+        &mut self,
+        seq_id: SeqId,
+        mut params: SamplingParams,
+    ) -> Result<()> {
+        check_sampling_tokens(&params, self.config.meta.vocab_size)?;
+        self.tokenize_bad_words(&mut params)?;
+        self.resolve_token_filters(&mut params);
+        self.scheduler.set_seq_sampling_params(seq_id, params)
+    }
+
+    /// Fill in params.bad_words_tokens, with each bad word tokenized as is,
+    /// and with a leading space.
+    pub fn tokenize_bad_words(&self, params: &mut SamplingParams) -> Result<()> {
+        let mut res: Vec<Vec<Token>> = Vec::new();
+        for word in params.bad_words.iter() {
+            for text in [word.clone(), format!(" {word}")] {
+                let tokens = self.tokenize(&text, false)?;
+                if !tokens.is_empty() && !res.contains(&tokens) {
+                    res.push(tokens);
+                }
+            }
+        }
+        params.bad_words_tokens = res;
+        Ok(())
+    }
+
+    /// Fill in the defaults of params.suppress_tokens and params.hide_tokens.
+    fn resolve_token_filters(&self, params: &mut SamplingParams) {
+        if params.suppress_tokens.is_none() {
+            params.suppress_tokens = Some(if params.controller.is_some() {
+                Vec::new()
+            } else {
+                self.added_tokens.clone()
+            });
+        }
+        if params.hide_tokens.is_none() {
+            params.hide_tokens = Some(self.added_tokens.clone());
+        }
+    }
+
+    /// The scheduler, to look at (or change) the queues between steps.
+    pub fn scheduler(&self) -> &Scheduler<ME> {
+        &self.scheduler
+    }
+
+    pub fn scheduler_mut(&mut self) -> &mut Scheduler<ME> {
+        &mut self.scheduler
+    }
+
+    pub fn abort_sequence(&mut self, seq_id: SeqId) -> Result<()> {
+        self.scheduler.abort_seq(seq_id)
+    }
+
+    /// Copy the KV cache of a running sequence to host memory, and drop its request.
+    /// See Scheduler::snapshot_seq().
+    pub fn snapshot_sequence(&mut self, seq_id: SeqId) -> Result<KvSnapshot<ME::KvData>> {
+        let tmodel = &mut self.tmodel;
+        let snapshot = self
+            .scheduler
+            .snapshot_seq(seq_id, |seq_id, num_kv| tmodel.save_kv(seq_id, num_kv))?;
+        self.hooks.detach(seq_id);
+        Ok(snapshot)
+    }
+
+    /// Continue a sequence from snapshot_sequence() as a new request.
+    pub fn restore_sequence(
+        &mut self,
+        snapshot: &KvSnapshot<ME::KvData>,
+        request_id: String,
+        mut sampling_params: SamplingParams,
+    ) -> Result<SeqId> {
+        check_sampling_tokens(&sampling_params, self.config.meta.vocab_size)?;
+        self.tokenize_bad_words(&mut sampling_params)?;
+        self.resolve_token_filters(&mut sampling_params);
+        let prompt = self
+            .tokenizer
+            .decode(&snapshot.tokens, false)
+            .map_err(anyhow::Error::msg)?;
+        let seq = Sequence::new(self.seq_mgr.new_sequence(), &snapshot.tokens);
+        let seq_id = seq.seq_id;
+        let seed = self.config.scheduler.request_seed(&request_id, 0);
+        let sg = SequenceGroup {
+            request_id,
+            prompt,
+            seqs: vec![seq],
+            logits_processor: LogitsProcessor::new(&sampling_params, seed),
+            sampling_params,
+            deadlock_steps: 0,
+            arrival_time: Instant::now(),
+            max_index: 0,
+            usage: TokenUsage::default(),
+            timing: SeqGroupTiming::default(),
+            coalesced_into: None,
+            deadline: None,
+        };
+        let tmodel = &mut self.tmodel;
+        self.scheduler
+            .restore_seq(sg, snapshot, |seq_id, data| tmodel.load_kv(seq_id, data))?;
+        Ok(seq_id)
+    }
+
+    /// Replace the model weights with the ones in `path`, a .safetensors file or a
+    /// folder of them, and continue serving. The shapes have to match the current model.
+    ///
+    /// This is done between steps. Running sequences keep their KV cache, computed
+    /// with the old weights, so their outputs mix both; with `restart`, the KV of
+    /// (single-sequence) requests is dropped and recomputed with the new weights instead.
+    pub fn swap_weights(&mut self, path: &Path, restart: bool) -> Result<()> {
+        let filenames = if path.is_dir() {
+            let mut files = std::fs::read_dir(path)?
+                .map(|e| Ok(e?.path()))
+                .collect::<Result<Vec<_>>>()?;
+            files.retain(|p| p.extension().map_or(false, |e| e == "safetensors"));
+            files.sort();
+            files
+        } else {
+            vec![path.to_path_buf()]
+        };
+        if filenames.is_empty() {
+            bail_user!("no .safetensors files in {}", path.display());
+        }
+        let t0 = Instant::now();
+        self.tmodel.swap_weights(filenames)?;
+        let num_restarted = if restart {
+            self.scheduler.recompute_running()
+        } else {
+            0
+        };
+        log::info!(
+            "swapped weights from {} in {:?}; {} requests restarted",
+            path.display(),
+            t0.elapsed(),
+            num_restarted
+        );
+        Ok(())
+    }
+
     pub fn abort_request(&mut self, request_id: &str) {
         self.scheduler.abort_seq_group(request_id);
     }
 
+    /// See ModelExec::memory_bytes().
+    pub fn memory_bytes(&self) -> usize {
+        self.tmodel.memory_bytes()
+    }
+
     pub fn num_pending_requests(&self) -> usize {
         self.scheduler.get_num_unfinished_seq_groups()
     }
@@ -284,7 +592,28 @@ impl<ME: ModelExec> RllmEngine<ME> {
         Ok(tokens.get_ids().to_vec())
     }
 
-    pub fn queue_request(&mut self, req: AddRequest) -> Result<()> {
+    /// Tokens for the prompt; text is tokenized with special tokens added,
+    /// token lists are used as is.
+    pub fn prompt_tokens(&self, prompt: PromptInput) -> Result<Vec<Token>> {
+        match prompt {
+            PromptInput::Text(text) => self.tokenize(text, true),
+            PromptInput::Tokens(tokens) => Ok(tokens),
+        }
+    }
+
+    pub fn queue_request(&mut self, mut req: AddRequest) -> Result<()> {
+        check_prompt_tokens(&req.prompt, self.config.meta.vocab_size)?;
+        check_sampling_tokens(&req.sampling_params, self.config.meta.vocab_size)?;
+        if let RequestKind::Score { continuation_len } = req.sampling_params.kind {
+            if continuation_len >= req.prompt.len() {
+                bail_user!(
+                    "continuation_len={continuation_len} but the prompt has only {} tokens",
+                    req.prompt.len()
+                );
+            }
+        }
+        self.tokenize_bad_words(&mut req.sampling_params)?;
+        self.resolve_token_filters(&mut req.sampling_params);
         let mut seq = Sequence::new(self.seq_mgr.new_sequence(), &req.prompt);
         match req.init_result {
             Some(r) => seq.aici_logs.push(r.clone()),
@@ -295,7 +624,8 @@ impl<ME: ModelExec> RllmEngine<ME> {
             .map(|_| self.seq_mgr.new_sequence())
             .collect::<Vec<_>>();
 
-        let logits_processor = LogitsProcessor::new(&req.sampling_params);
+        let seed = self.config.scheduler.request_seed(&req.request_id, 0);
+        let logits_processor = LogitsProcessor::new(&req.sampling_params, seed);
         let prompt = self
             .tokenizer
             .decode(&req.prompt, false)
@@ -307,12 +637,31 @@ impl<ME: ModelExec> RllmEngine<ME> {
             seqs: vec![seq],
             sampling_params: req.sampling_params,
             deadlock_steps: 0,
-            arrival_time: Instant::now(),
+            arrival_time: req.arrival_time.unwrap_or_else(Instant::now),
             logits_processor,
             max_index: 0,
             usage: TokenUsage::default(),
+            timing: SeqGroupTiming::default(),
+            coalesced_into: None,
+            deadline: req.deadline,
         };
 
+        if let Some(spec) = &req.controller {
+            if sg.sampling_params.kind != RequestKind::Generate {
+                bail!(
+                    "can't attach {:?} to a score or embed request",
+                    spec.module_id
+                );
+            }
+            if sg.sampling_params.controller.is_some() {
+                bail!(
+                    "can't attach {:?} to a request with an aicirt controller",
+                    spec.module_id
+                );
+            }
+            self.hooks.attach(sg.seqs[0].seq_id, spec)?;
+        }
+
         self.scheduler.add_seq_group(sg);
 
         Ok(())
@@ -333,22 +682,28 @@ impl<ME: ModelExec> RllmEngine<ME> {
             },
             expected: Some(exp_gen),
             init_result: None,
+            deadline: None,
+            arrival_time: None,
+            controller: None,
         })
     }
 
     pub fn add_request(
         &mut self,
         request_id: String,
-        prompt: &str,
+        prompt: PromptInput,
         sampling_params: SamplingParams,
     ) -> Result<()> {
-        let tokens = self.tokenize(prompt, true)?;
+        let tokens = self.prompt_tokens(prompt)?;
         self.queue_request(AddRequest {
             request_id,
             prompt: tokens,
             sampling_params,
             expected: None,
             init_result: None,
+            deadline: None,
+            arrival_time: None,
+            controller: None,
         })
     }
 
@@ -430,6 +785,33 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
     }
 
+    /// Answer a score or embed request from the prefill that just ran, and finish it.
+    fn finish_prefill_only(&mut self, seq: &mut Sequence, kind: &RequestKind, sidx: usize) {
+        let res = match kind {
+            RequestKind::Generate => unreachable!(),
+            RequestKind::Score { continuation_len } => {
+                let continuation = &seq.tokens()[seq.get_len() - continuation_len..];
+                self.tmodel
+                    .get_continuation_logprobs(sidx, continuation)
+                    .map(PrefillOutput::Scores)
+            }
+            RequestKind::Embed { pooling } => self
+                .tmodel
+                .get_embedding(sidx, *pooling)
+                .map(PrefillOutput::Embedding),
+        };
+        match res {
+            Ok(out) => {
+                seq.prefill_output = Some(out);
+                self.scheduler.finish_seq(seq, FinishReason::PrefillOnly);
+            }
+            Err(e) => {
+                log::warn!("seq {}: {e}", seq.seq_id);
+                self.scheduler.finish_seq(seq, FinishReason::Failed);
+            }
+        }
+    }
+
     fn check_expected(&mut self, mut logits: Vec<f32>, req_id: &str, seq: &mut Sequence) -> Token {
         let exp = seq.expected.as_ref().unwrap();
         let idx = seq.get_len() - exp.prompt.len();
@@ -500,10 +882,13 @@ impl<ME: ModelExec> RllmEngine<ME> {
             }
         });
 
-        sched_out
-            .dropped_seq_groups
-            .iter_mut()
-            .for_each(|sg| res.push(self.req_output(sg, true)));
+        for sg in sched_out.dropped_seq_groups.iter_mut() {
+            self.latency.add_finished(sg.arrival_time, &sg.timing);
+            res.push(self.req_output(sg, true));
+            for seq in sg.seqs.iter() {
+                self.hooks.detach(seq.seq_id);
+            }
+        }
 
         res
     }
@@ -513,11 +898,39 @@ impl<ME: ModelExec> RllmEngine<ME> {
         Ok(self.dropped_outputs(sched_out))
     }
 
+    /// Non-final outputs of the requests sampled in the last step, in the order they
+    /// were sampled. Empty unless SchedulerConfig::pipeline_outputs is set.
+    fn pipelined_outputs(&mut self, sched_out: &mut SchedulerOutputs) -> Vec<RequestOutput> {
+        let ids = std::mem::take(&mut self.deferred_outputs);
+        if ids.is_empty() {
+            return Vec::new();
+        }
+        let order = ids
+            .iter()
+            .enumerate()
+            .map(|(idx, id)| (id.as_str(), idx))
+            .collect::<HashMap<_, _>>();
+        let mut res = Vec::new();
+        let tok_trie = &self.tok_trie;
+        let mut add = |sg: &mut SequenceGroup| {
+            if let Some(idx) = order.get(sg.request_id.as_str()) {
+                res.push((*idx, sg.request_output(tok_trie, false)));
+            }
+        };
+        // the group may have been scheduled again, finished, or preempted since
+        sched_out.next_seq_groups.iter_mut().for_each(&mut add);
+        sched_out.dropped_seq_groups.iter_mut().for_each(&mut add);
+        self.scheduler.for_each_sg(&mut add);
+        res.sort_by_key(|(idx, _)| *idx);
+        res.into_iter().map(|(_, r)| r).collect()
+    }
+
     fn sample(
         &mut self,
         sched_out: &mut SchedulerOutputs,
+        mut outputs: Vec<RequestOutput>,
     ) -> Result<(Vec<RequestOutput>, Vec<AiciPostOp>)> {
-        let mut seq_id_mapping = HashMap::default();
+        let mut seq_id_mapping = self.scheduler.split_coalesced(sched_out);
 
         for sg in sched_out.next_seq_groups.iter_mut() {
             let mut to_add = Vec::new();
@@ -525,11 +938,14 @@ impl<ME: ModelExec> RllmEngine<ME> {
                 if seq.sched_phase != SchedulingPhase::Running {
                     continue;
                 }
+                let sidx = seq.seq_id.to_num();
+                let sidx = *seq_id_mapping.get(&sidx).unwrap_or(&sidx);
                 // get it even if no pending - make sure we have them all
                 let pending = std::mem::take(&mut seq.pending_fork_ids);
                 for copy_id in pending {
-                    seq_id_mapping.insert(copy_id.to_num(), seq.seq_id.to_num());
+                    seq_id_mapping.insert(copy_id.to_num(), sidx);
                     let copy = seq.fork_as(self.seq_mgr.deref(), copy_id, sg.max_index + 1);
+                    self.hooks.fork(seq.seq_id, copy_id);
                     sg.max_index += 1;
                     log::debug!("forked: {:?} -> {:?}", seq, copy);
                     to_add.push(copy);
@@ -540,9 +956,16 @@ impl<ME: ModelExec> RllmEngine<ME> {
 
         let aici_bias = with_timer!(self.tim_aici_bias, self.aici_bias(sched_out)?);
 
+        // this has to happen after aici_bias(), which needs all sequences sent to aicirt
+        self.scheduler.fail_seq_groups(sched_out);
+
         let mut post_ops = Vec::new();
+        let mut sampled_tokens = Vec::new();
+        let now = Instant::now();
 
         for sg in sched_out.next_seq_groups.iter_mut() {
+            let mut sampled = false;
+            let kind = sg.sampling_params.kind.clone();
             for seq in sg.seqs.iter_mut() {
                 if seq.sched_phase != SchedulingPhase::Running {
                     continue;
@@ -550,6 +973,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
 
                 let sidx = seq.seq_id.to_num();
                 let sidx = seq_id_mapping.get(&sidx).unwrap_or(&sidx);
+                if kind != RequestKind::Generate {
+                    self.finish_prefill_only(seq, &kind, *sidx);
+                    sampled = true;
+                    continue;
+                }
                 let mut logits = self.tmodel.get_logits(*sidx);
 
                 if let Some(op) = self.aici_apply_bias(seq, &mut logits, &aici_bias) {
@@ -561,10 +989,27 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     let logits = ME::tensor_to_vec1(&logits);
                     self.check_expected(logits, &sg.request_id, seq)
                 } else {
-                    with_timer!(
+                    let mut bias = match seq.own_sampling.as_ref() {
+                        Some((_, lp)) => lp,
+                        None => &sg.logits_processor,
+                    }
+                    .sparse_bias(seq.tokens());
+                    if !seq.has_aici {
+                        bias.extend(self.hooks.pre_sample(self.step_no, seq, &logits));
+                    }
+                    if !bias.is_empty() {
+                        self.tmodel.add_sparse_bias(&mut logits, &bias);
+                    }
+                    let logits_processor = match seq.own_sampling.as_mut() {
+                        Some((_, lp)) => lp,
+                        None => &mut sg.logits_processor,
+                    };
+                    let t = with_timer!(
                         self.tim_logit_sample,
-                        self.tmodel.sample(&mut sg.logits_processor, &logits)?
-                    )
+                        self.tmodel.sample(logits_processor, &logits)?
+                    );
+                    seq.cumulative_logprob += self.tmodel.logprob(&logits, t);
+                    t
                 };
 
                 let mut info = "";
@@ -576,6 +1021,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
                 } else {
                     seq.append_tokens(&[next_token]);
                 }
+                sampled = true;
 
                 if seq.has_aici {
                     post_ops.push(AiciPostOp {
@@ -583,6 +1029,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
                         tokens: vec![next_token],
                         backtrack: 0,
                     });
+                } else {
+                    sampled_tokens.push(SampledToken {
+                        seq_id: seq.seq_id,
+                        token: next_token,
+                    });
                 }
 
                 log::trace!(
@@ -592,37 +1043,60 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     info
                 );
 
-                if !sg.sampling_params.ignore_eos && next_token == self.eos_token_id {
+                let params = match &seq.own_sampling {
+                    Some((p, _)) => p,
+                    None => &sg.sampling_params,
+                };
+                let (ignore_eos, max_tokens) = (params.ignore_eos, params.max_tokens);
+                if !ignore_eos && next_token == self.eos_token_id {
                     self.scheduler.finish_seq(seq, FinishReason::FoundEos);
-                } else if seq.get_gen_len() >= sg.sampling_params.max_tokens {
+                } else if seq.get_gen_len() >= max_tokens {
                     self.scheduler
                         .finish_seq(seq, FinishReason::MaxTokensReached);
                 }
             }
+            if sampled {
+                sg.timing.on_tokens(now);
+            }
+        }
+
+        if !sampled_tokens.is_empty() {
+            let cmds = self.hooks.post_sample(self.step_no, &sampled_tokens);
+            let mut cmds = sampled_tokens
+                .iter()
+                .map(|s| s.seq_id)
+                .zip(cmds)
+                .collect::<HashMap<_, _>>();
+            for sg in sched_out.next_seq_groups.iter_mut() {
+                for seq in sg.seqs.iter_mut() {
+                    if let Some(cmd) = cmds.remove(&seq.seq_id) {
+                        self.scheduler.apply_seq_command(seq, cmd);
+                    }
+                }
+            }
         }
 
-        let mut outputs = self.dropped_outputs(sched_out);
-        outputs.extend(
-            sched_out
+        outputs.extend(self.dropped_outputs(sched_out));
+        if self.config.scheduler.pipeline_outputs {
+            self.deferred_outputs = sched_out
                 .next_seq_groups
-                .iter_mut()
-                .map(|sg| self.req_output(sg, false)),
-        );
+                .iter()
+                .map(|sg| sg.request_id.clone())
+                .collect();
+        } else {
+            outputs.extend(
+                sched_out
+                    .next_seq_groups
+                    .iter_mut()
+                    .map(|sg| self.req_output(sg, false)),
+            );
+        }
 
         Ok((outputs, post_ops))
     }
 
     fn req_output(&self, sg: &mut SequenceGroup, is_final: bool) -> RequestOutput {
-        RequestOutput {
-            request_id: sg.request_id.clone(),
-            seq_outputs: sg
-                .seqs
-                .iter_mut()
-                .map(|seq| seq.gen_output(&self.tok_trie))
-                .collect(),
-            usage: sg.usage.clone(),
-            is_final,
-        }
+        sg.request_output(&self.tok_trie, is_final)
     }
 
     fn run_model(
@@ -631,7 +1105,9 @@ impl<ME: ModelExec> RllmEngine<ME> {
     ) -> Result<(Vec<RequestOutput>, Vec<AiciPostOp>)> {
         if sched_out.is_empty() {
             log::debug!("no seqs to run");
-            return Ok((self.empty_outputs(sched_out)?, vec![]));
+            let mut outputs = self.pipelined_outputs(sched_out);
+            outputs.extend(self.empty_outputs(sched_out)?);
+            return Ok((outputs, vec![]));
         }
 
         self.tmodel.run(
@@ -641,7 +1117,9 @@ impl<ME: ModelExec> RllmEngine<ME> {
             sched_out,
         )?;
 
-        let r = with_timer!(self.tim_sample, { self.sample(sched_out) });
+        // the forward pass is still running on the GPU; sample() waits for it
+        let outputs = self.pipelined_outputs(sched_out);
+        let r = with_timer!(self.tim_sample, { self.sample(sched_out, outputs) });
 
         self.tmodel.finalize_run()?;
 
@@ -847,6 +1325,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
 
         if self.step_no % 20 == 0 {
             log::debug!("timers\n{}", self.timers.pp());
+            log::debug!("latency: {}", self.latency);
             self.timers.reset();
         }
 
@@ -868,11 +1347,13 @@ impl<ME: ModelExec> RllmEngine<ME> {
         });
 
         let mut sched_out = with_timer!(self.tim_schedule, self.scheduler.schedule());
+        self.hooks.scheduled(self.step_no, &sched_out);
 
         with_timer!(self.tim_aici_mid, self.aici_mid(&mut sched_out)?);
 
         log::trace!(
-            "scheduled: {} groups, dropped: {}",
+            "step {}: scheduled {} groups, dropped: {}",
+            self.step_no,
             sched_out.next_seq_groups.len(),
             sched_out.dropped_seq_groups.len()
         );
@@ -881,7 +1362,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
         self.scheduler.step_finished(sched_out);
 
         let (outputs, post_ops) = outputs?;
-        if outputs.is_empty() {
+        if outputs.is_empty() && self.deferred_outputs.is_empty() {
             assert!(!self.scheduler.has_unfinished_seqs());
         }
 
@@ -891,45 +1372,71 @@ impl<ME: ModelExec> RllmEngine<ME> {
         Ok(outputs)
     }
 
-    fn decode_seq(&self, tokens: &Vec<Token>) -> Result<String> {
+    fn decode_seq(&self, tokens: &[Token], hide_tokens: &[Token]) -> Result<String> {
+        let tokens = tokens
+            .iter()
+            .filter(|t| !hide_tokens.contains(t))
+            .cloned()
+            .collect::<Vec<_>>();
         let generated = self
             .tokenizer
-            .decode(tokens, true)
+            .decode(&tokens, true)
             .map_err(anyhow::Error::msg)?;
         Ok(generated)
     }
 
-    pub fn generate(&mut self, prompt: &str, sampling_params: SamplingParams) -> Result<String> {
+    pub fn generate(
+        &mut self,
+        prompt: PromptInput,
+        mut sampling_params: SamplingParams,
+    ) -> Result<String> {
         let req_id = self.gen_req_id();
-        self.add_request(req_id, prompt, sampling_params)?;
+        let tokens = self.prompt_tokens(prompt)?;
+        let prompt_tokens = tokens.len();
+        self.resolve_token_filters(&mut sampling_params);
+        let hide_tokens = sampling_params.hide_tokens.clone().unwrap();
+        self.add_request(req_id.clone(), PromptInput::Tokens(tokens), sampling_params)?;
 
         let mut outputs = Vec::new();
         let t0 = Instant::now();
+        let step0 = self.step_no;
 
         while self.scheduler.has_unfinished_seqs() {
+            // with pipeline_outputs, a step can return two outputs of the request:
+            // the one deferred from the previous step, and the final one
             let outp = self.step()?;
-            if !outp.is_empty() {
-                assert!(outp.len() == 1);
-                assert!(outp[0].seq_outputs.len() == 1);
-                outputs = outp[0].seq_outputs[0].output_tokens.clone();
+            if let Some(last) = outp.iter().filter(|o| o.request_id == req_id).last() {
+                assert!(last.seq_outputs.len() == 1);
+                outputs = last.seq_outputs[0].output_tokens.clone();
             }
         }
 
         let dur = Instant::now().duration_since(t0);
         log::debug!(
-            "generated {} tokens in {:?}; {:.2} t/s",
+            "generate {req_id}: {prompt_tokens} prompt tokens, {} generated in {} steps, {:?}; {:.2} t/s",
             outputs.len(),
+            self.step_no - step0,
             dur,
             outputs.len() as f64 / (dur.as_millis() as f64 / 1000.0)
         );
 
-        Ok(self.decode_seq(&outputs)?)
+        Ok(self.decode_seq(&outputs, &hide_tokens)?)
     }
 
     pub fn get_stats(&self) -> Stats {
         Stats {
             free_gpu_blocks: self.scheduler.block_manager.get_num_free_gpu_blocks(),
             free_cpu_blocks: self.scheduler.block_manager.get_num_free_cpu_blocks(),
+            ttft: self.latency.ttft.summary(),
+            inter_token: self.latency.inter_token.summary(),
+            blocks: self.block_manager_view(0),
+            coalescing: self.scheduler.coalescing_stats(),
+            admission: self.scheduler.admission_stats(),
         }
     }
+
+    /// Snapshot of the KV cache blocks; see TBlockSpaceManager::view().
+    pub fn block_manager_view(&self, max_seqs: usize) -> Option<BlockManagerView> {
+        self.scheduler.block_manager.view(max_seqs)
+    }
 }
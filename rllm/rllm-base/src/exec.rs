@@ -1,16 +1,17 @@
-use std::{fmt::Display, sync::Arc};
+use std::{fmt::Display, path::PathBuf, sync::Arc};
 
 use aicirt::TimerRef;
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{ModelMeta, RllmConfig},
+    config::{ConfigError, ModelMeta, Pooling, RllmConfig},
     scheduler::SchedulerOutputs,
-    seq::{Sequence, SequenceGroup},
+    seq::{Sequence, SequenceGroup, Token},
     HashMap, LoaderArgs, LogitsProcessor, RllmEngine,
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BlockLocation {
     GPU,
     CPU,
@@ -35,6 +36,58 @@ impl Display for SeqId {
     }
 }
 
+/// Failures while building or running a batch.
+///
+/// Errors that carry a `seq_id` only affect that sequence's group;
+/// the engine fails the group and lets the rest of the batch proceed.
+#[derive(Debug, Clone)]
+pub enum EngineError {
+    /// The KV cache doesn't have the blocks the sequence is supposed to own.
+    BlockAllocation { seq_id: SeqId, detail: String },
+    /// The sequence state is inconsistent with itself or with the KV cache.
+    InconsistentSequence { seq_id: SeqId, detail: String },
+    /// The sequence doesn't fit the limits of the attention kernels.
+    OverLimit { seq_id: SeqId, detail: String },
+    /// The model or one of its kernels failed for the whole batch.
+    KernelFailure(String),
+    /// The logits of the sequence have NaN or Inf values (with the check_logits setting).
+    NonFiniteLogits { seq_id: SeqId, detail: String },
+}
+
+impl EngineError {
+    pub fn seq_id(&self) -> Option<SeqId> {
+        match self {
+            EngineError::BlockAllocation { seq_id, .. }
+            | EngineError::InconsistentSequence { seq_id, .. }
+            | EngineError::OverLimit { seq_id, .. }
+            | EngineError::NonFiniteLogits { seq_id, .. } => Some(*seq_id),
+            EngineError::KernelFailure(_) => None,
+        }
+    }
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EngineError::BlockAllocation { seq_id, detail } => {
+                write!(f, "block allocation error in seq {seq_id}: {detail}")
+            }
+            EngineError::InconsistentSequence { seq_id, detail } => {
+                write!(f, "inconsistent seq {seq_id}: {detail}")
+            }
+            EngineError::OverLimit { seq_id, detail } => {
+                write!(f, "seq {seq_id} over kernel limits: {detail}")
+            }
+            EngineError::KernelFailure(detail) => write!(f, "kernel failure: {detail}"),
+            EngineError::NonFiniteLogits { seq_id, detail } => {
+                write!(f, "seq {seq_id} has non-finite logits: {detail}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
 pub trait SequenceManager {
     fn new_sequence(&self) -> SeqId;
     fn copy(&self, src: SeqId, dst: SeqId, length: usize);
@@ -49,6 +102,8 @@ pub trait ModelExec: Sized {
     type ModelConfig;
     type ModelLoaderArgs: Send + 'static;
     type SequenceManager: SequenceManager;
+    /// KV cache entries of one sequence, copied out of the cache; see Scheduler::snapshot_seq().
+    type KvData;
 
     fn tensor_to_vec1(tensor: &Self::Tensor) -> Vec<f32>;
 
@@ -56,7 +111,12 @@ pub trait ModelExec: Sized {
         args: &LoaderArgs,
         model_args: &mut Self::ModelLoaderArgs,
     ) -> Result<(ModelMeta, Self::ModelConfig)>;
-    fn verify_args(args: &RllmConfig<Self>) -> Result<()>;
+    /// Backend-specific part of RllmConfig::validate(); push any violations to `errors`.
+    fn validate_config(_config: &RllmConfig<Self>, _errors: &mut Vec<ConfigError>) {}
+    /// Memory held by the weights and the KV cache, in bytes; 0 if unknown.
+    fn memory_bytes(&self) -> usize {
+        0
+    }
     fn load_rllm_engine(
         args: LoaderArgs,
         model_args: Self::ModelLoaderArgs,
@@ -79,6 +139,80 @@ pub trait ModelExec: Sized {
         -> Self::AiciBias;
 
     fn sample(&self, processor: &mut LogitsProcessor, logits: &Self::Tensor) -> Result<u32>;
+
+    /// Add `bias` to the given entries of `logits`.
+    fn add_sparse_bias(&self, logits: &mut Self::Tensor, bias: &[(Token, f32)]);
+
+    /// Log-probability of `token` under `logits`. Called for every sampled token,
+    /// so it should be computed where the logits live.
+    fn logprob(&self, logits: &Self::Tensor, token: Token) -> f32;
+
+    /// Log-probabilities of the last `continuation.len()` tokens of the query of
+    /// `seq_id` in the last run, each given the tokens before it. Only for
+    /// sequences of score requests; see RequestKind::Score.
+    fn get_continuation_logprobs(
+        &self,
+        _seq_id: usize,
+        _continuation: &[Token],
+    ) -> Result<Vec<f32>> {
+        bail!("score requests not supported")
+    }
+
+    /// Final hidden state of the query of `seq_id` in the last run, pooled. Only
+    /// for sequences of embed requests; see RequestKind::Embed.
+    fn get_embedding(&self, _seq_id: usize, _pooling: Pooling) -> Result<Vec<f32>> {
+        bail!("embed requests not supported")
+    }
+
+    /// Copy the first `num_kv` KV entries of `seq_id` out of the cache.
+    fn save_kv(&mut self, _seq_id: SeqId, _num_kv: usize) -> Result<Self::KvData> {
+        bail!("KV snapshots not supported")
+    }
+
+    /// Copy `data` into the (already allocated) KV cache blocks of `seq_id`.
+    fn load_kv(&mut self, _seq_id: SeqId, _data: &Self::KvData) -> Result<()> {
+        bail!("KV snapshots not supported")
+    }
+
+    /// Replace the weights with the ones in `filenames` (safetensors), keeping the
+    /// configuration and the KV cache. On error, the current weights are kept.
+    fn swap_weights(&mut self, _filenames: Vec<PathBuf>) -> Result<()> {
+        bail!("swapping weights not supported")
+    }
+}
+
+/// Usage of one pool (GPU or CPU) of KV cache blocks.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockPoolView {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+    /// Blocks referenced by more than one sequence (forks sharing a prefix).
+    pub shared: usize,
+    /// Number of runs of consecutive free blocks.
+    pub free_runs: usize,
+    pub largest_free_run: usize,
+}
+
+/// Blocks of one sequence, as (block index, reference count).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeqBlocksView {
+    pub seq_id: usize,
+    pub location: BlockLocation,
+    pub blocks: Vec<(usize, usize)>,
+}
+
+/// Read-only snapshot of the block manager, for debugging and metrics.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockManagerView {
+    pub block_size: usize,
+    pub watermark_blocks: usize,
+    pub gpu: BlockPoolView,
+    pub cpu: BlockPoolView,
+    pub num_seqs: usize,
+    /// At most `max_seqs` (see TBlockSpaceManager::view()) entries, sorted by seq_id.
+    pub seqs: Vec<SeqBlocksView>,
+    pub seqs_truncated: bool,
 }
 
 pub trait TBlockSpaceManager<ME: ModelExec> {
@@ -105,4 +239,9 @@ pub trait TBlockSpaceManager<ME: ModelExec> {
     fn can_swap_out(&self, _seq_group: &SequenceGroup) -> bool {
         false
     }
+
+    /// Current state of the blocks, listing blocks of at most `max_seqs` sequences.
+    fn view(&self, _max_seqs: usize) -> Option<BlockManagerView> {
+        None
+    }
 }
@@ -0,0 +1,187 @@
+use crate::{
+    seq::{FinishReason, Sequence, Token},
+    HashMap, ModelExec, SchedulerOutputs, SeqId,
+};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, rc::Rc};
+
+/// A controller to attach to a request, run in-process through StepHooks
+/// (unlike SamplingParams::controller, which runs in aicirt).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ControllerSpec {
+    pub module_id: String,
+    #[serde(default)]
+    pub module_arg: serde_json::Value,
+}
+
+/// A token sampled in this step; see StepHooks::post_sample().
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampledToken {
+    pub seq_id: SeqId,
+    pub token: Token,
+}
+
+/// What to do with a sequence after its token was sampled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeqCommand {
+    Continue,
+    /// Remove the last `n` tokens (including the one just sampled), and append
+    /// `replacement_tokens`. KV entries of the removed tokens are dropped, and the
+    /// streamed text shows the backtrack; see Sequence::splice_tokens().
+    Backtrack {
+        n: usize,
+        replacement_tokens: Vec<Token>,
+    },
+    Finish {
+        reason: FinishReason,
+    },
+}
+
+/// Lets an external sampler or controller step in between "logits are ready"
+/// and "tokens are appended". Sequences with an AICI controller go through
+/// aicirt instead, and are not passed to the hooks.
+pub trait StepHooks<ME: ModelExec> {
+    /// The batch of step `step_no` was scheduled, and is about to run.
+    fn scheduled(&mut self, _step_no: usize, _sched_out: &SchedulerOutputs) {}
+
+    /// Bias to add to the logits of `seq` before sampling; use f32::NEG_INFINITY
+    /// to forbid a token.
+    fn pre_sample(
+        &mut self,
+        _step_no: usize,
+        _seq: &Sequence,
+        _logits: &ME::Tensor,
+    ) -> Vec<(Token, f32)> {
+        Vec::new()
+    }
+
+    /// One command for each entry of `sampled`, in the same order. Backtrack is
+    /// ignored for sequences that finished in this step (EOS, max_tokens).
+    fn post_sample(&mut self, _step_no: usize, sampled: &[SampledToken]) -> Vec<SeqCommand> {
+        vec![SeqCommand::Continue; sampled.len()]
+    }
+
+    /// A request with `spec` was admitted, with `seq_id` as its (first) sequence;
+    /// an error rejects the request.
+    fn attach(&mut self, _seq_id: SeqId, spec: &ControllerSpec) -> Result<()> {
+        bail!("no controller {:?}", spec.module_id)
+    }
+
+    /// `child` was forked from `parent`.
+    fn fork(&mut self, _parent: SeqId, _child: SeqId) {}
+
+    /// The request of `seq_id` is gone (finished, aborted, or failed, possibly
+    /// before it was ever scheduled).
+    fn detach(&mut self, _seq_id: SeqId) {}
+}
+
+/// The default StepHooks, which change nothing.
+pub struct NoStepHooks;
+
+impl<ME: ModelExec> StepHooks<ME> for NoStepHooks {}
+
+/// One controller instance, driving one sequence (or several, after forks that
+/// share it).
+pub trait SeqController<ME: ModelExec> {
+    /// See StepHooks::pre_sample().
+    fn mask(&mut self, _seq: &Sequence, _logits: &ME::Tensor) -> Vec<(Token, f32)> {
+        Vec::new()
+    }
+
+    /// `token` was sampled for `seq_id`.
+    fn on_token(&mut self, seq_id: SeqId, token: Token) -> SeqCommand;
+
+    /// A copy for a forked sequence; None if the controller can't be copied, and
+    /// the fork shares this instance instead.
+    fn fork(&self) -> Option<Box<dyn SeqController<ME>>> {
+        None
+    }
+}
+
+/// Creates a controller instance from ControllerSpec::module_arg.
+pub type ControllerFactory<ME> =
+    Box<dyn Fn(&serde_json::Value) -> Result<Box<dyn SeqController<ME>>>>;
+
+/// StepHooks that run a SeqController for each sequence of the requests with a
+/// ControllerSpec, created by the factory registered under its module_id.
+/// Instances are dropped when their request is.
+pub struct ControllerHooks<ME: ModelExec> {
+    factories: HashMap<String, ControllerFactory<ME>>,
+    instances: HashMap<SeqId, SharedController<ME>>,
+}
+
+type SharedController<ME> = Rc<RefCell<Box<dyn SeqController<ME>>>>;
+
+impl<ME: ModelExec> Default for ControllerHooks<ME> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<ME: ModelExec> ControllerHooks<ME> {
+    pub fn new() -> Self {
+        ControllerHooks {
+            factories: HashMap::default(),
+            instances: HashMap::default(),
+        }
+    }
+
+    pub fn register(&mut self, module_id: &str, factory: ControllerFactory<ME>) {
+        self.factories.insert(module_id.to_string(), factory);
+    }
+
+    /// Sequences with a controller attached.
+    pub fn num_attached(&self) -> usize {
+        self.instances.len()
+    }
+}
+
+impl<ME: ModelExec> StepHooks<ME> for ControllerHooks<ME> {
+    fn pre_sample(
+        &mut self,
+        _step_no: usize,
+        seq: &Sequence,
+        logits: &ME::Tensor,
+    ) -> Vec<(Token, f32)> {
+        match self.instances.get(&seq.seq_id) {
+            Some(ctrl) => ctrl.borrow_mut().mask(seq, logits),
+            None => Vec::new(),
+        }
+    }
+
+    fn post_sample(&mut self, _step_no: usize, sampled: &[SampledToken]) -> Vec<SeqCommand> {
+        sampled
+            .iter()
+            .map(|s| match self.instances.get(&s.seq_id) {
+                Some(ctrl) => ctrl.borrow_mut().on_token(s.seq_id, s.token),
+                None => SeqCommand::Continue,
+            })
+            .collect()
+    }
+
+    fn attach(&mut self, seq_id: SeqId, spec: &ControllerSpec) -> Result<()> {
+        let factory = match self.factories.get(&spec.module_id) {
+            Some(f) => f,
+            None => bail!("no controller {:?}", spec.module_id),
+        };
+        let ctrl = factory(&spec.module_arg)?;
+        self.instances.insert(seq_id, Rc::new(RefCell::new(ctrl)));
+        Ok(())
+    }
+
+    fn fork(&mut self, parent: SeqId, child: SeqId) {
+        let copy = match self.instances.get(&parent) {
+            Some(ctrl) => match ctrl.borrow().fork() {
+                Some(copy) => Rc::new(RefCell::new(copy)),
+                None => ctrl.clone(),
+            },
+            None => return,
+        };
+        self.instances.insert(child, copy);
+    }
+
+    fn detach(&mut self, seq_id: SeqId) {
+        self.instances.remove(&seq_id);
+    }
+}
@@ -2,11 +2,16 @@ pub mod seq;
 
 // vllm modules
 pub mod config;
+pub mod download;
 mod engine;
 mod exec;
 mod expected;
+mod hooks;
 pub mod iface;
 mod logits;
+pub mod metrics;
+mod registry;
+pub mod reqlog;
 mod scheduler;
 pub mod server;
 pub mod util;
@@ -14,9 +19,11 @@ pub mod util;
 use config::AiciConfig;
 pub use engine::*;
 pub use exec::*;
+pub use hooks::*;
 pub use logits::LogitsProcessor;
+pub use registry::ModelRegistry;
 pub use scheduler::*;
-use std::sync::atomic::AtomicBool;
+use std::{sync::atomic::AtomicBool, time::Duration};
 
 pub use fxhash::FxHashMap as HashMap;
 pub use fxhash::FxHashSet as HashSet;
@@ -29,6 +36,18 @@ pub struct LoaderArgs {
     pub local_weights: Option<String>,
     pub alt: usize,
     pub aici: AiciConfig,
+    pub kv_truncation_sink: Option<usize>,
+    pub deterministic_seed: Option<u64>,
+    /// See SchedulerConfig::coalesce_prompts.
+    pub coalesce_prompts: bool,
+    /// See SchedulerConfig::bill_coalesced_prompts.
+    pub bill_coalesced_prompts: bool,
+    /// See SchedulerConfig::queue_timeout.
+    pub queue_timeout: Option<Duration>,
+    /// See SchedulerConfig::pipeline_outputs.
+    pub pipeline_outputs: bool,
+    /// Checkpoint tensor renames, `from=to,...`; detected from the names if not set.
+    pub name_map: Option<String>,
 }
 
 impl Default for LoaderArgs {
@@ -41,6 +60,13 @@ impl Default for LoaderArgs {
             file: None,
             aici: AiciConfig::default(),
             alt: 0,
+            kv_truncation_sink: None,
+            deterministic_seed: None,
+            coalesce_prompts: true,
+            bill_coalesced_prompts: true,
+            queue_timeout: None,
+            pipeline_outputs: false,
+            name_map: None,
         }
     }
 }
@@ -1,27 +1,66 @@
 // based on https://github.com/huggingface/candle/blob/main/candle-transformers/src/generation/mod.rs
 
-use crate::config::{SamplingParams, SAMPLING_EPS};
+use crate::{
+    config::{SamplingParams, SAMPLING_EPS},
+    seq::Token,
+};
 use rand::SeedableRng;
 
 pub struct LogitsProcessor {
     pub rng: rand::rngs::StdRng,
+    /// Set when sampling has to be reproducible; samplers then only use `rng`.
+    pub seed: Option<u64>,
     pub temperature: Option<f32>,
     pub top_p: f32,
+    pub logit_bias: Vec<(Token, f32)>,
+    /// Token sequences that can't be generated; see sparse_bias().
+    pub bad_words: Vec<Vec<Token>>,
 }
 
 impl LogitsProcessor {
-    pub fn new(sampling_params: &SamplingParams) -> Self {
+    pub fn new(sampling_params: &SamplingParams, seed: Option<u64>) -> Self {
         let temperature = if sampling_params.temperature < SAMPLING_EPS {
             None
         } else {
             Some(sampling_params.temperature)
         };
 
+        let rng = match seed {
+            Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+            None => rand::rngs::StdRng::from_entropy(),
+        };
+
+        let mut logit_bias = sampling_params
+            .logit_bias
+            .iter()
+            .map(|(t, b)| (*t, *b))
+            .collect::<Vec<_>>();
+        for t in sampling_params.suppress_tokens.iter().flatten() {
+            logit_bias.retain(|(t2, _)| t2 != t);
+            logit_bias.push((*t, f32::NEG_INFINITY));
+        }
+        logit_bias.sort_by_key(|(t, _)| *t);
+
         Self {
-            rng: rand::rngs::StdRng::from_entropy(),
-            // seed_from_u64(42),
+            rng,
+            seed,
             temperature,
             top_p: sampling_params.top_p,
+            logit_bias,
+            bad_words: sampling_params.bad_words_tokens.clone(),
+        }
+    }
+
+    /// Bias to add to the logits of the next token of a sequence ending with `tokens`.
+    /// The last token of a bad word is banned when the sequence ends with the rest of it.
+    pub fn sparse_bias(&self, tokens: &[Token]) -> Vec<(Token, f32)> {
+        let mut bias = self.logit_bias.clone();
+        for word in &self.bad_words {
+            let (last, prefix) = word.split_last().unwrap();
+            if tokens.ends_with(prefix) {
+                bias.push((*last, f32::NEG_INFINITY));
+            }
         }
+        bias
     }
 }
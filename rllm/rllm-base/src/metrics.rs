@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, time::Duration, time::Instant};
+
+// buckets are spaced 2^(1/8) (~9%) apart, starting at 10us;
+// 192 buckets cover up to ~168s
+const MIN_MICROS: f64 = 10.0;
+const BUCKETS_PER_OCTAVE: f64 = 8.0;
+const NUM_BUCKETS: usize = 192;
+
+/// Log-bucketed latency histogram with constant memory use.
+/// Percentiles are approximate (within one bucket, ie ~9%).
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u32>,
+    count: u64,
+    sum_micros: u64,
+    min_micros: u64,
+    max_micros: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; NUM_BUCKETS],
+            count: 0,
+            sum_micros: 0,
+            min_micros: u64::MAX,
+            max_micros: 0,
+        }
+    }
+}
+
+fn bucket_idx(micros: u64) -> usize {
+    let v = micros as f64;
+    if v <= MIN_MICROS {
+        0
+    } else {
+        let idx = ((v / MIN_MICROS).log2() * BUCKETS_PER_OCTAVE) as usize;
+        std::cmp::min(idx, NUM_BUCKETS - 1)
+    }
+}
+
+fn bucket_mid(idx: usize) -> f64 {
+    MIN_MICROS * ((idx as f64 + 0.5) / BUCKETS_PER_OCTAVE).exp2()
+}
+
+impl LatencyHistogram {
+    pub fn add(&mut self, d: Duration) {
+        let micros = d.as_micros() as u64;
+        self.counts[bucket_idx(micros)] += 1;
+        self.count += 1;
+        self.sum_micros += micros;
+        self.min_micros = std::cmp::min(self.min_micros, micros);
+        self.max_micros = std::cmp::max(self.max_micros, micros);
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += *b;
+        }
+        self.count += other.count;
+        self.sum_micros += other.sum_micros;
+        self.min_micros = std::cmp::min(self.min_micros, other.min_micros);
+        self.max_micros = std::cmp::max(self.max_micros, other.max_micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn mean_ms(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum_micros as f64 / self.count as f64 / 1000.0
+        }
+    }
+
+    pub fn max_ms(&self) -> f64 {
+        self.max_micros as f64 / 1000.0
+    }
+
+    /// Approximate percentile (`p` in 0.0..=1.0) in milliseconds.
+    pub fn percentile_ms(&self, p: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let rank = std::cmp::max(1, (p * self.count as f64).ceil() as u64);
+        let mut seen = 0;
+        for (idx, cnt) in self.counts.iter().enumerate() {
+            seen += *cnt as u64;
+            if seen >= rank {
+                let micros = bucket_mid(idx)
+                    .max(self.min_micros as f64)
+                    .min(self.max_micros as f64);
+                return micros / 1000.0;
+            }
+        }
+        self.max_ms()
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            mean_ms: self.mean_ms(),
+            p50_ms: self.percentile_ms(0.5),
+            p90_ms: self.percentile_ms(0.9),
+            p99_ms: self.percentile_ms(0.99),
+            max_ms: self.max_ms(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Display for LatencySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "n={} mean={:.1}ms p50={:.1}ms p90={:.1}ms p99={:.1}ms max={:.1}ms",
+            self.count, self.mean_ms, self.p50_ms, self.p90_ms, self.p99_ms, self.max_ms
+        )
+    }
+}
+
+/// Timestamps of a single request (sequence group), as seen by the engine.
+/// Time spent preempted (waiting or swapped out) is not subtracted.
+#[derive(Debug, Clone, Default)]
+pub struct SeqGroupTiming {
+    /// When the group was first put in a batch.
+    pub first_scheduled: Option<Instant>,
+    /// When the first token was sampled.
+    pub first_token: Option<Instant>,
+    /// When the last token was sampled.
+    pub last_token: Option<Instant>,
+    /// Time between consecutive sampling steps of this group.
+    pub inter_token: LatencyHistogram,
+}
+
+impl SeqGroupTiming {
+    pub fn on_scheduled(&mut self, now: Instant) {
+        if self.first_scheduled.is_none() {
+            self.first_scheduled = Some(now);
+        }
+    }
+
+    /// Call once per step in which at least one sequence of the group
+    /// got a new sampled token.
+    pub fn on_tokens(&mut self, now: Instant) {
+        if self.first_token.is_none() {
+            self.first_token = Some(now);
+        }
+        if let Some(prev) = self.last_token {
+            self.inter_token.add(now.duration_since(prev));
+        }
+        self.last_token = Some(now);
+    }
+
+    pub fn to_request_timing(&self, arrival_time: Instant) -> RequestTiming {
+        let ms =
+            |t: Option<Instant>| t.map(|t| t.duration_since(arrival_time).as_secs_f64() * 1000.0);
+        RequestTiming {
+            queue_ms: ms(self.first_scheduled),
+            ttft_ms: ms(self.first_token),
+            total_ms: arrival_time.elapsed().as_secs_f64() * 1000.0,
+            inter_token: self.inter_token.summary(),
+        }
+    }
+}
+
+/// Latency information returned with the final output of a request.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RequestTiming {
+    /// Time from arrival until first scheduled.
+    pub queue_ms: Option<f64>,
+    /// Time from arrival until first token was sampled.
+    pub ttft_ms: Option<f64>,
+    /// Time from arrival until the request was finished.
+    pub total_ms: f64,
+    pub inter_token: LatencySummary,
+}
+
+/// Aggregate latencies over all finished requests.
+#[derive(Debug, Clone, Default)]
+pub struct EngineLatency {
+    pub ttft: LatencyHistogram,
+    pub inter_token: LatencyHistogram,
+}
+
+impl EngineLatency {
+    pub fn add_finished(&mut self, arrival_time: Instant, timing: &SeqGroupTiming) {
+        if let Some(t) = timing.first_token {
+            self.ttft.add(t.duration_since(arrival_time));
+        }
+        self.inter_token.merge(&timing.inter_token);
+    }
+}
+
+impl Display for EngineLatency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ttft: {}; itl: {}",
+            self.ttft.summary(),
+            self.inter_token.summary()
+        )
+    }
+}
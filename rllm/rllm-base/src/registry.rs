@@ -0,0 +1,176 @@
+use crate::{HashMap, LoaderArgs, ModelExec, RllmEngine};
+use anyhow::Result;
+use std::sync::{Arc, Condvar, Mutex};
+
+struct Entry<T> {
+    handle: Arc<Mutex<T>>,
+    memory_bytes: usize,
+}
+
+enum Slot<T> {
+    /// Being loaded by get_or_load(), outside of the registry lock.
+    Loading,
+    Loaded(Entry<T>),
+}
+
+/// Several models loaded in one process, by name.
+///
+/// Each model sits behind its own lock, so different models can be used from
+/// different threads at the same time. evict() only drops the registry's
+/// reference; the model is freed once the last handle returned by get() is dropped.
+pub struct ModelRegistry<T> {
+    models: Mutex<HashMap<String, Slot<T>>>,
+    load_done: Condvar,
+}
+
+/// Drops the Loading marker of a load that failed (or panicked), and wakes up
+/// the loads waiting for it.
+struct LoadGuard<'a, T> {
+    registry: &'a ModelRegistry<T>,
+    name: &'a str,
+}
+
+impl<T> Drop for LoadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut models = self.registry.models.lock().unwrap();
+        if let Some(Slot::Loading) = models.get(self.name) {
+            models.remove(self.name);
+        }
+        self.registry.load_done.notify_all();
+    }
+}
+
+impl<T> Default for ModelRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> ModelRegistry<T> {
+    pub fn new() -> Self {
+        ModelRegistry {
+            models: Mutex::new(HashMap::default()),
+            load_done: Condvar::new(),
+        }
+    }
+
+    /// Return the model registered as `name`, or register the result of `load`,
+    /// which returns the model and the memory it holds in bytes.
+    /// `load` runs without holding the registry lock, so other models can be used
+    /// and loaded meanwhile; concurrent calls for the same `name` wait for it
+    /// instead of loading the model twice, and try again if it fails.
+    pub fn get_or_load(
+        &self,
+        name: &str,
+        load: impl FnOnce() -> Result<(T, usize)>,
+    ) -> Result<Arc<Mutex<T>>> {
+        let mut models = self.models.lock().unwrap();
+        loop {
+            match models.get(name) {
+                Some(Slot::Loaded(e)) => return Ok(e.handle.clone()),
+                Some(Slot::Loading) => models = self.load_done.wait(models).unwrap(),
+                None => break,
+            }
+        }
+        models.insert(name.to_string(), Slot::Loading);
+        drop(models);
+
+        let _guard = LoadGuard {
+            registry: self,
+            name,
+        };
+        let (model, memory_bytes) = load()?;
+        log::info!("registered model {name}: {memory_bytes} bytes");
+        let handle = Arc::new(Mutex::new(model));
+        self.models.lock().unwrap().insert(
+            name.to_string(),
+            Slot::Loaded(Entry {
+                handle: handle.clone(),
+                memory_bytes,
+            }),
+        );
+        Ok(handle)
+    }
+
+    /// The model registered as `name`; None while it's still loading.
+    pub fn get(&self, name: &str) -> Option<Arc<Mutex<T>>> {
+        let models = self.models.lock().unwrap();
+        match models.get(name) {
+            Some(Slot::Loaded(e)) => Some(e.handle.clone()),
+            _ => None,
+        }
+    }
+
+    /// Unregister `name`; returns false if it wasn't registered (or is still loading).
+    pub fn evict(&self, name: &str) -> bool {
+        let mut models = self.models.lock().unwrap();
+        match models.get(name) {
+            Some(Slot::Loaded(e)) => {
+                log::info!("evicted model {name}: {} bytes", e.memory_bytes);
+                models.remove(name);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Memory held by `name`, as reported when it was loaded.
+    pub fn memory_bytes(&self, name: &str) -> Option<usize> {
+        let models = self.models.lock().unwrap();
+        match models.get(name) {
+            Some(Slot::Loaded(e)) => Some(e.memory_bytes),
+            _ => None,
+        }
+    }
+
+    pub fn total_memory_bytes(&self) -> usize {
+        let models = self.models.lock().unwrap();
+        models
+            .values()
+            .map(|s| match s {
+                Slot::Loaded(e) => e.memory_bytes,
+                Slot::Loading => 0,
+            })
+            .sum()
+    }
+
+    /// Names of the loaded models, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let models = self.models.lock().unwrap();
+        let mut names = models
+            .iter()
+            .filter(|(_, s)| matches!(s, Slot::Loaded(_)))
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+}
+
+impl<ME: ModelExec> ModelRegistry<RllmEngine<ME>> {
+    /// Load the model in `args`, named by its id, revision and file, unless it is
+    /// loaded already. The device is picked by `model_args`.
+    pub fn load(
+        &self,
+        args: LoaderArgs,
+        model_args: ME::ModelLoaderArgs,
+    ) -> Result<Arc<Mutex<RllmEngine<ME>>>> {
+        self.get_or_load(&Self::model_name(&args), || {
+            let engine = ME::load_rllm_engine(args, model_args)?;
+            let memory_bytes = engine.memory_bytes();
+            Ok((engine, memory_bytes))
+        })
+    }
+
+    /// The name load() registers the model in `args` under.
+    pub fn model_name(args: &LoaderArgs) -> String {
+        let mut name = args.model_id.clone();
+        if let Some(r) = &args.revision {
+            name += &format!("@{}", r);
+        }
+        if let Some(f) = &args.file {
+            name += &format!("::{}", f);
+        }
+        name
+    }
+}
@@ -0,0 +1,78 @@
+// Events in the life of a request, logged at debug level as one JSON object per
+// line under the `rllm::request` target, e.g. with RUST_LOG=info,rllm::request=debug.
+// Every event has the request id and the step, so a single request can be followed
+// through a busy engine, and lined up with the per-step logs.
+
+use crate::{config::SamplingParams, seq::FinishReason, PreemptionMode};
+use serde::{Deserialize, Serialize};
+
+pub const REQUEST_LOG_TARGET: &str = "rllm::request";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RequestEvent {
+    /// Scheduler step; the same as in the "model forward: step #N" logs.
+    pub step_no: usize,
+    pub request_id: String,
+    #[serde(flatten)]
+    pub kind: RequestEventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RequestEventKind {
+    /// Queued by Scheduler::add_seq_group().
+    Admitted {
+        prompt_tokens: usize,
+        sampling: SamplingSummary,
+    },
+    /// Got KV cache blocks for the first time.
+    Scheduled,
+    /// Lost its KV cache blocks to a higher priority request.
+    Preempted { mode: PreemptionMode },
+    /// Swapped back in, after Preempted with mode "swap".
+    SwappedIn,
+    /// A sequence couldn't be run (e.g., its KV cache blocks couldn't be found);
+    /// the whole request is finished with FinishReason::Failed.
+    Failed { error: String },
+    /// Dropped by the scheduler, after all its sequences finished.
+    Finished { reason: Option<FinishReason> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SamplingSummary {
+    pub n: usize,
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: isize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub controller: Option<String>,
+}
+
+impl SamplingSummary {
+    pub fn from(params: &SamplingParams) -> Self {
+        SamplingSummary {
+            n: params.n,
+            max_tokens: params.max_tokens,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            top_k: params.top_k,
+            controller: params.controller.clone(),
+        }
+    }
+}
+
+pub fn log_request_event(step_no: usize, request_id: &str, kind: RequestEventKind) {
+    if log::log_enabled!(target: REQUEST_LOG_TARGET, log::Level::Debug) {
+        let event = RequestEvent {
+            step_no,
+            request_id: request_id.to_string(),
+            kind,
+        };
+        log::debug!(
+            target: REQUEST_LOG_TARGET,
+            "{}",
+            serde_json::to_string(&event).unwrap()
+        );
+    }
+}
@@ -1,19 +1,25 @@
 use crate::{
-    config::RllmConfig,
-    seq::{FinishReason, SchedulingPhase, Sequence, SequenceGroup},
+    config::{RequestKind, RllmConfig, SamplingParams},
+    reqlog::{log_request_event, RequestEventKind, SamplingSummary},
+    seq::{FinishReason, KvSnapshot, SchedulingPhase, Sequence, SequenceGroup, TokenUsage},
     util::limit_str,
-    HashMap, ModelExec, SequenceManager, TBlockSpaceManager,
+    EngineError, HashMap, HashSet, LogitsProcessor, ModelExec, SeqCommand, SeqId, SequenceManager,
+    TBlockSpaceManager,
 };
 use aicirt::api::SequenceResult;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
     ops::Deref,
     sync::{Arc, Mutex},
+    time::Instant,
     vec::Vec,
 };
 
 /// Preemption modes.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PreemptionMode {
     /// Swap out the blocks of the preempted sequences to CPU memory
     /// and swap them back in when the sequences are resumed.
@@ -35,6 +41,13 @@ pub struct SchedulerOutputs {
 
     pub next_seq_groups: Vec<SequenceGroup>,
     pub dropped_seq_groups: Vec<SequenceGroup>,
+
+    /// Sequences that couldn't be included in the batch; filled in by ModelExec::run().
+    pub failed_seqs: Vec<EngineError>,
+
+    /// Groups sharing the prefill of the given sequence in next_seq_groups;
+    /// see Scheduler::split_coalesced().
+    pub coalesced: Vec<(SeqId, SequenceGroup)>,
 }
 
 impl SchedulerOutputs {
@@ -47,6 +60,8 @@ impl SchedulerOutputs {
             blocks_to_copy: HashMap::default(),
             dropped_seq_groups: Vec::new(),
             next_seq_groups: Vec::new(),
+            failed_seqs: Vec::new(),
+            coalesced: Vec::new(),
         }
     }
     fn validate(&self) {
@@ -92,6 +107,33 @@ enum Queue {
 
 const NUM_QUEUES: usize = Queue::Swapped as usize + 1;
 
+/// Counters for prompt coalescing; see Scheduler::add_seq_group().
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CoalescingStats {
+    /// Prefills shared by more than one request.
+    pub prefills: usize,
+    /// Requests that didn't run a prefill of their own.
+    pub requests: usize,
+}
+
+/// Counters for the waiting queue; see SchedulerConfig::queue_timeout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct AdmissionStats {
+    /// Requests waiting to be scheduled for the first time.
+    pub queued: usize,
+    /// Requests finished with FinishReason::QueueTimeout.
+    pub shed: usize,
+}
+
+fn can_coalesce(sg: &SequenceGroup) -> bool {
+    sg.seqs.len() == 1
+        && sg.sampling_params.controller.is_none()
+        && sg.sampling_params.kind == RequestKind::Generate
+        && sg.seqs[0].expected.is_none()
+        && sg.seqs[0].num_kv_computed == 0
+        && sg.seqs[0].get_len() > 0
+}
+
 /// Scheduler.
 pub struct Scheduler<ME: ModelExec> {
     pub(crate) config: Arc<RllmConfig<ME>>,
@@ -101,6 +143,13 @@ pub struct Scheduler<ME: ModelExec> {
     seq_mgr: Arc<ME::SequenceManager>,
 
     queues: Mutex<Vec<Vec<SequenceGroup>>>,
+
+    /// Prompt hash -> waiting sequence that identical prompts are coalesced into.
+    coalesce_leaders: HashMap<u64, SeqId>,
+    coalescing: CoalescingStats,
+    num_shed: usize,
+    /// Number of schedule() calls; see reqlog::RequestEvent::step_no.
+    step_no: usize,
 }
 
 impl<ME: ModelExec> Scheduler<ME> {
@@ -149,6 +198,154 @@ impl<ME: ModelExec> Scheduler<ME> {
         self.for_each_sg(|sg| sg.seqs.iter_mut().for_each(&mut f));
     }
 
+    /// Run `f` on the group containing sequence `seq_id`, and the index of the sequence in it.
+    pub fn with_seq<T>(
+        &self,
+        seq_id: SeqId,
+        f: impl FnOnce(&mut SequenceGroup, usize) -> T,
+    ) -> Option<T> {
+        let mut queues = self.queues.lock().unwrap();
+        for sg in queues.iter_mut().flat_map(|q| q.iter_mut()) {
+            if let Some(idx) = sg.seqs.iter().position(|seq| seq.seq_id == seq_id) {
+                return Some(f(sg, idx));
+            }
+        }
+        None
+    }
+
+    /// Fork running sequence `seq_id` into `n` new sequences in the same group.
+    /// The forks share KV cache blocks with the parent (copy-on-write), so the
+    /// prefix is not recomputed.
+    /// Sequences with a controller are only forked at the next step, when aicirt
+    /// clones the controller for each fork.
+    pub fn fork_seq(&self, seq_id: SeqId, n: usize) -> Result<Vec<SeqId>> {
+        self.with_seq(seq_id, |sg, idx| {
+            let phase = sg.seqs[idx].sched_phase;
+            if phase != SchedulingPhase::Running {
+                bail!("can only fork running sequences; {seq_id} is {phase:?}");
+            }
+            let ids = (0..n)
+                .map(|_| self.seq_mgr.new_sequence())
+                .collect::<Vec<_>>();
+            if sg.seqs[idx].has_aici {
+                sg.seqs[idx].pending_fork_ids.extend_from_slice(&ids);
+            } else {
+                for id in &ids {
+                    sg.max_index += 1;
+                    let copy = sg.seqs[idx].fork_as(self.seq_mgr.deref(), *id, sg.max_index);
+                    log::debug!("forked: {:?} -> {:?}", sg.seqs[idx], copy);
+                    sg.seqs.push(copy);
+                }
+            }
+            Ok(ids)
+        })
+        .unwrap_or_else(|| bail!("no sequence {seq_id}"))
+    }
+
+    /// Sample sequence `seq_id` with its own parameters instead of the group ones.
+    pub fn set_seq_sampling_params(&self, seq_id: SeqId, params: SamplingParams) -> Result<()> {
+        self.with_seq(seq_id, |sg, idx| {
+            let seed = self.config.scheduler.request_seed(&sg.request_id, idx + 1);
+            let lp = LogitsProcessor::new(&params, seed);
+            sg.seqs[idx].own_sampling = Some((params, lp));
+        })
+        .ok_or_else(|| anyhow::anyhow!("no sequence {seq_id}"))
+    }
+
+    /// Finish a single sequence; its KV blocks are released unless shared with siblings.
+    pub fn abort_seq(&self, seq_id: SeqId) -> Result<()> {
+        self.with_seq(seq_id, |sg, idx| {
+            self.finish_seq(&mut sg.seqs[idx], FinishReason::Aborted)
+        })
+        .ok_or_else(|| anyhow::anyhow!("no sequence {seq_id}"))
+    }
+
+    /// Take sequence `seq_id` out of the scheduler, copying its computed KV entries
+    /// with `save_kv`. The sequence has to be running on GPU, alone in its group,
+    /// and without a controller. The group is dropped without a final output,
+    /// and its KV blocks are freed.
+    pub fn snapshot_seq<D>(
+        &mut self,
+        seq_id: SeqId,
+        save_kv: impl FnOnce(SeqId, usize) -> Result<D>,
+    ) -> Result<KvSnapshot<D>> {
+        let sg = self.q_with(Queue::OnGpu, |q| {
+            q.iter()
+                .position(|sg| sg.seqs.iter().any(|seq| seq.seq_id == seq_id))
+                .map(|idx| q.remove(idx))
+        });
+        let sg = match sg {
+            Some(sg) => sg,
+            None => bail!("no sequence {seq_id} on GPU"),
+        };
+        let seq = &sg.seqs[0];
+        if sg.seqs.len() != 1 || seq.sched_phase != SchedulingPhase::Running || seq.has_aici {
+            let err = format!(
+                "can only snapshot lone running sequences without controller; {seq_id} is {:?}",
+                seq.sched_phase
+            );
+            self.q_push(Queue::OnGpu, sg);
+            bail!(err);
+        }
+        let data = match save_kv(seq_id, seq.num_kv_computed) {
+            Ok(d) => d,
+            Err(e) => {
+                self.q_push(Queue::OnGpu, sg);
+                return Err(e);
+            }
+        };
+        let snapshot = KvSnapshot {
+            tokens: seq.tokens().to_vec(),
+            prompt_len: seq.prompt_len,
+            num_kv: seq.num_kv_computed,
+            data,
+        };
+        self.seq_mgr.delete(seq_id);
+        log::debug!(
+            "snapshot of {seq_id} ({}): {} tokens",
+            sg.request_id,
+            snapshot.tokens.len()
+        );
+        Ok(snapshot)
+    }
+
+    /// Re-admit a sequence from snapshot_seq(). `sg` should be a fresh group whose only
+    /// sequence has the snapshot tokens (and no controller).
+    /// GPU blocks are allocated right away and filled using `load_kv`.
+    /// On error, the sequences of `sg` are deleted from the sequence manager.
+    pub fn restore_seq<D>(
+        &mut self,
+        mut sg: SequenceGroup,
+        snapshot: &KvSnapshot<D>,
+        load_kv: impl FnOnce(SeqId, &D) -> Result<()>,
+    ) -> Result<()> {
+        let err = if sg.seqs.len() != 1 || sg.seqs[0].tokens() != &snapshot.tokens[..] {
+            Some("restored group should have one sequence with the snapshot tokens")
+        } else if sg.sampling_params.controller.is_some() {
+            Some("can't restore a sequence with a controller")
+        } else if !self.block_manager.can_allocate(&sg) {
+            Some("not enough free KV cache blocks to restore")
+        } else {
+            None
+        };
+        if let Some(err) = err {
+            for seq in &sg.seqs {
+                self.seq_mgr.delete(seq.seq_id);
+            }
+            bail!(err);
+        }
+        sg.seqs[0].prompt_len = snapshot.prompt_len;
+        self._allocate(&mut sg);
+        let seq_id = sg.seqs[0].seq_id;
+        if let Err(e) = load_kv(seq_id, &snapshot.data) {
+            self.seq_mgr.delete(seq_id);
+            return Err(e);
+        }
+        sg.seqs[0].num_kv_computed = snapshot.num_kv;
+        self.q_push(Queue::OnGpu, sg);
+        Ok(())
+    }
+
     pub fn new(
         seq_mgr: Arc<ME::SequenceManager>,
         block_manager: ME::BlockSpaceManager,
@@ -165,6 +362,10 @@ impl<ME: ModelExec> Scheduler<ME> {
             block_manager,
             freed_seq_ids: RefCell::new(Vec::new()),
             queues: Mutex::new((0..NUM_QUEUES).map(|_| Vec::new()).collect()),
+            coalesce_leaders: HashMap::default(),
+            coalescing: CoalescingStats::default(),
+            num_shed: 0,
+            step_no: 0,
         }
     }
 
@@ -172,16 +373,122 @@ impl<ME: ModelExec> Scheduler<ME> {
         self.freed_seq_ids.borrow_mut().drain(..).collect()
     }
 
-    pub fn add_seq_group(&mut self, seq_group: SequenceGroup) {
+    /// Queue a new request.
+    ///
+    /// With SchedulerConfig::coalesce_prompts, a request with the same prompt tokens as
+    /// one still waiting (neither with a controller) is not prefilled on its own:
+    /// it is scheduled along with the first one, and after the prefill its sequence
+    /// is forked off it (sharing the KV blocks), to be sampled with its own parameters.
+    ///
+    /// With SchedulerConfig::queue_timeout, the group's deadline is moved up to
+    /// that long after its arrival.
+    pub fn add_seq_group(&mut self, mut seq_group: SequenceGroup) {
         let len = seq_group.seqs[0].prompt_len;
+        if let Some(timeout) = self.config.scheduler.queue_timeout {
+            let d = seq_group.arrival_time + timeout;
+            seq_group.deadline = Some(seq_group.deadline.map_or(d, |d0| d0.min(d)));
+        }
         log::debug!(
             "add_seq_group: {}; {len} tokens; {:?}",
             seq_group.request_id,
             limit_str(&seq_group.prompt, 200)
         );
+        log_request_event(
+            self.step_no,
+            &seq_group.request_id,
+            RequestEventKind::Admitted {
+                prompt_tokens: len,
+                sampling: SamplingSummary::from(&seq_group.sampling_params),
+            },
+        );
+        if self.config.scheduler.coalesce_prompts && can_coalesce(&seq_group) {
+            let tokens = seq_group.seqs[0].tokens();
+            let hash = fxhash::hash64(tokens);
+            let leader = self.coalesce_leaders.get(&hash).copied().filter(|id| {
+                self.q_with(Queue::Waiting, |q| {
+                    q.iter()
+                        .any(|sg| sg.seqs[0].seq_id == *id && sg.seqs[0].tokens() == tokens)
+                })
+            });
+            match leader {
+                Some(id) => {
+                    log::debug!("coalescing {} into seq {id}", seq_group.request_id);
+                    seq_group.coalesced_into = Some(id);
+                }
+                None => {
+                    self.coalesce_leaders.insert(hash, seq_group.seqs[0].seq_id);
+                }
+            }
+        }
         self.q_push(Queue::Waiting, seq_group);
     }
 
+    pub fn coalescing_stats(&self) -> CoalescingStats {
+        self.coalescing
+    }
+
+    pub fn admission_stats(&self) -> AdmissionStats {
+        let queued = self.q_with(Queue::Waiting, |q| {
+            q.iter()
+                .filter(|sg| sg.timing.first_scheduled.is_none())
+                .count()
+        });
+        AdmissionStats {
+            queued,
+            shed: self.num_shed,
+        }
+    }
+
+    /// Fork the groups in `outputs.coalesced` off the sequences whose prefill they
+    /// share, and add them to `outputs.next_seq_groups`. Call after the model has run,
+    /// before sampling. Returns a map from the new sequences to the ones to take
+    /// their logits from.
+    pub fn split_coalesced(&mut self, outputs: &mut SchedulerOutputs) -> HashMap<usize, usize> {
+        let mut seq_id_mapping = HashMap::default();
+        let mut leaders = HashSet::default();
+        let now = Instant::now();
+        for (leader_id, mut sg) in std::mem::take(&mut outputs.coalesced) {
+            sg.coalesced_into = None;
+            let leader = outputs.next_seq_groups.iter().find_map(|g| {
+                g.seqs
+                    .iter()
+                    .find(|seq| {
+                        seq.seq_id == leader_id && seq.sched_phase == SchedulingPhase::Running
+                    })
+                    .map(|seq| (g, seq))
+            });
+            let failed = outputs
+                .failed_seqs
+                .iter()
+                .any(|e| e.seq_id() == Some(leader_id));
+            let (leader_sg, leader) = match leader {
+                Some(l) if !failed => l,
+                // prefill it on its own
+                _ => {
+                    self.q_push(Queue::Waiting, sg);
+                    continue;
+                }
+            };
+            let seq_id = sg.seqs[0].seq_id;
+            let mut seq = leader.fork_as(self.seq_mgr.deref(), seq_id, 0);
+            seq.pending_fork_ids = std::mem::take(&mut sg.seqs[0].pending_fork_ids);
+            sg.seqs[0] = seq;
+            if self.config.scheduler.bill_coalesced_prompts {
+                sg.usage = leader_sg.usage.clone();
+            } else {
+                sg.usage.gen_tokens += leader_sg.usage.gen_tokens;
+            }
+            sg.timing.on_scheduled(now);
+            log::debug!("split {} off seq {leader_id}", sg.request_id);
+            seq_id_mapping.insert(seq_id.to_num(), leader_id.to_num());
+            leaders.insert(leader_id);
+            self.coalescing.requests += 1;
+            outputs.next_seq_groups.push(sg);
+        }
+        self.coalescing.prefills += leaders.len();
+        seq_id_mapping
+    }
+
     pub fn abort_seq_group(&mut self, request_id: &str) {
         self.for_each_sg(|seq_group| {
             if seq_group.request_id == request_id {
@@ -190,6 +497,57 @@ impl<ME: ModelExec> Scheduler<ME> {
         });
     }
 
+    /// Drop the KV of the groups on the GPU, so it is recomputed (as in preemption
+    /// by recompute) when they are next scheduled. Only single-sequence groups can be
+    /// recomputed; others, and swapped groups, keep their KV. Returns the number of
+    /// groups restarted.
+    pub fn recompute_running(&mut self) -> usize {
+        let groups = self.q_with(Queue::OnGpu, |q| std::mem::take(q));
+        let mut num_restarted = 0;
+        for mut sg in groups {
+            if sg.seqs.len() == 1 && !sg.seqs[0].is_finished() {
+                self.set_phase(&mut sg, SchedulingPhase::Waiting);
+                self.q_push(Queue::Waiting, sg);
+                num_restarted += 1;
+            } else {
+                self.q_push(Queue::OnGpu, sg);
+            }
+        }
+        num_restarted
+    }
+
+    /// Preempt `request_id` by recompute, as when the KV cache runs out: its KV is
+    /// dropped, and the whole sequence (prompt and generated tokens) is prefilled
+    /// again when it is next scheduled. Only single-sequence groups on the GPU can
+    /// be preempted this way; returns false for other requests.
+    pub fn preempt_seq_group(&mut self, request_id: &str) -> bool {
+        let sg = self.q_with(Queue::OnGpu, |q| {
+            let idx = q.iter().position(|sg| {
+                sg.request_id == request_id && sg.seqs.len() == 1 && !sg.seqs[0].is_finished()
+            })?;
+            Some(q.remove(idx))
+        });
+        let mut sg = match sg {
+            Some(sg) => sg,
+            None => return false,
+        };
+        log::debug!("preempting seq_group {} (forced)", request_id);
+        log_request_event(
+            self.step_no,
+            request_id,
+            RequestEventKind::Preempted {
+                mode: PreemptionMode::Recompute,
+            },
+        );
+        self.set_phase(&mut sg, SchedulingPhase::Waiting);
+        self.q_push(Queue::Waiting, sg);
+        true
+    }
+
+    pub fn block_manager(&self) -> &ME::BlockSpaceManager {
+        &self.block_manager
+    }
+
     pub fn has_unfinished_seqs(&self) -> bool {
         self.get_num_unfinished_seq_groups() > 0
     }
@@ -234,6 +592,9 @@ impl<ME: ModelExec> Scheduler<ME> {
         });
 
         self.q_for_each(Queue::Waiting, |seq_group| {
+            if seq_group.is_finished() {
+                return;
+            }
             assert!(seq_group.seqs.len() == 1);
             let num_prompt_tokens = seq_group.get_seqs(None)[0].get_len();
             if num_prompt_tokens > self.prompt_limit {
@@ -250,6 +611,36 @@ impl<ME: ModelExec> Scheduler<ME> {
         self.queues.lock().unwrap().iter_mut().for_each(|q| {
             Self::drop_finished(outputs, q);
         });
+        for sg in outputs.dropped_seq_groups.iter() {
+            let reason = sg.seqs[0].finish_reason();
+            log_request_event(
+                self.step_no,
+                &sg.request_id,
+                RequestEventKind::Finished { reason },
+            );
+        }
+    }
+
+    /// Finish the groups that haven't been scheduled yet, and are past their
+    /// deadline at `now`, with FinishReason::QueueTimeout. Groups that already
+    /// ran (and were preempted) are not shed. Called by schedule().
+    pub fn shed_expired(&mut self, now: Instant) {
+        let mut num_shed = 0;
+        self.q_for_each(Queue::Waiting, |seq_group| {
+            if !seq_group.is_finished()
+                && seq_group.timing.first_scheduled.is_none()
+                && seq_group.deadline.map_or(false, |d| d <= now)
+            {
+                log::debug!("seq_group {} not scheduled in time", seq_group.request_id);
+                seq_group.usage = TokenUsage::default();
+                self.set_phase(
+                    seq_group,
+                    SchedulingPhase::Finished(FinishReason::QueueTimeout),
+                );
+                num_shed += 1;
+            }
+        });
+        self.num_shed += num_shed;
     }
 
     fn max_num_running_seq(&self, q: Queue) -> usize {
@@ -262,10 +653,19 @@ impl<ME: ModelExec> Scheduler<ME> {
         log::trace!("step_start_waiting ({} seqs)", self.q_len(Queue::Waiting));
         self.sort_by_priority(Queue::Waiting);
 
+        // these are only scheduled along with the group they're coalesced into
+        let mut followers = self.q_with(Queue::Waiting, |q| {
+            let (followers, rest) = std::mem::take(q)
+                .into_iter()
+                .partition::<Vec<_>, _>(|sg| sg.coalesced_into.is_some());
+            *q = rest;
+            followers
+        });
+
         let mut num_curr_seqs = self.max_num_running_seq(Queue::OnGpu);
         while let Some(mut seq_group) = self.q_pop(Queue::Waiting) {
             let num_prompt_tokens = seq_group.only_seq().get_len();
-            let num_new_seqs = seq_group.get_max_num_running_seqs();
+            let mut num_new_seqs = seq_group.get_max_num_running_seqs();
 
             log::trace!(
                 "seq_group {} has {} prompt tokens and {} new seqs",
@@ -285,16 +685,61 @@ impl<ME: ModelExec> Scheduler<ME> {
             }
 
             self._allocate(&mut seq_group);
+            let leader_id = seq_group.seqs[0].seq_id;
+            let (coalesced, rest) = std::mem::take(&mut followers)
+                .into_iter()
+                .partition::<Vec<_>, _>(|sg| sg.coalesced_into == Some(leader_id));
+            followers = rest;
+            for mut sg in coalesced {
+                let n = sg.get_max_num_running_seqs();
+                if num_curr_seqs + num_new_seqs + n > self.config.scheduler.max_num_seqs {
+                    sg.coalesced_into = None;
+                    followers.push(sg);
+                } else {
+                    num_new_seqs += n;
+                    outputs.coalesced.push((leader_id, sg));
+                }
+            }
+
             outputs.next_seq_groups.push(seq_group);
+            outputs.prompt_run = true;
             outputs.num_batched_tokens += num_prompt_tokens;
             num_curr_seqs += num_new_seqs;
         }
+
+        // groups whose leader is gone (scheduled or aborted) go on their own
+        let waiting = self
+            .q_map(Queue::Waiting, |sg| sg.seqs[0].seq_id)
+            .into_iter()
+            .collect::<HashSet<_>>();
+        for sg in followers.iter_mut() {
+            if let Some(id) = sg.coalesced_into {
+                if !waiting.contains(&id) {
+                    sg.coalesced_into = None;
+                }
+            }
+        }
+        self.q_with(Queue::Waiting, |q| q.append(&mut followers));
+        self.coalesce_leaders.retain(|_, id| waiting.contains(id));
     }
 
     fn sort_by_priority(&self, q: Queue) {
         self.q_with(q, |seq_groups| {
             // note that we take elements first from the end of the queue (Vec::pop())
-            seq_groups.sort_by_key(|g| g.arrival_time);
+            if self.config.scheduler.deterministic_seed.is_some() {
+                // seq_ids are handed out in arrival order; arrival_time can tie
+                seq_groups.sort_by_key(|g| g.seqs[0].seq_id.to_num());
+            } else {
+                seq_groups.sort_by_key(|g| g.arrival_time);
+            }
+            if matches!(q, Queue::Waiting) {
+                // preempted groups first, so they aren't starved by new arrivals;
+                // then earliest deadline first; stable, so arrival order otherwise
+                seq_groups.sort_by_key(|g| match g.timing.first_scheduled {
+                    Some(_) => (false, false, None),
+                    None => (true, g.deadline.is_none(), g.deadline),
+                });
+            }
             seq_groups.reverse();
         });
     }
@@ -339,6 +784,14 @@ impl<ME: ModelExec> Scheduler<ME> {
     fn _allocate(&mut self, seq_group: &mut SequenceGroup) {
         self.block_manager.allocate(seq_group);
         self.set_phase(seq_group, SchedulingPhase::Running);
+        if seq_group.timing.first_scheduled.is_none() {
+            log_request_event(
+                self.step_no,
+                &seq_group.request_id,
+                RequestEventKind::Scheduled,
+            );
+        }
+        seq_group.timing.on_scheduled(Instant::now());
     }
 
     fn _append_slots(&mut self, seq_group: &mut SequenceGroup, outputs: &mut SchedulerOutputs) {
@@ -352,6 +805,11 @@ impl<ME: ModelExec> Scheduler<ME> {
     fn _swap_in(&mut self, seq_group: &mut SequenceGroup, outputs: &mut SchedulerOutputs) {
         let src_to_dst = self.block_manager.swap_in(seq_group);
         outputs.blocks_to_swap_in.extend(src_to_dst);
+        log_request_event(
+            self.step_no,
+            &seq_group.request_id,
+            RequestEventKind::SwappedIn,
+        );
     }
 
     fn _preempt(&mut self, mut seq_group: SequenceGroup, outputs: &mut SchedulerOutputs) {
@@ -362,6 +820,11 @@ impl<ME: ModelExec> Scheduler<ME> {
         };
 
         log::debug!("preempting seq_group {} ({:?})", seq_group.request_id, mode);
+        log_request_event(
+            self.step_no,
+            &seq_group.request_id,
+            RequestEventKind::Preempted { mode },
+        );
 
         match mode {
             PreemptionMode::Swap => {
@@ -399,6 +862,26 @@ impl<ME: ModelExec> Scheduler<ME> {
     }
 
     pub fn step_finished(&mut self, mut outputs: SchedulerOutputs) {
+        // finish sequences that would go over max_model_len in the next step,
+        // unless we're configured to truncate their KV instead
+        if self.config.scheduler.kv_truncation_sink.is_none() {
+            let max_len = self.config.scheduler.max_model_len;
+            for sg in outputs.next_seq_groups.iter_mut() {
+                for seq in sg.seqs.iter_mut() {
+                    if seq.sched_phase == SchedulingPhase::Running && seq.get_len() >= max_len {
+                        log::debug!("seq {} reached max_model_len {}", seq.seq_id, max_len);
+                        self.finish_seq(seq, FinishReason::MaxModelLenReached);
+                    }
+                }
+            }
+        }
+
+        // the model didn't run, or split_coalesced() wasn't called
+        for (_, mut sg) in std::mem::take(&mut outputs.coalesced) {
+            sg.coalesced_into = None;
+            self.q_push(Queue::Waiting, sg);
+        }
+
         // everything that used to be "next_step" is now just on the GPU
         self.q_with(Queue::OnGpu, |seq_groups| {
             seq_groups.append(&mut outputs.next_seq_groups);
@@ -412,6 +895,8 @@ impl<ME: ModelExec> Scheduler<ME> {
 
     pub fn schedule(&mut self) -> SchedulerOutputs {
         let mut outputs = SchedulerOutputs::new();
+        self.step_no += 1;
+        self.shed_expired(Instant::now());
         self.step_drop_finished(&mut outputs);
 
         if self.q_len(Queue::Swapped) == 0 {
@@ -442,6 +927,56 @@ impl<ME: ModelExec> Scheduler<ME> {
         outputs
     }
 
+    /// Fail the whole group of every sequence reported in `outputs.failed_seqs`.
+    /// The remaining groups in the batch are not affected.
+    pub fn fail_seq_groups(&self, outputs: &mut SchedulerOutputs) {
+        for err in std::mem::take(&mut outputs.failed_seqs) {
+            log::error!("{err}");
+            let seq_id = match err.seq_id() {
+                Some(id) => id,
+                None => continue,
+            };
+            for sg in outputs.next_seq_groups.iter_mut() {
+                if !sg.seqs.iter().any(|seq| seq.seq_id == seq_id) {
+                    continue;
+                }
+                log_request_event(
+                    self.step_no,
+                    &sg.request_id,
+                    RequestEventKind::Failed {
+                        error: err.to_string(),
+                    },
+                );
+                for seq in sg.seqs.iter_mut() {
+                    if !seq.is_finished() {
+                        seq.aici_logs
+                            .push(SequenceResult::from_error(format!("\nEngine error: {err}")));
+                        self.finish_seq(seq, FinishReason::Failed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply what StepHooks::post_sample() returned for `seq`.
+    pub fn apply_seq_command(&self, seq: &mut Sequence, cmd: SeqCommand) {
+        match cmd {
+            SeqCommand::Continue => {}
+            SeqCommand::Backtrack {
+                n,
+                replacement_tokens,
+            } => {
+                if seq.is_finished() {
+                    log::debug!("seq {} finished; not backtracking", seq.seq_id);
+                    return;
+                }
+                let n = std::cmp::min(n, seq.get_len());
+                seq.splice_tokens(self.seq_mgr.deref(), n, &replacement_tokens);
+            }
+            SeqCommand::Finish { reason } => self.finish_seq(seq, reason),
+        }
+    }
+
     pub fn finish_seq(&self, seq: &mut Sequence, reason: FinishReason) {
         if seq.is_finished() {
             return;
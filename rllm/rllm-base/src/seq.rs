@@ -1,5 +1,9 @@
 use crate::{
-    config::SamplingParams, engine::ExpectedGeneration, LogitsProcessor, SeqId, SequenceManager,
+    config::SamplingParams,
+    engine::ExpectedGeneration,
+    metrics::{RequestTiming, SeqGroupTiming},
+    util::utf8_complete_len,
+    LogitsProcessor, SeqId, SequenceManager,
 };
 use aici_abi::{toktree::TokTrie, TokenId};
 use aicirt::api::SequenceResult;
@@ -18,12 +22,18 @@ pub enum FinishReason {
     AiciOutOfFuel,
     /// SamplingParams.max_tokens reached.
     MaxTokensReached,
+    /// The sequence reached SchedulerConfig.max_model_len.
+    MaxModelLenReached,
     /// Explicit abort request on the engine.
     Aborted,
     /// The scheduler didn't like the sequence.
     Failed,
     /// All sequences in the group are suspended.
     Deadlock,
+    /// The request wasn't scheduled before its deadline; see SequenceGroup::deadline.
+    QueueTimeout,
+    /// A score or embed request got its output from the prefill; see RequestKind.
+    PrefillOnly,
 }
 
 impl FinishReason {
@@ -31,11 +41,14 @@ impl FinishReason {
         let r = match self {
             FinishReason::FoundEos => "eos",
             FinishReason::MaxTokensReached => "length",
+            FinishReason::MaxModelLenReached => "length",
             FinishReason::Aborted => "abort",
             FinishReason::Failed => "fail",
             FinishReason::AiciStop => "aici-stop",
             FinishReason::Deadlock => "deadlock",
             FinishReason::AiciOutOfFuel => "aici-out-of-fuel",
+            FinishReason::QueueTimeout => "queue-timeout",
+            FinishReason::PrefillOnly => "prefill",
         };
         r.to_string()
     }
@@ -62,6 +75,15 @@ pub enum AiciSampling {
     },
 }
 
+/// The result of a score or embed request; see RequestKind.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrefillOutput {
+    /// One log-probability per continuation token.
+    Scores(Vec<f32>),
+    Embedding(Vec<f32>),
+}
+
 impl Default for AiciSampling {
     fn default() -> Self {
         Self::Regular
@@ -75,12 +97,20 @@ pub struct Sequence {
     pub prompt_len: usize,
     pub(crate) output_ptr: usize,
     pub(crate) output_pending: Vec<u8>,
+    /// Everything returned in SeqOutput::new_text so far.
+    pub(crate) output_text: String,
+    /// Sum of log-probabilities of the sampled tokens; see CompletionOutput.
+    pub cumulative_logprob: f32,
     pub num_kv_computed: usize,
     pub(crate) has_aici: bool,
     pub(crate) aici_sampling: AiciSampling,
     pub aici_logs: Vec<SequenceResult>,
     pub pending_fork_ids: Vec<SeqId>,
     pub(crate) expected: Option<ExpectedGeneration>,
+    /// Overrides the group sampling params and logits processor.
+    pub(crate) own_sampling: Option<(SamplingParams, LogitsProcessor)>,
+    /// Set when a score or embed request finishes.
+    pub prefill_output: Option<PrefillOutput>,
 
     // state for Scheduler and BlockSpaceManager
     pub sched_phase: SchedulingPhase,
@@ -100,7 +130,7 @@ impl Debug for Sequence {
 }
 
 impl Sequence {
-    pub(crate) fn new(seq_id: SeqId, tokens: &[Token]) -> Self {
+    pub fn new(seq_id: SeqId, tokens: &[Token]) -> Self {
         let prompt_len = tokens.len();
         Self {
             seq_id,
@@ -111,11 +141,15 @@ impl Sequence {
             prompt_len,
             output_ptr: prompt_len,
             output_pending: Vec::new(),
+            output_text: String::new(),
+            cumulative_logprob: 0.0,
             has_aici: false,
             aici_logs: Vec::new(),
             aici_sampling: AiciSampling::Regular,
             pending_fork_ids: Vec::new(),
             expected: None,
+            own_sampling: None,
+            prefill_output: None,
         }
     }
 
@@ -123,6 +157,10 @@ impl Sequence {
         self.tokens.len()
     }
 
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
     /// Indicate that the generation will soon run for this sequence and thus
     /// all the tokens will have KV computed.
     pub fn sync_computed_kv(&mut self) {
@@ -187,11 +225,15 @@ impl Sequence {
             output_ptr: self.prompt_len,
             prompt_len: self.prompt_len,
             output_pending: Vec::new(),
+            output_text: String::new(),
+            cumulative_logprob: self.cumulative_logprob,
             has_aici: self.has_aici,
             aici_logs: Vec::new(),
             pending_fork_ids: Vec::new(),
             aici_sampling: AiciSampling::Regular,
             expected: None,
+            own_sampling: None,
+            prefill_output: None,
         }
     }
 
@@ -206,37 +248,25 @@ impl Sequence {
         }
     }
 
-    pub fn gen_output(&mut self, tok_trie: &TokTrie) -> SeqOutput {
+    /// New output since the last call; `hide_tokens` are left out of the text.
+    pub fn gen_output(&mut self, tok_trie: &TokTrie, hide_tokens: &[Token]) -> SeqOutput {
         let new_output_tokens = self.tokens[self.output_ptr..].to_vec();
+        let shown = new_output_tokens
+            .iter()
+            .filter(|t| !hide_tokens.contains(t))
+            .cloned()
+            .collect::<Vec<_>>();
         let mut buf = std::mem::take(&mut self.output_pending);
-        buf.append(&mut tok_trie.decode(&new_output_tokens));
-        if buf.len() > 0 {
-            let mut ep = buf.len() - 1;
-            if buf[ep] >= 0x80 {
-                let mut ln = 0;
-                // skip continuation bytes (0b10xx_xxxx), but not too many
-                while ln < 4 && buf[ep] & 0b1100_0000 == 0b1000_0000 {
-                    if ep == 0 {
-                        break;
-                    }
-                    ep -= 1;
-                    ln += 1;
-                }
-                // now buf[ep] is the first byte of the UTF-8 sequence
-                // make sure we have enough continuation bytes
-                if (buf[ep] & 0b1110_0000 == 0b1100_0000 && ln >= 1)
-                    || (buf[ep] & 0b1111_0000 == 0b1110_0000 && ln >= 2)
-                    || (ln >= 3)
-                {
-                    // OK
-                } else {
-                    // not enough, move the whole UTF-8 sequence to output_pending
-                    self.output_pending.extend(buf.drain(ep..));
-                }
-            }
+        buf.append(&mut tok_trie.decode(&shown));
+        if !self.is_finished() {
+            // hold back a trailing incomplete UTF-8 sequence until the next call;
+            // once finished, there is no next call, so flush whatever we have
+            let complete = utf8_complete_len(&buf);
+            self.output_pending.extend(buf.drain(complete..));
         }
         self.output_ptr = self.tokens.len();
         let new_text = String::from_utf8_lossy(&buf).to_string();
+        self.output_text.push_str(&new_text);
         SeqOutput {
             seq_id: self.seq_id.to_num(),
             index: self.index,
@@ -251,6 +281,28 @@ impl Sequence {
     pub fn is_finished(&self) -> bool {
         self.finish_reason().is_some()
     }
+
+    /// The result so far; `text` only covers what gen_output() returned.
+    pub fn completion_output(&self) -> CompletionOutput {
+        CompletionOutput {
+            index: self.index,
+            text: self.output_text.clone(),
+            token_ids: self.tokens[self.prompt_len..].to_vec(),
+            cumulative_logprob: self.cumulative_logprob,
+            finish_reason: self.finish_reason(),
+            prefill_output: self.prefill_output.clone(),
+        }
+    }
+}
+
+/// A sequence taken out of the scheduler with its KV cache entries,
+/// to be re-admitted later with Scheduler::restore_seq().
+pub struct KvSnapshot<D> {
+    pub tokens: Vec<Token>,
+    pub prompt_len: usize,
+    /// Number of leading tokens with KV entries in `data`.
+    pub num_kv: usize,
+    pub data: D,
 }
 
 /// A group of sequences that are generated from the same prompt.
@@ -264,6 +316,12 @@ pub struct SequenceGroup {
     pub logits_processor: LogitsProcessor,
     pub max_index: usize,
     pub usage: TokenUsage,
+    pub timing: SeqGroupTiming,
+    /// The waiting sequence whose prefill this group shares; see Scheduler::add_seq_group().
+    pub coalesced_into: Option<SeqId>,
+    /// The group is shed (FinishReason::QueueTimeout) if it's still waiting to be
+    /// scheduled for the first time at this point; see SchedulerConfig::queue_timeout.
+    pub deadline: Option<std::time::Instant>,
 }
 
 impl Debug for SequenceGroup {
@@ -332,6 +390,44 @@ impl SequenceGroup {
             .iter()
             .all(|seq| seq.sched_phase == SchedulingPhase::Suspended || seq.is_finished())
     }
+
+    /// The new output of every sequence since the last call; in the final output,
+    /// also the complete result of each sequence.
+    pub fn request_output(&mut self, tok_trie: &TokTrie, is_final: bool) -> RequestOutput {
+        let group_hide = self.sampling_params.hide_tokens.as_deref().unwrap_or(&[]);
+        let seq_outputs = self
+            .seqs
+            .iter_mut()
+            .map(|seq| {
+                let hide = match &seq.own_sampling {
+                    Some((p, _)) => p.hide_tokens.as_deref().unwrap_or(&[]),
+                    None => group_hide,
+                };
+                seq.gen_output(tok_trie, hide)
+            })
+            .collect();
+        let mut outputs = Vec::new();
+        if is_final {
+            outputs = self
+                .seqs
+                .iter()
+                .map(|seq| seq.completion_output())
+                .collect::<Vec<_>>();
+            outputs.sort_by(|a, b| b.cumulative_logprob.total_cmp(&a.cumulative_logprob));
+        }
+        RequestOutput {
+            request_id: self.request_id.clone(),
+            seq_outputs,
+            outputs,
+            usage: self.usage.clone(),
+            is_final,
+            timing: if is_final {
+                Some(self.timing.to_request_timing(self.arrival_time))
+            } else {
+                None
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -350,6 +446,12 @@ pub struct SeqOutput {
 pub struct TokenUsage {
     pub gen_tokens: usize,
     pub prompt_tokens: usize,
+    /// Prompt tokens of score requests that were scored (also in prompt_tokens).
+    #[serde(default)]
+    pub scored_tokens: usize,
+    /// Prompt tokens of embed requests (also in prompt_tokens).
+    #[serde(default)]
+    pub embedded_tokens: usize,
 }
 
 impl TokenUsage {
@@ -367,5 +469,26 @@ pub struct RequestOutput {
     pub request_id: String,
     pub usage: TokenUsage,
     pub seq_outputs: Vec<SeqOutput>,
+    /// Only set in the final output; one per sequence, highest cumulative_logprob first.
+    pub outputs: Vec<CompletionOutput>,
     pub is_final: bool,
+    /// Only set in the final output.
+    pub timing: Option<RequestTiming>,
+}
+
+/// The complete result of one sequence of a request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionOutput {
+    pub index: usize,
+    /// Same as all the SeqOutput::new_text of the sequence, concatenated.
+    pub text: String,
+    /// The tokens generated by the model. Doesn't include prompt tokens.
+    pub token_ids: Vec<Token>,
+    /// Sum of log-probabilities of sampled tokens, under the logits they were
+    /// sampled from (with AICI bias, before temperature). Tokens forced by AICI
+    /// don't count.
+    pub cumulative_logprob: f32,
+    pub finish_reason: Option<FinishReason>,
+    /// Only for score and embed requests.
+    pub prefill_output: Option<PrefillOutput>,
 }
@@ -1,3 +1,4 @@
+use crate::metrics::RequestTiming;
 use aici_abi::StorageCmd;
 use serde::{Deserialize, Serialize};
 
@@ -9,6 +10,8 @@ pub struct RunRequest {
     pub top_p: Option<f32>,        // defl 1.0
     pub top_k: Option<isize>,      // defl -1
     pub max_tokens: Option<usize>, // defl context size
+    /// Give up if the request isn't started within this many milliseconds.
+    pub queue_timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +34,8 @@ pub struct RunResponse {
     pub object: &'static str, // "run"
     pub forks: Vec<RunForkResponse>,
     pub usage: RunUsageResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timing: Option<RequestTiming>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -4,6 +4,7 @@ use crate::{config::SamplingParams, seq::Token, AddRequest};
 use actix_web::{post, web, web::Bytes, HttpResponse};
 use aicirt::{api::InstantiateReq, get_unix_time};
 use serde_json::{json, Value};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Receiver;
 use uuid::Uuid;
 
@@ -79,6 +80,9 @@ async fn run_controller(
     let (max_tokens, mut token_ids) = token_ids.unwrap();
 
     let request_id = format!("run-{}", Uuid::new_v4());
+    let deadline = request
+        .queue_timeout_ms
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
 
     let mut sampling_params = SamplingParams::default();
     sampling_params.max_tokens = max_tokens;
@@ -138,7 +142,9 @@ async fn run_controller(
                     finish_reason: Some(FinishReason::Failed),
                     aici_logs: vec![r],
                 }],
+                outputs: vec![],
                 is_final: true,
+                timing: None,
             };
             let (tx, rx) = tokio::sync::mpsc::channel(1);
             tx.send(Ok(outp)).await.unwrap();
@@ -151,6 +157,9 @@ async fn run_controller(
                 sampling_params,
                 expected: None,
                 init_result,
+                deadline,
+                arrival_time: None,
+                controller: None,
             });
 
             bail_if_error!(rx);
@@ -204,6 +213,7 @@ impl futures::Stream for Client {
                         ff_tokens: u.prompt_tokens,
                         cost: u.fuel_tokens(),
                     },
+                    timing: so.timing.clone(),
                     forks: so
                         .seq_outputs
                         .iter()
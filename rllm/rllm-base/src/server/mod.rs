@@ -18,7 +18,7 @@ use clap::Args;
 use std::{
     fmt::Display,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc::{channel, error::TryRecvError, Receiver, Sender};
 
@@ -150,6 +150,35 @@ pub struct RllmCliArgs {
     #[arg(short, long, help_heading = "Model")]
     pub tokenizer: Option<String>,
 
+    /// Instead of finishing sequences at the model's max length, keep this many
+    /// initial tokens and a sliding window of recent ones in attention
+    #[arg(long, help_heading = "Model")]
+    pub kv_truncation_sink: Option<usize>,
+
+    /// Seed all sampling from this, so that runs with the same requests are reproducible
+    #[arg(long, help_heading = "Model")]
+    pub deterministic_seed: Option<u64>,
+
+    /// Prefill every request separately, even when several have the same prompt
+    #[arg(long, default_value_t = false, help_heading = "Model")]
+    pub no_prompt_coalescing: bool,
+
+    /// Don't bill requests that share another request's prefill for their prompt tokens
+    #[arg(long, default_value_t = false, help_heading = "Model")]
+    pub free_coalesced_prompts: bool,
+
+    /// Finish requests not started within this many milliseconds of arrival
+    #[arg(long, help_heading = "Model")]
+    pub queue_timeout_ms: Option<u64>,
+
+    /// Stream each step's outputs during the next step's forward pass (one step later)
+    #[arg(long, default_value_t = false, help_heading = "Model")]
+    pub pipeline_outputs: bool,
+
+    /// Rename checkpoint tensors by prefix, e.g. 'base_model.model.=' (detected by default)
+    #[arg(long, help_heading = "Model")]
+    pub name_map: Option<String>,
+
     /// Host to serve on
     #[arg(long, default_value_t = String::from("127.0.0.1"), help_heading = "Server")]
     pub host: String,
@@ -166,6 +195,10 @@ pub struct RllmCliArgs {
     #[arg(long, default_value_t = false, help_heading = "Server")]
     pub daemon: bool,
 
+    /// Log one JSON object per line; add --log info,rllm::request=debug for request events
+    #[arg(long, default_value_t = false, help_heading = "Server")]
+    pub json_log: bool,
+
     /// Path to the aicirt binary.
     #[arg(long, help_heading = "AICI settings")]
     pub aicirt: Option<String>,
@@ -202,6 +235,10 @@ pub struct RllmCliArgs {
     #[arg(long, default_value_t = false, help_heading = "Development")]
     pub warmup_only: bool,
 
+    /// Check the engine configuration and exit, without loading weights
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub validate_config: bool,
+
     // these are copied from command-specific parsers
     #[arg(skip)]
     pub file: Option<String>,
@@ -489,7 +526,7 @@ fn spawn_inference_loop<ME: ModelExec>(
                 engine
                     .add_request(
                         wid,
-                        "The ultimate answer to life,",
+                        "The ultimate answer to life,".into(),
                         SamplingParams {
                             max_tokens: 10,
                             ..SamplingParams::default()
@@ -530,17 +567,17 @@ fn guess_aicirt() -> Result<String> {
     }
 }
 
-// #[actix_web::main]
-pub async fn server_main<ME: ModelExec>(
-    mut args: RllmCliArgs,
-    mut model_args: ME::ModelLoaderArgs,
-) -> () {
+/// Set up logging and settings from `args`, and the LoaderArgs for its model;
+/// exits if the tokenizer can't be guessed.
+pub fn init_from_cli(args: &mut RllmCliArgs) -> LoaderArgs {
     // we set env, so that aicirt process also gets it
     match &args.log {
         Some(v) => std::env::set_var("RUST_LOG", v),
         None => {}
     }
-    aicirt::init_log(if args.daemon {
+    aicirt::init_log(if args.json_log {
+        aicirt::LogMode::Json
+    } else if args.daemon {
         aicirt::LogMode::Daemon
     } else {
         aicirt::LogMode::Normal
@@ -588,6 +625,13 @@ pub async fn server_main<ME: ModelExec>(
     loader_args.revision = args.revision.clone();
     loader_args.local_weights = args.local_weights.clone();
     loader_args.file = args.file.clone();
+    loader_args.kv_truncation_sink = args.kv_truncation_sink;
+    loader_args.deterministic_seed = args.deterministic_seed;
+    loader_args.coalesce_prompts = !args.no_prompt_coalescing;
+    loader_args.bill_coalesced_prompts = !args.free_coalesced_prompts;
+    loader_args.queue_timeout = args.queue_timeout_ms.map(Duration::from_millis);
+    loader_args.pipeline_outputs = args.pipeline_outputs;
+    loader_args.name_map = args.name_map.clone();
 
     match &args.tokenizer {
         Some(v) => {
@@ -607,11 +651,32 @@ pub async fn server_main<ME: ModelExec>(
         },
     }
 
+    loader_args
+}
+
+// #[actix_web::main]
+pub async fn server_main<ME: ModelExec>(
+    mut args: RllmCliArgs,
+    mut model_args: ME::ModelLoaderArgs,
+) -> () {
+    let mut loader_args = init_from_cli(&mut args);
+
     if args.test.len() > 0 {
         run_tests::<ME>(&args, loader_args, model_args);
         return;
     }
 
+    if args.validate_config {
+        match RllmEngine::<ME>::build_config(&loader_args, &mut model_args) {
+            Ok(_) => println!("configuration is valid"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let (tokenizer, tok_trie) =
         RllmEngine::<ME>::load_tokenizer(&mut loader_args).expect("failed to load tokenizer");
 
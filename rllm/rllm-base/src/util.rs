@@ -4,11 +4,13 @@ use anyhow::{bail, Result};
 use clap::{Args, Command, Parser};
 use std::time::Instant;
 
-const SETTINGS: [(&'static str, &'static str, f64); 4] = [
+const SETTINGS: [(&'static str, &'static str, f64); 6] = [
     ("attn_rtol", "relative tolerance for flash attn check", 0.1),
     ("attn_atol", "absolute tolerance for flash attn check", 0.1),
     ("test_maxtol", "max allowed error for --test and --warmup", 0.5),
     ("test_avgtol", "avg allowed error for --test and --warmup", 0.2),
+    ("dump_failures", "write rllm-failure-step-N/ repro when a batch fails to build", 0.0),
+    ("check_logits", "fail sequences with NaN/Inf logits, dumping them to rllm-nonfinite-*", 0.0),
 ];
 
 lazy_static::lazy_static! {
@@ -110,6 +112,31 @@ pub fn limit_bytes(s: &[u8], max_len: usize) -> String {
     }
 }
 
+/// Length of the longest prefix of `buf` that doesn't end in the middle of a UTF-8 sequence.
+/// Invalid bytes count as complete.
+pub fn utf8_complete_len(buf: &[u8]) -> usize {
+    let n = buf.len();
+    // sequences are at most 4 bytes, so only the last 3 bytes can be an incomplete one
+    for back in 1..=std::cmp::min(3, n) {
+        let b = buf[n - back];
+        if b & 0b1100_0000 == 0b1000_0000 {
+            // continuation byte
+            continue;
+        }
+        let seq_len = if b & 0b1110_0000 == 0b1100_0000 {
+            2
+        } else if b & 0b1111_0000 == 0b1110_0000 {
+            3
+        } else if b & 0b1111_1000 == 0b1111_0000 {
+            4
+        } else {
+            1
+        };
+        return if seq_len > back { n - back } else { n };
+    }
+    n
+}
+
 pub fn pad_to_multiple<T>(v: &mut Vec<T>, multiple: usize)
 where
     T: Default + Clone,
@@ -0,0 +1,309 @@
+// Attention implementations, selected per batch in BatchInfoBuilder::finish().
+
+use super::{
+    config::ModelConfig,
+    kernels,
+    paged::BatchInfo,
+    refkernels, repeat_kv,
+    util::{check_all_close, check_all_close_attn},
+    DType, CHECK,
+};
+use anyhow::{bail, Result};
+use tch::Tensor;
+
+pub trait AttentionBackend {
+    fn name(&self) -> &'static str;
+
+    /// `q` is [num_tokens, num_heads, head_dim] for all tokens in the batch.
+    /// `k` and `v` are for the same tokens, and are already stored in `kv_cache`.
+    /// Returns [num_tokens, num_heads * head_dim].
+    fn forward(
+        &self,
+        config: &ModelConfig,
+        batch_info: &BatchInfo,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        kv_cache: &(Tensor, Tensor),
+    ) -> Tensor;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttnBackendKind {
+    /// All entries through varlen attention (flash-attn for f16/bf16).
+    Varlen,
+    /// Multi-token entries through varlen attention, single-token ones
+    /// through paged_attention_v1().
+    Paged,
+    /// All entries through the reference implementation; slow, for debugging.
+    Naive,
+}
+
+impl AttnBackendKind {
+    /// Parse --attn-backend: "" or "auto" (None), "varlen", "paged", or "naive".
+    /// "paged" is rejected in builds without the paged kernels.
+    pub fn parse(spec: &str) -> Result<Option<Self>> {
+        match spec {
+            "" | "auto" => Ok(None),
+            "varlen" => Ok(Some(AttnBackendKind::Varlen)),
+            #[cfg(any(feature = "cuda", feature = "cpu-fallback"))]
+            "paged" => Ok(Some(AttnBackendKind::Paged)),
+            #[cfg(not(any(feature = "cuda", feature = "cpu-fallback")))]
+            "paged" => bail!("paged attention not available in this build"),
+            "naive" => Ok(Some(AttnBackendKind::Naive)),
+            _ => bail!("invalid attention backend {spec:?}; try one of auto, varlen, paged, naive"),
+        }
+    }
+
+    /// Pick the backend for a batch with `num_single` single-token entries.
+    pub fn select(config: &ModelConfig, num_single: usize) -> Self {
+        if let Some(forced) = config.cache.attn_backend {
+            forced
+        } else if config.cache.paged_attn_kernel_v > 0 && num_single > 0 {
+            AttnBackendKind::Paged
+        } else {
+            AttnBackendKind::Varlen
+        }
+    }
+
+    pub fn backend(&self) -> &'static dyn AttentionBackend {
+        match self {
+            AttnBackendKind::Varlen => &VarlenAttn,
+            AttnBackendKind::Naive => &NaiveAttn,
+            #[cfg(any(feature = "cuda", feature = "cpu-fallback"))]
+            AttnBackendKind::Paged => &PagedAttn,
+            #[cfg(not(any(feature = "cuda", feature = "cpu-fallback")))]
+            AttnBackendKind::Paged => panic!("paged attention not available in this build"),
+        }
+    }
+}
+
+pub struct VarlenAttn;
+pub struct NaiveAttn;
+#[cfg(any(feature = "cuda", feature = "cpu-fallback"))]
+pub struct PagedAttn;
+
+impl AttentionBackend for VarlenAttn {
+    fn name(&self) -> &'static str {
+        "varlen"
+    }
+
+    fn forward(
+        &self,
+        config: &ModelConfig,
+        batch_info: &BatchInfo,
+        q: &Tensor,
+        _k: &Tensor,
+        _v: &Tensor,
+        kv_cache: &(Tensor, Tensor),
+    ) -> Tensor {
+        assert!(batch_info.q_multi == q.size()[0]);
+        let naive = !(config.dtype == DType::BFloat16 || config.dtype == DType::Half);
+        compute_varlen_attn(config, q, batch_info, kv_cache, naive)
+    }
+}
+
+impl AttentionBackend for NaiveAttn {
+    fn name(&self) -> &'static str {
+        "naive"
+    }
+
+    fn forward(
+        &self,
+        config: &ModelConfig,
+        batch_info: &BatchInfo,
+        q: &Tensor,
+        _k: &Tensor,
+        _v: &Tensor,
+        kv_cache: &(Tensor, Tensor),
+    ) -> Tensor {
+        assert!(batch_info.q_multi == q.size()[0]);
+        compute_varlen_attn(config, q, batch_info, kv_cache, true)
+    }
+}
+
+#[cfg(any(feature = "cuda", feature = "cpu-fallback"))]
+impl AttentionBackend for PagedAttn {
+    fn name(&self) -> &'static str {
+        "paged"
+    }
+
+    fn forward(
+        &self,
+        config: &ModelConfig,
+        batch_info: &BatchInfo,
+        q: &Tensor,
+        _k: &Tensor,
+        _v: &Tensor,
+        kv_cache: &(Tensor, Tensor),
+    ) -> Tensor {
+        let naive = !(config.dtype == DType::BFloat16 || config.dtype == DType::Half);
+        let q_multi = batch_info.q_multi;
+        let q_single = q.size()[0] - q_multi;
+        let y = compute_varlen_attn(
+            config,
+            &q.narrow(0, 0, q_multi),
+            batch_info,
+            kv_cache,
+            naive,
+        );
+        compute_paged_attn(
+            config,
+            &q.narrow(0, q_multi, q_single),
+            &y,
+            batch_info,
+            kv_cache,
+        )
+    }
+}
+
+fn compute_varlen_attn(
+    config: &ModelConfig,
+    q: &Tensor,
+    batch_info: &BatchInfo,
+    kv_cache: &(Tensor, Tensor),
+    naive: bool,
+) -> Tensor {
+    let (key_cache, value_cache) = kv_cache;
+
+    if q.size()[0] == 0 {
+        return Tensor::empty(&[0, config.hidden_size as i64], (q.kind(), q.device()));
+    }
+
+    // then, extend key/value and fill them from cache
+    let mut k = Tensor::empty(
+        &[
+            batch_info.gather_mapping.size()[0],
+            config.num_key_value_heads as i64,
+            config.head_dim as i64,
+        ],
+        (q.kind(), q.device()),
+    );
+
+    let mut v = k.empty_like();
+    kernels::gather_cached_kv(
+        &mut k,
+        &mut v,
+        key_cache,
+        value_cache,
+        &batch_info.gather_mapping,
+    );
+
+    if CHECK {
+        let mut kk = k.empty_like();
+        let mut vv = v.empty_like();
+
+        refkernels::gather_cached_kv(
+            &mut kk,
+            &mut vv,
+            key_cache,
+            value_cache,
+            &batch_info.gather_mapping,
+        );
+        check_all_close(&k, &kk, 1e-5);
+        check_all_close(&v, &vv, 1e-5);
+    }
+
+    let k = repeat_kv(config, k);
+    let v = repeat_kv(config, v);
+
+    let y = {
+        batch_info.log_tensor("q", &q);
+        batch_info.log_tensor("k", &k);
+        batch_info.log_tensor("v", &v);
+
+        // flash-attn expects (seq_len, nheads, head_dim)
+        let softmax_scale = 1f32 / (config.head_dim as f32).sqrt();
+
+        let causal = true;
+
+        let y = if !naive {
+            let y = kernels::varlen_attn(
+                &q,
+                &k,
+                &v,
+                &batch_info.seqlens_q,
+                &batch_info.seqlens_k,
+                batch_info.max_seqlen_q,
+                batch_info.max_seqlen_k,
+                softmax_scale,
+                causal,
+            );
+
+            if CHECK {
+                let y2 = refkernels::varlen_attn(
+                    &q,
+                    &k,
+                    &v,
+                    &batch_info.seqlens_q,
+                    &batch_info.seqlens_k,
+                    batch_info.max_seqlen_q,
+                    batch_info.max_seqlen_k,
+                    softmax_scale,
+                    causal,
+                );
+                check_all_close_attn(&y, &y2);
+            }
+
+            y
+        } else {
+            refkernels::varlen_attn(
+                &q,
+                &k,
+                &v,
+                &batch_info.seqlens_q,
+                &batch_info.seqlens_k,
+                batch_info.max_seqlen_q,
+                batch_info.max_seqlen_k,
+                softmax_scale,
+                causal,
+            )
+        };
+
+        y
+    };
+
+    batch_info.log_tensor("y", &v);
+
+    let y = y.reshape(&[-1, config.hidden_size as i64]);
+
+    y
+}
+
+#[cfg(any(feature = "cuda", feature = "cpu-fallback"))]
+fn compute_paged_attn(
+    config: &ModelConfig,
+    q: &Tensor,
+    y: &Tensor,
+    batch_info: &BatchInfo,
+    kv_cache: &(Tensor, Tensor),
+) -> Tensor {
+    use kernels::paged_attention_v1;
+
+    if q.size()[0] == 0 {
+        return y.shallow_clone();
+    }
+
+    let mut out = Tensor::empty_like(q);
+    let (key_cache, value_cache) = kv_cache;
+
+    let softmax_scale = 1f32 / (config.head_dim as f32).sqrt();
+
+    paged_attention_v1(
+        &mut out,
+        &q,
+        key_cache,
+        value_cache,
+        config.num_key_value_heads,
+        softmax_scale,
+        &batch_info.paged_block_tables,
+        &batch_info.paged_context_lens,
+        batch_info.paged_block_size,
+        batch_info.paged_max_context_len,
+        None,
+    );
+
+    let out = out.reshape(&[-1, config.hidden_size as i64]);
+
+    Tensor::cat(&[y, &out], 0)
+}
@@ -0,0 +1,234 @@
+// Per-layer activation checkpoints, for chasing divergences between dtypes
+// (e.g., BF16 vs F32) layer by layer, rather than only in the final logits.
+//
+// A BatchInfo with `checkpoints` set records the output of each decoder layer
+// (see Llama::forward()); record_activations() runs a prompt that way, and
+// compare_runs() finds the first layer where two runs disagree.
+
+use super::{
+    loader::load_model,
+    paged::{BatchEntry, BatchInfoBuilder, CacheEngine},
+    tmodel::{TModel, TModelInner},
+    DType,
+};
+use aicirt::api::Token;
+use anyhow::{bail, Result};
+use rllm::{config::RllmConfig, CacheSize};
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf, sync::Arc};
+use tch::Tensor;
+
+/// Number of leading values of the hidden state kept in a checkpoint.
+pub const CHECKPOINT_SLICE_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerCheckpoint {
+    /// L2 norm of the whole hidden state (all tokens of the step).
+    pub norm: f64,
+    /// The first CHECKPOINT_SLICE_LEN values of the hidden state of the last token.
+    pub slice: Vec<f32>,
+}
+
+impl LayerCheckpoint {
+    pub fn from_hidden(x: &Tensor) -> Self {
+        let _no_grad = tch::no_grad_guard();
+        let x = x.to_kind(DType::Float);
+        let hidden = *x.size().last().unwrap();
+        let last = x.reshape(&[-1, hidden]).select(0, -1);
+        let len = std::cmp::min(CHECKPOINT_SLICE_LEN as i64, hidden);
+        LayerCheckpoint {
+            norm: x.norm().double_value(&[]),
+            slice: Vec::<f32>::try_from(&last.narrow(0, 0, len)).unwrap(),
+        }
+    }
+}
+
+/// Checkpoints of one run, keyed by (layer, step).
+#[derive(Debug, Clone, Default)]
+pub struct ActivationLog {
+    pub entries: BTreeMap<(usize, usize), LayerCheckpoint>,
+}
+
+impl ActivationLog {
+    pub fn record(&mut self, layer: usize, step_no: usize, x: &Tensor) {
+        self.entries
+            .insert((layer, step_no), LayerCheckpoint::from_hidden(x));
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerances {
+    /// Largest allowed |norm_a - norm_b| / norm_a.
+    pub norm_rel: f64,
+    /// Largest allowed difference of any value in the slices.
+    pub slice_abs: f64,
+}
+
+impl Default for Tolerances {
+    // roughly what BF16 vs F32 gives on a healthy model
+    fn default() -> Self {
+        Tolerances {
+            norm_rel: 2e-2,
+            slice_abs: 5e-2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LayerDivergence {
+    pub layer: usize,
+    pub step_no: usize,
+    pub norm_rel_diff: f64,
+    pub slice_max_diff: f64,
+    pub exceeds: bool,
+}
+
+/// What compare_runs() finds; one entry per (layer, step) present in both runs,
+/// ordered by step and then layer.
+#[derive(Debug, Clone)]
+pub struct DivergenceReport {
+    pub layers: Vec<LayerDivergence>,
+    /// Checkpoints that are only in one of the runs.
+    pub missing: Vec<(usize, usize)>,
+}
+
+impl DivergenceReport {
+    /// The first layer over tolerance, in the earliest step that has one.
+    pub fn first_divergent(&self) -> Option<&LayerDivergence> {
+        self.layers.iter().find(|l| l.exceeds)
+    }
+}
+
+impl Display for DivergenceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for l in &self.layers {
+            writeln!(
+                f,
+                "step {:>3} layer {:>3}: norm {:.2e} slice {:.2e}{}",
+                l.step_no,
+                l.layer,
+                l.norm_rel_diff,
+                l.slice_max_diff,
+                if l.exceeds { " !" } else { "" }
+            )?;
+        }
+        if !self.missing.is_empty() {
+            writeln!(f, "only in one run: {:?}", self.missing)?;
+        }
+        match self.first_divergent() {
+            Some(l) => write!(f, "first divergence: step {} layer {}", l.step_no, l.layer),
+            None => write!(f, "no divergence"),
+        }
+    }
+}
+
+pub fn compare_runs(a: &ActivationLog, b: &ActivationLog, tol: Tolerances) -> DivergenceReport {
+    let mut layers = Vec::new();
+    let mut missing = Vec::new();
+    for (key, ca) in &a.entries {
+        let cb = match b.entries.get(key) {
+            Some(cb) => cb,
+            None => {
+                missing.push(*key);
+                continue;
+            }
+        };
+        let norm_rel_diff = (ca.norm - cb.norm).abs() / ca.norm.max(f64::MIN_POSITIVE);
+        let slice_max_diff = ca
+            .slice
+            .iter()
+            .zip(cb.slice.iter())
+            .map(|(x, y)| (*x as f64 - *y as f64).abs())
+            .fold(0.0, f64::max);
+        let exceeds = !(norm_rel_diff <= tol.norm_rel && slice_max_diff <= tol.slice_abs);
+        layers.push(LayerDivergence {
+            layer: key.0,
+            step_no: key.1,
+            norm_rel_diff,
+            slice_max_diff,
+            exceeds,
+        });
+    }
+    missing.extend(b.entries.keys().filter(|k| !a.entries.contains_key(k)));
+    missing.sort();
+    layers.sort_by_key(|l| (l.step_no, l.layer));
+    DivergenceReport { layers, missing }
+}
+
+/// Run `prompt` through `model`, and then `steps` decode steps, recording the
+/// checkpoints. Tokens are picked greedily, or taken from `follow` (the tokens
+/// returned by an earlier run), so that both runs see the same inputs.
+/// Returns the checkpoints and the generated tokens.
+pub fn record_activations(
+    config: &RllmConfig<TModel>,
+    model: &dyn TModelInner,
+    prompt: &[Token],
+    steps: usize,
+    follow: Option<&[Token]>,
+) -> Result<(ActivationLog, Vec<Token>)> {
+    let _no_grad = tch::no_grad_guard();
+    let config = Arc::new(config.clone());
+    let block_size = config.model.cache.block_size;
+    let len = prompt.len() + steps;
+    if prompt.is_empty() || len > config.scheduler.max_model_len {
+        bail!("prompt of {} tokens and {steps} steps", prompt.len());
+    }
+    let mut cache_engine = CacheEngine::new(
+        config.clone(),
+        &CacheSize {
+            gpu: (len + block_size - 1) / block_size,
+            cpu: 0,
+        },
+    );
+
+    let mut log = ActivationLog::default();
+    let mut tokens = prompt.to_vec();
+    let mut generated = Vec::new();
+    for step_no in 0..=steps {
+        let start = if step_no == 0 { 0 } else { tokens.len() - 1 };
+        let mut builder = BatchInfoBuilder::new(config.clone());
+        builder.push_entry(BatchEntry {
+            seq_id: 1,
+            query_pos_token: (start..tokens.len()).map(|p| (p, tokens[p])).collect(),
+            kv_slots: (0..tokens.len()).collect(),
+        });
+        let mut batch_info = builder.finish(step_no, cache_engine.get_cache_iface())?;
+        batch_info.checkpoints = Some(ActivationLog::default());
+        let logits = model.forward(&mut batch_info);
+        model.synchronize();
+        log.entries
+            .extend(batch_info.checkpoints.take().unwrap().entries);
+        if step_no == steps {
+            break;
+        }
+        let next = match follow.and_then(|f| f.get(step_no)) {
+            Some(t) => *t,
+            None => logits.select(0, 0).argmax(0, false).int64_value(&[]) as Token,
+        };
+        generated.push(next);
+        tokens.push(next);
+    }
+    Ok((log, generated))
+}
+
+/// Load the model from `filenames` twice, in config.model.dtype and in `dtype`,
+/// run `prompt` for `steps` steps in both (the second one following the tokens
+/// of the first), and compare the checkpoints. Meant for debugging numerics.
+pub fn diagnose_dtype(
+    config: &RllmConfig<TModel>,
+    filenames: Vec<PathBuf>,
+    dtype: DType,
+    prompt: &[Token],
+    steps: usize,
+    tol: Tolerances,
+) -> Result<DivergenceReport> {
+    let (model_a, _) = load_model(config, filenames.clone(), None)?;
+    let (log_a, tokens) = record_activations(config, model_a.as_ref(), prompt, steps, None)?;
+    drop(model_a);
+
+    let mut config_b = config.clone();
+    config_b.model.dtype = dtype;
+    let (model_b, _) = load_model(&config_b, filenames, None)?;
+    let (log_b, _) = record_activations(&config_b, model_b.as_ref(), prompt, steps, Some(&tokens))?;
+
+    Ok(compare_runs(&log_a, &log_b, tol))
+}
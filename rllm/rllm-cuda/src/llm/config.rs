@@ -1,43 +1,157 @@
-use rllm::config::{ModelMeta, RllmConfig};
 use aicirt::bail_user;
 use anyhow::Result;
+use rllm::config::{ConfigError, ModelMeta, RllmConfig};
 use tch::Device;
 
-use super::{tmodel::TModel, DType};
+use super::{attn::AttnBackendKind, tmodel::TModel, DType};
 
 const GB: usize = 1 << 30;
 
+/// Slot ids (block_idx * block_size + offset) go to the gather kernels, and
+/// block ids to paged attention, as i32.
+pub const MAX_NUM_SLOTS: usize = i32::MAX as usize;
+
 pub trait TchRllmConfig {
     fn get_hidden_size(&self) -> usize;
     fn get_head_size(&self) -> usize;
     fn get_num_heads_parallel(&self) -> usize;
     fn get_num_layers_parallel(&self) -> usize;
     fn get_max_model_len(&self) -> usize;
-    fn verify_args(&self) -> Result<()>;
+    fn validate_model(&self, errors: &mut Vec<ConfigError>);
 }
 
 impl TchRllmConfig for RllmConfig<TModel> {
-    fn verify_args(&self) -> Result<()> {
+    fn validate_model(&self, errors: &mut Vec<ConfigError>) {
         let model = &self.model;
         let parallel = &self.parallel;
-        if model.num_hidden_layers % parallel.pipeline_parallel_size != 0 {
-            bail_user!(
-                "Number of hidden layers ({}) must be divisible by the pipeline parallel size ({}).",
-                model.num_hidden_layers,
-                parallel.pipeline_parallel_size
+        let mut err = |field: &'static str, message: String, fix: String| {
+            errors.push(ConfigError {
+                field,
+                message,
+                fix,
+            })
+        };
+        if parallel.pipeline_parallel_size > 0
+            && model.num_hidden_layers % parallel.pipeline_parallel_size != 0
+        {
+            err(
+                "parallel.pipeline_parallel_size",
+                format!(
+                    "number of hidden layers ({}) must be divisible by it ({})",
+                    model.num_hidden_layers, parallel.pipeline_parallel_size
+                ),
+                "use a divisor of the number of layers".to_string(),
             );
         }
-        if model.num_key_value_heads % parallel.tensor_parallel_size != 0 {
-            bail_user!(
-                "Number of key/value heads ({}) must be divisible by the tensor parallel size ({}).",
-                model.num_key_value_heads,
-                parallel.tensor_parallel_size
+        if parallel.tensor_parallel_size > 0
+            && model.num_key_value_heads % parallel.tensor_parallel_size != 0
+        {
+            err(
+                "parallel.tensor_parallel_size",
+                format!(
+                    "number of key/value heads ({}) must be divisible by it ({})",
+                    model.num_key_value_heads, parallel.tensor_parallel_size
+                ),
+                "use a divisor of the number of key/value heads".to_string(),
+            );
+        }
+        if cfg!(feature = "cuda") && !cfg!(feature = "cpu-fallback") && !model.device.is_cuda() {
+            err(
+                "model.device",
+                format!("{:?} is not supported", model.device),
+                "build with the 'cpu-fallback' feature to run without CUDA".to_string(),
             );
         }
-        if self.aici.max_fuel < 100 {
-            bail_user!("max_fuel not configured");
+        if !model.layer_devices.is_empty() {
+            let devs = &model.layer_devices;
+            if devs.len() != model.num_hidden_layers {
+                err(
+                    "model.layer_devices",
+                    format!(
+                        "has {} entries for {} layers",
+                        devs.len(),
+                        model.num_hidden_layers
+                    ),
+                    "give a device for every layer".to_string(),
+                );
+            } else if model.model_type != ModelType::Llama {
+                err(
+                    "model.layer_devices",
+                    format!("not supported for {:?} models", model.model_type),
+                    "run the model on a single device".to_string(),
+                );
+            } else if let Some(d) = (1..devs.len())
+                .find(|&i| devs[i] != devs[i - 1] && devs[..i].contains(&devs[i]))
+                .map(|i| devs[i])
+            {
+                err(
+                    "model.layer_devices",
+                    format!("layers on {:?} are not consecutive", d),
+                    "place each device's layers next to each other".to_string(),
+                );
+            }
+            if cfg!(feature = "cuda") && !cfg!(feature = "cpu-fallback") {
+                if let Some(d) = devs.iter().find(|d| !d.is_cuda()) {
+                    err(
+                        "model.layer_devices",
+                        format!("{:?} is not supported", d),
+                        "build with the 'cpu-fallback' feature to run without CUDA".to_string(),
+                    );
+                }
+            }
+        }
+        if !model.device.is_cuda() && model.dtype == DType::Half {
+            err(
+                "model.dtype",
+                "f16 is not supported on the CPU".to_string(),
+                "use --dtype f32 (or bf16)".to_string(),
+            );
+        }
+        if model.cache.block_size == 0 {
+            err(
+                "model.cache.block_size",
+                "must be at least 1".to_string(),
+                "use 16".to_string(),
+            );
+        } else if model.cache.paged_attn_kernel_v > 0
+            && model.device.is_cuda()
+            && ![8, 16, 32].contains(&model.cache.block_size)
+        {
+            err(
+                "model.cache.block_size",
+                format!(
+                    "{} is not supported by the paged attention kernels",
+                    model.cache.block_size
+                ),
+                "use 8, 16 or 32".to_string(),
+            );
+        }
+        if let Some(sink) = self.scheduler.kv_truncation_sink {
+            let bl_size = model.cache.block_size.max(1);
+            let min_len = sink.div_ceil(bl_size) * bl_size + bl_size;
+            if min_len > self.scheduler.max_model_len {
+                err(
+                    "scheduler.kv_truncation_sink",
+                    format!(
+                        "{} rounded up to whole blocks of {} leaves no room below max_model_len ({})",
+                        sink, bl_size, self.scheduler.max_model_len
+                    ),
+                    format!(
+                        "use a sink of at most {}",
+                        (self.scheduler.max_model_len / bl_size).saturating_sub(1) * bl_size
+                    ),
+                );
+            }
+        }
+        if model.cache.attn_backend == Some(AttnBackendKind::Paged)
+            && model.cache.paged_attn_kernel_v == 0
+        {
+            err(
+                "model.cache.attn_backend",
+                "paged attention backend is not available in this build".to_string(),
+                "leave it unset to pick the backend per batch".to_string(),
+            );
         }
-        Ok(())
     }
 
     fn get_hidden_size(&self) -> usize {
@@ -85,9 +199,14 @@ pub struct ModelConfig {
 
     pub layer_norm_eps: f64, // default 1e-5
     pub rope_theta: f32,     // default 10000
+    /// lm_head reuses the token embedding matrix
+    pub tie_word_embeddings: bool,
 
     pub device: Device,
     pub dtype: DType,
+    /// Device of each layer's weights and KV cache; empty - all on `device`.
+    /// Embeddings, the head and the batch tensors stay on `device`.
+    pub layer_devices: Vec<Device>,
 
     pub profile_step_no: usize,
     pub cache: CacheConfig,
@@ -105,6 +224,127 @@ impl ModelConfig {
             _ => panic!("Unknown dtype {}", torch_dtype),
         }
     }
+
+    pub fn layer_device(&self, layer_no: usize) -> Device {
+        self.layer_devices
+            .get(layer_no)
+            .copied()
+            .unwrap_or(self.device)
+    }
+
+    /// `device`, followed by the other devices layers are on, in layer order.
+    pub fn devices(&self) -> Vec<Device> {
+        let mut r = vec![self.device];
+        for d in &self.layer_devices {
+            if !r.contains(d) {
+                r.push(*d);
+            }
+        }
+        r
+    }
+
+    /// Parse --layer-split: "" (all layers on one device), "cuda:0=20,cuda:1=12"
+    /// (number of layers per device, in order), or "cuda:0,cuda:1" (layers split
+    /// in proportion to `memory_size` of each device, evenly if it's unknown).
+    pub fn parse_layer_split(
+        spec: &str,
+        num_layers: usize,
+        memory_size: impl Fn(Device) -> usize,
+    ) -> Result<Vec<Device>> {
+        if spec.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut counts = Vec::new();
+        for part in spec.split(',') {
+            let (dev, count) = match part.split_once('=') {
+                Some((dev, count)) => match count.trim().parse::<usize>() {
+                    Ok(n) => (dev, Some(n)),
+                    Err(_) => bail_user!("invalid number of layers in {part:?}"),
+                },
+                None => (part, None),
+            };
+            counts.push((parse_device(dev.trim())?, count));
+        }
+        if counts.iter().all(|(_, n)| n.is_some()) {
+            let total = counts.iter().map(|(_, n)| n.unwrap()).sum::<usize>();
+            if total != num_layers {
+                bail_user!("layer split {spec:?} has {total} layers; the model has {num_layers}");
+            }
+            Ok(counts
+                .iter()
+                .flat_map(|(d, n)| std::iter::repeat(*d).take(n.unwrap()))
+                .collect())
+        } else if counts.iter().all(|(_, n)| n.is_none()) {
+            let mut sizes = counts
+                .iter()
+                .map(|(d, _)| (*d, memory_size(*d)))
+                .collect::<Vec<_>>();
+            if sizes.iter().any(|(_, m)| *m == 0) {
+                sizes.iter_mut().for_each(|(_, m)| *m = 1);
+            }
+            Ok(split_layers(&sizes, num_layers))
+        } else {
+            bail_user!(
+                "layer split {spec:?} should give the number of layers for all devices or none"
+            )
+        }
+    }
+}
+
+fn parse_device(name: &str) -> Result<Device> {
+    let dev = match name {
+        "cpu" => Device::Cpu,
+        "mps" => Device::Mps,
+        "cuda" => Device::Cuda(0),
+        _ => match name.strip_prefix("cuda:").map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) => Device::Cuda(n),
+            _ => bail_user!("invalid device {name:?}; try cpu, cuda or cuda:N"),
+        },
+    };
+    Ok(dev)
+}
+
+/// Assign `num_layers` consecutive layers to the devices, in order, in proportion
+/// to their memory (as (device, bytes)). Every device gets at least one layer
+/// if there are enough of them.
+pub fn split_layers(devices: &[(Device, usize)], num_layers: usize) -> Vec<Device> {
+    let total = devices.iter().map(|(_, m)| *m as f64).sum::<f64>();
+    let shares = devices
+        .iter()
+        .map(|(_, m)| *m as f64 / total * num_layers as f64)
+        .collect::<Vec<_>>();
+    let mut counts = shares
+        .iter()
+        .map(|s| s.floor() as usize)
+        .collect::<Vec<_>>();
+    // hand out the rest by largest remainder
+    let mut order = (0..devices.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| {
+        (shares[*b] - counts[*b] as f64).total_cmp(&(shares[*a] - counts[*a] as f64))
+    });
+    let left = num_layers.saturating_sub(counts.iter().sum::<usize>());
+    for i in order.iter().take(left) {
+        counts[*i] += 1;
+    }
+    // don't leave a device empty, if possible
+    while let Some(empty) = counts.iter().position(|n| *n == 0) {
+        let (largest, n) = counts
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by_key(|(_, n)| *n)
+            .unwrap();
+        if n < 2 {
+            break;
+        }
+        counts[largest] -= 1;
+        counts[empty] += 1;
+    }
+    devices
+        .iter()
+        .zip(counts)
+        .flat_map(|((d, _), n)| std::iter::repeat(*d).take(n))
+        .collect()
 }
 pub trait RllmModelConfig {
     fn into_config(self, common: CommonModelConfig) -> ModelConfig;
@@ -122,6 +362,15 @@ pub struct CacheConfig {
     /// 0 - don't use paged_attention_v1/2(), otherwise version
     pub paged_attn_kernel_v: usize,
 
+    /// Pad decode batches up to the next of these sizes (sorted); empty - no padding.
+    pub decode_pad_buckets: Vec<usize>,
+
+    /// Force attention implementation; None - pick per batch.
+    pub attn_backend: Option<AttnBackendKind>,
+
+    /// Limits of the attention kernels; batches over them are split.
+    pub attn_limits: AttnLimits,
+
     // #[serde(skip)]
     pub swap_space_bytes: usize,
 }
@@ -152,9 +401,9 @@ impl CacheConfig {
         } else if swap_space_bytes > (total_cpu_memory * 4 / 10) {
             log::warn!("Possibly too large swap space. {}", msg);
         }
-        #[cfg(feature = "cuda")]
+        #[cfg(any(feature = "cuda", feature = "cpu-fallback"))]
         let paged_attn_kernel_v = 1;
-        #[cfg(not(feature = "cuda"))]
+        #[cfg(not(any(feature = "cuda", feature = "cpu-fallback")))]
         let paged_attn_kernel_v = 0;
         Ok(Self {
             block_size,
@@ -162,8 +411,95 @@ impl CacheConfig {
             swap_space,
             swap_space_bytes,
             paged_attn_kernel_v,
+            decode_pad_buckets: Vec::new(),
+            attn_backend: None,
+            attn_limits: AttnLimits::unlimited(),
         })
     }
+
+    /// Check that a GPU cache of `num_blocks` blocks (plus the scratch block)
+    /// can be addressed with i32 slot ids.
+    pub fn check_num_gpu_blocks(&self, num_blocks: usize) -> Result<()> {
+        let num_slots = (num_blocks + 1).saturating_mul(self.block_size);
+        if num_slots > MAX_NUM_SLOTS {
+            bail_user!(
+                "KV cache of {} blocks of {} tokens has {} slots, more than the {} supported by the kernels; lower gpu_memory_utilization.",
+                num_blocks,
+                self.block_size,
+                num_slots,
+                MAX_NUM_SLOTS
+            );
+        }
+        Ok(())
+    }
+
+    /// Parse --decode-pad: "" (off), "pow2", or comma-separated list of sizes.
+    pub fn parse_pad_buckets(spec: &str) -> Result<Vec<usize>> {
+        let mut buckets = match spec {
+            "" => Vec::new(),
+            "pow2" => (0..=12).map(|i| 1 << i).collect(),
+            _ => {
+                let mut r = Vec::new();
+                for s in spec.split(',') {
+                    match s.trim().parse::<usize>() {
+                        Ok(n) if n > 0 => r.push(n),
+                        _ => bail_user!("invalid decode pad bucket: {s:?}"),
+                    }
+                }
+                r
+            }
+        };
+        buckets.sort();
+        buckets.dedup();
+        Ok(buckets)
+    }
+
+    /// Size the decode batch of `n` sequences should be padded to.
+    pub fn decode_pad_size(&self, n: usize) -> usize {
+        self.decode_pad_buckets
+            .iter()
+            .find(|b| **b >= n)
+            .map_or(n, |b| *b)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AttnLimits {
+    /// Maximum number of query tokens of a single sequence.
+    pub max_seqlen_q: usize,
+    /// Maximum number of KV entries of a single sequence.
+    pub max_seqlen_k: usize,
+    /// Maximum number of query tokens in a batch.
+    pub max_batch_tokens: usize,
+    /// Maximum number of KV entries gathered for varlen attention in a batch.
+    pub max_gather_tokens: usize,
+}
+
+impl AttnLimits {
+    pub fn unlimited() -> Self {
+        AttnLimits {
+            max_seqlen_q: usize::MAX,
+            max_seqlen_k: usize::MAX,
+            max_batch_tokens: usize::MAX,
+            max_gather_tokens: usize::MAX,
+        }
+    }
+
+    /// Limits of the kernels we're built with, for the given model.
+    pub fn detect(model: &ModelConfig) -> Self {
+        #[allow(unused_mut)]
+        let mut r = Self::unlimited();
+        #[cfg(feature = "cuda")]
+        if model.device.is_cuda() {
+            // the CUDA kernels index the gathered K/V (after repeat_kv()) with i32
+            let elts_per_token = model.num_attention_heads * model.head_dim;
+            r.max_gather_tokens = i32::MAX as usize / elts_per_token;
+            r.max_batch_tokens = r.max_gather_tokens;
+        }
+        #[cfg(not(feature = "cuda"))]
+        let _ = model;
+        r
+    }
 }
 
 fn get_cpu_memory() -> usize {
@@ -1,21 +1,165 @@
 #[cfg(not(feature = "cuda"))]
 pub use super::refkernels::*;
 use tch::{Device, Tensor};
-#[cfg(feature = "cuda")]
+#[cfg(all(feature = "cuda", not(feature = "cpu-fallback")))]
 pub use tch_cuda::flash_attn_varlen as varlen_attn;
 #[cfg(feature = "cuda")]
 pub use tch_cuda::*;
 
+#[cfg(all(feature = "cuda", feature = "cpu-fallback"))]
+pub use fallback::{
+    copy_blocks, gather_cached_kv, paged_attention_v1, reshape_and_cache, rotary_embedding,
+    varlen_attn,
+};
+
 /// Convert a vector of lengths into a tensor of offsets, as expected by flash attn.
-pub fn to_offsets(seqlens: impl Iterator<Item = usize>, device: Device) -> (usize, Tensor) {
-    let mut offsets = Vec::new();
+/// Prefix sums of `seqlens` as the i32 `cu_seqlens` tensor expected by varlen
+/// attention, together with the maximum length (computed on the host).
+pub fn to_offsets(seqlens: &[usize], device: Device) -> (usize, Tensor) {
+    let (max, offsets) = host_offsets(seqlens);
+    (max, Tensor::from_slice(offsets.as_slice()).to(device))
+}
+
+/// to_offsets(), without the upload.
+pub fn host_offsets(seqlens: &[usize]) -> (usize, Vec<i32>) {
+    let mut offsets = Vec::with_capacity(seqlens.len() + 1);
     let mut offset = 0;
     let mut max = 0;
-    for len in seqlens {
+    for &len in seqlens {
         max = std::cmp::max(len, max);
         offsets.push(offset as i32);
         offset += len;
     }
+    assert!(
+        offset <= i32::MAX as usize,
+        "seqlens sum {offset} overflows i32"
+    );
     offsets.push(offset as i32);
-    (max, Tensor::from_slice(offsets.as_slice()).to(device))
+    (max, offsets)
+}
+
+/// Kernels that run the CUDA implementation for tensors on the GPU, and the
+/// (slow) reference implementation for tensors on the CPU.
+#[cfg(all(feature = "cuda", feature = "cpu-fallback"))]
+mod fallback {
+    use super::super::refkernels;
+    use rllm::HashMap;
+    use tch::Tensor;
+
+    pub fn reshape_and_cache(
+        key: &Tensor,
+        value: &Tensor,
+        key_cache: &mut Tensor,
+        value_cache: &mut Tensor,
+        slot_mapping: &Tensor,
+    ) {
+        if key_cache.device().is_cuda() {
+            tch_cuda::reshape_and_cache(key, value, key_cache, value_cache, slot_mapping)
+        } else {
+            refkernels::reshape_and_cache(key, value, key_cache, value_cache, slot_mapping)
+        }
+    }
+
+    pub fn gather_cached_kv(
+        key: &mut Tensor,
+        value: &mut Tensor,
+        key_cache: &Tensor,
+        value_cache: &Tensor,
+        slot_mapping: &Tensor,
+    ) {
+        if key_cache.device().is_cuda() {
+            tch_cuda::gather_cached_kv(key, value, key_cache, value_cache, slot_mapping)
+        } else {
+            refkernels::gather_cached_kv(key, value, key_cache, value_cache, slot_mapping)
+        }
+    }
+
+    pub fn rotary_embedding(
+        positions: &Tensor,
+        query: &mut Tensor,
+        key: &mut Tensor,
+        head_size: usize,
+        cos_sin_cache: &Tensor,
+        is_neox: bool,
+    ) {
+        if query.device().is_cuda() {
+            tch_cuda::rotary_embedding(positions, query, key, head_size, cos_sin_cache, is_neox)
+        } else {
+            refkernels::rotary_embedding(positions, query, key, head_size, cos_sin_cache, is_neox)
+        }
+    }
+
+    pub fn varlen_attn(
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        seqlens_q: &Tensor,
+        seqlens_k: &Tensor,
+        max_seqlen_q: usize,
+        max_seqlen_k: usize,
+        softmax_scale: f32,
+        causal: bool,
+    ) -> Tensor {
+        let f = if q.device().is_cuda() {
+            tch_cuda::flash_attn_varlen
+        } else {
+            refkernels::varlen_attn
+        };
+        f(
+            q,
+            k,
+            v,
+            seqlens_q,
+            seqlens_k,
+            max_seqlen_q,
+            max_seqlen_k,
+            softmax_scale,
+            causal,
+        )
+    }
+
+    pub fn paged_attention_v1(
+        out: &mut Tensor,
+        query: &Tensor,
+        key_cache: &Tensor,
+        value_cache: &Tensor,
+        num_kv_heads: usize,
+        scale: f32,
+        block_tables: &Tensor,
+        context_lens: &Tensor,
+        block_size: usize,
+        max_context_len: usize,
+        alibi_slopes: Option<&Tensor>,
+    ) {
+        let f = if query.device().is_cuda() {
+            tch_cuda::paged_attention_v1
+        } else {
+            refkernels::paged_attention_v1
+        };
+        f(
+            out,
+            query,
+            key_cache,
+            value_cache,
+            num_kv_heads,
+            scale,
+            block_tables,
+            context_lens,
+            block_size,
+            max_context_len,
+            alibi_slopes,
+        )
+    }
+
+    pub fn copy_blocks(
+        key_caches: &mut Vec<Tensor>,
+        value_caches: &mut Vec<Tensor>,
+        block_mapping: &HashMap<usize, Vec<usize>>,
+    ) {
+        if !key_caches.is_empty() && key_caches[0].device().is_cuda() {
+            tch_cuda::copy_blocks(key_caches, value_caches, block_mapping)
+        } else {
+            refkernels::copy_blocks(key_caches, value_caches, block_mapping)
+        }
+    }
 }
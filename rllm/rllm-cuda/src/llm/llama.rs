@@ -6,12 +6,12 @@ use super::{
     paged::BatchInfo,
     varlen_attn, RmsNorm, RotaryEmbedding,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use serde::Deserialize;
 use std::rc::Rc;
 use tch::{
     nn::{self, Module, Path},
-    Tensor,
+    Device, Tensor,
 };
 
 use super::tmodel::TModelInner;
@@ -29,6 +29,8 @@ pub struct LlamaConfig {
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
     pub torch_dtype: String,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
 }
 
 fn default_rope() -> f32 {
@@ -52,10 +54,12 @@ impl RllmModelConfig for LlamaConfig {
             num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
             layer_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
+            tie_word_embeddings: self.tie_word_embeddings,
             head_dim,
             rotary_dim: head_dim,
             dtype: ModelConfig::dtype_from_str(common.dtype, &self.torch_dtype),
             device: common.device,
+            layer_devices: Vec::new(),
             profile_step_no: 0,
             cache: Default::default(),
         }
@@ -157,6 +161,7 @@ struct Block {
     attn: CausalSelfAttention,
     rms_2: RmsNorm,
     mlp: Mlp,
+    device: Device,
 }
 
 impl Block {
@@ -175,7 +180,12 @@ impl Block {
         x
     }
 
-    fn load(mut vb: Path, rotary: &RotaryEmbedding, cfg: &Rc<ModelConfig>) -> Result<Self> {
+    fn load(
+        mut vb: Path,
+        rotary: &RotaryEmbedding,
+        cfg: &Rc<ModelConfig>,
+        device: Device,
+    ) -> Result<Self> {
         let attn = CausalSelfAttention::load(&vb / "self_attn", rotary, cfg)?;
         let mlp = Mlp::load(&vb / "mlp", cfg)?;
         let rms_1 = RmsNorm::from_cfg(&vb / "input_layernorm", cfg);
@@ -187,6 +197,7 @@ impl Block {
             attn,
             rms_2,
             mlp,
+            device,
         })
     }
 }
@@ -200,13 +211,32 @@ pub struct Llama {
 
 impl TModelInner for Llama {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let main_device = self.wte.ws.device();
+        let mut device = main_device;
         let mut x = self.wte.forward(&batch_info.tokens).unsqueeze(0);
         for (block_idx, block) in self.blocks.iter().enumerate() {
+            if block.device != device {
+                device = block.device;
+                x = x.to_device(device);
+                batch_info.move_to(device);
+            }
             x = block.forward(&x, batch_info, block_idx);
+            batch_info.record_checkpoint(block_idx, &x);
         }
-        let x0 = self.ln_f.forward(&x);
+        if device != main_device {
+            x = x.to_device(main_device);
+            batch_info.move_to(main_device);
+        }
+        let x0 = self.ln_f.forward(&x).squeeze_dim(0);
         // println!("x: {}", x0);
-        let x = batch_info.extract_positions(&x0.squeeze_dim(0));
+        if let Some(idxs) = &batch_info.full_logit_idxs {
+            let hidden = x0.index_select(0, idxs);
+            batch_info.full_logits = Some(self.lm_head.forward(&hidden));
+        }
+        if let Some(idxs) = &batch_info.full_hidden_idxs {
+            batch_info.full_hidden = Some(x0.index_select(0, idxs));
+        }
+        let x = batch_info.extract_positions(&x0);
         let logits = self.lm_head.forward(&x);
         logits
     }
@@ -214,9 +244,17 @@ impl TModelInner for Llama {
 
 impl Llama {
     pub fn load(vs: Path, cfg: &Rc<ModelConfig>) -> Result<Self> {
-        let rotary = RotaryEmbedding::new(cfg);
+        Self::load_placed(vs, &[], cfg)
+    }
 
-        let lm_head = linear_no_bias(cfg.hidden_size, cfg.meta.vocab_size, &vs / "lm_head");
+    /// Load with layers placed as in cfg.layer_devices; weights of layers
+    /// not on cfg.device go to the store in `layer_stores` for their device.
+    pub fn load_placed(
+        vs: Path,
+        layer_stores: &[(Device, Path)],
+        cfg: &Rc<ModelConfig>,
+    ) -> Result<Self> {
+        let mut rotaries = vec![(cfg.device, RotaryEmbedding::new(cfg))];
 
         let wte = nn::embedding(
             &vs / "model" / "embed_tokens",
@@ -225,11 +263,40 @@ impl Llama {
             Default::default(),
         );
 
+        let lm_head = if cfg.tie_word_embeddings {
+            // shares the storage with wte, also after VarStore::set_kind()
+            nn::Linear {
+                ws: wte.ws.shallow_clone(),
+                bs: None,
+            }
+        } else {
+            linear_no_bias(cfg.hidden_size, cfg.meta.vocab_size, &vs / "lm_head")
+        };
+
         let ln_f = RmsNorm::from_cfg(&vs / "model" / "norm", cfg);
 
-        let blocks: Vec<_> = (0..cfg.num_hidden_layers)
-            .map(|i| Block::load(&vs / "model" / "layers" / i, &rotary, cfg).unwrap())
-            .collect();
+        let mut blocks = Vec::new();
+        for i in 0..cfg.num_hidden_layers {
+            let device = cfg.layer_device(i);
+            let root = if device == cfg.device {
+                &vs
+            } else {
+                match layer_stores.iter().find(|(d, _)| *d == device) {
+                    Some((_, p)) => p,
+                    None => bail!("no weight store for {device:?} (layer {i})"),
+                }
+            };
+            if !rotaries.iter().any(|(d, _)| *d == device) {
+                rotaries.push((device, RotaryEmbedding::new_on(cfg, device)));
+            }
+            let rotary = &rotaries.iter().find(|(d, _)| *d == device).unwrap().1;
+            blocks.push(Block::load(
+                root / "model" / "layers" / i,
+                rotary,
+                cfg,
+                device,
+            )?);
+        }
 
         Ok(Self {
             wte,
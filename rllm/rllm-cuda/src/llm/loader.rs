@@ -1,7 +1,7 @@
 use super::{
     config::ModelType,
     llama,
-    paged::{BatchInfoBuilder, BlockSpaceManager, CacheEngine},
+    paged::{replay_failure, BatchInfoBuilder, BlockSpaceManager, CacheEngine},
     phi,
     tmodel::TModel,
     util::{gpu_memory_size, gpu_peak_allocated_bytes, log_mem_stats, reset_mem_stats},
@@ -12,11 +12,17 @@ use rllm::{
     CacheSize, HashSet, LoaderArgs, Repo, RllmEngine,
 };
 use safetensors::Dtype;
-use std::{path::PathBuf, rc::Rc, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::Instant,
+};
 use tch::{nn::VarStore, Device, Kind, Tensor};
 
 use super::{
-    config::{CommonModelConfig, ModelConfig, RllmModelConfig},
+    config::{AttnLimits, CommonModelConfig, ModelConfig, RllmModelConfig},
     tmodel::{TModelInner, TchLoaderArgs},
     DType,
 };
@@ -46,21 +52,136 @@ fn read_tensor(s: &safetensors::SafeTensors, name: &str) -> Result<Tensor> {
     Ok(tensor)
 }
 
-fn load_model(
+/// Renames checkpoint tensors to the names used by the model, for fine-tunes
+/// exported with extra (`base_model.model.`) or missing (`model.`) prefixes.
+/// A rule `(from, to)` replaces the prefix `from` with `to`; the first matching rule wins.
+/// Names that the model already knows are never renamed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WeightNameMapper {
+    pub rules: Vec<(String, String)>,
+}
+
+impl WeightNameMapper {
+    /// Parse `from=to,from2=to2`; `to` can be empty to strip a prefix.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut rules = vec![];
+        for rule in spec.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+            match rule.split_once('=') {
+                Some((from, to)) => rules.push((from.to_string(), to.to_string())),
+                None => bail!("invalid name mapping {rule:?}; expecting from=to"),
+            }
+        }
+        Ok(Self { rules })
+    }
+
+    /// Find the prefix rule that makes the most checkpoint names match the model.
+    /// Returns no rules when the names mostly match already.
+    pub fn detect<'a>(
+        checkpoint: impl Iterator<Item = &'a str>,
+        model: &HashMap<String, Tensor>,
+    ) -> Self {
+        let mut unmatched = 0;
+        let mut counts = HashMap::<(String, String), usize>::default();
+        for name in checkpoint {
+            if model.contains_key(name) {
+                continue;
+            }
+            unmatched += 1;
+            for var in model.keys() {
+                let rule = if name.len() > var.len()
+                    && name.ends_with(var.as_str())
+                    && name[..name.len() - var.len()].ends_with('.')
+                {
+                    (name[..name.len() - var.len()].to_string(), String::new())
+                } else if var.len() > name.len()
+                    && var.ends_with(name)
+                    && var[..var.len() - name.len()].ends_with('.')
+                {
+                    (String::new(), var[..var.len() - name.len()].to_string())
+                } else {
+                    continue;
+                };
+                *counts.entry(rule).or_insert(0) += 1;
+            }
+        }
+        let best = counts
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)));
+        match best {
+            Some((rule, n)) if unmatched > 0 && 2 * n >= unmatched => Self { rules: vec![rule] },
+            _ => Self::default(),
+        }
+    }
+
+    pub fn map(&self, name: &str) -> String {
+        for (from, to) in &self.rules {
+            if let Some(rest) = name.strip_prefix(from.as_str()) {
+                return format!("{to}{rest}");
+            }
+        }
+        name.to_string()
+    }
+}
+
+impl std::fmt::Display for WeightNameMapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rules = self
+            .rules
+            .iter()
+            .map(|(from, to)| format!("{from}={to}"))
+            .collect::<Vec<_>>();
+        write!(f, "{}", rules.join(","))
+    }
+}
+
+/// Checkpoint tensors the model doesn't need, and that are fine to skip.
+fn is_optional_tensor(config: &ModelConfig, name: &str) -> bool {
+    name.ends_with(".inv_freq") || (config.tie_word_embeddings && name == "lm_head.weight")
+}
+
+/// Load weights from `filenames`; checkpoint names are mapped with `name_map`,
+/// or with a WeightNameMapper::detect()ed one if not given.
+/// Returns the model and the number of bytes in its weights.
+pub(super) fn load_model(
     rllm_config: &RllmConfig<TModel>,
     filenames: Vec<PathBuf>,
-) -> Result<Box<dyn TModelInner>> {
+    name_map: Option<WeightNameMapper>,
+) -> Result<(Box<dyn TModelInner>, usize)> {
+    let t0 = Instant::now();
     let mut vs = VarStore::new(rllm_config.model.device.clone());
+    // weights of layers placed on other devices (see ModelConfig::layer_devices)
+    let mut layer_stores = rllm_config.model.devices()[1..]
+        .iter()
+        .map(|d| (*d, VarStore::new(*d)))
+        .collect::<Vec<_>>();
 
     let rc_cfg = Rc::new(rllm_config.model.clone());
     let mut model: Box<dyn TModelInner> = match rllm_config.model.model_type {
-        ModelType::Llama => Box::new(llama::Llama::load(vs.root(), &rc_cfg).unwrap()),
+        ModelType::Llama => {
+            let roots = layer_stores
+                .iter()
+                .map(|(d, s)| (*d, s.root()))
+                .collect::<Vec<_>>();
+            Box::new(llama::Llama::load_placed(vs.root(), &roots, &rc_cfg)?)
+        }
         ModelType::Phi => Box::new(phi::MixFormerSequentialForCausalLM::new(&rc_cfg, vs.root())),
     };
 
     vs.set_kind(rllm_config.model.dtype);
+    for (_, s) in layer_stores.iter_mut() {
+        s.set_kind(rllm_config.model.dtype);
+    }
 
     let mut vars = vs.variables();
+    for (_, s) in &layer_stores {
+        vars.extend(s.variables());
+    }
+    let num_vars = vars.len();
+    log::debug!(
+        "load {}: built {num_vars} variables in {:?}",
+        rllm_config.meta.id,
+        t0.elapsed()
+    );
 
     let bar = indicatif::ProgressBar::new(vars.len() as u64);
     bar.set_style(
@@ -70,50 +191,107 @@ fn load_model(
         .unwrap(),
     );
 
+    let mut unexpected = vec![];
+    let mut mismatched = vec![];
+
+    let mut mmaps = vec![];
     for f in &filenames {
+        log::debug!("load {}: reading {}", rllm_config.meta.id, f.display());
         let fp = std::fs::File::open(f)?;
-        let content = unsafe { memmap2::MmapOptions::new().map(&fp)? };
-        let safetensors = safetensors::SafeTensors::deserialize(&content)?;
+        mmaps.push(unsafe { memmap2::MmapOptions::new().map(&fp)? });
+    }
+    let files = mmaps
+        .iter()
+        .map(|m| safetensors::SafeTensors::deserialize(m))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let name_map = match name_map {
+        Some(m) => m,
+        None => {
+            let names = files.iter().flat_map(|s| s.names().into_iter());
+            WeightNameMapper::detect(names.map(|n| n.as_str()), &vars)
+        }
+    };
+    if name_map.rules.len() > 0 {
+        log::info!(
+            "load {}: mapping checkpoint names with {name_map}",
+            rllm_config.meta.id
+        );
+    }
 
+    for safetensors in &files {
         for vname in safetensors.names() {
-            let target_name = vname.to_string();
+            let target_name = if vars.contains_key(vname) {
+                vname.to_string()
+            } else {
+                name_map.map(vname)
+            };
             if !vars.contains_key(&target_name) {
-                if vname.ends_with(".inv_freq") {
-                    // OK
-                } else {
+                if !is_optional_tensor(&rllm_config.model, vname) {
                     log::warn!("variable {} not found in the model", target_name);
+                    unexpected.push(target_name);
                 }
                 continue;
             }
 
             // Using from_blob here instead of from_data_size avoids some unnecessary copy.
-            let src_tensor = read_tensor(&safetensors, vname)?;
+            let src_tensor = read_tensor(safetensors, vname)?;
             let mut var = vars.remove(&target_name).unwrap();
-            assert!(var.size() == src_tensor.size());
+            if var.size() != src_tensor.size() {
+                mismatched.push(format!(
+                    "{target_name}: {:?} in checkpoint, {:?} in model",
+                    src_tensor.size(),
+                    var.size()
+                ));
+                continue;
+            }
             // println!("copying to {var:?} from {src_tensor:?}");
             var.f_copy_(&src_tensor)?;
 
             bar.inc(1);
-            if bar.is_hidden() {
-                eprint!(".");
-            }
         }
     }
 
-    if vars.len() > 0 {
-        bail!("{} variables not found in the model: {vars:?}", vars.len());
+    if vars.len() > 0 || mismatched.len() > 0 {
+        let mut missing = vars.into_keys().collect::<Vec<_>>();
+        missing.sort();
+        let mut msg = format!("failed to load {}:", rllm_config.meta.id);
+        if missing.len() > 0 {
+            msg += &format!(
+                "\n{} tensors missing in checkpoint: {missing:?}",
+                missing.len()
+            );
+            if missing.iter().any(|n| n == "lm_head.weight") {
+                msg += "\n(lm_head.weight may be tied to the embeddings; see tie_word_embeddings)";
+            }
+        }
+        if mismatched.len() > 0 {
+            msg += &format!("\nshape mismatch: {mismatched:?}");
+        }
+        if unexpected.len() > 0 {
+            msg += &format!("\nunused tensors in checkpoint: {unexpected:?}");
+        }
+        bail!("{msg}");
     }
 
-    if bar.is_hidden() {
-        eprintln!(" done");
-    }
     bar.finish();
 
-    log::info!("model loaded");
+    log::info!(
+        "load {}: {num_vars} tensors from {} files in {:?}",
+        rllm_config.meta.id,
+        filenames.len(),
+        t0.elapsed()
+    );
 
     model.finalize();
 
-    Ok(model)
+    let weight_bytes = std::iter::once(&vs)
+        .chain(layer_stores.iter().map(|(_, s)| s))
+        .flat_map(|s| s.variables().into_values())
+        .map(|t| t.numel() * t.kind().elt_size_in_bytes())
+        .sum();
+
+    Ok((model, weight_bytes))
 }
 
 fn model_filenames(repo: &Repo) -> Result<Vec<PathBuf>> {
@@ -158,19 +336,33 @@ pub(super) fn load_rllm_engine(
 
     let rllm_config = RllmEngine::<TModel>::build_config(&args, &mut model_args)?;
 
+    let t0 = Instant::now();
     let filenames = model_filenames(&repo)?;
-    log::info!("building the model");
+    log::info!(
+        "fetch {}: {} weight files in {:?}",
+        args.model_id,
+        filenames.len(),
+        t0.elapsed()
+    );
 
     let _ = Tensor::zeros(&[1], (rllm_config.model.dtype, device));
     reset_mem_stats(device);
     log_mem_stats("initial", device);
 
-    let model = load_model(&rllm_config, filenames)?;
+    let name_map = match &args.name_map {
+        Some(spec) => Some(WeightNameMapper::parse(spec)?),
+        None => None,
+    };
+    let (model, weight_bytes) = load_model(&rllm_config, filenames, name_map)?;
 
     log_mem_stats("model fully loaded", device);
 
     let rllm_config = Arc::new(rllm_config);
     let cache_size = profile_model(rllm_config.clone(), &model);
+    rllm_config
+        .model
+        .cache
+        .check_num_gpu_blocks(cache_size.gpu)?;
     let cache_engine = CacheEngine::new(rllm_config.clone(), &cache_size);
 
     let block_mgr = BlockSpaceManager::new(
@@ -180,41 +372,76 @@ pub(super) fn load_rllm_engine(
         &rllm_config,
     );
     let seq_mgr = Arc::new(block_mgr.build_seq_mgr());
-    let tmodel = TModel::new(rllm_config.clone(), cache_engine, seq_mgr, model);
+    let mut tmodel = TModel::new(rllm_config.clone(), cache_engine, seq_mgr, model);
+    tmodel.weight_bytes = weight_bytes;
 
     RllmEngine::build(args, tmodel, block_mgr, rllm_config)
 }
 
+/// Load the model of `args`, and replay the step dumped to `dir` (with the
+/// dump_failures setting) on it; see replay_failure().
+pub fn replay_failure_dump(
+    args: LoaderArgs,
+    mut model_args: TchLoaderArgs,
+    dir: &Path,
+) -> Result<()> {
+    let _no_grad = tch::no_grad_guard();
+    let repo = Repo::from(&args)?;
+    let rllm_config = RllmEngine::<TModel>::build_config(&args, &mut model_args)?;
+    let name_map = match &args.name_map {
+        Some(spec) => Some(WeightNameMapper::parse(spec)?),
+        None => None,
+    };
+    let (model, _) = load_model(&rllm_config, model_filenames(&repo)?, name_map)?;
+    replay_failure(&rllm_config, dir, Some(model.as_ref()))
+}
+
 fn profile_model(config: Arc<RllmConfig<TModel>>, model: &Box<dyn TModelInner>) -> CacheSize {
-    let device = config.model.device.clone();
-    let gpu_mem = gpu_memory_size(device);
+    let devices = config.model.devices();
 
-    let gpu_cache_size = if gpu_mem > 0 {
+    if devices.iter().any(|d| gpu_memory_size(*d) > 0) {
         let mut info = BatchInfoBuilder::new(config.clone()).profile_run();
-        reset_mem_stats(device);
-        log_mem_stats("before model profile", device);
+        for d in &devices {
+            reset_mem_stats(*d);
+            log_mem_stats("before model profile", *d);
+        }
         let _logits = model.forward(&mut info);
-        log_mem_stats("after model profile", device);
+        for d in &devices {
+            log_mem_stats("after model profile", *d);
+        }
+    }
 
-        let frac = config.model.cache.gpu_memory_utilization;
-        let peak = gpu_peak_allocated_bytes(device) as isize;
-        let left = (gpu_mem as f64 * frac) as isize - peak;
-        if left < 0 {
-            panic!("not enough GPU memory for the cache: {gpu_mem} * {frac} < {peak}");
+    // every layer has the same number of blocks; the device with least room decides
+    let mut num_gpu_blocks = usize::MAX;
+    for device in devices {
+        let block_size = CacheEngine::get_cache_block_size_on(&config, device);
+        if block_size == 0 {
+            continue;
         }
-        left as usize
-    } else {
-        512 << 20 // 512MiB
-    };
+        let gpu_mem = gpu_memory_size(device);
+        let budget = if gpu_mem > 0 {
+            let frac = config.model.cache.gpu_memory_utilization;
+            let peak = gpu_peak_allocated_bytes(device) as isize;
+            let left = (gpu_mem as f64 * frac) as isize - peak;
+            if left < 0 {
+                panic!("not enough GPU memory for the cache on {device:?}: {gpu_mem} * {frac} < {peak}");
+            }
+            left as usize
+        } else {
+            512 << 20 // 512MiB
+        };
+        num_gpu_blocks = std::cmp::min(num_gpu_blocks, budget / block_size);
+    }
+
+    let elt_size = CacheEngine::get_cache_block_size(&config);
+    let gpu_cache_size = num_gpu_blocks * elt_size;
 
     let max_cpu = 2 << 30; // 2GiB
     let cpu_cache_size = std::cmp::min(max_cpu, gpu_cache_size);
 
-    let elt_size = CacheEngine::get_cache_block_size(&config);
-
     let r = CacheSize {
         cpu: cpu_cache_size / elt_size,
-        gpu: gpu_cache_size / elt_size,
+        gpu: num_gpu_blocks,
     };
 
     let token_kv_size = elt_size / config.model.cache.block_size;
@@ -252,6 +479,14 @@ pub(super) fn load_model_config(
             let tok = aicirt::bintokens::find_tokenizer(&args.tokenizer)?;
             v.meta.tok_vocab_size = tok.tokrx_info().vocab_size as usize;
             v.profile_step_no = model_args.profile_step_no;
+            v.cache.decode_pad_buckets = model_args.decode_pad_buckets.clone();
+            v.cache.attn_backend = model_args.attn_backend;
+            v.cache.attn_limits = AttnLimits::detect(&v);
+            v.layer_devices = ModelConfig::parse_layer_split(
+                &model_args.layer_split,
+                v.num_hidden_layers,
+                gpu_memory_size,
+            )?;
             Ok(v)
         }
         None => bail!("failed to load model config:\n{}", err),
@@ -1,3 +1,5 @@
+pub mod attn;
+pub mod checkpoints;
 pub mod config;
 pub mod kernels;
 pub mod llama;
@@ -8,55 +10,111 @@ pub mod tmodel;
 pub mod util;
 pub mod paged;
 
+#[cfg(all(test, any(not(feature = "cuda"), feature = "cpu-fallback")))]
+mod sim;
+#[cfg(all(test, any(not(feature = "cuda"), feature = "cpu-fallback")))]
+mod tests;
+
 use self::config::ModelConfig;
 use paged::BatchInfo;
-use std::rc::Rc;
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
 use tch::{
     nn::{self, Module, Path},
-    IndexOp, Tensor,
+    Tensor,
 };
-use util::{check_all_close, check_all_close_attn};
+use util::check_all_close;
 
 // note that this doesn't work for phi-2 - it seems particularly numerically unstable
 const CHECK: bool = false;
 
 pub type DType = tch::Kind;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RotaryEmbedding {
     config: Rc<ModelConfig>,
-    cos_sin: Tensor,
+    cos_sin: Rc<Tensor>,
 }
 
-impl Clone for RotaryEmbedding {
-    fn clone(&self) -> Self {
-        Self {
-            config: self.config.clone(),
-            cos_sin: self.cos_sin.shallow_clone(),
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RotaryKey {
+    rotary_dim: usize,
+    max_position: usize,
+    rope_theta: u32, // f32 bits
+    dtype: DType,
+    device: tch::Device,
+}
+
+thread_local! {
+    /// cos/sin tables shared by all RotaryEmbeddings (layers and models) with the same
+    /// parameters. The cache doesn't keep them alive: a table is freed with the last model
+    /// using it (when it's unloaded, evicted or replaced), and its entry dropped on the
+    /// next lookup.
+    static ROTARY_CACHE: RefCell<Vec<(RotaryKey, Weak<Tensor>)>> = RefCell::new(Vec::new());
+}
+
+/// Bytes held by the rotary tables still in use.
+pub fn rotary_cache_bytes() -> usize {
+    ROTARY_CACHE.with(|cache| {
+        cache
+            .borrow()
+            .iter()
+            .filter_map(|(_, t)| t.upgrade())
+            .map(|t| t.numel() * t.kind().elt_size_in_bytes())
+            .sum()
+    })
 }
 
 impl RotaryEmbedding {
     pub fn new(config: &Rc<ModelConfig>) -> Self {
+        Self::new_on(config, config.device)
+    }
+
+    /// Tables for layers placed on `device` (see ModelConfig::layer_devices).
+    pub fn new_on(config: &Rc<ModelConfig>, device: tch::Device) -> Self {
+        let key = RotaryKey {
+            rotary_dim: config.rotary_dim,
+            max_position: config.meta.max_sequence_length,
+            rope_theta: config.rope_theta.to_bits(),
+            dtype: config.dtype,
+            device,
+        };
+        let cos_sin = ROTARY_CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            cache.retain(|(_, t)| t.strong_count() > 0);
+            let found = cache.iter().find(|(k, _)| *k == key);
+            match found.and_then(|(_, t)| t.upgrade()) {
+                Some(t) => t,
+                None => {
+                    let t = Rc::new(Self::cos_sin(config, device));
+                    cache.push((key, Rc::downgrade(&t)));
+                    t
+                }
+            }
+        });
+        Self {
+            config: config.clone(),
+            cos_sin,
+        }
+    }
+
+    fn cos_sin(config: &ModelConfig, device: tch::Device) -> Tensor {
         // pre-compute freqs_cis
         let rotary_dim = config.rotary_dim;
         let theta: Vec<_> = (0..rotary_dim)
             .step_by(2)
             .map(|i| 1f32 / config.rope_theta.powf(i as f32 / rotary_dim as f32))
             .collect();
-        let theta = Tensor::from_slice(theta.as_slice()).to(config.device);
+        let theta = Tensor::from_slice(theta.as_slice()).to(device);
         let len = config.meta.max_sequence_length as i64;
-        let idx_theta = Tensor::arange(len, (DType::Float, config.device))
+        let idx_theta = Tensor::arange(len, (DType::Float, device))
             .reshape(&[len, 1])
             .matmul(&theta.reshape(&[1, theta.numel() as i64]));
         let cos = idx_theta.cos().to_kind(config.dtype);
         let sin = idx_theta.sin().to_kind(config.dtype);
-        let cos_sin = Tensor::cat(&[&cos, &sin], -1).contiguous();
-        Self {
-            config: config.clone(),
-            cos_sin,
-        }
+        Tensor::cat(&[&cos, &sin], -1).contiguous()
     }
 
     pub fn forward(
@@ -165,156 +223,6 @@ fn save_attn(
     }
 }
 
-fn compute_varlen_attn(
-    config: &ModelConfig,
-    q: &Tensor,
-    batch_info: &mut BatchInfo,
-    block_idx: usize,
-) -> Tensor {
-    let (key_cache, value_cache) = batch_info.kv_cache.get(block_idx);
-
-    if q.size()[0] == 0 {
-        return Tensor::empty(&[0, config.hidden_size as i64], (q.kind(), q.device()));
-    }
-
-    // then, extend key/value and fill them from cache
-    let mut k = Tensor::empty(
-        &[
-            batch_info.gather_mapping.size()[0],
-            config.num_key_value_heads as i64,
-            config.head_dim as i64,
-        ],
-        (q.kind(), q.device()),
-    );
-
-    let mut v = k.empty_like();
-    kernels::gather_cached_kv(
-        &mut k,
-        &mut v,
-        &key_cache,
-        &value_cache,
-        &batch_info.gather_mapping,
-    );
-
-    if CHECK {
-        let mut kk = k.empty_like();
-        let mut vv = v.empty_like();
-
-        refkernels::gather_cached_kv(
-            &mut kk,
-            &mut vv,
-            &key_cache,
-            &value_cache,
-            &batch_info.gather_mapping,
-        );
-        check_all_close(&k, &kk, 1e-5);
-        check_all_close(&v, &vv, 1e-5);
-    }
-
-    let k = repeat_kv(config, k);
-    let v = repeat_kv(config, v);
-
-    let y = {
-        batch_info.log_tensor("q", &q);
-        batch_info.log_tensor("k", &k);
-        batch_info.log_tensor("v", &v);
-
-        // flash-attn expects (seq_len, nheads, head_dim)
-        let softmax_scale = 1f32 / (config.head_dim as f32).sqrt();
-
-        let causal = true;
-
-        let y = if config.dtype == DType::BFloat16 || config.dtype == DType::Half {
-            let y = kernels::varlen_attn(
-                &q,
-                &k,
-                &v,
-                &batch_info.seqlens_q,
-                &batch_info.seqlens_k,
-                batch_info.max_seqlen_q,
-                batch_info.max_seqlen_k,
-                softmax_scale,
-                causal,
-            );
-
-            if CHECK {
-                let y2 = refkernels::varlen_attn(
-                    &q,
-                    &k,
-                    &v,
-                    &batch_info.seqlens_q,
-                    &batch_info.seqlens_k,
-                    batch_info.max_seqlen_q,
-                    batch_info.max_seqlen_k,
-                    softmax_scale,
-                    causal,
-                );
-                check_all_close_attn(&y, &y2);
-            }
-
-            y
-        } else {
-            refkernels::varlen_attn(
-                &q,
-                &k,
-                &v,
-                &batch_info.seqlens_q,
-                &batch_info.seqlens_k,
-                batch_info.max_seqlen_q,
-                batch_info.max_seqlen_k,
-                softmax_scale,
-                causal,
-            )
-        };
-
-        y
-    };
-
-    batch_info.log_tensor("y", &v);
-
-    let y = y.reshape(&[-1, config.hidden_size as i64]);
-
-    y
-}
-
-#[cfg(feature = "cuda")]
-fn compute_paged_attn(
-    config: &ModelConfig,
-    q: &Tensor,
-    y: &Tensor,
-    batch_info: &mut BatchInfo,
-    block_idx: usize,
-) -> Tensor {
-    use tch_cuda::paged_attention_v1;
-
-    if q.size()[0] == 0 {
-        return y.shallow_clone();
-    }
-
-    let mut out = Tensor::empty_like(q);
-    let (key_cache, value_cache) = batch_info.kv_cache.get(block_idx);
-
-    let softmax_scale = 1f32 / (config.head_dim as f32).sqrt();
-
-    paged_attention_v1(
-        &mut out,
-        &q,
-        &key_cache,
-        &value_cache,
-        config.num_key_value_heads,
-        softmax_scale,
-        &batch_info.paged_block_tables,
-        &batch_info.paged_context_lens,
-        batch_info.paged_block_size,
-        batch_info.paged_max_context_len,
-        None,
-    );
-
-    let out = out.reshape(&[-1, config.hidden_size as i64]);
-
-    Tensor::cat(&[y, &out], 0)
-}
-
 pub fn varlen_attn(
     config: &ModelConfig,
     q: Tensor, // [num_tokens, num_heads, head_size]
@@ -331,26 +239,17 @@ pub fn varlen_attn(
 
     save_attn(config, &k, &v, batch_info, block_idx);
 
-    let y = compute_varlen_attn(
-        config,
-        &q.i((0..batch_info.q_multi, .., ..)),
-        batch_info,
-        block_idx,
-    );
-
-    #[cfg(not(feature = "cuda"))]
-    assert!(q.i((batch_info.q_multi.., .., ..)).numel() == 0);
-
-    #[cfg(feature = "cuda")]
-    let y = compute_paged_attn(
-        config,
-        &q.i((batch_info.q_multi.., .., ..)),
-        &y,
-        batch_info,
-        block_idx,
-    );
-
-    y
+    let kv_cache = batch_info.kv_cache.get(block_idx);
+    let backend = batch_info.attn_backend.backend();
+    if block_idx == 0 {
+        log::trace!(
+            "step {}: {} attention for {} entries",
+            batch_info.step_no,
+            backend.name(),
+            batch_info.batch_size()
+        );
+    }
+    backend.forward(config, batch_info, &q, &k, &v, &kv_cache)
 }
 
 // x is [seq_len, num_heads, head_dim]
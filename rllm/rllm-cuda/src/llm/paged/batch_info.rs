@@ -1,36 +1,63 @@
-use super::super::{kernels::to_offsets, tmodel::TModel};
+use super::super::{
+    attn::AttnBackendKind, checkpoints::ActivationLog, kernels::host_offsets, tmodel::TModel,
+};
 use super::cache_engine::CacheEngine;
+use super::staging::HostStaging;
 use super::BlockAllocator;
+use anyhow::{bail, Result};
 use rllm::{
-    config::RllmConfig, seq::SchedulingPhase, util::pad_to_multiple, HashMap, SchedulerOutputs,
+    config::{RequestKind, RllmConfig},
+    seq::SchedulingPhase,
+    util::pad_to_multiple,
+    EngineError, HashMap, SchedulerOutputs, SeqId,
 };
 use aicirt::api::Token;
 use std::{
     fmt::Debug,
+    ops::Range,
+    path::Path,
     sync::{Arc, Mutex},
 };
-use tch::{IndexOp, Tensor};
+use tch::{Device, IndexOp, Tensor};
 
 pub trait CacheIface {
     fn get(&self, layer_no: usize) -> (Tensor, Tensor);
+    /// Total number of KV slots (blocks * block_size) in each layer.
+    fn num_slots(&self) -> usize;
 }
 
 pub struct BatchInfo {
-    pub tokens: Tensor,         // u32, [num_tokens]
+    pub tokens: Tensor,         // i32, [num_tokens]
     pub positions: Tensor,      // i64, [num_tokens]
-    pub seqlens_q: Tensor,      // u32, [batch_size + 1]; points to tokens/positions
-    pub seqlens_k: Tensor,      // u32, [batch_size + 1]; can go outside tokens/positions
-    pub gather_mapping: Tensor, // u32, [sum(context_len + prompt_len)]
-    pub slot_mapping: Tensor,   // u32, [num_tokens]
-    pub logit_idxs: Tensor,     // u32, [batch_size]
+    pub seqlens_q: Tensor,      // i32, [batch_size + 1]; points to tokens/positions
+    pub seqlens_k: Tensor,      // i32, [batch_size + 1]; can go outside tokens/positions
+    pub gather_mapping: Tensor, // i32, [sum(context_len + prompt_len)]
+    pub slot_mapping: Tensor,   // i64, [num_tokens]
+    pub logit_idxs: Tensor,     // i32, [batch_size]
     pub max_seqlen_q: usize,
     pub max_seqlen_k: usize,
     pub seq_id_to_idx: HashMap<usize, usize>, // seq_id -> index into seqlens_*
+    pub real_batch_size: usize,               // entries past this one are padding
 
     pub infer_log: Mutex<Vec<(String, Tensor)>>,
     pub step_no: usize,
+    pub trace: bool, // save tensors passed to log_tensor()
+    /// Outputs of the decoder layers, when set; see record_checkpoint().
+    pub checkpoints: Option<ActivationLog>,
+
+    /// For sequences that need more than the last output (score and embed
+    /// requests): seq_id -> rows of full_logits (score; the positions predicting
+    /// the continuation), or of full_hidden (embed; every query position).
+    pub full_rows: HashMap<usize, Range<usize>>,
+    pub full_logit_idxs: Option<Tensor>, // i64, [num_logit_rows]; points to tokens/positions
+    pub full_hidden_idxs: Option<Tensor>, // i64, [num_hidden_rows]; points to tokens/positions
+    /// Set by the model when the idxs are: logits at full_logit_idxs, and the
+    /// final hidden states (after the last norm) at full_hidden_idxs.
+    pub full_logits: Option<Tensor>,
+    pub full_hidden: Option<Tensor>,
 
     pub kv_cache: Box<dyn CacheIface>,
+    pub attn_backend: AttnBackendKind,
 
     // for paged attn
     pub paged_block_tables: Tensor, // [num_seqs, max_num_blocks_per_seq]
@@ -44,7 +71,7 @@ pub struct BatchInfo {
 
 impl BatchInfo {
     pub fn log_tensor(&self, key: &str, value: &Tensor) {
-        if false {
+        if self.trace {
             self.infer_log
                 .lock()
                 .unwrap()
@@ -52,6 +79,13 @@ impl BatchInfo {
         }
     }
 
+    /// Record the output `x` of layer `layer`, if checkpoints are on.
+    pub fn record_checkpoint(&mut self, layer: usize, x: &Tensor) {
+        if let Some(log) = &mut self.checkpoints {
+            log.record(layer, self.step_no, x);
+        }
+    }
+
     pub fn save_log(&self, filename: &str) {
         let mut lck = self.infer_log.lock().unwrap();
         if lck.len() == 0 {
@@ -66,15 +100,57 @@ impl BatchInfo {
         Tensor::write_safetensors(&tensors, filename).unwrap();
     }
 
+    /// Write the layout of the batch, and `extra` tensors, to a safetensors file.
+    pub fn save_summary(&self, filename: &Path, extra: &[(&str, &Tensor)]) -> Result<()> {
+        let tensors = [
+            ("tokens", &self.tokens),
+            ("positions", &self.positions),
+            ("seqlens_q", &self.seqlens_q),
+            ("seqlens_k", &self.seqlens_k),
+            ("logit_idxs", &self.logit_idxs),
+            ("paged_context_lens", &self.paged_context_lens),
+        ]
+        .iter()
+        .chain(extra.iter())
+        .map(|(k, v)| (k.to_string(), v.to_device(Device::Cpu)))
+        .collect::<Vec<_>>();
+        Tensor::write_safetensors(&tensors, filename)?;
+        Ok(())
+    }
+
     pub fn extract_positions(&self, x: &Tensor) -> Tensor {
         x.i((&self.logit_idxs, ..))
     }
+
+    /// Number of entries including padding.
+    pub fn batch_size(&self) -> usize {
+        self.logit_idxs.size()[0] as usize
+    }
+
+    /// Move the batch tensors to `device`, for layers placed there.
+    pub fn move_to(&mut self, device: Device) {
+        for t in [
+            &mut self.tokens,
+            &mut self.positions,
+            &mut self.seqlens_q,
+            &mut self.seqlens_k,
+            &mut self.gather_mapping,
+            &mut self.slot_mapping,
+            &mut self.logit_idxs,
+            &mut self.paged_block_tables,
+            &mut self.paged_context_lens,
+        ] {
+            *t = t.to_device(device);
+        }
+    }
 }
 
 impl Debug for BatchInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("BatchInfo")
             .field("step_no", &self.step_no)
+            .field("real_batch_size", &self.real_batch_size)
+            .field("attn_backend", &self.attn_backend)
             .field("tokens", &self.tokens)
             .field("positions", &self.positions)
             .field("seqlens_q", &self.seqlens_q)
@@ -94,14 +170,27 @@ impl Debug for BatchInfo {
 }
 
 pub struct BatchInfoBuilder {
-    entries: Vec<BatchEntry>,
-    config: Arc<RllmConfig<TModel>>,
+    pub(super) entries: Vec<BatchEntry>,
+    pub(super) config: Arc<RllmConfig<TModel>>,
+    /// Sequences that need more than the last output; see BatchInfo::full_rows.
+    pub(super) full_output: HashMap<usize, FullOutput>,
 }
 
-struct BatchEntry {
-    seq_id: usize,
-    query_pos_token: Vec<(usize, Token)>,
-    kv_slots: Vec<usize>,
+#[derive(Clone, Copy)]
+pub(super) enum FullOutput {
+    /// Logits predicting the last this many query tokens.
+    Logits(usize),
+    /// Final hidden states at every query position.
+    Hidden,
+}
+
+const PAD_SEQ_ID: usize = usize::MAX;
+const PAD_TOKEN: Token = 0;
+
+pub struct BatchEntry {
+    pub seq_id: usize,
+    pub query_pos_token: Vec<(usize, Token)>,
+    pub kv_slots: Vec<usize>,
 }
 
 impl BatchInfoBuilder {
@@ -109,6 +198,7 @@ impl BatchInfoBuilder {
         Self {
             entries: Vec::new(),
             config,
+            full_output: HashMap::default(),
         }
     }
 
@@ -118,6 +208,7 @@ impl BatchInfoBuilder {
         alloc: &BlockAllocator,
     ) -> &mut Self {
         assert!(sched_out.next_seq_groups.len() > 0);
+        let max_model_len = self.config.scheduler.max_model_len;
         for sg in sched_out.next_seq_groups.iter_mut() {
             for seq in sg.seqs.iter_mut() {
                 if seq.sched_phase != SchedulingPhase::Running {
@@ -127,21 +218,92 @@ impl BatchInfoBuilder {
                 let seq_len = seq.get_len();
                 let k_len = seq_len;
                 log::trace!("seq: {seq:?}");
-                let mut q_len = seq.get_len() - seq.num_kv_computed;
+                if seq_len == 0 || seq.num_kv_computed > seq_len {
+                    sched_out
+                        .failed_seqs
+                        .push(EngineError::InconsistentSequence {
+                            seq_id: seq.seq_id,
+                            detail: format!(
+                                "num_kv_computed={} but length is {}",
+                                seq.num_kv_computed, seq_len
+                            ),
+                        });
+                    continue;
+                }
+                let mut kv_slots = match alloc.get_block_idxes(seq.seq_id, k_len) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        sched_out.failed_seqs.push(e);
+                        continue;
+                    }
+                };
+
+                let mut q_len = seq_len - seq.num_kv_computed;
                 if q_len == 0 {
                     // just re-compute the last token
                     q_len = 1;
                 }
-                sg.usage.gen_tokens += 1;
-                sg.usage.prompt_tokens += q_len;
 
                 let off = k_len - q_len;
+                // position of token idx, as seen by the model
+                let mut pos_shift = 0;
+                if k_len > max_model_len {
+                    // only happens with kv_truncation_sink set; see SchedulerConfig
+                    // whole blocks are dropped, so that the paged block table stays aligned
+                    let bl_size = self.config.model.cache.block_size;
+                    let sink = self.config.scheduler.kv_truncation_sink.unwrap_or(0);
+                    let start = sink.div_ceil(bl_size) * bl_size;
+                    let end = start + (k_len - max_model_len).div_ceil(bl_size) * bl_size;
+                    if end + q_len > k_len {
+                        let window = k_len.saturating_sub(end);
+                        sched_out.failed_seqs.push(EngineError::InconsistentSequence {
+                            seq_id: seq.seq_id,
+                            detail: format!(
+                                "query of {q_len} tokens doesn't fit truncation window of {window}"
+                            ),
+                        });
+                        continue;
+                    }
+                    kv_slots.drain(start..end);
+                    pos_shift = end - start;
+                }
+
+                let limits = &self.config.model.cache.attn_limits;
+                if q_len > limits.max_seqlen_q || kv_slots.len() > limits.max_seqlen_k {
+                    sched_out.failed_seqs.push(EngineError::OverLimit {
+                        seq_id: seq.seq_id,
+                        detail: format!(
+                            "q_len={} (max {}), k_len={} (max {})",
+                            q_len,
+                            limits.max_seqlen_q,
+                            kv_slots.len(),
+                            limits.max_seqlen_k
+                        ),
+                    });
+                    continue;
+                }
+
+                match sg.sampling_params.kind {
+                    RequestKind::Generate => sg.usage.gen_tokens += 1,
+                    RequestKind::Score { continuation_len } => {
+                        sg.usage.scored_tokens += continuation_len;
+                        let full = FullOutput::Logits(continuation_len);
+                        self.full_output.insert(seq.seq_id.to_num(), full);
+                    }
+                    RequestKind::Embed { .. } => {
+                        sg.usage.embedded_tokens += q_len;
+                        let full = FullOutput::Hidden;
+                        self.full_output.insert(seq.seq_id.to_num(), full);
+                    }
+                }
+                sg.usage.prompt_tokens += q_len;
+
                 self.entries.push(BatchEntry {
                     seq_id: seq.seq_id.to_num(),
                     query_pos_token: (off..off + q_len)
-                        .map(|idx| (idx, seq.get_token(idx)))
+                        .map(|idx| (idx - pos_shift, seq.get_token(idx)))
                         .collect(),
-                    kv_slots: alloc.get_block_idxes(seq.seq_id, k_len),
+                    kv_slots,
                 });
 
                 seq.sync_computed_kv();
@@ -151,6 +313,44 @@ impl BatchInfoBuilder {
         self
     }
 
+    /// Pad the single-token (decode) entries up to the next configured bucket size.
+    /// Padding entries attend only to `scratch_slot`, and get no logits.
+    /// The batch is left as is if the padding wouldn't fit max_num_seqs or
+    /// config.model.cache.attn_limits; this is run after split().
+    pub fn pad_decode(&mut self, scratch_slot: usize) -> &mut Self {
+        let num_single = self
+            .entries
+            .iter()
+            .filter(|e| e.query_pos_token.len() == 1)
+            .count();
+        if num_single == 0 {
+            return self;
+        }
+        let num_pad = self.config.model.cache.decode_pad_size(num_single) - num_single;
+        if num_pad == 0 {
+            return self;
+        }
+        let pad = || BatchEntry {
+            seq_id: PAD_SEQ_ID,
+            query_pos_token: vec![(0, PAD_TOKEN)],
+            kv_slots: vec![scratch_slot],
+        };
+        let limits = &self.config.model.cache.attn_limits;
+        let num_tokens: usize = self.entries.iter().map(|e| e.query_pos_token.len()).sum();
+        let num_gather: usize = self.entries.iter().map(|e| self.gather_len(e)).sum();
+        let fits = self.entries.len() + num_pad <= self.config.scheduler.max_num_seqs
+            && num_tokens + num_pad <= limits.max_batch_tokens
+            && num_gather + num_pad * self.gather_len(&pad()) <= limits.max_gather_tokens;
+        if !fits {
+            log::debug!("not padding {num_single} decode entries; {num_pad} more don't fit");
+            return self;
+        }
+        for _ in 0..num_pad {
+            self.entries.push(pad());
+        }
+        self
+    }
+
     pub fn profile_run(&mut self) -> BatchInfo {
         let sch_cfg = &self.config.clone().scheduler;
         let seq_len = sch_cfg.max_model_len;
@@ -188,25 +388,139 @@ impl BatchInfoBuilder {
     }
 
     fn fake_finish(&mut self) -> BatchInfo {
-        let (k, v) = CacheEngine::alloc_gpu_cache_layer(&self.config, 1);
-        let kv_cache = Box::new(FakeKVCache { k, v });
-        self.finish(0, kv_cache)
+        let kv_cache = Box::new(FakeKVCache::new(&self.config, 1));
+        self.finish(0, kv_cache).unwrap()
+    }
+
+    /// Number of KV entries `e` adds to the varlen gather.
+    fn gather_len(&self, e: &BatchEntry) -> usize {
+        let cache = &self.config.model.cache;
+        let paged = match cache.attn_backend {
+            Some(b) => b == AttnBackendKind::Paged,
+            None => cache.paged_attn_kernel_v > 0,
+        };
+        if paged && e.query_pos_token.len() == 1 {
+            0
+        } else {
+            e.kv_slots.len()
+        }
+    }
+
+    /// Split into micro-batches that each fit config.model.cache.attn_limits,
+    /// to be run one after another in the same step.
+    /// Entries are never split, and keep their order.
+    pub fn split(mut self) -> Vec<BatchInfoBuilder> {
+        let limits = self.config.model.cache.attn_limits.clone();
+        let mut res = Vec::new();
+        let mut curr = BatchInfoBuilder::new(self.config.clone());
+        let (mut num_tokens, mut num_gather) = (0, 0);
+        for e in std::mem::take(&mut self.entries) {
+            let q = e.query_pos_token.len();
+            let g = self.gather_len(&e);
+            if !curr.is_empty()
+                && (num_tokens + q > limits.max_batch_tokens
+                    || num_gather + g > limits.max_gather_tokens)
+            {
+                res.push(std::mem::replace(
+                    &mut curr,
+                    BatchInfoBuilder::new(self.config.clone()),
+                ));
+                num_tokens = 0;
+                num_gather = 0;
+            }
+            num_tokens += q;
+            num_gather += g;
+            curr.entries.push(e);
+        }
+        if !curr.is_empty() || res.is_empty() {
+            res.push(curr);
+        }
+        for b in res.iter_mut() {
+            b.full_output = self.full_output.clone();
+        }
+        res
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Why finish() would reject `e`, if it would.
+    fn entry_error(&self, e: &BatchEntry, num_slots: usize) -> Option<EngineError> {
+        let max_seq = self.config.scheduler.max_model_len;
+        let detail = if let Some(slot) = e.kv_slots.iter().find(|s| **s >= num_slots) {
+            format!("kv slot {slot} out of range (cache has {num_slots} slots)")
+        } else if let Some((tpos, _)) = e.query_pos_token.iter().find(|(p, _)| *p >= max_seq) {
+            format!("position {tpos} exceeds max_model_len {max_seq}")
+        } else {
+            return None;
+        };
+        Some(EngineError::InconsistentSequence {
+            seq_id: SeqId(e.seq_id),
+            detail,
+        })
     }
 
-    pub fn finish(&mut self, step_no: usize, kv_cache: Box<dyn CacheIface>) -> BatchInfo {
+    /// Drop the entries finish() would reject, and report their sequences in
+    /// `failed`, so that the rest of the batch still runs.
+    pub fn drop_invalid(&mut self, num_slots: usize, failed: &mut Vec<EngineError>) -> &mut Self {
+        let entries = std::mem::take(&mut self.entries);
+        for e in entries {
+            match self.entry_error(&e, num_slots) {
+                Some(err) => failed.push(err),
+                None => self.entries.push(e),
+            }
+        }
+        self
+    }
+
+    pub fn push_entry(&mut self, entry: BatchEntry) -> &mut Self {
+        self.entries.push(entry);
+        self
+    }
+
+    pub fn finish(&mut self, step_no: usize, kv_cache: Box<dyn CacheIface>) -> Result<BatchInfo> {
+        let mut staging = HostStaging::unpinned(self.config.model.device);
+        self.finish_staged(step_no, kv_cache, &mut staging)
+    }
+
+    /// finish(), uploading the batch tensors through `staging`.
+    pub fn finish_staged(
+        &mut self,
+        step_no: usize,
+        kv_cache: Box<dyn CacheIface>,
+        staging: &mut HostStaging,
+    ) -> Result<BatchInfo> {
+        if self.entries.is_empty() {
+            bail!("empty batch");
+        }
+
         let mut positions: Vec<i64> = Vec::new();
         let mut tokens: Vec<i32> = Vec::new();
         let mut logit_idxs: Vec<i32> = Vec::new();
         let mut seqlens_q: Vec<usize> = Vec::new();
         let mut seqlens_k: Vec<usize> = Vec::new();
         let mut gather_mapping: Vec<i32> = Vec::new();
-        let mut slot_mapping: Vec<i32> = Vec::new();
+        // reshape_and_cache() takes i64 slots; gather and block tables are i32,
+        // see CacheConfig::check_num_gpu_blocks()
+        let mut slot_mapping: Vec<i64> = Vec::new();
         let mut seq_id_to_idx: HashMap<usize, usize> = HashMap::default();
 
         let mut paged_block_tables: Vec<Vec<i32>> = Vec::new();
         let mut paged_context_lens: Vec<i32> = Vec::new();
 
-        let num_multitoken = if self.config.model.cache.paged_attn_kernel_v > 0 {
+        let mut full_rows: HashMap<usize, Range<usize>> = HashMap::default();
+        let mut full_logit_idxs: Vec<i64> = Vec::new();
+        let mut full_hidden_idxs: Vec<i64> = Vec::new();
+
+        let num_single = self
+            .entries
+            .iter()
+            .filter(|e| e.query_pos_token.len() == 1)
+            .count();
+        let attn_backend = AttnBackendKind::select(&self.config.model, num_single);
+
+        let num_multitoken = if attn_backend == AttnBackendKind::Paged {
             // sort single-token entries to the back
             let (single, multi) = std::mem::take(&mut self.entries)
                 .into_iter()
@@ -221,19 +535,42 @@ impl BatchInfoBuilder {
 
         let mut first_single_token = 0;
 
-        let max_seq = self.config.scheduler.max_model_len;
+        let num_slots = kv_cache.num_slots();
         let mut idx = 0;
+        let mut real_batch_size = 0;
         for e in &self.entries {
-            seq_id_to_idx.insert(e.seq_id, idx);
+            if e.seq_id != PAD_SEQ_ID {
+                seq_id_to_idx.insert(e.seq_id, idx);
+                real_batch_size = idx + 1;
+            }
+            // drop_invalid() removes these before the batch is built
+            if let Some(err) = self.entry_error(e, num_slots) {
+                return Err(err.into());
+            }
             let query = &e.query_pos_token;
             let off = e.kv_slots.len() - query.len();
             for (qidx, (tpos, token)) in query.iter().enumerate() {
-                assert!(*tpos < max_seq);
                 positions.push(*tpos as i64);
                 tokens.push(*token as i32);
-                slot_mapping.push(e.kv_slots[off + qidx] as i32);
+                slot_mapping.push(e.kv_slots[off + qidx] as i64);
             }
             logit_idxs.push((tokens.len() - 1) as i32);
+            // the logits at position i predict token i + 1
+            let full = match self.full_output.get(&e.seq_id) {
+                Some(FullOutput::Logits(n)) => {
+                    let n = std::cmp::min(*n, query.len() - 1);
+                    Some((&mut full_logit_idxs, tokens.len() - n - 1..tokens.len() - 1))
+                }
+                Some(FullOutput::Hidden) => Some((
+                    &mut full_hidden_idxs,
+                    tokens.len() - query.len()..tokens.len(),
+                )),
+                None => None,
+            };
+            if let Some((idxs, rows)) = full {
+                full_rows.insert(e.seq_id, idxs.len()..idxs.len() + rows.len());
+                idxs.extend(rows.map(|i| i as i64));
+            }
             if idx < num_multitoken {
                 for slot in e.kv_slots.iter() {
                     gather_mapping.push(*slot as i32);
@@ -259,18 +596,30 @@ impl BatchInfoBuilder {
             idx += 1;
         }
 
-        assert!(seqlens_q.len() + paged_context_lens.len() > 0);
+        let limits = &self.config.model.cache.attn_limits;
+        if tokens.len() > limits.max_batch_tokens || gather_mapping.len() > limits.max_gather_tokens
+        {
+            bail!(
+                "batch over kernel limits: {} tokens (max {}), {} gathered (max {}); split() it first",
+                tokens.len(),
+                limits.max_batch_tokens,
+                gather_mapping.len(),
+                limits.max_gather_tokens
+            );
+        }
 
-        let device = self.config.model.device;
-        let (max_seqlen_q, seqlens_q) = to_offsets(seqlens_q.into_iter(), device);
-        let (max_seqlen_k, seqlens_k) = to_offsets(seqlens_k.into_iter(), device);
+        let (max_seqlen_q, seqlens_q) = host_offsets(&seqlens_q);
+        let (max_seqlen_k, seqlens_k) = host_offsets(&seqlens_k);
 
+        staging.begin();
+        let seqlens_q = staging.upload(&seqlens_q);
+        let seqlens_k = staging.upload(&seqlens_k);
         // TODO positions, tokens should be padded to 8? see worker.py, search for multiple_of=8
-        let positions = Tensor::from_slice(positions.as_slice()).to(device);
-        let tokens = Tensor::from_slice(tokens.as_slice()).to(device);
-        let slot_mapping = Tensor::from_slice(slot_mapping.as_slice()).to(device);
-        let gather_mapping = Tensor::from_slice(gather_mapping.as_slice()).to(device);
-        let logit_idxs = Tensor::from_slice(logit_idxs.as_slice()).to(device);
+        let positions = staging.upload(&positions);
+        let tokens = staging.upload(&tokens);
+        let slot_mapping = staging.upload(&slot_mapping);
+        let gather_mapping = staging.upload(&gather_mapping);
+        let logit_idxs = staging.upload(&logit_idxs);
 
         let num_paged = paged_context_lens.len() as i64;
         let paged_max_context_len = *paged_context_lens.iter().max().unwrap_or(&0) as usize;
@@ -286,12 +635,22 @@ impl BatchInfoBuilder {
                 v.into_iter()
             })
             .collect::<Vec<_>>();
-        let paged_block_tables = Tensor::from_slice(&flat_block_tables)
-            .to(device)
+        let paged_block_tables = staging
+            .upload(&flat_block_tables)
             .reshape(&[num_paged, paged_block_tables_max_len as i64]);
-        let paged_context_lens = Tensor::from_slice(paged_context_lens.as_slice()).to(device);
+        let paged_context_lens = staging.upload(&paged_context_lens);
+        let mut upload_nonempty = |idxs: Vec<i64>| {
+            if idxs.is_empty() {
+                None
+            } else {
+                Some(staging.upload(&idxs))
+            }
+        };
+        let full_logit_idxs = upload_nonempty(full_logit_idxs);
+        let full_hidden_idxs = upload_nonempty(full_hidden_idxs);
+        staging.end();
 
-        BatchInfo {
+        Ok(BatchInfo {
             tokens,
             positions,
             seqlens_q,
@@ -304,24 +663,66 @@ impl BatchInfoBuilder {
             max_seqlen_q,
             max_seqlen_k,
             kv_cache,
+            attn_backend,
             seq_id_to_idx,
+            real_batch_size,
             infer_log: Mutex::new(Vec::new()),
             step_no,
+            trace: false,
+            checkpoints: None,
+            full_rows,
+            full_logit_idxs,
+            full_hidden_idxs,
+            full_logits: None,
+            full_hidden: None,
             paged_block_size: self.config.model.cache.block_size,
             paged_max_context_len,
             paged_block_tables,
             paged_context_lens,
-        }
+        })
     }
 }
 
-struct FakeKVCache {
-    k: Tensor,
-    v: Tensor,
+/// A single cache layer per device, shared by all layers placed there.
+pub(super) struct FakeKVCache {
+    per_device: Vec<(Device, Tensor, Tensor)>,
+    layer_devices: Vec<Device>,
+}
+
+impl FakeKVCache {
+    pub(super) fn new(config: &RllmConfig<TModel>, num_bl: i64) -> Self {
+        let per_device = config
+            .model
+            .devices()
+            .into_iter()
+            .map(|d| {
+                let (k, v) = CacheEngine::alloc_gpu_cache_layer(config, num_bl, d);
+                (d, k, v)
+            })
+            .collect();
+        FakeKVCache {
+            per_device,
+            layer_devices: config.model.layer_devices.clone(),
+        }
+    }
 }
 
 impl CacheIface for FakeKVCache {
-    fn get(&self, _layer_no: usize) -> (Tensor, Tensor) {
-        (self.k.shallow_clone(), self.v.shallow_clone())
+    fn get(&self, layer_no: usize) -> (Tensor, Tensor) {
+        let (_, k, v) = match self.layer_devices.get(layer_no) {
+            Some(d) => self.per_device.iter().find(|(d2, _, _)| d2 == d).unwrap(),
+            None => &self.per_device[0],
+        };
+        (k.shallow_clone(), v.shallow_clone())
+    }
+
+    fn num_slots(&self) -> usize {
+        num_slots(&self.per_device[0].1)
     }
 }
+
+/// Number of slots in a key cache tensor [num_blocks, num_heads, head_size/x, block_size, x]
+pub(super) fn num_slots(key_cache: &Tensor) -> usize {
+    let size = key_cache.size();
+    (size[0] * size[3]) as usize
+}
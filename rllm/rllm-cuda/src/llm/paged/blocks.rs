@@ -3,8 +3,8 @@ use super::cache_engine::CacheEngine;
 use rllm::{
     config::RllmConfig,
     seq::{SchedulingPhase, Sequence, SequenceGroup},
-    BlockLocation, CacheSize, HashMap, SchedulerOutputs, SeqId, SequenceManager,
-    TBlockSpaceManager,
+    BlockLocation, BlockManagerView, BlockPoolView, CacheSize, EngineError, HashMap,
+    SchedulerOutputs, SeqBlocksView, SeqId, SequenceManager, TBlockSpaceManager,
 };
 use std::{
     sync::{Arc, Mutex},
@@ -119,11 +119,68 @@ impl BlockAllocatorInner {
         }
     }
 
-    fn get_block_idx(&self, seq: SeqId, position: usize) -> usize {
-        let blocks = self.seq_blocks.get(&seq).unwrap();
+    fn view(&self) -> BlockPoolView {
+        let all_blocks = &self.alloc.all_blocks;
+        let free = self.alloc.free_list.len();
+        let mut r = BlockPoolView {
+            total: all_blocks.len(),
+            free,
+            used: all_blocks.len() - free,
+            shared: all_blocks.iter().filter(|b| b.ref_count > 1).count(),
+            ..BlockPoolView::default()
+        };
+        let mut run = 0;
+        for b in all_blocks.iter() {
+            if b.ref_count == 0 {
+                run += 1;
+                if run == 1 {
+                    r.free_runs += 1;
+                }
+                r.largest_free_run = std::cmp::max(r.largest_free_run, run);
+            } else {
+                run = 0;
+            }
+        }
+        r
+    }
+
+    fn seq_views(&self, location: BlockLocation, dst: &mut Vec<SeqBlocksView>) {
+        for (seq_id, blocks) in self.seq_blocks.iter() {
+            dst.push(SeqBlocksView {
+                seq_id: seq_id.to_num(),
+                location,
+                blocks: blocks
+                    .iter()
+                    .map(|b| (b.block_idx, self.alloc.all_blocks[b.block_idx].ref_count))
+                    .collect(),
+            });
+        }
+    }
+
+    fn get_block_idxes(&self, seq: SeqId, len: usize) -> Result<Vec<usize>, EngineError> {
+        let blocks = match self.seq_blocks.get(&seq) {
+            Some(b) => b,
+            None if len == 0 => return Ok(Vec::new()),
+            None => {
+                return Err(EngineError::BlockAllocation {
+                    seq_id: seq,
+                    detail: "no blocks allocated".to_string(),
+                })
+            }
+        };
         let block_size = self.alloc.block_size;
-        let block_offset = position % block_size;
-        blocks[position / block_size].block_idx * block_size + block_offset
+        if blocks.len() * block_size < len {
+            return Err(EngineError::BlockAllocation {
+                seq_id: seq,
+                detail: format!("{} blocks allocated; {len} slots requested", blocks.len()),
+            });
+        }
+        Ok((0..len)
+            .map(|position| {
+                let block_offset = position % block_size;
+                blocks[position / block_size].block_idx * block_size + block_offset
+            })
+            .collect())
     }
 }
 
@@ -149,9 +206,8 @@ impl BlockAllocator {
         self.inner.lock().unwrap().alloc.free_list.len()
     }
 
-    pub fn get_block_idxes(&self, seq: SeqId, len: usize) -> Vec<usize> {
-        let l = self.inner.lock().unwrap();
-        (0..len).map(|k| l.get_block_idx(seq, k)).collect()
+    pub fn get_block_idxes(&self, seq: SeqId, len: usize) -> Result<Vec<usize>, EngineError> {
+        self.inner.lock().unwrap().get_block_idxes(seq, len)
     }
 
     fn num_needed_blocks(&self, seq: &Sequence) -> usize {
@@ -328,6 +384,27 @@ impl TBlockSpaceManager<TModel> for BlockSpaceManager {
     fn get_num_free_cpu_blocks(&self) -> usize {
         self.cpu_allocator.get_num_free_blocks()
     }
+
+    fn view(&self, max_seqs: usize) -> Option<BlockManagerView> {
+        let gpu = self.gpu_allocator.inner.lock().unwrap();
+        let cpu = self.cpu_allocator.inner.lock().unwrap();
+        let mut seqs = Vec::new();
+        gpu.seq_views(BlockLocation::GPU, &mut seqs);
+        cpu.seq_views(BlockLocation::CPU, &mut seqs);
+        seqs.sort_by_key(|s| (s.seq_id, s.location == BlockLocation::CPU));
+        let num_seqs = seqs.len();
+        let seqs_truncated = num_seqs > max_seqs;
+        seqs.truncate(max_seqs);
+        Some(BlockManagerView {
+            block_size: gpu.alloc.block_size,
+            watermark_blocks: self.watermark_blocks,
+            gpu: gpu.view(),
+            cpu: cpu.view(),
+            num_seqs,
+            seqs,
+            seqs_truncated,
+        })
+    }
 }
 
 impl BlockSpaceManager {
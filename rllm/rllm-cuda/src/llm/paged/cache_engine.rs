@@ -1,7 +1,7 @@
 // based on https://github.com/vllm-project/vllm/blob/b9fe4616f98b77b4b9458bce203aa6544cb31ef2/vllm/worker/cache_engine.py
 
-use super::super::{config::TchRllmConfig, kernels, tmodel::TModel};
-use super::CacheIface;
+use super::super::{config::TchRllmConfig, kernels, refkernels, tmodel::TModel, util::synchronize};
+use super::{batch_info::num_slots, CacheIface};
 use rllm::{config::RllmConfig, CacheSize, HashMap};
 use std::sync::Arc;
 use tch::{Device, Tensor};
@@ -14,42 +14,67 @@ use tch_cuda::{CudaEvent, CudaStream};
 type KVCache = (Tensor, Tensor);
 
 pub struct CacheEngine {
+    config: Arc<RllmConfig<TModel>>,
     gpu_cache: Arc<Vec<KVCache>>,
     cpu_cache: Vec<KVCache>,
-    cache_stream: CudaStream,
+    // None when the "gpu" cache lives on the CPU, or is split between devices;
+    // swaps are synchronous then
+    cache_stream: Option<CudaStream>,
     events: Arc<Vec<CudaEvent>>,
     used_events: bool,
+    scratch_slot: usize,
 }
 
 struct MyCacheAwaiter {
     gpu_cache: Arc<Vec<KVCache>>,
     events: Option<Arc<Vec<CudaEvent>>>,
-    stream: CudaStream,
+    stream: Option<CudaStream>,
 }
 
 impl CacheIface for MyCacheAwaiter {
     fn get(&self, layer_no: usize) -> (Tensor, Tensor) {
         let (key, value) = &self.gpu_cache[layer_no];
-        if let Some(events) = &self.events {
-            events[layer_no].wait(&self.stream);
+        if let (Some(events), Some(stream)) = (&self.events, &self.stream) {
+            events[layer_no].wait(stream);
         }
         (key.shallow_clone(), value.shallow_clone())
     }
+
+    fn num_slots(&self) -> usize {
+        num_slots(&self.gpu_cache[0].0)
+    }
 }
 
 impl CacheEngine {
     pub fn new(config: Arc<RllmConfig<TModel>>, num_blocks: &CacheSize) -> Self {
         let num_layers = config.get_num_layers_parallel();
         let (gpu_cache, cpu_cache) = Self::allocate_caches(&config, num_blocks);
+        let device = config.model.device;
+        let (cache_stream, events) = if device.is_cuda() && config.model.devices().len() == 1 {
+            (
+                Some(CudaStream::new(device)),
+                (0..num_layers).map(|_| CudaEvent::new()).collect(),
+            )
+        } else {
+            (None, Vec::new())
+        };
         Self {
+            config: config.clone(),
             gpu_cache: Arc::new(gpu_cache),
             cpu_cache,
-            cache_stream: CudaStream::new(config.model.device),
-            events: Arc::new((0..num_layers).map(|_| CudaEvent::new()).collect()),
+            cache_stream,
+            events: Arc::new(events),
             used_events: false,
+            scratch_slot: num_blocks.gpu * config.model.cache.block_size,
         }
     }
 
+    /// First slot of the extra GPU block, which is never handed out by the allocator.
+    /// Padding entries write their KV there.
+    pub fn scratch_slot(&self) -> usize {
+        self.scratch_slot
+    }
+
     pub fn get_cache_iface(&mut self) -> Box<dyn CacheIface> {
         let d = self.gpu_cache[0].0.device();
         let events = if self.used_events {
@@ -59,23 +84,81 @@ impl CacheEngine {
         };
         Box::new(MyCacheAwaiter {
             events,
-            stream: CudaStream::current(d),
+            stream: self.cache_stream.as_ref().map(|_| CudaStream::current(d)),
             gpu_cache: self.gpu_cache.clone(),
         })
     }
 
+    /// Bytes held by the GPU cache on each device it's split between.
+    pub fn device_bytes(&self) -> Vec<(Device, usize)> {
+        let mut r: Vec<(Device, usize)> = Vec::new();
+        for (k, v) in self.gpu_cache.iter() {
+            let bytes = (k.numel() + v.numel()) * self.config.model.dtype.elt_size_in_bytes();
+            match r.iter_mut().find(|(d, _)| *d == k.device()) {
+                Some((_, b)) => *b += bytes,
+                None => r.push((k.device(), bytes)),
+            }
+        }
+        r
+    }
+
+    /// Bytes held by the GPU cache and the CPU swap space.
+    pub fn num_bytes(&self) -> usize {
+        self.gpu_cache
+            .iter()
+            .chain(self.cpu_cache.iter())
+            .map(|(k, v)| {
+                (k.numel() + v.numel()) * self.config.model.dtype.elt_size_in_bytes()
+            })
+            .sum()
+    }
+
     pub fn new_round(&mut self) {
         self.used_events = false;
     }
 
     pub fn swap_in(&mut self, src_to_dst: &HashMap<usize, usize>) {
         self.swap(&self.cpu_cache, &self.gpu_cache, src_to_dst);
-        self.used_events = true;
+        self.used_events = self.cache_stream.is_some();
     }
 
     pub fn swap_out(&mut self, src_to_dst: &HashMap<usize, usize>) {
         self.swap(&self.gpu_cache, &self.cpu_cache, src_to_dst);
-        self.used_events = true;
+        self.used_events = self.cache_stream.is_some();
+    }
+
+    /// Copy GPU `blocks` into a new host cache, where block `i` holds `blocks[i]`.
+    pub fn save_blocks(&self, blocks: &[usize]) -> Vec<KVCache> {
+        let host = self.alloc_host_cache(blocks.len());
+        let mapping = blocks.iter().enumerate().map(|(i, b)| (*b, i)).collect();
+        self.swap(&self.gpu_cache, &host, &mapping);
+        self.synchronize();
+        host
+    }
+
+    /// Inverse of save_blocks().
+    pub fn load_blocks(&self, blocks: &[usize], host: &[KVCache]) {
+        let mapping = blocks.iter().enumerate().map(|(i, b)| (i, *b)).collect();
+        self.swap(host, &self.gpu_cache, &mapping);
+        self.synchronize();
+    }
+
+    fn synchronize(&self) {
+        for d in self.config.model.devices() {
+            synchronize(d);
+        }
+    }
+
+    fn alloc_host_cache(&self, num_bl: usize) -> Vec<KVCache> {
+        let config = &self.config;
+        (0..config.get_num_layers_parallel())
+            .map(|_| {
+                (
+                    Self::alloc_key_block(config, num_bl as i64, Device::Cpu),
+                    Self::alloc_value_block(config, num_bl as i64, Device::Cpu),
+                )
+            })
+            .collect()
     }
 
     fn alloc_key_block(config: &RllmConfig<TModel>, num_bl: i64, device: Device) -> Tensor {
@@ -99,8 +182,11 @@ impl CacheEngine {
         )
     }
 
-    pub fn alloc_gpu_cache_layer(config: &RllmConfig<TModel>, num_bl: i64) -> (Tensor, Tensor) {
-        let device = config.model.device;
+    pub fn alloc_gpu_cache_layer(
+        config: &RllmConfig<TModel>,
+        num_bl: i64,
+        device: Device,
+    ) -> (Tensor, Tensor) {
         (
             Self::alloc_key_block(config, num_bl, device),
             Self::alloc_value_block(config, num_bl, device),
@@ -113,8 +199,12 @@ impl CacheEngine {
     ) -> (Vec<KVCache>, Vec<KVCache>) {
         let num_layers = config.get_num_layers_parallel() as i64;
 
+        // one extra block for scratch_slot()
         let gpu_cache = (0..num_layers)
-            .map(|_| Self::alloc_gpu_cache_layer(config, num_blocks.gpu as i64))
+            .map(|i| {
+                let device = config.model.layer_device(i as usize);
+                Self::alloc_gpu_cache_layer(config, num_blocks.gpu as i64 + 1, device)
+            })
             .collect();
 
         let cpu_cache = (0..num_layers)
@@ -131,46 +221,65 @@ impl CacheEngine {
         (gpu_cache, cpu_cache)
     }
 
-    #[cfg(not(feature = "cuda"))]
-    fn swap(&self, _src: &[KVCache], _dst: &[KVCache], _src_to_dst: &HashMap<usize, usize>) {
-        let _ = self.cache_stream;
-        panic!("swap not implemented for CPU");
-    }
-
-    #[cfg(feature = "cuda")]
     fn swap(&self, src: &[KVCache], dst: &[KVCache], src_to_dst: &HashMap<usize, usize>) {
-        let stream = &self.cache_stream;
-        for (i, (src_k_cache, src_v_cache)) in src.iter().enumerate() {
-            let (dst_k_cache, dst_v_cache) = &dst[i];
-            kernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst, &self.cache_stream);
-            kernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst, &self.cache_stream);
-            self.events[i].record(stream);
+        match &self.cache_stream {
+            #[cfg(feature = "cuda")]
+            Some(stream) => {
+                for (i, (src_k_cache, src_v_cache)) in src.iter().enumerate() {
+                    let (dst_k_cache, dst_v_cache) = &dst[i];
+                    kernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst, stream);
+                    kernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst, stream);
+                    self.events[i].record(stream);
+                }
+            }
+            _ => {
+                // both caches are on the CPU; copy synchronously
+                for (i, (src_k_cache, src_v_cache)) in src.iter().enumerate() {
+                    let (dst_k_cache, dst_v_cache) = &dst[i];
+                    refkernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst);
+                    refkernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst);
+                }
+            }
         }
     }
 
     pub fn copy(&mut self, src_to_dsts: &HashMap<usize, Vec<usize>>) {
-        let mut key_caches: Vec<_> = self
-            .gpu_cache
-            .iter()
-            .map(|(key, _)| key.shallow_clone())
-            .collect();
-        let mut value_caches: Vec<_> = self
-            .gpu_cache
-            .iter()
-            .map(|(_, value)| value.shallow_clone())
-            .collect();
-        kernels::copy_blocks(&mut key_caches, &mut value_caches, &src_to_dsts);
+        // the kernel takes the caches of one device at a time
+        for device in self.config.model.devices() {
+            let mut key_caches = Vec::new();
+            let mut value_caches = Vec::new();
+            for (key, value) in self.gpu_cache.iter() {
+                if key.device() == device {
+                    key_caches.push(key.shallow_clone());
+                    value_caches.push(value.shallow_clone());
+                }
+            }
+            if !key_caches.is_empty() {
+                kernels::copy_blocks(&mut key_caches, &mut value_caches, &src_to_dsts);
+            }
+        }
     }
 
     pub fn get_cache_block_size(config: &RllmConfig<TModel>) -> usize {
+        let num_layers = config.get_num_layers_parallel();
+        num_layers * Self::get_layer_block_size(config)
+    }
+
+    /// Size of a block in the layers placed on `device`.
+    pub fn get_cache_block_size_on(config: &RllmConfig<TModel>, device: Device) -> usize {
+        let num_layers = (0..config.get_num_layers_parallel())
+            .filter(|i| config.model.layer_device(*i) == device)
+            .count();
+        num_layers * Self::get_layer_block_size(config)
+    }
+
+    fn get_layer_block_size(config: &RllmConfig<TModel>) -> usize {
         let block_size = config.model.cache.block_size;
         let head_size = config.get_head_size();
         let num_heads = config.get_num_heads_parallel();
-        let num_layers = config.get_num_layers_parallel();
 
         let key_cache_block = block_size * num_heads * head_size;
         let value_cache_block = key_cache_block;
-        let total = num_layers * (key_cache_block + value_cache_block);
-        config.model.dtype.elt_size_in_bytes() * total
+        config.model.dtype.elt_size_in_bytes() * (key_cache_block + value_cache_block)
     }
 }
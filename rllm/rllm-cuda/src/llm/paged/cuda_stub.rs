@@ -8,6 +8,10 @@ impl CudaEvent {
     }
 
     pub fn wait(&self, _stream: &CudaStream) {}
+
+    pub fn record(&self, _stream: &CudaStream) {}
+
+    pub fn synchronize(&self) {}
 }
 
 pub struct CudaStream {}
@@ -20,4 +24,6 @@ impl CudaStream {
     pub fn current(_device: Device) -> Self {
         CudaStream {}
     }
+
+    pub fn guard(&self) {}
 }
@@ -0,0 +1,109 @@
+// KV cache of a single sequence in host memory; see Scheduler::snapshot_seq().
+//
+// On disk, a snapshot is a single safetensors file with:
+//   tokens      - all tokens of the sequence
+//   meta        - [prompt_len, num_kv, block_size]
+//   k.N, v.N    - key and value blocks of layer N, in the cache layout
+
+use super::super::tmodel::TModel;
+use aicirt::api::Token;
+use anyhow::{anyhow, bail, Result};
+use rllm::{config::RllmConfig, seq::KvSnapshot};
+use std::path::Path;
+use tch::Tensor;
+
+pub struct KvBlocks {
+    /// Number of KV entries stored.
+    pub num_kv: usize,
+    pub block_size: usize,
+    /// (key, value) blocks for each layer; block `i` holds entries `i*block_size..`
+    pub layers: Vec<(Tensor, Tensor)>,
+}
+
+impl KvBlocks {
+    pub fn check_compatible(&self, config: &RllmConfig<TModel>) -> Result<()> {
+        let model = &config.model;
+        if self.block_size != model.cache.block_size {
+            bail!(
+                "snapshot block size {} != cache block size {}",
+                self.block_size,
+                model.cache.block_size
+            );
+        }
+        if self.layers.len() != model.num_hidden_layers {
+            bail!(
+                "snapshot has {} layers, model has {}",
+                self.layers.len(),
+                model.num_hidden_layers
+            );
+        }
+        if let Some((k, _)) = self.layers.first() {
+            if k.kind() != model.dtype {
+                bail!(
+                    "snapshot dtype {:?} != model dtype {:?}",
+                    k.kind(),
+                    model.dtype
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_tensor(v: &[usize]) -> Tensor {
+    Tensor::from_slice(&v.iter().map(|x| *x as i64).collect::<Vec<_>>())
+}
+
+pub fn save_kv_snapshot(snapshot: &KvSnapshot<KvBlocks>, path: &Path) -> Result<()> {
+    let data = &snapshot.data;
+    let tokens = snapshot
+        .tokens
+        .iter()
+        .map(|t| *t as usize)
+        .collect::<Vec<_>>();
+    let mut tensors = vec![
+        ("tokens".to_string(), to_tensor(&tokens)),
+        (
+            "meta".to_string(),
+            to_tensor(&[snapshot.prompt_len, snapshot.num_kv, data.block_size]),
+        ),
+    ];
+    for (idx, (k, v)) in data.layers.iter().enumerate() {
+        tensors.push((format!("k.{idx}"), k.shallow_clone()));
+        tensors.push((format!("v.{idx}"), v.shallow_clone()));
+    }
+    Tensor::write_safetensors(&tensors, path)?;
+    Ok(())
+}
+
+pub fn load_kv_snapshot(path: &Path) -> Result<KvSnapshot<KvBlocks>> {
+    let mut tensors = Tensor::read_safetensors(path)?;
+    let mut take = |name: &str| {
+        tensors
+            .iter()
+            .position(|(n, _)| n == name)
+            .map(|idx| tensors.swap_remove(idx).1)
+            .ok_or_else(|| anyhow!("missing {name} in {}", path.display()))
+    };
+    let tokens: Vec<i64> = Vec::<i64>::try_from(&take("tokens")?)?;
+    let meta: Vec<i64> = Vec::<i64>::try_from(&take("meta")?)?;
+    if meta.len() != 3 {
+        bail!("invalid meta in {}", path.display());
+    }
+    let mut layers = Vec::new();
+    while let Ok(k) = take(&format!("k.{}", layers.len())) {
+        let v = take(&format!("v.{}", layers.len()))?;
+        layers.push((k, v));
+    }
+    let num_kv = meta[1] as usize;
+    Ok(KvSnapshot {
+        tokens: tokens.into_iter().map(|t| t as Token).collect(),
+        prompt_len: meta[0] as usize,
+        num_kv,
+        data: KvBlocks {
+            num_kv,
+            block_size: meta[2] as usize,
+            layers,
+        },
+    })
+}
@@ -4,7 +4,13 @@ mod cuda_stub;
 mod batch_info;
 mod blocks;
 mod cache_engine;
+mod kv_snapshot;
+mod repro;
+mod staging;
 
 pub use batch_info::*;
 pub use blocks::*;
 pub use cache_engine::*;
+pub use kv_snapshot::*;
+pub use repro::*;
+pub use staging::*;
@@ -0,0 +1,189 @@
+// Self-contained dumps of steps that failed to build a batch, and a way to replay them.
+//
+// A dump directory contains:
+//   error.txt          - the error
+//   config.json        - the parts of the config that affect batch building
+//   batch.safetensors  - the builder entries, flattened (see write_entries())
+//   seqs.json          - token histories of all running sequences in the step
+
+use super::super::tmodel::{TModel, TModelInner};
+use super::batch_info::{BatchEntry, BatchInfoBuilder, FakeKVCache};
+use aicirt::api::Token;
+use anyhow::{anyhow, Result};
+use rllm::{config::RllmConfig, seq::SchedulingPhase, SchedulerOutputs};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Arc};
+use tch::Tensor;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeqDump {
+    pub seq_id: usize,
+    pub num_kv_computed: usize,
+    pub tokens: Vec<Token>,
+}
+
+impl SeqDump {
+    pub fn from_sched_out(sched_out: &SchedulerOutputs) -> Vec<SeqDump> {
+        sched_out
+            .next_seq_groups
+            .iter()
+            .flat_map(|sg| sg.seqs.iter())
+            .filter(|seq| seq.sched_phase == SchedulingPhase::Running)
+            .map(|seq| SeqDump {
+                seq_id: seq.seq_id.to_num(),
+                num_kv_computed: seq.num_kv_computed,
+                tokens: (0..seq.get_len()).map(|idx| seq.get_token(idx)).collect(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ConfigDump {
+    step_no: usize,
+    num_slots: usize,
+    block_size: usize,
+    max_model_len: usize,
+    paged_attn_kernel_v: usize,
+    decode_pad_buckets: Vec<usize>,
+    model_id: String,
+    num_hidden_layers: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    dtype: String,
+}
+
+fn to_tensor(v: Vec<usize>) -> Tensor {
+    Tensor::from_slice(&v.into_iter().map(|x| x as i64).collect::<Vec<_>>())
+}
+
+fn from_tensor(t: &Tensor) -> Result<Vec<usize>> {
+    let v: Vec<i64> = Vec::<i64>::try_from(t)?;
+    Ok(v.into_iter().map(|x| x as usize).collect())
+}
+
+impl BatchInfoBuilder {
+    /// Write everything needed by replay_failure() to `dir`.
+    pub fn dump_failure(
+        &self,
+        dir: &Path,
+        step_no: usize,
+        num_slots: usize,
+        err: &anyhow::Error,
+        seqs: &[SeqDump],
+    ) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("error.txt"), format!("{err}\n"))?;
+
+        let config = &self.config;
+        let model = &config.model;
+        let cfg = ConfigDump {
+            step_no,
+            num_slots,
+            block_size: model.cache.block_size,
+            max_model_len: config.scheduler.max_model_len,
+            paged_attn_kernel_v: model.cache.paged_attn_kernel_v,
+            decode_pad_buckets: model.cache.decode_pad_buckets.clone(),
+            model_id: model.meta.id.clone(),
+            num_hidden_layers: model.num_hidden_layers,
+            num_key_value_heads: model.num_key_value_heads,
+            head_dim: model.head_dim,
+            dtype: format!("{:?}", model.dtype),
+        };
+        std::fs::write(dir.join("config.json"), serde_json::to_string_pretty(&cfg)?)?;
+        std::fs::write(dir.join("seqs.json"), serde_json::to_string(seqs)?)?;
+
+        self.write_entries(&dir.join("batch.safetensors"))
+    }
+
+    fn write_entries(&self, path: &Path) -> Result<()> {
+        let mut seq_ids = Vec::new();
+        let mut query_offsets = vec![0];
+        let mut positions = Vec::new();
+        let mut tokens = Vec::new();
+        let mut kv_offsets = vec![0];
+        let mut kv_slots = Vec::new();
+        for e in &self.entries {
+            seq_ids.push(e.seq_id);
+            for (pos, tok) in &e.query_pos_token {
+                positions.push(*pos);
+                tokens.push(*tok as usize);
+            }
+            query_offsets.push(positions.len());
+            kv_slots.extend_from_slice(&e.kv_slots);
+            kv_offsets.push(kv_slots.len());
+        }
+        let tensors = vec![
+            ("seq_ids", to_tensor(seq_ids)),
+            ("query_offsets", to_tensor(query_offsets)),
+            ("positions", to_tensor(positions)),
+            ("tokens", to_tensor(tokens)),
+            ("kv_offsets", to_tensor(kv_offsets)),
+            ("kv_slots", to_tensor(kv_slots)),
+        ];
+        Tensor::write_safetensors(&tensors, path)?;
+        Ok(())
+    }
+}
+
+/// Rebuild the batch from a dump written by BatchInfoBuilder::dump_failure()
+/// against a fresh cache, and (if `model` is given, and the batch builds) run
+/// the forward pass with tensor tracing enabled, writing `trace.safetensors` to `dir`.
+/// Returns the error of the original step, if it reproduces.
+pub fn replay_failure(
+    config: &RllmConfig<TModel>,
+    dir: &Path,
+    model: Option<&dyn TModelInner>,
+) -> Result<()> {
+    let cfg: ConfigDump = serde_json::from_slice(&std::fs::read(dir.join("config.json"))?)?;
+    let mut config = config.clone();
+    config.model.cache.block_size = cfg.block_size;
+    config.model.cache.paged_attn_kernel_v = cfg.paged_attn_kernel_v;
+    config.model.cache.decode_pad_buckets = cfg.decode_pad_buckets.clone();
+    config.scheduler.max_model_len = cfg.max_model_len;
+    let config = Arc::new(config);
+
+    let tensors = Tensor::read_safetensors(dir.join("batch.safetensors"))?;
+    let get = |name: &str| {
+        tensors
+            .iter()
+            .find(|(n, _)| n == name)
+            .ok_or_else(|| anyhow!("missing {name} in batch.safetensors"))
+            .and_then(|(_, t)| from_tensor(t))
+    };
+    let seq_ids = get("seq_ids")?;
+    let query_offsets = get("query_offsets")?;
+    let positions = get("positions")?;
+    let tokens = get("tokens")?;
+    let kv_offsets = get("kv_offsets")?;
+    let kv_slots = get("kv_slots")?;
+
+    let mut builder = BatchInfoBuilder::new(config.clone());
+    for (idx, seq_id) in seq_ids.iter().enumerate() {
+        let q = query_offsets[idx]..query_offsets[idx + 1];
+        builder.push_entry(BatchEntry {
+            seq_id: *seq_id,
+            query_pos_token: positions[q.clone()]
+                .iter()
+                .zip(tokens[q].iter())
+                .map(|(p, t)| (*p, *t as Token))
+                .collect(),
+            kv_slots: kv_slots[kv_offsets[idx]..kv_offsets[idx + 1]].to_vec(),
+        });
+    }
+
+    let num_blocks = (cfg.num_slots / cfg.block_size) as i64;
+    let kv_cache = Box::new(FakeKVCache::new(&config, num_blocks));
+
+    let mut info = builder.finish(cfg.step_no, kv_cache)?;
+    log::info!("replayed batch: {info:?}");
+
+    if let Some(model) = model {
+        let _no_grad = tch::no_grad_guard();
+        info.trace = true;
+        let _logits = model.forward(&mut info);
+        info.save_log(&dir.join("trace.safetensors").to_string_lossy());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use tch::{kind::Element, Device, Tensor};
+
+#[cfg(not(feature = "cuda"))]
+use super::cuda_stub::{CudaEvent, CudaStream};
+#[cfg(feature = "cuda")]
+use tch_cuda::{CudaEvent, CudaStream};
+
+/// Page-locked host buffers for the per-step tensors of BatchInfo (tokens,
+/// positions, slot mappings, ...), reused from step to step, and uploaded with
+/// asynchronous copies on a separate stream. The current stream only waits for
+/// the copies (on the GPU) in end(), right before the forward pass.
+///
+/// On the CPU (or without the "cuda" feature) tensors are made directly from the
+/// slices, as before.
+pub struct HostStaging {
+    device: Device,
+    copy: Option<CopyStream>,
+    buffers: Vec<Tensor>,
+    next_buffer: usize,
+}
+
+struct CopyStream {
+    stream: CudaStream,
+    /// Recorded on `stream` after the last upload; the buffers can only be
+    /// overwritten once it's done.
+    uploaded: CudaEvent,
+}
+
+impl HostStaging {
+    pub fn new(device: Device) -> Self {
+        let copy = if cfg!(feature = "cuda") && device.is_cuda() {
+            Some(CopyStream {
+                stream: CudaStream::new(device),
+                uploaded: CudaEvent::new(),
+            })
+        } else {
+            None
+        };
+        HostStaging {
+            device,
+            copy,
+            buffers: Vec::new(),
+            next_buffer: 0,
+        }
+    }
+
+    /// No pinned buffers and no copy stream (nor CUDA event); for one-off batches.
+    pub fn unpinned(device: Device) -> Self {
+        HostStaging {
+            device,
+            copy: None,
+            buffers: Vec::new(),
+            next_buffer: 0,
+        }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.copy.is_some()
+    }
+
+    /// Start staging a batch; waits for the uploads of the previous one.
+    pub fn begin(&mut self) {
+        if let Some(copy) = &self.copy {
+            copy.uploaded.synchronize();
+        }
+        self.next_buffer = 0;
+    }
+
+    /// Copy `data` to a device tensor. The i-th upload after begin() goes through
+    /// the i-th buffer, which grows (to a power of 2) when needed.
+    pub fn upload<T: Element + Copy>(&mut self, data: &[T]) -> Tensor {
+        let stream = match &self.copy {
+            Some(copy) => &copy.stream,
+            None => return Tensor::from_slice(data).to(self.device),
+        };
+
+        let idx = self.next_buffer;
+        self.next_buffer += 1;
+        let len = data.len();
+        let fits = self
+            .buffers
+            .get(idx)
+            .map_or(false, |b| b.kind() == T::KIND && b.numel() >= len);
+        if !fits {
+            let cap = std::cmp::max(len, 1).next_power_of_two() as i64;
+            let buf = Tensor::empty(&[cap], (T::KIND, Device::Cpu)).pin_memory(self.device);
+            if idx < self.buffers.len() {
+                self.buffers[idx] = buf;
+            } else {
+                self.buffers.push(buf);
+            }
+        }
+
+        let buf = &self.buffers[idx];
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), buf.data_ptr() as *mut T, len) };
+        // allocate on the current stream, where the tensor is used
+        let dst = Tensor::empty(&[len as i64], (T::KIND, self.device));
+        let _guard = stream.guard();
+        let _ = buf.narrow(0, 0, len as i64).internal_copy_from(&dst, true);
+        dst
+    }
+
+    /// Make the current stream wait for the uploads since begin().
+    pub fn end(&mut self) {
+        if let Some(copy) = &self.copy {
+            copy.uploaded.record(&copy.stream);
+            copy.uploaded.wait(&CudaStream::current(self.device));
+        }
+    }
+
+    /// Host addresses of the staging buffers, to check they are reused.
+    #[cfg(test)]
+    pub(crate) fn buffer_ptrs(&self) -> Vec<usize> {
+        self.buffers.iter().map(|b| b.data_ptr() as usize).collect()
+    }
+}
@@ -47,10 +47,12 @@ impl RllmModelConfig for PhiConfig {
             num_key_value_heads: self.n_head,
             layer_norm_eps: self.layer_norm_epsilon,
             rope_theta: 10000.0,
+            tie_word_embeddings: false,
             head_dim: self.n_embd / self.n_head,
             rotary_dim: self.rotary_dim,
             dtype: ModelConfig::dtype_from_str(common.dtype, &self.torch_dtype),
             device: common.device,
+            layer_devices: Vec::new(),
             profile_step_no: 0,
             cache: Default::default(),
         }
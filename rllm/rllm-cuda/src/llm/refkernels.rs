@@ -1,3 +1,5 @@
+#[cfg(feature = "cpu-fallback")]
+use super::util::to_vec2;
 use super::util::{check_all_close_attn, to_vec1};
 use rllm::HashMap;
 use tch::{IndexOp, Kind, Tensor};
@@ -11,11 +13,11 @@ pub fn reshape_and_cache(
 ) {
     let (_num_blocks, num_heads, head_size_x, block_size, x) = key_cache.size5().unwrap();
 
-    let slot_mapping = to_vec1::<i32>(slot_mapping);
+    let slot_mapping = to_vec1::<i64>(slot_mapping);
     let key_reshaped = key.reshape(&[-1, num_heads, head_size_x, x]);
 
     for (idx, slot) in slot_mapping.iter().enumerate() {
-        let slot = *slot as i64;
+        let slot = *slot;
         let idx = idx as i64;
         let block_idx = slot / block_size;
         let block_off = slot % block_size;
@@ -209,21 +211,91 @@ pub fn rotary_embedding(
     key0.copy_(&key_rot.reshape(key0.size()));
 }
 
+/// Naive paged attention: gather each sequence's context from the cache
+/// and run a plain softmax(Q @ K^T) @ V for its single query token.
+#[cfg(feature = "cpu-fallback")]
+pub fn paged_attention_v1(
+    out: &mut Tensor,     // [num_seqs, num_heads, head_size]
+    query: &Tensor,       // [num_seqs, num_heads, head_size]
+    key_cache: &Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x]
+    value_cache: &Tensor, // [num_blocks, num_heads, head_size, block_size]
+    num_kv_heads: usize,
+    scale: f32,
+    block_tables: &Tensor, // [num_seqs, max_num_blocks_per_seq], int
+    context_lens: &Tensor, // [num_seqs], int
+    block_size: usize,
+    max_context_len: usize,
+    alibi_slopes: Option<&Tensor>,
+) {
+    assert!(alibi_slopes.is_none());
+
+    let (num_seqs, num_heads, head_size) = query.size3().unwrap();
+    let n_rep = num_heads / num_kv_heads as i64;
+    let context_lens = to_vec1::<i32>(context_lens);
+    let block_tables = to_vec2::<i32>(block_tables);
+    let block_size = block_size as i32;
+
+    for i in 0..num_seqs {
+        let ctx_len = context_lens[i as usize];
+        assert!(ctx_len as usize <= max_context_len);
+        let table = &block_tables[i as usize];
+        let slots = (0..ctx_len)
+            .map(|pos| table[(pos / block_size) as usize] * block_size + pos % block_size)
+            .collect::<Vec<_>>();
+        let slots = Tensor::from_slice(&slots).to(query.device());
+
+        let mut k = Tensor::empty(
+            &[ctx_len as i64, num_kv_heads as i64, head_size],
+            (query.kind(), query.device()),
+        );
+        let mut v = k.empty_like();
+        gather_cached_kv(&mut k, &mut v, key_cache, value_cache, &slots);
+
+        // [num_heads, ctx_len, head_size]
+        let k = k
+            .repeat_interleave_self_int(n_rep, Some(1), None)
+            .transpose(0, 1);
+        let v = v
+            .repeat_interleave_self_int(n_rep, Some(1), None)
+            .transpose(0, 1);
+
+        // [num_heads, 1, head_size]
+        let q = query.i(i).unsqueeze(1);
+        let attn = (q.matmul(&k.transpose(1, 2)) * scale as f64)
+            .softmax(-1, Kind::Float)
+            .to_kind(q.kind());
+        let y = attn.matmul(&v).squeeze_dim(1);
+
+        out.i(i).copy_(&y);
+    }
+}
+
 #[allow(dead_code)]
 pub fn copy_blocks(
-    _key_caches: &mut Vec<Tensor>,
-    _value_caches: &mut Vec<Tensor>,
-    _block_mapping: &HashMap<usize, Vec<usize>>,
+    key_caches: &mut Vec<Tensor>,
+    value_caches: &mut Vec<Tensor>,
+    block_mapping: &HashMap<usize, Vec<usize>>,
 ) {
-    todo!()
+    for (src, dsts) in block_mapping {
+        let src = *src as i64;
+        for dst in dsts {
+            let dst = *dst as i64;
+            for cache in key_caches.iter().chain(value_caches.iter()) {
+                cache.i(dst).copy_(&cache.i(src));
+            }
+        }
+    }
 }
 
 #[allow(dead_code)]
 pub fn swap_blocks(
-    _src: &Tensor,
-    _dst: &Tensor,
-    _block_mapping: &HashMap<usize, usize>,
+    src: &Tensor,
+    dst: &Tensor,
+    block_mapping: &HashMap<usize, usize>,
     // _stream: &CudaStream,
 ) {
-    todo!()
+    for (src_idx, dst_idx) in block_mapping {
+        dst.i(*dst_idx as i64)
+            .copy_(&src.i(*src_idx as i64).to(dst.device()));
+    }
 }
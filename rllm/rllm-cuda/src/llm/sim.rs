@@ -0,0 +1,466 @@
+// Scheduler simulations: the real engine, scheduler and block manager, driven
+// by scripted request arrivals, with a model that costs nothing to run.
+
+use super::{
+    paged::BatchInfo,
+    tests::{prompt, tiny_config, TestEngine},
+    tmodel::{TModel, TModelInner},
+};
+use rllm::{config::RllmConfig, seq::FinishReason, CacheSize};
+use std::time::{Duration, Instant};
+use tch::{Device, Tensor};
+
+/// Simulated time between steps; long enough that the real time spent
+/// in a simulation doesn't matter.
+pub(super) const STEP_TIME: Duration = Duration::from_secs(3600);
+
+/// Stands in for the model. The logits of a sequence are one-hot at a token
+/// derived from a hash of (seq_id, position), so runs are deterministic.
+pub(super) struct MockModel {
+    vocab_size: usize,
+    device: Device,
+}
+
+impl MockModel {
+    pub fn new(config: &RllmConfig<TModel>) -> Self {
+        Self {
+            vocab_size: config.meta.vocab_size,
+            device: config.model.device,
+        }
+    }
+
+    pub fn token_for(&self, seq_id: usize, position: usize) -> usize {
+        let mut h = (seq_id as u64) << 32 | position as u64;
+        h = (h ^ (h >> 33)).wrapping_mul(0xff51afd7ed558ccd);
+        h = (h ^ (h >> 33)).wrapping_mul(0xc4ceb9fe1a85ec53);
+        (h ^ (h >> 33)) as usize % self.vocab_size
+    }
+}
+
+impl TModelInner for MockModel {
+    fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let batch_size = batch_info.batch_size();
+        let mut seq_ids = vec![usize::MAX; batch_size]; // padding
+        for (seq_id, idx) in batch_info.seq_id_to_idx.iter() {
+            seq_ids[*idx] = *seq_id;
+        }
+        let mut logits = vec![0f32; batch_size * self.vocab_size];
+        for (idx, seq_id) in seq_ids.into_iter().enumerate() {
+            let tok_idx = batch_info.logit_idxs.int64_value(&[idx as i64]);
+            let position = batch_info.positions.int64_value(&[tok_idx]) as usize;
+            logits[idx * self.vocab_size + self.token_for(seq_id, position)] = 1.0;
+        }
+        Tensor::from_slice(&logits)
+            .reshape(&[batch_size as i64, self.vocab_size as i64])
+            .to(self.device)
+    }
+}
+
+/// Everything the scheduler decided in one step.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct StepTrace {
+    pub step_no: usize,
+    pub prompt_run: bool,
+    /// Request ids in the batch, in scheduling order.
+    pub scheduled: Vec<String>,
+    pub num_batched_tokens: usize,
+    /// Requests that were running before the step, and got sent back
+    /// to the waiting queue (recompute) or swapped out.
+    pub preempted: Vec<String>,
+    /// Requests from the batch that finished in this step.
+    pub finished: Vec<String>,
+    pub free_gpu_blocks: usize,
+}
+
+/// A request added right before step number `step` (starting from 1).
+pub(super) struct Arrival {
+    pub step: usize,
+    pub request_id: &'static str,
+    pub prompt_len: usize,
+    pub max_tokens: usize,
+    /// The request is shed if it isn't scheduled before this step.
+    pub deadline: Option<usize>,
+}
+
+pub(super) struct Simulation {
+    pub engine: TestEngine,
+    arrivals: Vec<Arrival>,
+    next_arrival: usize,
+    start: Instant,
+    pub trace: Vec<StepTrace>,
+}
+
+impl Simulation {
+    pub fn new(
+        config: RllmConfig<TModel>,
+        num_gpu_blocks: usize,
+        watermark: f32,
+        mut arrivals: Vec<Arrival>,
+    ) -> Self {
+        let model = Box::new(MockModel::new(&config));
+        let cache_size = CacheSize {
+            gpu: num_gpu_blocks,
+            cpu: 8,
+        };
+        arrivals.sort_by_key(|a| a.step);
+        Self {
+            engine: TestEngine::with_model(config, cache_size, watermark, model),
+            arrivals,
+            next_arrival: 0,
+            start: Instant::now(),
+            trace: Vec::new(),
+        }
+    }
+
+    /// Simulated time at the start of step `step_no`.
+    pub fn time(&self, step_no: usize) -> Instant {
+        self.start + STEP_TIME * step_no as u32
+    }
+
+    /// Step until all requests arrived and finished; panics after `max_steps`.
+    pub fn run(&mut self, max_steps: usize) {
+        for step_no in 1..=max_steps {
+            while let Some(a) = self.arrivals.get(self.next_arrival) {
+                if a.step > step_no {
+                    break;
+                }
+                let tokens = prompt(a.prompt_len, self.next_arrival);
+                // keep the arrival order within a step
+                let arrival_time =
+                    self.time(a.step) + Duration::from_micros(self.next_arrival as u64);
+                let deadline = a.deadline.map(|s| self.time(s));
+                self.engine.add_prompt_at(
+                    a.request_id,
+                    &tokens,
+                    a.max_tokens,
+                    arrival_time,
+                    deadline,
+                );
+                self.next_arrival += 1;
+            }
+            self.engine.scheduler_mut().shed_expired(self.time(step_no));
+            if self.next_arrival == self.arrivals.len() && self.engine.all_finished() {
+                return;
+            }
+            let (_, trace) = self.engine.step_traced();
+            log::debug!("{trace:?}");
+            self.trace.push(trace);
+        }
+        panic!("simulation didn't finish in {max_steps} steps");
+    }
+
+    /// The first step that ran `request_id`, if any.
+    pub fn first_scheduled(&self, request_id: &str) -> Option<usize> {
+        self.trace
+            .iter()
+            .find(|t| t.scheduled.iter().any(|r| r == request_id))
+            .map(|t| t.step_no)
+    }
+
+    pub fn step(&self, step_no: usize) -> &StepTrace {
+        &self.trace[step_no - 1]
+    }
+}
+
+fn arrivals(reqs: &[(&'static str, usize)], max_tokens: usize) -> Vec<Arrival> {
+    reqs.iter()
+        .map(|&(request_id, prompt_len)| Arrival {
+            step: 1,
+            request_id,
+            prompt_len,
+            max_tokens,
+            deadline: None,
+        })
+        .collect()
+}
+
+#[test]
+fn sim_mock_model_is_deterministic() {
+    let gen = || {
+        let config = tiny_config();
+        let mut sim = Simulation::new(config, 32, 0.0, arrivals(&[("a", 20), ("b", 5)], 8));
+        sim.run(20);
+        (sim.engine.run_to_completion(), sim.trace)
+    };
+    let (out1, trace1) = gen();
+    let (out2, trace2) = gen();
+    assert!(out1.len() == 2);
+    assert!(out1.iter().all(|(_, toks, _)| toks.len() == 8));
+    assert!(out1 == out2);
+    assert!(trace1 == trace2);
+    // prefill of both, then 7 decode steps
+    assert!(trace1.len() == 8);
+    assert!(trace1[0].prompt_run && trace1[0].num_batched_tokens == 25);
+}
+
+#[test]
+fn sim_admission() {
+    struct Case {
+        name: &'static str,
+        num_gpu_blocks: usize,
+        watermark: f32,
+        max_num_seqs: usize,
+        max_num_batched_tokens: usize,
+        prompts: &'static [(&'static str, usize)],
+        /// Step at which each request is first scheduled.
+        expected: &'static [(&'static str, usize)],
+    }
+    let cases = [
+        Case {
+            name: "everything fits",
+            num_gpu_blocks: 32,
+            watermark: 0.0,
+            max_num_seqs: 8,
+            max_num_batched_tokens: 128,
+            prompts: &[("a", 20), ("b", 5), ("c", 9)],
+            expected: &[("a", 1), ("b", 1), ("c", 1)],
+        },
+        Case {
+            // c waits until a and b finish (3 tokens each), and are dropped
+            name: "max_num_seqs",
+            num_gpu_blocks: 32,
+            watermark: 0.0,
+            max_num_seqs: 2,
+            max_num_batched_tokens: 128,
+            prompts: &[("a", 20), ("b", 5), ("c", 9)],
+            expected: &[("a", 1), ("b", 1), ("c", 4)],
+        },
+        Case {
+            // c gets its own prefill step; a and b don't decode in it
+            name: "max_num_batched_tokens",
+            num_gpu_blocks: 32,
+            watermark: 0.0,
+            max_num_seqs: 8,
+            max_num_batched_tokens: 40,
+            prompts: &[("a", 20), ("b", 15), ("c", 10)],
+            expected: &[("a", 1), ("b", 1), ("c", 2)],
+        },
+        Case {
+            // a takes 3 of 4 blocks; b (2 blocks) waits until a is done
+            name: "gpu blocks",
+            num_gpu_blocks: 4,
+            watermark: 0.0,
+            max_num_seqs: 8,
+            max_num_batched_tokens: 128,
+            prompts: &[("a", 40), ("b", 20)],
+            expected: &[("a", 1), ("b", 4)],
+        },
+        Case {
+            name: "no watermark",
+            num_gpu_blocks: 6,
+            watermark: 0.0,
+            max_num_seqs: 8,
+            max_num_batched_tokens: 128,
+            prompts: &[("a", 32), ("b", 48)],
+            expected: &[("a", 1), ("b", 1)],
+        },
+        Case {
+            // 2 blocks are held back, so b (3 blocks) doesn't fit next to a (2 blocks)
+            name: "watermark",
+            num_gpu_blocks: 6,
+            watermark: 0.34,
+            max_num_seqs: 8,
+            max_num_batched_tokens: 128,
+            prompts: &[("a", 32), ("b", 48)],
+            expected: &[("a", 1), ("b", 4)],
+        },
+    ];
+
+    for case in cases.iter() {
+        let mut config = tiny_config();
+        config.scheduler.max_num_seqs = case.max_num_seqs;
+        config.scheduler.max_num_batched_tokens = case.max_num_batched_tokens;
+        let mut sim = Simulation::new(
+            config,
+            case.num_gpu_blocks,
+            case.watermark,
+            arrivals(case.prompts, 3),
+        );
+        sim.run(50);
+        for (request_id, step_no) in case.expected {
+            assert!(
+                sim.first_scheduled(request_id) == Some(*step_no),
+                "{}: {request_id} first scheduled at {:?}, expected {step_no}\n{:#?}",
+                case.name,
+                sim.first_scheduled(request_id),
+                sim.trace
+            );
+        }
+        assert!(
+            sim.trace.iter().all(|t| t.preempted.is_empty()),
+            "{}",
+            case.name
+        );
+    }
+}
+
+#[test]
+fn sim_preemption() {
+    struct Case {
+        name: &'static str,
+        num_gpu_blocks: usize,
+        prompts: &'static [(&'static str, usize)],
+        /// Batch and preempted requests in the step that runs out of blocks.
+        scheduled: &'static [&'static str],
+        preempted: &'static [&'static str],
+        /// Step where the preempted requests are prefilled again.
+        readmitted: usize,
+    }
+    let cases = [
+        Case {
+            // each prompt fills 2 blocks exactly; the first decode needs one more
+            name: "two requests",
+            num_gpu_blocks: 4,
+            prompts: &[("a", 32), ("b", 32)],
+            scheduled: &["a"],
+            preempted: &["b"],
+            readmitted: 5,
+        },
+        Case {
+            // a still gets its block; b doesn't, and the latest arrival (c)
+            // is preempted to make room for it
+            name: "latest arrival goes first",
+            num_gpu_blocks: 7,
+            prompts: &[("a", 32), ("b", 32), ("c", 32)],
+            scheduled: &["a", "b"],
+            preempted: &["c"],
+            readmitted: 5,
+        },
+    ];
+
+    for case in cases.iter() {
+        let mut sim = Simulation::new(
+            tiny_config(),
+            case.num_gpu_blocks,
+            0.0,
+            arrivals(case.prompts, 4),
+        );
+        sim.run(50);
+        let step = sim.step(2);
+        assert!(step.scheduled == case.scheduled, "{}: {step:?}", case.name);
+        assert!(step.preempted == case.preempted, "{}: {step:?}", case.name);
+        for request_id in case.preempted {
+            let again = sim
+                .trace
+                .iter()
+                .skip(2)
+                .find(|t| t.scheduled.iter().any(|r| r == request_id))
+                .unwrap();
+            assert!(again.step_no == case.readmitted, "{}: {again:?}", case.name);
+            assert!(again.prompt_run, "{}: {again:?}", case.name);
+        }
+        // preempted requests keep their generated tokens and still finish normally
+        let outputs = sim.engine.run_to_completion();
+        assert!(outputs.len() == case.prompts.len());
+        assert!(
+            outputs.iter().all(|(_, toks, _)| toks.len() == 4),
+            "{}",
+            case.name
+        );
+        assert!(sim.trace.last().unwrap().free_gpu_blocks == case.num_gpu_blocks);
+    }
+}
+
+#[test]
+fn sim_queue_timeout() {
+    struct Case {
+        name: &'static str,
+        /// SchedulerConfig::queue_timeout, in steps.
+        queue_timeout: Option<u32>,
+        /// Request ids and deadlines; all arrive at step 1, two run at a time.
+        requests: &'static [(&'static str, Option<usize>)],
+        /// Step at which each served request is first scheduled.
+        served: &'static [(&'static str, usize)],
+        shed: &'static [&'static str],
+    }
+    let cases = [
+        Case {
+            // a and b finish after 3 steps; c and d just make it, e and f don't
+            name: "overload burst",
+            queue_timeout: Some(3),
+            requests: &[
+                ("a", None),
+                ("b", None),
+                ("c", None),
+                ("d", None),
+                ("e", None),
+                ("f", None),
+            ],
+            served: &[("a", 1), ("b", 1), ("c", 4), ("d", 4)],
+            shed: &["e", "f"],
+        },
+        Case {
+            name: "no timeout",
+            queue_timeout: None,
+            requests: &[("a", None), ("b", None), ("c", None), ("d", None)],
+            served: &[("a", 1), ("b", 1), ("c", 4), ("d", 4)],
+            shed: &[],
+        },
+        Case {
+            name: "earliest deadline first",
+            queue_timeout: None,
+            requests: &[("a", None), ("b", None), ("c", Some(20)), ("d", Some(10))],
+            served: &[("c", 1), ("d", 1), ("a", 4), ("b", 4)],
+            shed: &[],
+        },
+        Case {
+            // per-request deadlines come before the timeout; a and b are past
+            // theirs while running, which doesn't matter
+            name: "request deadline",
+            queue_timeout: Some(10),
+            requests: &[("a", Some(2)), ("b", Some(2)), ("c", Some(3))],
+            served: &[("a", 1), ("b", 1)],
+            shed: &["c"],
+        },
+    ];
+
+    for case in cases.iter() {
+        let mut config = tiny_config();
+        config.scheduler.max_num_seqs = 2;
+        config.scheduler.queue_timeout = case.queue_timeout.map(|n| STEP_TIME * n);
+        let arrivals = case
+            .requests
+            .iter()
+            .map(|&(request_id, deadline)| Arrival {
+                step: 1,
+                request_id,
+                prompt_len: 5,
+                max_tokens: 3,
+                deadline,
+            })
+            .collect();
+        let mut sim = Simulation::new(config, 32, 0.0, arrivals);
+        sim.run(50);
+        for (request_id, step_no) in case.served {
+            assert!(
+                sim.first_scheduled(request_id) == Some(*step_no),
+                "{}: {request_id} first scheduled at {:?}, expected {step_no}\n{:#?}",
+                case.name,
+                sim.first_scheduled(request_id),
+                sim.trace
+            );
+        }
+
+        let outputs = sim.engine.run_to_completion();
+        assert!(outputs.len() == case.requests.len(), "{}", case.name);
+        for (request_id, toks, finish_reason) in outputs {
+            if case.shed.contains(&request_id.as_str()) {
+                assert!(sim.first_scheduled(&request_id).is_none(), "{}", case.name);
+                assert!(toks.is_empty(), "{}: {request_id}", case.name);
+                assert!(finish_reason == Some(FinishReason::QueueTimeout));
+            } else {
+                // including a and b, which are past the timeout when they finish
+                assert!(toks.len() == 3, "{}: {request_id}", case.name);
+                assert!(finish_reason == Some(FinishReason::MaxTokensReached));
+            }
+        }
+        let usage = sim.engine.usage();
+        for request_id in case.shed {
+            assert!(usage[*request_id].total_tokens() == 0, "{}", case.name);
+        }
+
+        let stats = sim.engine.scheduler().admission_stats();
+        assert!(stats.shed == case.shed.len(), "{}: {stats:?}", case.name);
+        assert!(stats.queued == 0, "{}: {stats:?}", case.name);
+    }
+}
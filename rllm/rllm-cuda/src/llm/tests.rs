@@ -0,0 +1,3017 @@
+// These run a tiny random-weight llama on the CPU through the same
+// scheduler -> BatchInfo -> paged KV cache path that is used on the GPU.
+
+use super::{
+    attn::AttnBackendKind,
+    checkpoints::{compare_runs, diagnose_dtype, record_activations, Tolerances},
+    config::{split_layers, CacheConfig, ModelConfig, ModelType, MAX_NUM_SLOTS},
+    llama::Llama,
+    loader::{load_model, WeightNameMapper},
+    paged::{
+        load_kv_snapshot, replay_failure, save_kv_snapshot, BatchEntry, BatchInfo,
+        BatchInfoBuilder, BlockSpaceManager, CacheEngine, HostStaging, SeqDump,
+    },
+    rotary_cache_bytes,
+    sim::StepTrace,
+    tmodel::{TModel, TModelInner, TchAiciBias, TchLoaderArgs},
+    util::check_all_close,
+    DType, RotaryEmbedding,
+};
+use aici_abi::{bytes::TokRxInfo, toktree::TokTrie};
+use aicirt::api::{add_token_bias, decode_token_bias, LOGIT_BIAS_ALLOW, LOGIT_BIAS_DISALLOW};
+use rllm::{
+    check_prompt_tokens,
+    config::{
+        AiciConfig, ModelMeta, ParallelConfig, Pooling, RequestKind, RllmConfig, SamplingParams,
+        SchedulerConfig,
+    },
+    metrics::SeqGroupTiming,
+    reqlog::{RequestEvent, RequestEventKind, REQUEST_LOG_TARGET},
+    seq::{
+        FinishReason, KvSnapshot, PrefillOutput, RequestOutput, SchedulingPhase, Sequence,
+        SequenceGroup, Token, TokenUsage,
+    },
+    util::set_setting,
+    AddRequest, AiciBias, BlockLocation, CacheSize, CoalescingStats, ControllerHooks,
+    ControllerSpec, HashMap, HashSet, LoaderArgs, LogitsProcessor, ModelExec, ModelRegistry,
+    NoStepHooks, PreemptionMode, Repo, RllmEngine, SampledToken, SchedulerOutputs, SeqCommand,
+    SeqController, SeqId, SequenceManager, StepHooks, TBlockSpaceManager,
+};
+use serde_json::json;
+use std::{
+    cell::RefCell,
+    ops::{Deref, DerefMut},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tch::{nn::VarStore, Device, IndexOp, Tensor};
+use tokenizers::{AddedToken, Tokenizer};
+
+const VOCAB_SIZE: usize = 256;
+const BLOCK_SIZE: usize = 16;
+const NUM_GPU_BLOCKS: usize = 32;
+const MODEL_SEED: i64 = 42;
+
+pub(super) fn tiny_config() -> RllmConfig<TModel> {
+    let meta = ModelMeta {
+        id: "tiny-llama".to_string(),
+        max_sequence_length: 128,
+        vocab_size: VOCAB_SIZE,
+        tok_vocab_size: VOCAB_SIZE,
+    };
+    let model = ModelConfig {
+        model_type: ModelType::Llama,
+        meta: meta.clone(),
+        num_attention_heads: 4,
+        hidden_size: 64,
+        num_hidden_layers: 2,
+        num_key_value_heads: 2,
+        head_dim: 16,
+        rotary_dim: 16,
+        intermediate_size: 128,
+        layer_norm_eps: 1e-5,
+        rope_theta: 10_000.0,
+        tie_word_embeddings: false,
+        device: Device::Cpu,
+        dtype: DType::Float,
+        layer_devices: Vec::new(),
+        profile_step_no: 0,
+        cache: CacheConfig::new(BLOCK_SIZE, 0.9, 0).unwrap(),
+    };
+    RllmConfig {
+        model,
+        meta,
+        parallel: ParallelConfig::single(),
+        scheduler: SchedulerConfig {
+            max_num_batched_tokens: 128,
+            max_num_kv_tokens: NUM_GPU_BLOCKS * BLOCK_SIZE,
+            max_num_seqs: 8,
+            max_model_len: 128,
+            kv_truncation_sink: None,
+            deterministic_seed: None,
+            coalesce_prompts: true,
+            bill_coalesced_prompts: true,
+            queue_timeout: None,
+            pipeline_outputs: false,
+        },
+        aici: AiciConfig { max_fuel: 10_000 },
+    }
+}
+
+/// Build the model and overwrite all weights with small pseudo-random values
+/// that only depend on `seed` (the global torch RNG is shared between tests).
+pub(super) fn tiny_model(config: &RllmConfig<TModel>, seed: i64) -> Box<dyn TModelInner> {
+    tiny_model_vs(config, seed).1
+}
+
+/// tiny_model(), with the VarStore holding its weights.
+fn tiny_model_vs(config: &RllmConfig<TModel>, seed: i64) -> (VarStore, Box<dyn TModelInner>) {
+    let _no_grad = tch::no_grad_guard();
+    let vs = VarStore::new(config.model.device);
+    let model = Llama::load(vs.root(), &Rc::new(config.model.clone())).unwrap();
+    let mut vars = vs.variables().into_iter().collect::<Vec<_>>();
+    vars.sort_by(|a, b| a.0.cmp(&b.0));
+    for (idx, (_, mut var)) in vars.into_iter().enumerate() {
+        let freq = 0.37 + 0.11 * idx as f64;
+        let rnd = (Tensor::arange(var.numel() as i64, (DType::Float, config.model.device)) * freq
+            + seed as f64)
+            .sin()
+            * 0.1;
+        var.copy_(&rnd.reshape(&var.size()).to_kind(config.model.dtype));
+    }
+    (vs, Box::new(model))
+}
+
+/// What the hooks of a TestEngine saw in the current step.
+#[derive(Default)]
+struct Probe {
+    /// The last batch scheduled, as the StepTrace of its step.
+    batch: Option<StepTrace>,
+    /// Logits of every sequence sampled, before any bias.
+    logits: Vec<(SeqId, Tensor)>,
+    /// Token to sample next for a given sequence, instead of what the model picks.
+    forced: HashMap<SeqId, Token>,
+}
+
+/// The StepHooks of a TestEngine: records into the Probe, forces tokens, and
+/// passes everything on to the hooks under test.
+struct ProbeHooks {
+    probe: Rc<RefCell<Probe>>,
+    inner: Box<dyn StepHooks<TModel>>,
+}
+
+impl StepHooks<TModel> for ProbeHooks {
+    fn scheduled(&mut self, step_no: usize, sched_out: &SchedulerOutputs) {
+        let batch = StepTrace {
+            step_no,
+            prompt_run: sched_out.prompt_run,
+            scheduled: sched_out
+                .next_seq_groups
+                .iter()
+                .map(|sg| sg.request_id.clone())
+                .collect(),
+            num_batched_tokens: sched_out.num_batched_tokens,
+            preempted: Vec::new(),
+            finished: Vec::new(),
+            free_gpu_blocks: 0,
+        };
+        self.probe.borrow_mut().batch = Some(batch);
+        self.inner.scheduled(step_no, sched_out);
+    }
+
+    fn pre_sample(&mut self, step_no: usize, seq: &Sequence, logits: &Tensor) -> Vec<(Token, f32)> {
+        let mut probe = self.probe.borrow_mut();
+        probe.logits.push((seq.seq_id, logits.copy()));
+        let bias = self.inner.pre_sample(step_no, seq, logits);
+        match probe.forced.remove(&seq.seq_id) {
+            Some(t) => (0..VOCAB_SIZE as Token)
+                .filter(|t2| *t2 != t)
+                .map(|t2| (t2, f32::NEG_INFINITY))
+                .collect(),
+            None => bias,
+        }
+    }
+
+    fn post_sample(&mut self, step_no: usize, sampled: &[SampledToken]) -> Vec<SeqCommand> {
+        self.inner.post_sample(step_no, sampled)
+    }
+
+    fn attach(&mut self, seq_id: SeqId, spec: &ControllerSpec) -> anyhow::Result<()> {
+        self.inner.attach(seq_id, spec)
+    }
+
+    fn fork(&mut self, parent: SeqId, child: SeqId) {
+        self.inner.fork(parent, child)
+    }
+
+    fn detach(&mut self, seq_id: SeqId) {
+        self.inner.detach(seq_id)
+    }
+}
+
+/// An RllmEngine running the tiny model, with what the tests look at between
+/// steps: the logits of every sampled sequence, tokens to force, the outputs
+/// streamed so far, and what the scheduler did in each step.
+pub(super) struct TestEngine {
+    engine: RllmEngine<TModel>,
+    probe: Rc<RefCell<Probe>>,
+    /// Sampling temperature of new requests; 0.0 means argmax.
+    pub temperature: f32,
+    /// Outputs of every step, as RllmEngine::step() returned them.
+    pub request_outputs: Vec<RequestOutput>,
+    /// Request of every sequence seen at the start of a step.
+    seq_requests: HashMap<SeqId, String>,
+    num_gpu_blocks: usize,
+}
+
+impl Deref for TestEngine {
+    type Target = RllmEngine<TModel>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.engine
+    }
+}
+
+impl DerefMut for TestEngine {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.engine
+    }
+}
+
+impl TestEngine {
+    pub fn new(seed: i64) -> Self {
+        Self::with_config(tiny_config(), seed)
+    }
+
+    pub fn with_config(config: RllmConfig<TModel>, seed: i64) -> Self {
+        let model = tiny_model(&config, seed);
+        let cache_size = CacheSize {
+            gpu: NUM_GPU_BLOCKS,
+            cpu: 8,
+        };
+        Self::with_model(config, cache_size, 0.0, model)
+    }
+
+    pub fn with_model(
+        config: RllmConfig<TModel>,
+        cache_size: CacheSize,
+        watermark: f32,
+        model: Box<dyn TModelInner>,
+    ) -> Self {
+        let num_gpu_blocks = cache_size.gpu;
+        let engine = engine_with_model(config, byte_tokenizer(), cache_size, watermark, model);
+        let mut engine = Self {
+            engine,
+            probe: Rc::new(RefCell::new(Probe::default())),
+            temperature: 0.0,
+            request_outputs: Vec::new(),
+            seq_requests: HashMap::default(),
+            num_gpu_blocks,
+        };
+        engine.set_hooks(NoStepHooks);
+        engine
+    }
+
+    /// Install `hooks` on the engine, under the ones recording the steps.
+    pub fn set_hooks(&mut self, hooks: impl StepHooks<TModel> + 'static) {
+        let hooks = ProbeHooks {
+            probe: self.probe.clone(),
+            inner: Box::new(hooks),
+        };
+        self.engine.set_step_hooks(Box::new(hooks));
+    }
+
+    /// Sample `token` the next time `seq_id` is sampled.
+    pub fn force(&mut self, seq_id: SeqId, token: Token) {
+        self.probe.borrow_mut().forced.insert(seq_id, token);
+    }
+
+    fn queue(
+        &mut self,
+        request_id: &str,
+        prompt: &[Token],
+        sampling_params: SamplingParams,
+        controller: Option<ControllerSpec>,
+    ) -> anyhow::Result<()> {
+        self.engine.queue_request(AddRequest {
+            request_id: request_id.to_string(),
+            prompt: prompt.to_vec(),
+            sampling_params,
+            expected: None,
+            init_result: None,
+            deadline: None,
+            arrival_time: None,
+            controller,
+        })
+    }
+
+    fn sampling_params(&self, max_tokens: usize) -> SamplingParams {
+        SamplingParams {
+            max_tokens,
+            temperature: self.temperature,
+            ignore_eos: true,
+            ..SamplingParams::default()
+        }
+    }
+
+    pub fn add_prompt(&mut self, request_id: &str, prompt: &[Token], max_tokens: usize) {
+        let params = self.sampling_params(max_tokens);
+        self.queue(request_id, prompt, params, None).unwrap();
+    }
+
+    /// add_prompt(), with the given arrival time and deadline.
+    pub fn add_prompt_at(
+        &mut self,
+        request_id: &str,
+        prompt: &[Token],
+        max_tokens: usize,
+        arrival_time: Instant,
+        deadline: Option<Instant>,
+    ) {
+        let sampling_params = self.sampling_params(max_tokens);
+        self.engine
+            .queue_request(AddRequest {
+                request_id: request_id.to_string(),
+                prompt: prompt.to_vec(),
+                sampling_params,
+                expected: None,
+                init_result: None,
+                deadline,
+                arrival_time: Some(arrival_time),
+                controller: None,
+            })
+            .unwrap();
+    }
+
+    /// add_prompt() for a score or embed request.
+    pub fn add_prefill_request(&mut self, request_id: &str, prompt: &[Token], kind: RequestKind) {
+        let params = SamplingParams {
+            kind,
+            ..SamplingParams::default()
+        };
+        self.queue(request_id, prompt, params, None).unwrap();
+    }
+
+    /// add_prompt(), attaching a controller through the hooks.
+    pub fn add_prompt_with_controller(
+        &mut self,
+        request_id: &str,
+        prompt: &[Token],
+        max_tokens: usize,
+        spec: &ControllerSpec,
+    ) -> anyhow::Result<()> {
+        let params = self.sampling_params(max_tokens);
+        self.queue(request_id, prompt, params, Some(spec.clone()))
+    }
+
+    pub fn fork(&mut self, seq_id: SeqId, n: usize) -> Vec<SeqId> {
+        self.engine.fork_sequence(seq_id, n).unwrap()
+    }
+
+    pub fn restore(
+        &mut self,
+        request_id: &str,
+        snapshot: &KvSnapshot<<TModel as ModelExec>::KvData>,
+        max_tokens: usize,
+    ) {
+        let params = self.sampling_params(max_tokens);
+        self.engine
+            .restore_sequence(snapshot, request_id.to_string(), params)
+            .unwrap();
+    }
+
+    /// Run one step. Returns (request_id, last-position logits) for every sequence
+    /// that was sampled.
+    pub fn step(&mut self) -> Vec<(String, Tensor)> {
+        let (res, trace) = self.step_traced();
+        assert!(!trace.scheduled.is_empty());
+        res
+    }
+
+    /// Like step(), but also describes what the scheduler did, and allows
+    /// steps where nothing could be scheduled.
+    pub fn step_traced(&mut self) -> (Vec<(String, Tensor)>, StepTrace) {
+        let mut running = HashSet::default();
+        let scheduler = self.engine.scheduler();
+        scheduler.for_each_sg(|sg| {
+            for seq in sg.seqs.iter() {
+                self.seq_requests.insert(seq.seq_id, sg.request_id.clone());
+            }
+        });
+        scheduler.for_each_ongpu_sg(|sg| {
+            if sg
+                .seqs
+                .iter()
+                .any(|s| s.sched_phase == SchedulingPhase::Running)
+            {
+                running.insert(sg.request_id.clone());
+            }
+        });
+
+        self.request_outputs.extend(self.engine.step().unwrap());
+
+        let (mut trace, logits) = {
+            let mut probe = self.probe.borrow_mut();
+            (
+                probe.batch.take().unwrap(),
+                std::mem::take(&mut probe.logits),
+            )
+        };
+        let scheduler = self.engine.scheduler();
+        trace.free_gpu_blocks = scheduler.block_manager().get_num_free_gpu_blocks();
+
+        // every token that went through the model has exactly one KV slot,
+        // and all allocated blocks are used by some sequence (possibly shared by forks)
+        let seq_mgr = self.engine.tmodel.sequence_manager();
+        let mut used_blocks = HashSet::default();
+        scheduler.for_each_seq(|seq| {
+            if seq.sched_phase == SchedulingPhase::Running {
+                let slots = seq_mgr
+                    .get_gpu_allocator()
+                    .get_block_idxes(seq.seq_id, seq.num_kv_computed)
+                    .unwrap();
+                used_blocks.extend(slots.iter().map(|slot| slot / BLOCK_SIZE));
+            }
+        });
+        assert!(trace.free_gpu_blocks == self.num_gpu_blocks - used_blocks.len());
+
+        scheduler.for_each_sg(|sg| {
+            let phase = sg.seqs[0].sched_phase;
+            if running.contains(&sg.request_id)
+                && (phase == SchedulingPhase::Waiting || phase == SchedulingPhase::Swapped)
+            {
+                trace.preempted.push(sg.request_id.clone());
+            }
+            if sg.is_finished() && trace.scheduled.contains(&sg.request_id) {
+                trace.finished.push(sg.request_id.clone());
+            }
+        });
+
+        let res = logits
+            .into_iter()
+            .map(|(seq_id, logits)| (self.seq_requests[&seq_id].clone(), logits))
+            .collect();
+        (res, trace)
+    }
+
+    pub fn all_finished(&self) -> bool {
+        let mut r = true;
+        self.engine
+            .scheduler()
+            .for_each_seq(|seq| r &= seq.is_finished());
+        r
+    }
+
+    /// The final outputs streamed so far.
+    pub fn final_outputs(&self) -> impl Iterator<Item = &RequestOutput> {
+        self.request_outputs.iter().filter(|o| o.is_final)
+    }
+
+    /// Usage of every request, whether its final output went out or not.
+    pub fn usage(&self) -> HashMap<String, TokenUsage> {
+        let mut usage = self
+            .final_outputs()
+            .map(|o| (o.request_id.clone(), o.usage.clone()))
+            .collect::<HashMap<_, _>>();
+        self.engine.scheduler().for_each_sg(|sg| {
+            usage.insert(sg.request_id.clone(), sg.usage.clone());
+        });
+        usage
+    }
+
+    /// Run everything to completion, returning generated tokens and finish reason
+    /// of the first sequence of each request, sorted by request id.
+    pub fn run_to_completion(&mut self) -> Vec<(String, Vec<Token>, Option<FinishReason>)> {
+        while !self.all_finished() {
+            self.step();
+        }
+        let mut outputs = self
+            .final_outputs()
+            .map(|o| {
+                let seq = &o.seq_outputs[0];
+                (
+                    o.request_id.clone(),
+                    seq.output_tokens.clone(),
+                    seq.finish_reason,
+                )
+            })
+            .collect::<Vec<_>>();
+        self.engine.scheduler().for_each_sg(|sg| {
+            let seq = &sg.seqs[0];
+            let gen = seq.tokens()[seq.prompt_len..].to_vec();
+            outputs.push((sg.request_id.clone(), gen, seq.finish_reason()));
+        });
+        outputs.sort_by(|a, b| a.0.cmp(&b.0));
+        outputs
+    }
+}
+
+/// One token per byte.
+fn byte_trie() -> TokTrie {
+    let words = (0..VOCAB_SIZE).map(|b| vec![b as u8]).collect::<Vec<_>>();
+    let info = TokRxInfo {
+        vocab_size: VOCAB_SIZE as u32,
+        tok_eos: 0,
+    };
+    TokTrie::from(&info, &words)
+}
+
+/// The tokenizer of byte_trie(): sentencepiece-style byte fallback pieces only.
+fn byte_tokenizer() -> Tokenizer {
+    let vocab = (0..VOCAB_SIZE)
+        .map(|b| (format!("<0x{b:02X}>"), json!(b)))
+        .collect::<serde_json::Map<_, _>>();
+    let spec = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": null,
+        "post_processor": null,
+        "decoder": {
+            "type": "Sequence",
+            "decoders": [{ "type": "ByteFallback" }, { "type": "Fuse" }]
+        },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": true,
+            "vocab": vocab,
+            "merges": []
+        }
+    });
+    Tokenizer::from_bytes(spec.to_string()).unwrap()
+}
+
+/// An RllmEngine running the tiny model, with byte_tokenizer(); see also TestEngine.
+fn tiny_engine(config: RllmConfig<TModel>, seed: i64) -> RllmEngine<TModel> {
+    let cache_size = CacheSize {
+        gpu: NUM_GPU_BLOCKS,
+        cpu: 8,
+    };
+    tiny_engine_with(config, seed, byte_tokenizer(), cache_size)
+}
+
+fn tiny_engine_with(
+    config: RllmConfig<TModel>,
+    seed: i64,
+    tokenizer: Tokenizer,
+    cache_size: CacheSize,
+) -> RllmEngine<TModel> {
+    let model = tiny_model(&config, seed);
+    engine_with_model(config, tokenizer, cache_size, 0.0, model)
+}
+
+fn engine_with_model(
+    config: RllmConfig<TModel>,
+    tokenizer: Tokenizer,
+    cache_size: CacheSize,
+    watermark: f32,
+    model: Box<dyn TModelInner>,
+) -> RllmEngine<TModel> {
+    let config = Arc::new(config);
+    let cache_engine = CacheEngine::new(config.clone(), &cache_size);
+    let block_mgr = BlockSpaceManager::new(BLOCK_SIZE, &cache_size, watermark, &config);
+    let seq_mgr = Arc::new(block_mgr.build_seq_mgr());
+    let tmodel = TModel::new(config.clone(), cache_engine, seq_mgr, model);
+    RllmEngine::from_parts(
+        config.meta.id.clone(),
+        tokenizer,
+        byte_trie(),
+        tmodel,
+        block_mgr,
+        config,
+    )
+}
+
+pub(super) fn prompt(len: usize, off: usize) -> Vec<Token> {
+    (0..len)
+        .map(|i| ((i * 7 + off) % VOCAB_SIZE) as Token)
+        .collect()
+}
+
+#[test]
+fn cpu_prefill_and_decode() {
+    let mut engine = TestEngine::new(MODEL_SEED);
+    engine.add_prompt("a", &prompt(20, 1), 11);
+    engine.add_prompt("b", &prompt(5, 3), 11);
+
+    // prefill
+    let res = engine.step();
+    assert!(res.len() == 2);
+
+    // 10 decode steps
+    for _ in 0..10 {
+        let res = engine.step();
+        assert!(res.len() == 2);
+    }
+
+    assert!(engine.all_finished());
+}
+
+#[test]
+fn cpu_determinism() {
+    let gen = || {
+        let mut engine = TestEngine::new(MODEL_SEED);
+        engine.add_prompt("a", &prompt(20, 1), 11);
+        engine.add_prompt("b", &prompt(5, 3), 11);
+        engine.run_to_completion()
+    };
+    let r1 = gen();
+    let r2 = gen();
+    assert!(r1.len() == 2);
+    assert!(r1 == r2);
+}
+
+#[test]
+fn cpu_deterministic_sampling() {
+    let gen = |seed: Option<u64>| {
+        let mut config = tiny_config();
+        config.scheduler.deterministic_seed = seed;
+        let mut engine = TestEngine::with_config(config, MODEL_SEED);
+        engine.temperature = 1.5;
+        engine.add_prompt("a", &prompt(20, 1), 12);
+        engine.add_prompt("b", &prompt(5, 3), 12);
+        engine.add_prompt("c", &prompt(9, 5), 12);
+        engine.run_to_completion()
+    };
+    let r1 = gen(Some(7));
+    assert!(r1.len() == 3);
+    assert!(r1 == gen(Some(7)));
+    assert!(r1 != gen(Some(8)));
+}
+
+#[test]
+fn cpu_token_bias_shifts_sampling() {
+    // 8 allowed tokens; the bias is composed with the mask the way aicirt does it
+    // for aici_host_return_token_bias()
+    let engine = TestEngine::new(MODEL_SEED);
+    let allowed = 0..8;
+    let counts = |bias: f32| {
+        let mut mask = vec![LOGIT_BIAS_DISALLOW; VOCAB_SIZE];
+        for t in allowed.clone() {
+            mask[t] = LOGIT_BIAS_ALLOW;
+        }
+        // as encoded by aici_abi::return_token_bias(); the bias on 12 must not
+        // re-enable it, and the out-of-range and NaN entries are dropped
+        let entries = [
+            (3, bias),
+            (12, 50.0),
+            (VOCAB_SIZE as u32, 1.0),
+            (5, f32::NAN),
+        ];
+        let words: Vec<u32> = entries
+            .iter()
+            .flat_map(|(t, b)| [*t, b.to_bits()])
+            .collect();
+        let (token_bias, invalid) = decode_token_bias(&words, VOCAB_SIZE as u32);
+        assert!(token_bias == [(3, bias), (12, 50.0)]);
+        assert!(invalid.len() == 2);
+        assert!(add_token_bias(&mut mask, &token_bias) == 1);
+        let bias = TchAiciBias {
+            vocab_size: VOCAB_SIZE,
+            bias: Some(Tensor::from_slice(&mask).reshape(&[1, VOCAB_SIZE as i64])),
+        };
+        let mut counts = vec![0; VOCAB_SIZE];
+        for seed in 0..400 {
+            let mut logits = Tensor::zeros(&[VOCAB_SIZE as i64], (DType::Float, Device::Cpu));
+            bias.apply(&mut logits, 0);
+            let mut sampling_params = SamplingParams::default();
+            sampling_params.temperature = 1.0;
+            let mut state = LogitsProcessor::new(&sampling_params, Some(seed));
+            let t = engine.tmodel.sample(&mut state, &logits).unwrap() as usize;
+            counts[t] += 1;
+        }
+        counts
+    };
+    let plain = counts(0.0);
+    let boosted = counts(2.0);
+    let discouraged = counts(-2.0);
+    for c in [&plain, &boosted, &discouraged] {
+        assert!(c.iter().sum::<usize>() == 400);
+        assert!(c[8..].iter().all(|n| *n == 0), "{c:?}");
+    }
+    // uniform over 8 tokens: ~50 each; e^2 boost: ~200; e^-2: ~7
+    assert!(plain[3] > 25 && plain[3] < 80, "{plain:?}");
+    assert!(boosted[3] > 150, "{boosted:?}");
+    assert!(discouraged[3] < 20 && discouraged[3] > 0, "{discouraged:?}");
+}
+
+#[test]
+fn cpu_bad_words() {
+    // the model strongly prefers tokens 10 and 11; ban 10 followed by 11
+    let engine = TestEngine::new(MODEL_SEED);
+    let mut base = vec![0f32; VOCAB_SIZE];
+    base[10] = 6.0;
+    base[11] = 6.0;
+    let base = Tensor::from_slice(&base);
+    let mut sampling_params = SamplingParams::default();
+    sampling_params.temperature = 1.0;
+    sampling_params.bad_words = vec!["\n\x0b".to_string()];
+    sampling_params.logit_bias.insert(12, 5.0);
+    // byte_tokenizer() maps each byte to its own token
+    engine.tokenize_bad_words(&mut sampling_params).unwrap();
+    assert!(sampling_params.bad_words_tokens == [vec![10, 11], vec![32, 10, 11]]);
+
+    let mut num_prefix = 0;
+    let mut num_biased = 0;
+    for seed in 0..100 {
+        let mut state = LogitsProcessor::new(&sampling_params, Some(seed));
+        let mut tokens = prompt(5, seed as usize);
+        for _ in 0..8 {
+            let mut logits = base.copy();
+            let bias = state.sparse_bias(&tokens);
+            engine.tmodel.add_sparse_bias(&mut logits, &bias);
+            let t = engine.tmodel.sample(&mut state, &logits).unwrap();
+            tokens.push(t);
+        }
+        let gen = &tokens[5..];
+        assert!(
+            !gen.windows(2).any(|w| w == [10, 11]),
+            "seed {seed}: {gen:?}"
+        );
+        num_prefix += gen.iter().filter(|t| **t == 10).count();
+        num_biased += gen.iter().filter(|t| **t == 12).count();
+    }
+    // 10 alone is still generated, about a third of the time; 12 about an eighth
+    assert!(num_prefix > 150, "{num_prefix}");
+    assert!(num_biased > 40, "{num_biased}");
+}
+
+#[test]
+fn cpu_prompt_tokens_checked() {
+    let config = tiny_config();
+    let vocab_size = config.meta.vocab_size;
+    let p = prompt(20, 1);
+    assert!(check_prompt_tokens(&p, vocab_size).is_ok());
+    assert!(check_prompt_tokens(&[], vocab_size).is_err());
+    let mut bad = p.clone();
+    bad[7] = vocab_size as Token;
+    let err = check_prompt_tokens(&bad, vocab_size).unwrap_err();
+    assert!(err.to_string().contains("at 7"), "{err}");
+}
+
+#[test]
+fn cpu_sampling_tokens_checked() {
+    let mut engine = tiny_engine(tiny_config(), MODEL_SEED);
+    let last = VOCAB_SIZE as u32 - 1;
+    let mut params = SamplingParams::default();
+    params.logit_bias.insert(last, 1.0);
+    params.suppress_tokens = Some(vec![last]);
+    params.hide_tokens = Some(vec![last]);
+    assert!(engine
+        .add_request("a".to_string(), prompt(8, 1).into(), params.clone())
+        .is_ok());
+
+    let cases: &[(&str, fn(&mut SamplingParams))] = &[
+        ("logit_bias", |p| {
+            p.logit_bias.insert(VOCAB_SIZE as u32 + 3, 1.0);
+        }),
+        ("suppress_tokens", |p| {
+            p.suppress_tokens = Some(vec![1, VOCAB_SIZE as u32])
+        }),
+        ("hide_tokens", |p| {
+            p.hide_tokens = Some(vec![VOCAB_SIZE as u32])
+        }),
+    ];
+    for (name, update) in cases {
+        let mut params = params.clone();
+        update(&mut params);
+        let err = engine
+            .add_request("b".to_string(), prompt(8, 1).into(), params)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("{name} token out of range")),
+            "{err}"
+        );
+    }
+    assert!(engine.num_pending_requests() == 1);
+}
+
+#[test]
+fn cpu_added_tokens_special_only() {
+    // <0x00> is EOS in byte_trie(); only special added tokens are filtered by default
+    let mut tokenizer = byte_tokenizer();
+    tokenizer.add_special_tokens(&[
+        AddedToken::from("<0x00>", true),
+        AddedToken::from("<0xC8>", true),
+    ]);
+    tokenizer.add_tokens(&[AddedToken::from("<0xC9>", false)]);
+    let cache_size = CacheSize {
+        gpu: NUM_GPU_BLOCKS,
+        cpu: 8,
+    };
+    let engine = tiny_engine_with(tiny_config(), MODEL_SEED, tokenizer, cache_size);
+    assert!(engine.added_tokens == [0xC8], "{:?}", engine.added_tokens);
+}
+
+#[test]
+fn cpu_request_output() {
+    let mut config = tiny_config();
+    config.scheduler.deterministic_seed = Some(5);
+    let mut engine = TestEngine::with_config(config, MODEL_SEED);
+    engine.temperature = 1.0;
+    engine.add_prompt("a", &prompt(20, 1), 12);
+    engine.add_prompt("b", &prompt(5, 3), 7);
+    let generated = engine.run_to_completion();
+    // the finished groups are dropped, with their final output, on the next schedule
+    engine.step_traced();
+
+    for (request_id, tokens, finish_reason) in generated {
+        let outputs = engine
+            .request_outputs
+            .iter()
+            .filter(|o| o.request_id == request_id)
+            .collect::<Vec<_>>();
+        let last = outputs.last().unwrap();
+        assert!(last.is_final && last.timing.is_some());
+        assert!(outputs.iter().filter(|o| o.is_final).count() == 1);
+        assert!(outputs[..outputs.len() - 1]
+            .iter()
+            .all(|o| o.outputs.is_empty() && o.timing.is_none()));
+
+        assert!(last.outputs.len() == 1);
+        let out = &last.outputs[0];
+        assert!(out.token_ids == tokens);
+        assert!(out.token_ids.len() == last.usage.gen_tokens);
+        assert!(out.finish_reason == finish_reason);
+        assert!(out.cumulative_logprob < 0.0 && out.cumulative_logprob.is_finite());
+
+        let streamed = outputs
+            .iter()
+            .flat_map(|o| o.seq_outputs.iter().map(|s| s.new_text.as_str()))
+            .collect::<String>();
+        assert!(streamed == out.text, "{streamed:?} != {:?}", out.text);
+        let stream_tokens = outputs
+            .iter()
+            .flat_map(|o| {
+                o.seq_outputs
+                    .iter()
+                    .flat_map(|s| s.new_output_tokens.clone())
+            })
+            .collect::<Vec<_>>();
+        assert!(stream_tokens == tokens);
+    }
+}
+
+#[test]
+fn request_output_orders_by_logprob() {
+    let trie = byte_trie();
+    let mut seqs = Vec::new();
+    for (index, logprob) in [-3.5f32, -1.25, -7.0].into_iter().enumerate() {
+        let mut seq = Sequence::new(SeqId(index + 1), &[b'>' as Token]);
+        seq.index = index;
+        seq.append_tokens(&[b'a' as Token + index as Token]);
+        seq.cumulative_logprob = logprob;
+        seq.sched_phase = SchedulingPhase::Finished(FinishReason::MaxTokensReached);
+        seqs.push(seq);
+    }
+    let mut sg = SequenceGroup {
+        request_id: "a".to_string(),
+        prompt: String::new(),
+        seqs,
+        sampling_params: SamplingParams::default(),
+        deadlock_steps: 0,
+        arrival_time: Instant::now(),
+        logits_processor: LogitsProcessor::new(&SamplingParams::default(), Some(1)),
+        max_index: 2,
+        usage: TokenUsage::default(),
+        timing: SeqGroupTiming::default(),
+        coalesced_into: None,
+        deadline: None,
+    };
+    let out = sg.request_output(&trie, true);
+    let order = out.outputs.iter().map(|o| o.index).collect::<Vec<_>>();
+    assert!(order == vec![1, 0, 2], "{order:?}");
+    assert!(out.outputs[0].text == "b");
+    assert!(out.outputs[2].token_ids == vec![b'c' as Token]);
+}
+
+#[test]
+fn cpu_decode_matches_prefill() {
+    // logits computed incrementally through the paged cache should match
+    // a single prefill over the same tokens
+    let mut engine = TestEngine::new(MODEL_SEED);
+    let p = prompt(20, 1);
+    engine.add_prompt("a", &p, 4);
+    let mut last = None;
+    let mut tokens = p.clone();
+    for _ in 0..4 {
+        let res = engine.step();
+        let (_, logits) = &res[0];
+        tokens.push(logits.argmax(0, false).int64_value(&[]) as Token);
+        last = Some(logits.shallow_clone());
+    }
+    tokens.pop();
+
+    let mut engine2 = TestEngine::new(MODEL_SEED);
+    engine2.add_prompt("a", &tokens, 1);
+    let res = engine2.step();
+    check_all_close(&res[0].1, &last.unwrap(), 1e-4);
+}
+
+#[test]
+fn cpu_failed_seq_only_fails_its_group() {
+    let mut engine = TestEngine::new(MODEL_SEED);
+    engine.add_prompt("a", &prompt(20, 1), 5);
+    engine.add_prompt("b", &prompt(5, 3), 5);
+    engine.step();
+
+    // claim more KV than "b" has tokens (but still within its blocks)
+    engine.scheduler().for_each_sg(|sg| {
+        if sg.request_id == "b" {
+            sg.seqs[0].num_kv_computed = 10;
+        }
+    });
+
+    let res = engine.step();
+    assert!(res.len() == 1);
+    assert!(res[0].0 == "a");
+
+    let outputs = engine.run_to_completion();
+    assert!(outputs.len() == 2);
+    assert!(outputs[0].0 == "a");
+    assert!(outputs[0].1.len() == 5);
+    assert!(outputs[0].2 == Some(FinishReason::MaxTokensReached));
+    assert!(outputs[1].0 == "b");
+    assert!(outputs[1].2 == Some(FinishReason::Failed));
+}
+
+/// An entry with a KV slot outside the cache, or a position past max_model_len,
+/// is dropped and reported, instead of failing the whole batch.
+#[test]
+fn batch_info_drops_invalid_entries() {
+    let config = Arc::new(tiny_config());
+    let mut cache_engine = CacheEngine::new(
+        config.clone(),
+        &CacheSize {
+            gpu: NUM_GPU_BLOCKS,
+            cpu: 0,
+        },
+    );
+    let num_slots = NUM_GPU_BLOCKS * BLOCK_SIZE;
+    let max_model_len = config.scheduler.max_model_len;
+    let mut builder = BatchInfoBuilder::new(config.clone());
+    builder.push_entry(BatchEntry {
+        seq_id: 1,
+        query_pos_token: vec![(0, 1), (1, 2)],
+        kv_slots: vec![0, 1],
+    });
+    builder.push_entry(BatchEntry {
+        seq_id: 2,
+        query_pos_token: vec![(0, 1)],
+        kv_slots: vec![num_slots],
+    });
+    builder.push_entry(BatchEntry {
+        seq_id: 3,
+        query_pos_token: vec![(max_model_len, 1)],
+        kv_slots: vec![BLOCK_SIZE],
+    });
+    assert!(builder.finish(1, cache_engine.get_cache_iface()).is_err());
+
+    let mut failed = Vec::new();
+    builder.drop_invalid(num_slots, &mut failed);
+    let failed = failed
+        .iter()
+        .map(|e| e.seq_id().unwrap())
+        .collect::<Vec<_>>();
+    assert!(failed == vec![SeqId(2), SeqId(3)]);
+    let info = builder.finish(1, cache_engine.get_cache_iface()).unwrap();
+    assert!(info.real_batch_size == 1);
+    assert!(info.seq_id_to_idx.contains_key(&1));
+}
+
+#[test]
+fn batch_info_padding_respects_limits() {
+    let padded_size = |buckets: &str, max_num_seqs: usize, max_batch_tokens: usize| {
+        let mut config = tiny_config();
+        config.model.cache.decode_pad_buckets = CacheConfig::parse_pad_buckets(buckets).unwrap();
+        config.scheduler.max_num_seqs = max_num_seqs;
+        config.model.cache.attn_limits.max_batch_tokens = max_batch_tokens;
+        let config = Arc::new(config);
+        let mut cache_engine = CacheEngine::new(
+            config.clone(),
+            &CacheSize {
+                gpu: NUM_GPU_BLOCKS,
+                cpu: 0,
+            },
+        );
+        let mut builder = BatchInfoBuilder::new(config.clone());
+        for seq_id in 0..3 {
+            builder.push_entry(BatchEntry {
+                seq_id,
+                query_pos_token: vec![(0, 1)],
+                kv_slots: vec![seq_id * BLOCK_SIZE],
+            });
+        }
+        builder.pad_decode(cache_engine.scratch_slot());
+        let info = builder.finish(1, cache_engine.get_cache_iface()).unwrap();
+        assert!(info.real_batch_size == 3);
+        info.batch_size()
+    };
+    assert!(padded_size("4", 8, 128) == 4);
+    assert!(padded_size("8", 8, 128) == 8);
+    // padding would go over max_num_seqs or the token limit
+    assert!(padded_size("8", 4, 128) == 3);
+    assert!(padded_size("4", 8, 3) == 3);
+}
+
+/// Sets a few logits of one sequence to NaN.
+struct NanInjector {
+    inner: Box<dyn TModelInner>,
+    seq_id: Arc<Mutex<Option<usize>>>,
+}
+
+impl TModelInner for NanInjector {
+    fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let logits = self.inner.forward(batch_info);
+        let seq_id = *self.seq_id.lock().unwrap();
+        if let Some(idx) = seq_id.and_then(|id| batch_info.seq_id_to_idx.get(&id)) {
+            let _ = logits.i((*idx as i64, 0..3)).fill_(f64::NAN);
+        }
+        logits
+    }
+}
+
+#[test]
+fn cpu_nonfinite_logits() {
+    set_setting("check_logits", 1.0).unwrap();
+    let config = tiny_config();
+    let target = Arc::new(Mutex::new(None));
+    let model = NanInjector {
+        inner: tiny_model(&config, MODEL_SEED),
+        seq_id: target.clone(),
+    };
+    let cache_size = CacheSize {
+        gpu: NUM_GPU_BLOCKS,
+        cpu: 8,
+    };
+    let mut engine = TestEngine::with_model(config, cache_size, 0.0, Box::new(model));
+    engine.add_prompt("a", &prompt(20, 1), 5);
+    engine.add_prompt("b", &prompt(5, 3), 5);
+    engine.step();
+    engine.step();
+
+    let mut seq_id = 0;
+    engine.scheduler().for_each_sg(|sg| {
+        if sg.request_id == "b" {
+            seq_id = sg.seqs[0].seq_id.to_num();
+        }
+    });
+    *target.lock().unwrap() = Some(seq_id);
+    let res = engine.step();
+    assert!(res.len() == 1);
+    assert!(res[0].0 == "a");
+
+    let outputs = engine.run_to_completion();
+    assert!(outputs[0].0 == "a");
+    assert!(outputs[0].1.len() == 5);
+    assert!(outputs[0].2 == Some(FinishReason::MaxTokensReached));
+    assert!(outputs[1].0 == "b");
+    assert!(outputs[1].1.len() == 2);
+    assert!(outputs[1].2 == Some(FinishReason::Failed));
+
+    let error = engine
+        .request_outputs
+        .iter()
+        .filter(|o| o.request_id == "b")
+        .flat_map(|o| o.seq_outputs[0].aici_logs.iter())
+        .last()
+        .unwrap()
+        .error
+        .clone();
+    assert!(error.contains("3 NaN, 0 Inf in step 3"), "{error}");
+
+    let path = format!("rllm-nonfinite-step-3-seq-{seq_id}.safetensors");
+    assert!(error.contains(&path), "{error}");
+    let tensors = Tensor::read_safetensors(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let logits = &tensors.iter().find(|(n, _)| n == "logits").unwrap().1;
+    assert!(logits.size() == [VOCAB_SIZE as i64]);
+    assert!(logits.isnan().sum(DType::Int64).int64_value(&[]) == 3);
+    assert!(tensors.iter().any(|(n, _)| n == "tokens"));
+    set_setting("check_logits", 0.0).unwrap();
+}
+
+/// Simulates a GPU: forward() returns right away, and the logits are only
+/// ready `latency` later.
+struct SlowForward {
+    inner: Box<dyn TModelInner>,
+    latency: Duration,
+    ready_at: Mutex<Instant>,
+}
+
+impl TModelInner for SlowForward {
+    fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let t0 = Instant::now();
+        let logits = self.inner.forward(batch_info);
+        *self.ready_at.lock().unwrap() = t0 + self.latency;
+        logits
+    }
+
+    fn synchronize(&self) {
+        let ready_at = *self.ready_at.lock().unwrap();
+        std::thread::sleep(ready_at.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// With pipeline_outputs, every request streams the same outputs, one step later.
+#[test]
+fn cpu_pipeline_outputs() {
+    let run = |pipeline: bool| {
+        let mut config = tiny_config();
+        config.scheduler.deterministic_seed = Some(1);
+        config.scheduler.pipeline_outputs = pipeline;
+        let mut engine = TestEngine::with_config(config, MODEL_SEED);
+        engine.temperature = 0.8;
+        engine.add_prompt("a", &prompt(20, 1), 6);
+        engine.add_prompt("b", &prompt(5, 3), 3);
+        engine.add_prompt("c", &prompt(9, 5), 8);
+        engine.step();
+        let first_step = engine.request_outputs.len();
+        let generated = engine.run_to_completion();
+        // drop the finished requests, which streams their last outputs
+        engine.step_traced();
+        let streamed = engine
+            .request_outputs
+            .iter()
+            .map(|r| {
+                let seqs = r
+                    .seq_outputs
+                    .iter()
+                    .map(|s| (s.new_output_tokens.clone(), s.finish_reason))
+                    .collect::<Vec<_>>();
+                (r.request_id.clone(), seqs, r.is_final)
+            })
+            .collect::<Vec<_>>();
+        (first_step, generated, streamed)
+    };
+
+    let (first_step, generated, streamed) = run(false);
+    let (first_step_p, generated_p, streamed_p) = run(true);
+    assert!(first_step == 3);
+    assert!(first_step_p == 0);
+    assert!(generated == generated_p);
+    for id in ["a", "b", "c"] {
+        let of_request = |s: &Vec<(String, _, bool)>| {
+            s.iter().filter(|r| r.0 == id).cloned().collect::<Vec<_>>()
+        };
+        let expected = of_request(&streamed);
+        assert!(expected.last().unwrap().2);
+        assert!(expected == of_request(&streamed_p), "{id}");
+    }
+}
+
+#[test]
+fn cpu_generate_pipelined() {
+    let generate = |pipeline: bool| {
+        let mut config = tiny_config();
+        config.scheduler.pipeline_outputs = pipeline;
+        let mut engine = tiny_engine(config, MODEL_SEED);
+        let params = SamplingParams {
+            max_tokens: 6,
+            ignore_eos: true,
+            ..SamplingParams::default()
+        };
+        let text = engine.generate(prompt(20, 1).into(), params).unwrap();
+        assert!(engine.num_pending_requests() == 0);
+        text
+    };
+    let text = generate(false);
+    assert!(!text.is_empty());
+    assert!(generate(true) == text);
+}
+
+/// Bans `banned`, and once a sequence has sampled `at` tokens, backtracks `n`
+/// of them and appends `replacement`.
+struct BacktrackOnce {
+    banned: Option<Token>,
+    at: usize,
+    n: usize,
+    replacement: Vec<Token>,
+    num_sampled: HashMap<SeqId, usize>,
+}
+
+impl StepHooks<TModel> for BacktrackOnce {
+    fn pre_sample(
+        &mut self,
+        _step_no: usize,
+        _seq: &Sequence,
+        _logits: &Tensor,
+    ) -> Vec<(Token, f32)> {
+        self.banned
+            .iter()
+            .map(|t| (*t, f32::NEG_INFINITY))
+            .collect()
+    }
+
+    fn post_sample(&mut self, _step_no: usize, sampled: &[SampledToken]) -> Vec<SeqCommand> {
+        sampled
+            .iter()
+            .map(|s| {
+                let num = self.num_sampled.entry(s.seq_id).or_insert(0);
+                *num += 1;
+                if *num == self.at {
+                    SeqCommand::Backtrack {
+                        n: self.n,
+                        replacement_tokens: self.replacement.clone(),
+                    }
+                } else {
+                    SeqCommand::Continue
+                }
+            })
+            .collect()
+    }
+}
+
+/// StepHooks can bias sampling, and backtrack sequences; the KV cache follows
+/// (checked by step_traced()), and generation continues as if the spliced
+/// tokens were the prompt.
+#[test]
+fn cpu_step_hooks() {
+    let run = |p: &[Token], max_tokens: usize, hooks: Option<BacktrackOnce>| {
+        let mut engine = TestEngine::new(MODEL_SEED);
+        if let Some(hooks) = hooks {
+            engine.set_hooks(hooks);
+        }
+        engine.add_prompt("a", p, max_tokens);
+        engine.run_to_completion().pop().unwrap().1
+    };
+    let hooks = |banned: Option<Token>, replacement: Vec<Token>| {
+        Some(BacktrackOnce {
+            banned,
+            at: 4,
+            n: 2,
+            replacement,
+            num_sampled: HashMap::default(),
+        })
+    };
+
+    let p = prompt(20, 1);
+    let expected = run(&p, 8, None);
+    assert!(expected.len() == 8);
+
+    // backtracking without replacement re-generates the same tokens
+    assert!(run(&p, 8, hooks(None, vec![])) == expected);
+
+    let replacement = (expected[2] + 1) % VOCAB_SIZE as Token;
+    let spliced = [&p[..], &expected[..2], &[replacement]].concat();
+    let continued = run(&spliced, 5, None);
+    assert!(
+        run(&p, 8, hooks(None, vec![replacement]))
+            == spliced[p.len()..]
+                .iter()
+                .chain(&continued)
+                .copied()
+                .collect::<Vec<_>>()
+    );
+
+    let banned = run(&p, 8, hooks(Some(expected[0]), vec![]));
+    assert!(banned.len() == 8);
+    assert!(!banned.contains(&expected[0]));
+}
+
+/// Logs what happens to it to `log`: bans `ban`, stops the sequence after
+/// `stop_after` tokens, and copies itself on fork if `copy`.
+struct ScriptedController {
+    name: String,
+    ban: Token,
+    stop_after: usize,
+    copy: bool,
+    num_tokens: usize,
+    log: Rc<RefCell<Vec<String>>>,
+}
+
+impl ScriptedController {
+    fn log(&self, event: String) {
+        self.log.borrow_mut().push(event);
+    }
+}
+
+impl SeqController<TModel> for ScriptedController {
+    fn mask(&mut self, _seq: &Sequence, _logits: &Tensor) -> Vec<(Token, f32)> {
+        vec![(self.ban, f32::NEG_INFINITY)]
+    }
+
+    fn on_token(&mut self, seq_id: SeqId, token: Token) -> SeqCommand {
+        self.log(format!("token {} {seq_id} {token}", self.name));
+        self.num_tokens += 1;
+        if self.num_tokens == self.stop_after {
+            SeqCommand::Finish {
+                reason: FinishReason::AiciStop,
+            }
+        } else {
+            SeqCommand::Continue
+        }
+    }
+
+    fn fork(&self) -> Option<Box<dyn SeqController<TModel>>> {
+        if !self.copy {
+            return None;
+        }
+        self.log(format!("copy {}", self.name));
+        Some(Box::new(ScriptedController {
+            name: self.name.clone(),
+            log: self.log.clone(),
+            ..*self
+        }))
+    }
+}
+
+impl Drop for ScriptedController {
+    fn drop(&mut self) {
+        self.log(format!("drop {}", self.name));
+    }
+}
+
+/// ControllerHooks with ScriptedController registered as "scripted", logging to `log`.
+fn scripted_hooks(log: &Rc<RefCell<Vec<String>>>) -> ControllerHooks<TModel> {
+    let log2 = log.clone();
+    let factory = move |arg: &serde_json::Value| {
+        let ctrl = ScriptedController {
+            name: arg["name"].as_str().unwrap().to_string(),
+            ban: arg["ban"].as_u64().unwrap_or(0) as Token,
+            stop_after: arg["stop_after"].as_u64().unwrap_or(1000) as usize,
+            copy: arg["copy"].as_bool().unwrap_or(false),
+            num_tokens: 0,
+            log: log2.clone(),
+        };
+        ctrl.log(format!("new {}", ctrl.name));
+        let ctrl: Box<dyn SeqController<TModel>> = Box::new(ctrl);
+        Ok::<_, anyhow::Error>(ctrl)
+    };
+    let mut hooks = ControllerHooks::<TModel>::new();
+    hooks.register("scripted", Box::new(factory));
+    hooks
+}
+
+/// Controllers attached to requests are created at admission, get the tokens of
+/// their sequences (and of forks, shared or copied), and are dropped with their
+/// request, whether it finished or was aborted before it ran.
+#[test]
+fn cpu_controller_lifecycle() {
+    let log = Rc::new(RefCell::new(Vec::<String>::new()));
+    let hooks = scripted_hooks(&log);
+
+    let p = prompt(20, 1);
+    let mut reference = TestEngine::new(MODEL_SEED);
+    reference.add_prompt("a", &p, 6);
+    let expected = reference.run_to_completion().pop().unwrap().1;
+
+    let mut engine = TestEngine::new(MODEL_SEED);
+    engine.set_hooks(hooks);
+    let spec = |arg: serde_json::Value| ControllerSpec {
+        module_id: "scripted".to_string(),
+        module_arg: arg,
+    };
+    let unknown = ControllerSpec {
+        module_id: "unknown".to_string(),
+        module_arg: serde_json::Value::Null,
+    };
+    assert!(engine
+        .add_prompt_with_controller("x", &p, 6, &unknown)
+        .is_err());
+
+    let ban = expected[0];
+    engine
+        .add_prompt_with_controller(
+            "a",
+            &p,
+            6,
+            &spec(json!({ "name": "a", "ban": ban, "stop_after": 3 })),
+        )
+        .unwrap();
+    engine.add_prompt("b", &p, 6);
+    engine
+        .add_prompt_with_controller("c", &prompt(8, 2), 6, &spec(json!({ "name": "c" })))
+        .unwrap();
+    engine
+        .add_prompt_with_controller("d", &prompt(9, 3), 4, &spec(json!({ "name": "d" })))
+        .unwrap();
+    engine
+        .add_prompt_with_controller(
+            "e",
+            &prompt(10, 4),
+            4,
+            &spec(json!({ "name": "e", "copy": true })),
+        )
+        .unwrap();
+    // aborted before it ever ran
+    engine.abort_request("c");
+    engine.step();
+
+    let seq_of = |engine: &TestEngine, request_id: &str| {
+        let mut res = None;
+        engine.scheduler().for_each_sg(|sg| {
+            if sg.request_id == request_id {
+                res = Some(sg.seqs[0].seq_id);
+            }
+        });
+        res.unwrap()
+    };
+    let d = seq_of(&engine, "d");
+    let d_fork = engine.fork(d, 1)[0];
+    let e = seq_of(&engine, "e");
+    let e_fork = engine.fork(e, 1)[0];
+    assert!(engine.fork_sequence(SeqId(10_000), 1).is_err());
+
+    let res = engine.run_to_completion();
+    engine.step_traced();
+    let log = log.borrow();
+    let count = |prefix: &str| log.iter().filter(|l| l.starts_with(prefix)).count();
+
+    // the controller banned a token, and stopped the sequence
+    let (_, tokens, reason) = &res[0];
+    assert!(tokens.len() == 3 && !tokens.contains(&ban));
+    assert!(*reason == Some(FinishReason::AiciStop));
+    // no controller, no change
+    assert!(res[1].1 == expected);
+    assert!(res[2].1.is_empty());
+
+    assert!(count("token c") == 0);
+    // a shared instance sees the tokens of both sequences
+    assert!(count(&format!("token d {d} ")) > 0);
+    assert!(count(&format!("token d {d_fork} ")) > 0);
+    assert!(count("copy d") == 0);
+    assert!(count("copy e") == 1);
+    assert!(count(&format!("token e {e_fork} ")) > 0);
+
+    // one instance per request, one more for the copy; all are gone
+    assert!(count("new ") == 4);
+    assert!(count("drop ") == 5, "{log:?}");
+    for name in ["a", "c", "d"] {
+        assert!(count(&format!("drop {name}")) == 1);
+    }
+    assert!(count("drop e") == 2);
+}
+
+#[test]
+fn cpu_request_timing() {
+    let mut engine = tiny_engine(tiny_config(), MODEL_SEED);
+    let params = SamplingParams {
+        max_tokens: 4,
+        ..SamplingParams::default()
+    };
+    engine
+        .add_request("a".to_string(), prompt(8, 1).into(), params)
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    let mut outputs = Vec::new();
+    while engine.num_pending_requests() > 0 {
+        outputs.extend(engine.step().unwrap());
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let last = outputs.last().unwrap();
+    assert!(last.is_final);
+    assert!(outputs.iter().all(|o| o.is_final == o.timing.is_some()));
+    let timing = last.timing.clone().unwrap();
+
+    // the sleep before the first step counts as queueing time
+    let queue_ms = timing.queue_ms.unwrap();
+    let ttft_ms = timing.ttft_ms.unwrap();
+    assert!(queue_ms >= 50.0);
+    assert!(ttft_ms >= queue_ms);
+    assert!(ttft_ms < 50.0 + 1000.0);
+
+    // 4 tokens, so 3 gaps, each at least the sleep in the step loop
+    let itl = &timing.inter_token;
+    assert!(itl.count == 3);
+    assert!(itl.p50_ms >= 20.0);
+    assert!(itl.max_ms >= itl.p50_ms);
+    assert!(itl.max_ms < 20.0 + 1000.0);
+    assert!(timing.total_ms >= ttft_ms + 3.0 * 20.0);
+
+    // and the engine-wide latencies include the request
+    let stats = engine.get_stats();
+    assert!(stats.ttft.count == 1);
+    assert!(stats.inter_token.count == 3);
+}
+
+#[test]
+fn cpu_decode_padding_is_transparent() {
+    let mut cache = CacheConfig::new(BLOCK_SIZE, 0.9, 0).unwrap();
+    cache.decode_pad_buckets = CacheConfig::parse_pad_buckets("16, 8,16").unwrap();
+    assert!(cache.decode_pad_buckets == vec![8, 16]);
+    assert!(cache.decode_pad_size(3) == 8);
+    assert!(cache.decode_pad_size(9) == 16);
+    assert!(cache.decode_pad_size(17) == 17);
+    assert!(CacheConfig::parse_pad_buckets("8,x").is_err());
+
+    let gen = |buckets: Vec<usize>| {
+        let mut config = tiny_config();
+        config.model.cache.decode_pad_buckets = buckets;
+        let mut engine = TestEngine::with_config(config, MODEL_SEED);
+        // 3, then 2, then 1 sequences decoding
+        engine.add_prompt("a", &prompt(20, 1), 6);
+        engine.add_prompt("b", &prompt(5, 3), 3);
+        engine.add_prompt("c", &prompt(9, 5), 9);
+        engine.run_to_completion()
+    };
+    let plain = gen(vec![]);
+    let padded = gen(CacheConfig::parse_pad_buckets("pow2").unwrap());
+    assert!(plain.len() == 3);
+    assert!(plain == padded);
+}
+
+#[test]
+fn cpu_failure_dump_and_replay() {
+    let config = Arc::new(tiny_config());
+    let cache_size = CacheSize { gpu: 4, cpu: 1 };
+    let mut cache_engine = CacheEngine::new(config.clone(), &cache_size);
+    let num_slots = (cache_size.gpu + 1) * BLOCK_SIZE; // including scratch block
+
+    let p = prompt(3, 1);
+    let mut builder = BatchInfoBuilder::new(config.clone());
+    builder.push_entry(BatchEntry {
+        seq_id: 7,
+        query_pos_token: p.iter().cloned().enumerate().collect(),
+        kv_slots: vec![0, 1, num_slots + 2],
+    });
+    let err = builder
+        .finish(3, cache_engine.get_cache_iface())
+        .unwrap_err();
+    assert!(format!("{err}").contains("out of range"));
+
+    let dir = std::env::temp_dir().join(format!("rllm-failure-test-{}", std::process::id()));
+    let seqs = vec![SeqDump {
+        seq_id: 7,
+        num_kv_computed: 0,
+        tokens: p.clone(),
+    }];
+    builder
+        .dump_failure(&dir, 3, num_slots, &err, &seqs)
+        .unwrap();
+    for f in ["error.txt", "config.json", "seqs.json", "batch.safetensors"] {
+        assert!(dir.join(f).exists(), "missing {f}");
+    }
+
+    let model = tiny_model(&config, MODEL_SEED);
+    let err2 = replay_failure(&config, &dir, Some(model.as_ref())).unwrap_err();
+    assert!(format!("{err2}") == format!("{err}"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cpu_fork_shares_prefix() {
+    let mut engine = TestEngine::new(MODEL_SEED);
+    let p = prompt(20, 1);
+    engine.add_prompt("a", &p, 20);
+    for _ in 0..10 {
+        engine.step();
+    }
+
+    let mut parent = None;
+    engine
+        .scheduler()
+        .for_each_seq(|seq| parent = Some(seq.seq_id));
+    let parent = parent.unwrap();
+
+    let free_before = engine.scheduler().block_manager().get_num_free_gpu_blocks();
+    let forks = engine.fork(parent, 2);
+    assert!(forks.len() == 2);
+    // nothing is copied until the forks write to the shared (partial) last block
+    assert!(engine.scheduler().block_manager().get_num_free_gpu_blocks() == free_before);
+
+    let prompt_tokens = |engine: &TestEngine| {
+        let mut n = 0;
+        engine
+            .scheduler()
+            .for_each_sg(|sg| n = sg.usage.prompt_tokens);
+        n
+    };
+    let before = prompt_tokens(&engine);
+    engine.force(parent, 1);
+    engine.force(forks[0], 2);
+    engine.force(forks[1], 3);
+    let res = engine.step();
+    assert!(res.len() == 3);
+    // only the last token of each sequence went through the model
+    assert!(prompt_tokens(&engine) - before == 3);
+    // at most one copy-on-write block per fork
+    assert!(free_before - engine.scheduler().block_manager().get_num_free_gpu_blocks() <= 2);
+
+    // killing one fork doesn't free the blocks still used by the others;
+    // step() checks block accounting
+    engine.abort_sequence(forks[1]).unwrap();
+
+    let fork_len = p.len() + 10;
+    let mut seqs = Vec::new();
+    while !engine.all_finished() {
+        engine.step();
+    }
+    engine.scheduler().for_each_seq(|seq| {
+        let tokens = (0..seq.get_len())
+            .map(|i| seq.get_token(i))
+            .collect::<Vec<_>>();
+        seqs.push((seq.seq_id, tokens, seq.finish_reason()));
+    });
+    seqs.sort_by_key(|s| s.0.to_num());
+    assert!(seqs.len() == 3);
+    for (_, tokens, _) in &seqs {
+        assert!(tokens[..fork_len] == seqs[0].1[..fork_len]);
+    }
+    assert!(seqs[0].1[fork_len] == 1);
+    assert!(seqs[1].1[fork_len] == 2);
+    assert!(seqs[0].2 == Some(FinishReason::MaxTokensReached));
+    assert!(seqs[1].2 == Some(FinishReason::MaxTokensReached));
+    assert!(seqs[2].2 == Some(FinishReason::Aborted));
+    assert!(seqs[2].1.len() == fork_len + 1);
+}
+
+/// Counts the tokens that go through the model.
+struct CountTokens {
+    inner: Box<dyn TModelInner>,
+    num_tokens: Arc<Mutex<usize>>,
+}
+
+impl TModelInner for CountTokens {
+    fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        *self.num_tokens.lock().unwrap() += batch_info.tokens.size()[0] as usize;
+        self.inner.forward(batch_info)
+    }
+}
+
+#[test]
+fn cpu_coalesce_identical_prompts() {
+    let p = prompt(10, 1);
+    let run = |coalesce: bool, bill: bool| {
+        let mut config = tiny_config();
+        config.scheduler.coalesce_prompts = coalesce;
+        config.scheduler.bill_coalesced_prompts = bill;
+        let num_tokens = Arc::new(Mutex::new(0));
+        let model = CountTokens {
+            inner: tiny_model(&config, MODEL_SEED),
+            num_tokens: num_tokens.clone(),
+        };
+        let cache_size = CacheSize {
+            gpu: NUM_GPU_BLOCKS,
+            cpu: 8,
+        };
+        let mut engine = TestEngine::with_model(config, cache_size, 0.0, Box::new(model));
+        for i in 0..8 {
+            engine.add_prompt(&format!("r{i}"), &p, 5);
+        }
+        let (res, trace) = engine.step_traced();
+        // every request got its first token in the first step
+        assert!(res.len() == 8);
+        assert!(trace.prompt_run);
+        let fwd_tokens = *num_tokens.lock().unwrap();
+        assert!(trace.num_batched_tokens == fwd_tokens);
+        let outputs = engine.run_to_completion();
+        let usage = engine.usage().into_values().collect::<Vec<_>>();
+        (
+            fwd_tokens,
+            outputs,
+            usage,
+            engine.scheduler().coalescing_stats(),
+        )
+    };
+
+    let (fwd_tokens, outputs, usage, stats) = run(true, true);
+    // a single prefill's worth of prompt tokens went through the model
+    assert!(fwd_tokens == p.len());
+    assert!(stats.prefills == 1);
+    assert!(stats.requests == 7);
+    // each request is billed as if it had its own prefill
+    assert!(usage.len() == 8);
+    assert!(usage
+        .iter()
+        .all(|u| u.prompt_tokens == p.len() + 4 && u.gen_tokens == 5));
+
+    let (fwd_tokens2, outputs2, usage2, stats2) = run(false, true);
+    assert!(fwd_tokens2 == 8 * p.len());
+    assert!(stats2 == CoalescingStats::default());
+    // greedy sampling, so coalescing doesn't change the outputs
+    assert!(outputs == outputs2);
+    assert!(outputs.iter().all(|o| o.1.len() == 5));
+    assert!(usage2
+        .iter()
+        .all(|u| u.prompt_tokens == p.len() + 4 && u.gen_tokens == 5));
+
+    // without billing, only the request that ran the prefill pays for the prompt
+    let (_, _, usage3, _) = run(true, false);
+    let mut prompt_tokens = usage3.iter().map(|u| u.prompt_tokens).collect::<Vec<_>>();
+    prompt_tokens.sort();
+    assert!(prompt_tokens[..7] == [4; 7]);
+    assert!(prompt_tokens[7] == p.len() + 4);
+}
+
+#[test]
+fn cpu_max_model_len() {
+    let gen_with = |kv_truncation_sink: Option<usize>, backend: Option<AttnBackendKind>| {
+        let mut config = tiny_config();
+        config.scheduler.max_model_len = 32;
+        config.scheduler.kv_truncation_sink = kv_truncation_sink;
+        config.model.cache.attn_backend = backend;
+        let mut engine = TestEngine::with_config(config, MODEL_SEED);
+        engine.add_prompt("a", &prompt(10, 1), 40);
+        let outputs = engine.run_to_completion();
+        (outputs[0].clone(), engine.usage()["a"].clone())
+    };
+    let gen = |kv_truncation_sink: Option<usize>| gen_with(kv_truncation_sink, None);
+
+    // stops cleanly at the limit
+    let ((_, tokens, reason), usage) = gen(None);
+    assert!(tokens.len() == 32 - 10);
+    assert!(reason == Some(FinishReason::MaxModelLenReached));
+    assert!(usage.gen_tokens == tokens.len());
+    // prefill, and then one token per step
+    assert!(usage.prompt_tokens == 10 + tokens.len() - 1);
+
+    // with truncation, the generation goes on past the limit,
+    // and is unchanged up to it
+    let ((_, tokens2, reason2), usage2) = gen(Some(4));
+    assert!(tokens2.len() == 40);
+    assert!(reason2 == Some(FinishReason::MaxTokensReached));
+    assert!(usage2.gen_tokens == 40);
+    assert!(tokens2[..tokens.len()] == tokens[..]);
+
+    // the paged block table only takes whole blocks, which the truncation keeps
+    if tiny_config().model.cache.paged_attn_kernel_v > 0 {
+        let ((_, tokens3, reason3), _) = gen_with(Some(4), Some(AttnBackendKind::Paged));
+        assert!(reason3 == Some(FinishReason::MaxTokensReached));
+        assert!(tokens3 == tokens2);
+    }
+}
+
+/// --attn-backend paged fails at startup in builds without the paged kernels,
+/// rather than at the first forward pass.
+#[test]
+fn attn_backend_parse() {
+    assert!(AttnBackendKind::parse("auto").unwrap().is_none());
+    let naive = AttnBackendKind::parse("naive").unwrap();
+    assert!(naive == Some(AttnBackendKind::Naive));
+    let paged = AttnBackendKind::parse("paged");
+    assert!(paged.is_ok() == cfg!(any(feature = "cuda", feature = "cpu-fallback")));
+    assert!(AttnBackendKind::parse("flash").is_err());
+}
+
+#[test]
+fn cpu_attn_backends_agree() {
+    let mut backends = vec![AttnBackendKind::Varlen, AttnBackendKind::Naive];
+    if tiny_config().model.cache.paged_attn_kernel_v > 0 {
+        backends.push(AttnBackendKind::Paged);
+    }
+
+    // one prefill step with two prompts, then mixed prefill+decode, then decode only
+    let gen = |backend: AttnBackendKind| {
+        let mut config = tiny_config();
+        config.model.cache.attn_backend = Some(backend);
+        let mut engine = TestEngine::with_config(config, MODEL_SEED);
+        engine.add_prompt("a", &prompt(20, 1), 6);
+        engine.add_prompt("b", &prompt(5, 3), 6);
+        let mut logits = engine.step();
+        engine.add_prompt("c", &prompt(9, 5), 3);
+        while !engine.all_finished() {
+            logits.extend(engine.step());
+        }
+        logits.sort_by(|a, b| a.0.cmp(&b.0));
+        logits
+    };
+
+    let expected = gen(backends[0]);
+    assert!(expected.len() == 6 + 6 + 3);
+    for backend in &backends[1..] {
+        let res = gen(*backend);
+        assert!(res.len() == expected.len());
+        for ((id0, l0), (id1, l1)) in expected.iter().zip(res.iter()) {
+            assert!(id0 == id1);
+            check_all_close(l0, l1, 1e-4);
+        }
+    }
+}
+
+#[test]
+fn cpu_split_over_limit_batch() {
+    let gen = |max_gather_tokens: usize| {
+        let mut config = tiny_config();
+        config.model.cache.attn_limits.max_gather_tokens = max_gather_tokens;
+        let mut engine = TestEngine::with_config(config, MODEL_SEED);
+        engine.add_prompt("a", &prompt(20, 1), 4);
+        engine.add_prompt("b", &prompt(15, 3), 4);
+        let mut logits = engine.step();
+        let num_micro_batches = engine.tmodel.num_micro_batches();
+        while !engine.all_finished() {
+            logits.extend(engine.step());
+        }
+        logits.sort_by(|a, b| a.0.cmp(&b.0));
+        (num_micro_batches, logits)
+    };
+
+    let (n1, expected) = gen(usize::MAX);
+    assert!(n1 == 1);
+    // the prompts need 20+15 gathered KV entries, so they go in separate forward passes
+    let (n2, res) = gen(30);
+    assert!(n2 == 2);
+    assert!(res.len() == expected.len());
+    for ((id0, l0), (id1, l1)) in expected.iter().zip(res.iter()) {
+        assert!(id0 == id1);
+        check_all_close(l0, l1, 1e-4);
+    }
+}
+
+#[test]
+fn cpu_kv_snapshot_and_restore() {
+    let p = prompt(10, 1);
+    let mut engine = TestEngine::new(MODEL_SEED);
+    engine.add_prompt("a", &p, 70);
+    let expected = engine.run_to_completion();
+
+    let mut engine = TestEngine::new(MODEL_SEED);
+    engine.add_prompt("a", &p, 70);
+    for _ in 0..50 {
+        engine.step();
+    }
+    let mut seq_id = None;
+    engine
+        .scheduler()
+        .for_each_seq(|seq| seq_id = Some(seq.seq_id));
+    let snapshot = engine.snapshot_sequence(seq_id.unwrap()).unwrap();
+    assert!(snapshot.tokens.len() == p.len() + 50);
+
+    // the sequence is gone, with all its blocks
+    assert!(engine.all_finished());
+    assert!(engine.scheduler().get_num_unfinished_seq_groups() == 0);
+    assert!(engine.scheduler().block_manager().get_num_free_gpu_blocks() == NUM_GPU_BLOCKS);
+
+    let path =
+        std::env::temp_dir().join(format!("rllm-snapshot-{}.safetensors", std::process::id()));
+    save_kv_snapshot(&snapshot, &path).unwrap();
+    let snapshot = load_kv_snapshot(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // failed restores give back the blocks
+    let seq_mgr = engine.tmodel.sequence_manager();
+    let new_seq_group = |tokens: &[Token]| SequenceGroup {
+        request_id: "b".to_string(),
+        prompt: String::new(),
+        seqs: vec![Sequence::new(seq_mgr.new_sequence(), tokens)],
+        sampling_params: SamplingParams::default(),
+        deadlock_steps: 0,
+        arrival_time: Instant::now(),
+        logits_processor: LogitsProcessor::new(&SamplingParams::default(), Some(1)),
+        max_index: 0,
+        usage: TokenUsage::default(),
+        timing: SeqGroupTiming::default(),
+        coalesced_into: None,
+        deadline: None,
+    };
+    let err = engine
+        .scheduler_mut()
+        .restore_seq(new_seq_group(&snapshot.tokens), &snapshot, |_, _| {
+            anyhow::bail!("bad snapshot")
+        })
+        .unwrap_err();
+    assert!(format!("{err}") == "bad snapshot");
+    assert!(engine
+        .scheduler_mut()
+        .restore_seq(new_seq_group(&p), &snapshot, |_, _| Ok(()))
+        .is_err());
+    assert!(engine.scheduler().get_num_unfinished_seq_groups() == 0);
+    assert!(engine.scheduler().block_manager().get_num_free_gpu_blocks() == NUM_GPU_BLOCKS);
+
+    engine.restore("a", &snapshot, 70);
+    let res = engine.run_to_completion();
+    assert!(res[0].1 == expected[0].1);
+    assert!(res[0].2 == Some(FinishReason::MaxTokensReached));
+}
+
+#[test]
+fn utf8_split_output_deltas() {
+    // one token per byte, so we can split the text anywhere
+    let trie = byte_trie();
+    let text = "hi 👋🏽 zażółć ✓!";
+    let bytes = text.as_bytes();
+
+    // every split into three gen_output() calls, the last one after the sequence finished
+    for a in 0..=bytes.len() {
+        for b in a..=bytes.len() {
+            let mut seq = Sequence::new(SeqId(1), &[]);
+            let mut out = String::new();
+            for (chunk, last) in [
+                (&bytes[..a], false),
+                (&bytes[a..b], false),
+                (&bytes[b..], true),
+            ] {
+                seq.append_tokens(&chunk.iter().map(|b| *b as Token).collect::<Vec<_>>());
+                if last {
+                    seq.sched_phase = SchedulingPhase::Finished(FinishReason::MaxTokensReached);
+                }
+                let new_text = seq.gen_output(&trie, &[]).new_text;
+                assert!(!new_text.contains('\u{FFFD}'), "split at {a}, {b}");
+                out.push_str(&new_text);
+            }
+            assert!(out == text, "split at {a}, {b}: {out:?}");
+        }
+    }
+
+    // incomplete bytes are flushed when the sequence finishes
+    let mut seq = Sequence::new(SeqId(1), &[]);
+    seq.append_tokens(&bytes[..4].iter().map(|b| *b as Token).collect::<Vec<_>>());
+    assert!(seq.gen_output(&trie, &[]).new_text == "hi ");
+    seq.sched_phase = SchedulingPhase::Finished(FinishReason::MaxTokensReached);
+    assert!(seq.gen_output(&trie, &[]).new_text == "\u{FFFD}");
+}
+
+#[test]
+fn hide_and_suppress_tokens() {
+    let trie = byte_trie();
+    let mut seq = Sequence::new(SeqId(1), &[]);
+    seq.append_tokens(&[b'a' as Token, 0, b'b' as Token]);
+    let out = seq.gen_output(&trie, &[0]);
+    assert!(out.new_text == "ab");
+    assert!(out.new_output_tokens == vec![b'a' as Token, 0, b'b' as Token]);
+    seq.sched_phase = SchedulingPhase::Finished(FinishReason::MaxTokensReached);
+    seq.gen_output(&trie, &[0]);
+    let out = seq.completion_output();
+    assert!(out.text == "ab");
+    assert!(out.token_ids == vec![b'a' as Token, 0, b'b' as Token]);
+
+    // suppression overrides logit_bias for the same token
+    let engine = TestEngine::new(MODEL_SEED);
+    let mut base = vec![0f32; VOCAB_SIZE];
+    base[10] = 6.0;
+    let base = Tensor::from_slice(&base);
+    let mut sampling_params = SamplingParams::default();
+    sampling_params.temperature = 1.0;
+    sampling_params.logit_bias.insert(10, 5.0);
+    sampling_params.suppress_tokens = Some(vec![10, 11]);
+    for seed in 0..20 {
+        let mut state = LogitsProcessor::new(&sampling_params, Some(seed));
+        let mut logits = base.copy();
+        let bias = state.sparse_bias(&[]);
+        engine.tmodel.add_sparse_bias(&mut logits, &bias);
+        let t = engine.tmodel.sample(&mut state, &logits).unwrap();
+        assert!(t != 10 && t != 11, "seed {seed}: {t}");
+    }
+}
+
+#[test]
+fn cpu_cache_slots_fit_i32() {
+    let cache = &tiny_config().model.cache;
+    assert!(cache.check_num_gpu_blocks(NUM_GPU_BLOCKS).is_ok());
+    let max_blocks = MAX_NUM_SLOTS / BLOCK_SIZE - 1;
+    assert!(cache.check_num_gpu_blocks(max_blocks).is_ok());
+    assert!(cache.check_num_gpu_blocks(max_blocks + 1).is_err());
+    assert!(cache.check_num_gpu_blocks(usize::MAX).is_err());
+}
+
+#[test]
+fn layer_split_parsing() {
+    const GB: usize = 1 << 30;
+    let cuda = |n| Device::Cuda(n);
+    let mem = |d: Device| match d {
+        Device::Cuda(0) => 24 * GB,
+        Device::Cuda(1) => 12 * GB,
+        _ => 0,
+    };
+    let count = |devs: &[Device], d| devs.iter().filter(|x| **x == d).count();
+
+    assert!(ModelConfig::parse_layer_split("", 32, mem)
+        .unwrap()
+        .is_empty());
+
+    // by memory: 24GB + 12GB is a 2:1 split, first device first
+    let devs = ModelConfig::parse_layer_split("cuda:0,cuda:1", 32, mem).unwrap();
+    assert!(devs.len() == 32);
+    assert!(count(&devs, cuda(0)) == 21 && count(&devs, cuda(1)) == 11);
+    assert!(devs[20] == cuda(0) && devs[21] == cuda(1));
+
+    // explicit counts
+    let devs = ModelConfig::parse_layer_split("cuda:1=2, cuda:0=30", 32, mem).unwrap();
+    assert!(devs[..2] == [cuda(1), cuda(1)] && devs[2] == cuda(0));
+
+    // unknown memory splits evenly
+    let devs = ModelConfig::parse_layer_split("cpu,cuda", 3, mem).unwrap();
+    assert!(count(&devs, Device::Cpu) == 2 && count(&devs, cuda(0)) == 1);
+
+    // no device is left without layers
+    let devs = split_layers(&[(cuda(0), 100 * GB), (cuda(1), GB)], 8);
+    assert!(count(&devs, cuda(0)) == 7 && count(&devs, cuda(1)) == 1);
+
+    for bad in [
+        "cuda:0=20,cuda:1=10",
+        "cuda:0=20,cuda:1",
+        "gpu:0",
+        "cuda:0=x",
+    ] {
+        assert!(
+            ModelConfig::parse_layer_split(bad, 32, mem).is_err(),
+            "{bad}"
+        );
+    }
+}
+
+#[test]
+fn cpu_layer_split() {
+    let run = |config: RllmConfig<TModel>| {
+        let mut engine = TestEngine::with_config(config, MODEL_SEED);
+        engine.add_prompt("a", &prompt(20, 1), 11);
+        engine.add_prompt("b", &prompt(5, 3), 11);
+        engine.run_to_completion()
+    };
+    // both "devices" are the CPU, so this checks the placement plumbing,
+    // not actual transfers between devices
+    let mut config = tiny_config();
+    config.model.layer_devices = ModelConfig::parse_layer_split("cpu=1,cpu=1", 2, |_| 0).unwrap();
+    assert!(config.validate().is_ok());
+    assert!(config.model.devices() == [Device::Cpu]);
+    assert!(run(config.clone()) == run(tiny_config()));
+
+    let cache_size = CacheSize { gpu: 4, cpu: 0 };
+    let block_bytes = CacheEngine::get_cache_block_size(&config);
+    assert!(CacheEngine::get_cache_block_size_on(&config, Device::Cpu) == block_bytes);
+    let cache_engine = CacheEngine::new(Arc::new(config), &cache_size);
+    // one extra block for the scratch slot
+    assert!(cache_engine.device_bytes() == [(Device::Cpu, 5 * block_bytes)]);
+}
+
+#[test]
+fn cpu_block_manager_view() {
+    let mut engine = TestEngine::new(MODEL_SEED);
+    let view =
+        |engine: &TestEngine, max_seqs| engine.scheduler().block_manager().view(max_seqs).unwrap();
+
+    let v = view(&engine, 10);
+    assert!(v.block_size == BLOCK_SIZE);
+    assert!(v.gpu.total == NUM_GPU_BLOCKS && v.gpu.free == NUM_GPU_BLOCKS && v.gpu.used == 0);
+    assert!(v.gpu.free_runs == 1 && v.gpu.largest_free_run == NUM_GPU_BLOCKS);
+    assert!(v.num_seqs == 0 && v.seqs.is_empty() && !v.seqs_truncated);
+
+    // a takes blocks 0 and 1, and finishes right away; b takes block 2
+    engine.add_prompt("a", &prompt(20, 1), 1);
+    engine.add_prompt("b", &prompt(5, 3), 3);
+    let v = view(&engine, 0);
+    assert!(v.gpu.used == 0);
+    engine.step();
+
+    let v = view(&engine, 10);
+    assert!(v.gpu.used == 1 && v.gpu.free == NUM_GPU_BLOCKS - 1);
+    assert!(v.gpu.shared == 0);
+    assert!(v.gpu.free_runs == 2 && v.gpu.largest_free_run == NUM_GPU_BLOCKS - 3);
+    assert!(v.num_seqs == 1 && !v.seqs_truncated);
+    assert!(v.seqs[0].location == BlockLocation::GPU);
+    assert!(v.seqs[0].blocks == vec![(2, 1)]);
+
+    // the per-sequence lists are capped, the totals aren't
+    let v0 = view(&engine, 0);
+    assert!(v0.num_seqs == 1 && v0.seqs.is_empty() && v0.seqs_truncated);
+    assert!(v0.gpu == v.gpu);
+    let json = serde_json::to_string(&v).unwrap();
+    assert!(json.contains("\"largest_free_run\""));
+
+    engine.run_to_completion();
+    let v = view(&engine, 10);
+    assert!(v.gpu.used == 0 && v.gpu.free_runs == 1 && v.num_seqs == 0);
+}
+
+#[test]
+fn rotary_tables_shared() {
+    let cfg = tiny_config().model;
+    let mut other = cfg.clone();
+    other.num_hidden_layers = 4;
+    other.hidden_size = 128;
+    let a = RotaryEmbedding::new(&Rc::new(cfg));
+    let b = RotaryEmbedding::new(&Rc::new(other.clone()));
+    assert!(a.cos_sin.data_ptr() == b.cos_sin.data_ptr());
+
+    other.rope_theta = 500_000.0;
+    let c = RotaryEmbedding::new(&Rc::new(other));
+    assert!(a.cos_sin.data_ptr() != c.cos_sin.data_ptr());
+    // two [max_position, rotary_dim] f32 tables
+    let bytes = rotary_cache_bytes();
+    assert!(bytes >= 2 * 128 * 16 * 4);
+
+    // the cache doesn't keep the tables of unloaded models
+    drop(c);
+    assert!(rotary_cache_bytes() < bytes);
+    drop((a, b));
+    assert!(rotary_cache_bytes() == 0);
+}
+
+#[test]
+fn cpu_load_tied_embeddings() {
+    let mut config = tiny_config();
+    config.model.tie_word_embeddings = true;
+
+    let vs = VarStore::new(Device::Cpu);
+    let _model = Llama::load(vs.root(), &Rc::new(config.model.clone())).unwrap();
+    let mut tensors = vs.variables().into_iter().collect::<Vec<_>>();
+    assert!(!tensors.iter().any(|(n, _)| n == "lm_head.weight"));
+    let path = std::env::temp_dir().join(format!("rllm-tied-{}.safetensors", std::process::id()));
+    Tensor::write_safetensors(&tensors, &path).unwrap();
+
+    // an untied model can't find its head, and says why
+    let err = load_model(&tiny_config(), vec![path.clone()], None)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("lm_head.weight"), "{err}");
+    assert!(err.contains("tie_word_embeddings"), "{err}");
+
+    // tied checkpoints often include lm_head.weight anyway
+    let (_, wte) = tensors
+        .iter()
+        .find(|(n, _)| n == "model.embed_tokens.weight")
+        .unwrap();
+    let head = wte.copy();
+    tensors.push(("lm_head.weight".to_string(), head));
+    Tensor::write_safetensors(&tensors, &path).unwrap();
+    assert!(load_model(&config, vec![path.clone()], None).is_ok());
+    std::fs::remove_file(&path).unwrap();
+
+    // the tied head produces full logits
+    let mut engine = TestEngine::with_config(config, MODEL_SEED);
+    engine.add_prompt("a", &prompt(5, 1), 1);
+    let res = engine.step();
+    assert!(res[0].1.size() == vec![VOCAB_SIZE as i64]);
+}
+
+#[test]
+fn cpu_load_prefixed_names() {
+    let config = tiny_config();
+    let vs = VarStore::new(Device::Cpu);
+    let _model = Llama::load(vs.root(), &Rc::new(config.model.clone())).unwrap();
+    let vars = vs.variables();
+    let path = std::env::temp_dir().join(format!("rllm-names-{}.safetensors", std::process::id()));
+    let write = |rename: &dyn Fn(&str) -> String| {
+        let tensors = vars
+            .iter()
+            .map(|(n, t)| (rename(n.as_str()), t.shallow_clone()))
+            .collect::<Vec<_>>();
+        Tensor::write_safetensors(&tensors, &path).unwrap();
+    };
+
+    // as exported after merging an adapter
+    write(&|n| format!("base_model.model.{n}"));
+    assert!(load_model(&config, vec![path.clone()], None).is_ok());
+
+    // no "model." prefix; lm_head.weight matches as is
+    write(&|n| n.strip_prefix("model.").unwrap_or(n).to_string());
+    assert!(load_model(&config, vec![path.clone()], None).is_ok());
+
+    // explicit mappings are used as given
+    let wrong = WeightNameMapper::parse("base_model.=").unwrap();
+    assert!(load_model(&config, vec![path.clone()], Some(wrong)).is_err());
+    let right = WeightNameMapper::parse(
+        "layers.=model.layers., embed_tokens.=model.embed_tokens.,norm.=model.norm.",
+    )
+    .unwrap();
+    assert!(right.rules.len() == 3);
+    assert!(right.map("norm.weight") == "model.norm.weight");
+    assert!(load_model(&config, vec![path.clone()], Some(right)).is_ok());
+    assert!(WeightNameMapper::parse("layers.").is_err());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Truncated weight files are caught when fetched, with the path and both sizes
+/// in the error, instead of failing later in the loader.
+#[test]
+fn truncated_weights_detected() {
+    let dir = std::env::temp_dir().join(format!("rllm-truncated-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("model.safetensors");
+    let (vs, _model) = tiny_model_vs(&tiny_config(), MODEL_SEED);
+    let vars = vs.variables().into_iter().collect::<Vec<_>>();
+    Tensor::write_safetensors(&vars, &path).unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+    let repo = Repo::Local(format!("{}/", dir.display()));
+    assert!(repo.get("model.safetensors").unwrap() == path);
+
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(size - 100).unwrap();
+    let err = repo.get("model.safetensors").unwrap_err().to_string();
+    assert!(err.contains(&path.display().to_string()), "{err}");
+    assert!(
+        err.contains(&format!("{} bytes, expected {size}", size - 100)),
+        "{err}"
+    );
+
+    // cut inside the header
+    file.set_len(20).unwrap();
+    assert!(repo.get("model.safetensors").is_err());
+    file.set_len(0).unwrap();
+    let err = repo.get("model.safetensors").unwrap_err().to_string();
+    assert!(err.contains("empty file"), "{err}");
+
+    // other files are only checked to be non-empty
+    std::fs::write(dir.join("config.json"), "{}").unwrap();
+    assert!(repo.get("config.json").is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cpu_activation_checkpoints() {
+    let config = tiny_config();
+    let (vs, _model) = tiny_model_vs(&config, MODEL_SEED);
+    let path = std::env::temp_dir().join(format!("rllm-ckpt-{}.safetensors", std::process::id()));
+    let vars = vs.variables().into_iter().collect::<Vec<_>>();
+    Tensor::write_safetensors(&vars, &path).unwrap();
+    let p = prompt(10, 3);
+    let tol = Tolerances::default();
+
+    // the same dtype gives the same activations
+    let report = diagnose_dtype(&config, vec![path.clone()], DType::Float, &p, 3, tol).unwrap();
+    assert!(report.first_divergent().is_none(), "{report}");
+    assert!(report.missing.is_empty());
+    assert!(report.layers.len() == 4 * config.model.num_hidden_layers);
+
+    // a perturbation in layer 1 is found there, and not before
+    let perturbed = vars
+        .iter()
+        .map(|(n, t)| match n.as_str() {
+            "model.layers.1.mlp.down_proj.weight" => (n.clone(), t * 4.0 + 0.5),
+            _ => (n.clone(), t.shallow_clone()),
+        })
+        .collect::<Vec<_>>();
+    let path_b =
+        std::env::temp_dir().join(format!("rllm-ckpt-b-{}.safetensors", std::process::id()));
+    Tensor::write_safetensors(&perturbed, &path_b).unwrap();
+    let (model_a, _) = load_model(&config, vec![path.clone()], None).unwrap();
+    let (model_b, _) = load_model(&config, vec![path_b.clone()], None).unwrap();
+    let (log_a, tokens) = record_activations(&config, model_a.as_ref(), &p, 3, None).unwrap();
+    let (log_b, _) = record_activations(&config, model_b.as_ref(), &p, 3, Some(&tokens)).unwrap();
+    let report = compare_runs(&log_a, &log_b, tol);
+    let first = report.first_divergent().unwrap();
+    assert!(first.layer == 1 && first.step_no == 0, "{report}");
+    assert!(report.layers.iter().all(|l| l.exceeds == (l.layer == 1)));
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&path_b).unwrap();
+}
+
+/// Keeps the messages logged by the loader, and request events, so tests can look at them.
+struct CaptureLogger;
+
+static CAPTURED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().ends_with("::loader") || metadata.target() == REQUEST_LOG_TARGET
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            CAPTURED.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn capture_logs() {
+    static LOGGER: CaptureLogger = CaptureLogger;
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Debug);
+}
+
+#[test]
+fn cpu_load_logs_timing() {
+    capture_logs();
+
+    let mut config = tiny_config();
+    config.meta.id = "tiny-llama-logged".to_string();
+    let vs = VarStore::new(Device::Cpu);
+    let _model = Llama::load(vs.root(), &Rc::new(config.model.clone())).unwrap();
+    let tensors = vs.variables().into_iter().collect::<Vec<_>>();
+    let path = std::env::temp_dir().join(format!("rllm-log-{}.safetensors", std::process::id()));
+    Tensor::write_safetensors(&tensors, &path).unwrap();
+    load_model(&config, vec![path.clone()], None).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let captured = CAPTURED.lock().unwrap();
+    let done = captured
+        .iter()
+        .find(|m| {
+            m.starts_with("load tiny-llama-logged:") && m.contains(" tensors from 1 files in ")
+        })
+        .expect("no load summary logged");
+    // the duration is Debug-formatted, e.g. "12.3ms"
+    assert!(done.ends_with('s'), "{done}");
+}
+
+/// There's only room for one of the requests in the KV cache, so the second is preempted.
+#[test]
+fn cpu_request_events() {
+    capture_logs();
+    let config = tiny_config();
+    let model = tiny_model(&config, MODEL_SEED);
+    let cache_size = CacheSize { gpu: 4, cpu: 1 };
+    let mut engine = TestEngine::with_model(config, cache_size, 0.0, model);
+    engine.add_prompt("events-a", &prompt(20, 1), 30);
+    engine.add_prompt("events-b", &prompt(20, 2), 30);
+    engine.run_to_completion();
+    // drop the finished requests
+    engine.step_traced();
+
+    let events = CAPTURED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|m| serde_json::from_str::<RequestEvent>(m).ok())
+        .collect::<Vec<_>>();
+    let of_request = |id: &str| {
+        events
+            .iter()
+            .filter(|e| e.request_id == id)
+            .map(|e| (e.step_no, e.kind.clone()))
+            .collect::<Vec<_>>()
+    };
+    let names = |events: &[(usize, RequestEventKind)]| {
+        events
+            .iter()
+            .map(|(_, k)| serde_json::to_value(k).unwrap()["event"].to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let a = of_request("events-a");
+    let b = of_request("events-b");
+    assert!(names(&a) == r#""admitted","scheduled","finished""#, "{a:?}");
+    assert!(
+        names(&b) == r#""admitted","scheduled","preempted","finished""#,
+        "{b:?}"
+    );
+    assert!(
+        b[2].1
+            == RequestEventKind::Preempted {
+                mode: PreemptionMode::Recompute
+            }
+    );
+    assert!(
+        a[2].1
+            == RequestEventKind::Finished {
+                reason: Some(FinishReason::MaxTokensReached)
+            }
+    );
+    // both are prefilled in the first step, and b finishes after a
+    assert!(a[1].0 == 1 && b[1].0 == 1);
+    assert!(a[2].0 < b[3].0);
+}
+
+/// Sequences preempted by recompute go on as if they never were, whether the
+/// scheduler runs out of KV cache, or the preemption is forced at some step.
+#[test]
+fn cpu_preemption_recompute() {
+    let config = tiny_config();
+    let alone = |p: &[Token], max_tokens: usize| {
+        let mut engine = TestEngine::new(MODEL_SEED);
+        engine.add_prompt("alone", p, max_tokens);
+        engine.run_to_completion().remove(0).1
+    };
+
+    for preempt_at in [1, 2, 5, 9] {
+        let mut engine = TestEngine::new(MODEL_SEED);
+        engine.add_prompt("a", &prompt(20, 1), 12);
+        engine.add_prompt("b", &prompt(7, 2), 12);
+        for _ in 0..preempt_at {
+            engine.step();
+        }
+        assert!(engine.scheduler_mut().preempt_seq_group("a"));
+        // already off the GPU
+        assert!(!engine.scheduler_mut().preempt_seq_group("a"));
+        // the blocks of a are free; b (at most 16 tokens) keeps one
+        assert!(engine.scheduler().block_manager().get_num_free_gpu_blocks() == NUM_GPU_BLOCKS - 1);
+        engine.scheduler().for_each_waiting_sg(|sg| {
+            assert!(sg.request_id == "a");
+            assert!(sg.seqs[0].num_kv_computed == 0);
+            assert!(sg.seqs[0].get_gen_len() == preempt_at);
+        });
+        // a is prefilled again, with the tokens it generated so far
+        let (_, trace) = engine.step_traced();
+        assert!(trace.prompt_run && trace.scheduled.contains(&"a".to_string()));
+        let out = engine.run_to_completion();
+        assert!(out[0].1 == alone(&prompt(20, 1), 12));
+        assert!(out[1].1 == alone(&prompt(7, 2), 12));
+    }
+
+    // only room for one of them, as in cpu_request_events()
+    let model = tiny_model(&config, MODEL_SEED);
+    let cache_size = CacheSize { gpu: 4, cpu: 1 };
+    let mut engine = TestEngine::with_model(config, cache_size, 0.0, model);
+    engine.add_prompt("a", &prompt(20, 1), 30);
+    engine.add_prompt("b", &prompt(20, 2), 30);
+    let mut preempted = false;
+    while !engine.all_finished() {
+        let (_, trace) = engine.step_traced();
+        preempted |= !trace.preempted.is_empty();
+    }
+    assert!(preempted);
+    let out = engine.run_to_completion();
+    assert!(out[0].1 == alone(&prompt(20, 1), 30));
+    assert!(out[1].1 == alone(&prompt(20, 2), 30));
+}
+
+#[test]
+fn cpu_preempted_before_deadlines() {
+    // a preempted group is rescheduled before new arrivals, even ones with a deadline
+    let mut config = tiny_config();
+    config.scheduler.max_num_seqs = 1;
+    let mut engine = TestEngine::with_config(config, MODEL_SEED);
+    engine.add_prompt("a", &prompt(20, 1), 12);
+    engine.step();
+    engine.step();
+    assert!(engine.scheduler_mut().preempt_seq_group("a"));
+    let deadline = Instant::now() + Duration::from_secs(3600);
+    engine.add_prompt_at("b", &prompt(5, 2), 4, Instant::now(), Some(deadline));
+    engine.step();
+    let mut waiting = Vec::new();
+    engine
+        .scheduler()
+        .for_each_waiting_sg(|sg| waiting.push(sg.request_id.clone()));
+    assert!(waiting == ["b"], "{waiting:?}");
+
+    let out = engine.run_to_completion();
+    assert!(out.iter().all(|(_, gen, _)| !gen.is_empty()));
+}
+
+/// Score and embed requests are answered from their prefill, in the same batch
+/// as generation, and agree with what generation computes for the same tokens.
+#[test]
+fn cpu_score_and_embed() {
+    let p = prompt(20, 3);
+    let cont = 6;
+
+    // last-position logits of each prefix, through the generation path
+    let mut reference = TestEngine::new(MODEL_SEED);
+    for len in p.len() - cont..=p.len() {
+        reference.add_prompt(&format!("p{len}"), &p[..len], 1);
+    }
+    let mut last_logits = HashMap::default();
+    while !reference.all_finished() {
+        for (id, logits) in reference.step() {
+            last_logits.entry(id).or_insert(logits);
+        }
+    }
+    let expected = (p.len() - cont..p.len())
+        .map(|len| {
+            reference
+                .tmodel
+                .logprob(&last_logits[&format!("p{len}")], p[len])
+        })
+        .collect::<Vec<_>>();
+
+    let mut engine = TestEngine::new(MODEL_SEED);
+    engine.add_prompt("g", &prompt(10, 1), 8);
+    let score = RequestKind::Score {
+        continuation_len: cont,
+    };
+    engine.add_prefill_request("s", &p, score);
+    // same prompt; not coalesced with the score request
+    let last = RequestKind::Embed {
+        pooling: Pooling::Last,
+    };
+    engine.add_prefill_request("e_last", &p, last);
+    let mean = RequestKind::Embed {
+        pooling: Pooling::Mean,
+    };
+    engine.add_prefill_request("e_mean", &p, mean);
+    let (_, trace) = engine.step_traced();
+    assert!(trace.scheduled.len() == 4);
+    let out = engine.run_to_completion();
+    assert!(engine.scheduler().block_manager().get_num_free_gpu_blocks() == NUM_GPU_BLOCKS);
+    // drop g, which streams its final output
+    engine.step_traced();
+
+    let mut alone = TestEngine::new(MODEL_SEED);
+    alone.add_prompt("g", &prompt(10, 1), 8);
+    assert!(alone.run_to_completion()[0].1 == out.iter().find(|o| o.0 == "g").unwrap().1);
+
+    let prefill_output = |id: &str| {
+        let out = engine.final_outputs().find(|o| o.request_id == id).unwrap();
+        let seq = &out.outputs[0];
+        assert!(seq.finish_reason == Some(FinishReason::PrefillOnly));
+        assert!(seq.token_ids.is_empty());
+        assert!(out.usage.gen_tokens == 0 && out.usage.prompt_tokens == p.len());
+        (out.usage.clone(), seq.prefill_output.clone().unwrap())
+    };
+
+    let (usage, scores) = prefill_output("s");
+    assert!(usage.scored_tokens == cont);
+    let scores = match scores {
+        PrefillOutput::Scores(s) => s,
+        other => panic!("{other:?}"),
+    };
+    assert!(scores.len() == cont);
+    for (a, b) in scores.iter().zip(expected.iter()) {
+        assert!((a - b).abs() < 1e-4, "{scores:?} != {expected:?}");
+    }
+
+    // the last hidden state, through lm_head, gives the last logits
+    let (usage, last) = prefill_output("e_last");
+    assert!(usage.embedded_tokens == p.len());
+    let last = match last {
+        PrefillOutput::Embedding(e) => Tensor::from_slice(&e),
+        other => panic!("{other:?}"),
+    };
+    let (vs, _) = tiny_model_vs(&tiny_config(), MODEL_SEED);
+    let lm_head = vs.variables()["lm_head.weight"].shallow_clone();
+    let logits = lm_head.matmul(&last);
+    check_all_close(&logits, &last_logits[&format!("p{}", p.len())], 1e-4);
+
+    let mean = match prefill_output("e_mean").1 {
+        PrefillOutput::Embedding(e) => Tensor::from_slice(&e),
+        other => panic!("{other:?}"),
+    };
+    assert!(mean.size() == last.size());
+    assert!(mean.isfinite().all().int64_value(&[]) == 1);
+    assert!(!mean.allclose(&last, 1e-3, 1e-3, false));
+
+    let params = SamplingParams {
+        kind: RequestKind::Score {
+            continuation_len: 0,
+        },
+        ..SamplingParams::default()
+    };
+    assert!(params.verify_args().is_err());
+    let params = SamplingParams {
+        n: 2,
+        best_of: 2,
+        kind: RequestKind::Embed {
+            pooling: Pooling::Mean,
+        },
+        ..SamplingParams::default()
+    };
+    assert!(params.verify_args().is_err());
+}
+
+#[test]
+fn cpu_swap_weights() {
+    let write = |config: &RllmConfig<TModel>, seed: i64, name: &str| {
+        let (vs, _model) = tiny_model_vs(config, seed);
+        let tensors = vs.variables().into_iter().collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!(
+            "rllm-swap-{name}-{}.safetensors",
+            std::process::id()
+        ));
+        Tensor::write_safetensors(&tensors, &path).unwrap();
+        path
+    };
+    let path_a = write(&tiny_config(), MODEL_SEED, "a");
+    let path_b = write(&tiny_config(), MODEL_SEED + 1, "b");
+    let mut other = tiny_config();
+    other.model.intermediate_size = 96;
+    let path_bad = write(&other, MODEL_SEED, "bad");
+
+    let run_alone = |seed: i64, p: &[Token], max_tokens: usize| {
+        let mut engine = TestEngine::new(seed);
+        engine.add_prompt("r", p, max_tokens);
+        engine.run_to_completion().remove(0).1
+    };
+    let free_blocks = |engine: &mut TestEngine| {
+        let (_, trace) = engine.step_traced();
+        trace.free_gpu_blocks
+    };
+
+    let mut engine = TestEngine::new(MODEL_SEED);
+    engine.add_prompt("a", &prompt(20, 1), 12);
+    for _ in 0..4 {
+        engine.step();
+    }
+
+    // checkpoints that don't fit the model are rejected, and the old weights kept
+    assert!(engine.swap_weights(&path_bad, false).is_err());
+
+    // the sequence keeps going, with the old KV
+    engine.swap_weights(&path_b, false).unwrap();
+    let out = engine.run_to_completion();
+    assert!(out[0].1.len() == 12);
+    assert!(out[0].1[..4] == run_alone(MODEL_SEED, &prompt(20, 1), 4));
+    assert!(free_blocks(&mut engine) == NUM_GPU_BLOCKS);
+
+    // new requests only see the new weights
+    engine.add_prompt("b", &prompt(9, 3), 8);
+    let out = engine.run_to_completion();
+    let (_, gen, _) = out.iter().find(|(id, _, _)| id == "b").unwrap();
+    assert!(*gen == run_alone(MODEL_SEED + 1, &prompt(9, 3), 8));
+
+    // with restart, the KV is dropped and recomputed
+    engine.add_prompt("c", &prompt(30, 5), 10);
+    for _ in 0..3 {
+        engine.step();
+    }
+    engine.swap_weights(&path_a, true).unwrap();
+    let mut waiting = 0;
+    engine.scheduler().for_each_waiting_sg(|_| waiting += 1);
+    assert!(waiting == 1);
+    assert!(engine.scheduler().block_manager().get_num_free_gpu_blocks() == NUM_GPU_BLOCKS);
+    let out = engine.run_to_completion();
+    let (_, gen, _) = out.iter().find(|(id, _, _)| id == "c").unwrap();
+    assert!(gen.len() == 10);
+    assert!(free_blocks(&mut engine) == NUM_GPU_BLOCKS);
+
+    for path in [path_a, path_b, path_bad] {
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn cpu_model_registry() {
+    let registry = ModelRegistry::<TestEngine>::new();
+    let load = |seed| {
+        move || {
+            let engine = TestEngine::new(seed);
+            let bytes = engine.tmodel.memory_bytes();
+            Ok((engine, bytes))
+        }
+    };
+    let a = registry.get_or_load("a", load(MODEL_SEED)).unwrap();
+    let b = registry.get_or_load("b", load(MODEL_SEED + 1)).unwrap();
+    let a2 = registry
+        .get_or_load("a", || panic!("loaded twice"))
+        .unwrap();
+    assert!(Arc::ptr_eq(&a, &a2));
+    assert!(registry.names() == ["a", "b"]);
+    let a_bytes = registry.memory_bytes("a").unwrap();
+    assert!(a_bytes > 0);
+    assert!(registry.total_memory_bytes() == a_bytes + registry.memory_bytes("b").unwrap());
+
+    let free_blocks = |e: &Arc<Mutex<TestEngine>>| {
+        e.lock()
+            .unwrap()
+            .scheduler()
+            .block_manager()
+            .get_num_free_gpu_blocks()
+    };
+    a.lock().unwrap().add_prompt("r", &prompt(40, 1), 6);
+    a.lock().unwrap().step();
+    let a_free = free_blocks(&a);
+    assert!(a_free < NUM_GPU_BLOCKS);
+    assert!(free_blocks(&b) == NUM_GPU_BLOCKS);
+
+    b.lock().unwrap().add_prompt("r", &prompt(40, 1), 6);
+    b.lock().unwrap().step();
+    assert!(free_blocks(&a) == a_free);
+    assert!(free_blocks(&b) == a_free);
+
+    // alternate steps between the models
+    loop {
+        let mut any = false;
+        for e in [&a, &b] {
+            let mut e = e.lock().unwrap();
+            if !e.all_finished() {
+                e.step();
+                any = true;
+            }
+        }
+        if !any {
+            break;
+        }
+    }
+
+    // same output as running each model alone
+    for (handle, seed) in [(&a, MODEL_SEED), (&b, MODEL_SEED + 1)] {
+        let mut alone = TestEngine::new(seed);
+        alone.add_prompt("r", &prompt(40, 1), 6);
+        let expected = alone.run_to_completion();
+        assert!(handle.lock().unwrap().run_to_completion() == expected);
+    }
+
+    drop(a2);
+    assert!(registry.evict("a"));
+    assert!(!registry.evict("a"));
+    assert!(registry.get("a").is_none());
+    assert!(registry.names() == ["b"]);
+    // the caller's handle stays usable until dropped
+    assert!(a.lock().unwrap().all_finished());
+}
+
+#[test]
+fn model_registry_loads_outside_lock() {
+    let registry = &ModelRegistry::<usize>::new();
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    std::thread::scope(|s| {
+        let slow = s.spawn(move || {
+            registry.get_or_load("slow", || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                Ok((1, 100))
+            })
+        });
+        started_rx.recv().unwrap();
+
+        // other models can be loaded and used while "slow" loads
+        assert!(registry.get("slow").is_none());
+        assert!(!registry.evict("slow"));
+        let fast = registry.get_or_load("fast", || Ok((2, 10))).unwrap();
+        assert!(*fast.lock().unwrap() == 2);
+        assert!(registry.names() == ["fast"]);
+
+        // a second load of "slow" waits for the first one
+        let waiter = s.spawn(|| registry.get_or_load("slow", || panic!("loaded twice")));
+        std::thread::sleep(Duration::from_millis(20));
+        release_tx.send(()).unwrap();
+        let slow = slow.join().unwrap().unwrap();
+        assert!(Arc::ptr_eq(&slow, &waiter.join().unwrap().unwrap()));
+    });
+    assert!(registry.names() == ["fast", "slow"]);
+    assert!(registry.total_memory_bytes() == 110);
+
+    // a failed load leaves nothing behind, and the next one tries again
+    assert!(registry
+        .get_or_load("flaky", || anyhow::bail!("no weights"))
+        .is_err());
+    assert!(registry.names() == ["fast", "slow"]);
+    assert!(
+        *registry
+            .get_or_load("flaky", || Ok((3, 1)))
+            .unwrap()
+            .lock()
+            .unwrap()
+            == 3
+    );
+}
+
+#[test]
+fn model_registry_load() {
+    let mut args = LoaderArgs {
+        model_id: "tiny/llama".to_string(),
+        local_weights: Some("/nonexistent/tiny-llama".to_string()),
+        ..LoaderArgs::default()
+    };
+    type Registry = ModelRegistry<RllmEngine<TModel>>;
+    assert!(Registry::model_name(&args) == "tiny/llama");
+    args.revision = Some("v2".to_string());
+    args.file = Some("q4.gguf".to_string());
+    assert!(Registry::model_name(&args) == "tiny/llama@v2::q4.gguf");
+
+    let registry = Registry::new();
+    let model_args = TchLoaderArgs {
+        profile_step_no: 0,
+        device: Device::Cpu,
+        dtype: None,
+        decode_pad_buckets: Vec::new(),
+        attn_backend: None,
+        layer_split: String::new(),
+    };
+    assert!(registry.load(args, model_args).is_err());
+    assert!(registry.names().is_empty());
+    assert!(registry.get("tiny/llama@v2::q4.gguf").is_none());
+}
+
+#[test]
+fn config_validation() {
+    assert!(tiny_config().validate().is_ok());
+
+    let cases: &[(&str, fn(&mut RllmConfig<TModel>))] = &[
+        ("parallel.pipeline_parallel_size", |c| {
+            c.parallel.pipeline_parallel_size = 0
+        }),
+        ("parallel.pipeline_parallel_size", |c| {
+            c.parallel.pipeline_parallel_size = 3
+        }),
+        ("parallel.tensor_parallel_size", |c| {
+            c.parallel.tensor_parallel_size = 0
+        }),
+        ("parallel.tensor_parallel_size", |c| {
+            c.parallel.tensor_parallel_size = 4
+        }),
+        ("scheduler.max_num_seqs", |c| c.scheduler.max_num_seqs = 0),
+        ("scheduler.max_model_len", |c| {
+            c.scheduler.max_model_len = 256
+        }),
+        ("scheduler.max_num_batched_tokens", |c| {
+            c.scheduler.max_num_batched_tokens = 4
+        }),
+        ("scheduler.max_num_kv_tokens", |c| {
+            c.scheduler.max_num_kv_tokens = 64
+        }),
+        ("scheduler.kv_truncation_sink", |c| {
+            c.scheduler.kv_truncation_sink = Some(128)
+        }),
+        ("scheduler.kv_truncation_sink", |c| {
+            c.scheduler.max_model_len = 32;
+            c.scheduler.kv_truncation_sink = Some(20)
+        }),
+        ("aici.max_fuel", |c| c.aici.max_fuel = 10),
+        ("model.dtype", |c| c.model.dtype = DType::Half),
+        ("model.cache.block_size", |c| c.model.cache.block_size = 0),
+        ("model.layer_devices", |c| {
+            c.model.layer_devices = vec![Device::Cpu]
+        }),
+        ("model.layer_devices", |c| {
+            c.model.num_hidden_layers = 3;
+            c.model.layer_devices = vec![Device::Cpu, Device::Mps, Device::Cpu];
+        }),
+    ];
+    for (field, mutate) in cases {
+        let mut config = tiny_config();
+        mutate(&mut config);
+        let errors = config.validate().unwrap_err();
+        assert!(
+            errors.iter().any(|e| e.field == *field),
+            "{field}: {errors:?}"
+        );
+        assert!(errors.iter().all(|e| !e.fix.is_empty()));
+    }
+
+    // all violations are reported at once
+    let mut config = tiny_config();
+    for (_, mutate) in &cases[..3] {
+        mutate(&mut config);
+    }
+    config.scheduler.max_num_seqs = 0;
+    config.aici.max_fuel = 0;
+    let errors = config.validate().unwrap_err();
+    let fields = errors.iter().map(|e| e.field).collect::<HashSet<_>>();
+    for field in [
+        "parallel.pipeline_parallel_size",
+        "parallel.tensor_parallel_size",
+        "scheduler.max_num_seqs",
+        "aici.max_fuel",
+    ] {
+        assert!(fields.contains(&field), "{field}: {errors:?}");
+    }
+}
+
+/// Batches of different sizes, staged through the same buffers one after another,
+/// give the same tensors as unstaged ones.
+#[test]
+fn batch_info_staging_reuse() {
+    let config = Arc::new(tiny_config());
+    let mut cache_engine = CacheEngine::new(
+        config.clone(),
+        &CacheSize {
+            gpu: NUM_GPU_BLOCKS,
+            cpu: 0,
+        },
+    );
+    let mut staging = HostStaging::new(config.model.device);
+
+    let builder = |batch_size: usize, ctx_len: usize| {
+        let mut builder = BatchInfoBuilder::new(config.clone());
+        for idx in 0..batch_size {
+            let p = prompt(ctx_len, idx);
+            builder.push_entry(BatchEntry {
+                seq_id: idx + 1,
+                query_pos_token: p.iter().cloned().enumerate().skip(ctx_len / 2).collect(),
+                kv_slots: (idx * ctx_len..(idx + 1) * ctx_len).collect(),
+            });
+        }
+        builder
+    };
+    for (step_no, (batch_size, ctx_len)) in [(3, 20), (7, 16), (2, 48)].into_iter().enumerate() {
+        let expected = builder(batch_size, ctx_len)
+            .finish(step_no, cache_engine.get_cache_iface())
+            .unwrap();
+        let staged = builder(batch_size, ctx_len)
+            .finish_staged(step_no, cache_engine.get_cache_iface(), &mut staging)
+            .unwrap();
+        for (a, b) in [
+            (&expected.tokens, &staged.tokens),
+            (&expected.positions, &staged.positions),
+            (&expected.seqlens_q, &staged.seqlens_q),
+            (&expected.seqlens_k, &staged.seqlens_k),
+            (&expected.slot_mapping, &staged.slot_mapping),
+            (&expected.gather_mapping, &staged.gather_mapping),
+            (&expected.logit_idxs, &staged.logit_idxs),
+            (&expected.paged_block_tables, &staged.paged_block_tables),
+            (&expected.paged_context_lens, &staged.paged_context_lens),
+        ] {
+            assert!(a.size() == b.size() && a.kind() == b.kind());
+            assert!(a.equal(b), "step {step_no}: {a:?} != {b:?}");
+        }
+    }
+}
+
+/// On the GPU, uploads go through pinned buffers on the copy stream: the buffers
+/// are reused across batches, and staging the next batch (which overwrites them)
+/// doesn't change the tensors of the previous one.
+#[cfg(feature = "cuda")]
+#[test]
+fn batch_info_staging_gpu() {
+    if !tch::Cuda::is_available() {
+        return;
+    }
+    let mut config = tiny_config();
+    config.model.device = Device::Cuda(0);
+    let config = Arc::new(config);
+    let mut cache_engine = CacheEngine::new(
+        config.clone(),
+        &CacheSize {
+            gpu: NUM_GPU_BLOCKS,
+            cpu: 0,
+        },
+    );
+    let mut staging = HostStaging::new(config.model.device);
+    assert!(staging.is_pinned());
+
+    let builder = |batch_size: usize, ctx_len: usize, seed: usize| {
+        let mut builder = BatchInfoBuilder::new(config.clone());
+        for idx in 0..batch_size {
+            let p = prompt(ctx_len, idx + seed);
+            builder.push_entry(BatchEntry {
+                seq_id: idx + 1,
+                query_pos_token: p.iter().cloned().enumerate().skip(ctx_len / 2).collect(),
+                kv_slots: (idx * ctx_len..(idx + 1) * ctx_len).collect(),
+            });
+        }
+        builder
+    };
+    let tensors = |info: &BatchInfo| {
+        [
+            &info.tokens,
+            &info.positions,
+            &info.seqlens_q,
+            &info.seqlens_k,
+            &info.slot_mapping,
+            &info.gather_mapping,
+            &info.logit_idxs,
+        ]
+        .map(|t| t.to_device(Device::Cpu))
+    };
+
+    let mut buffer_ptrs = None;
+    let mut prev: Option<(BatchInfo, [Tensor; 7])> = None;
+    // the first batch is the largest, so later ones fit in its buffers
+    for (step_no, (batch_size, ctx_len)) in [(7, 32), (3, 20), (2, 16)].into_iter().enumerate() {
+        let expected = builder(batch_size, ctx_len, step_no)
+            .finish(step_no, cache_engine.get_cache_iface())
+            .unwrap();
+        let staged = builder(batch_size, ctx_len, step_no)
+            .finish_staged(step_no, cache_engine.get_cache_iface(), &mut staging)
+            .unwrap();
+        for (a, b) in tensors(&expected).iter().zip(tensors(&staged).iter()) {
+            assert!(a.equal(b), "step {step_no}: {a:?} != {b:?}");
+        }
+        let ptrs = staging.buffer_ptrs();
+        assert!(*buffer_ptrs.get_or_insert(ptrs.clone()) == ptrs);
+        if let Some((info, host)) = prev {
+            for (a, b) in host.iter().zip(tensors(&info).iter()) {
+                assert!(a.equal(b), "step {step_no}: previous batch changed");
+            }
+        }
+        let host = tensors(&staged);
+        prev = Some((staged, host));
+    }
+}
+
+/// Per-step CPU time of building a decode batch of 256 sequences, with and without
+/// reusing (pinned, on GPU) staging buffers.
+/// Run with `cargo test --release -- --ignored --nocapture bench_`.
+#[test]
+#[ignore]
+fn bench_batch_info_finish() {
+    const BATCH_SIZE: usize = 256;
+    const CTX_LEN: usize = 64;
+    const ITERS: u32 = 200;
+
+    let config = Arc::new(tiny_config());
+    let num_blocks = BATCH_SIZE * CTX_LEN / BLOCK_SIZE;
+    let mut cache_engine = CacheEngine::new(
+        config.clone(),
+        &CacheSize {
+            gpu: num_blocks,
+            cpu: 0,
+        },
+    );
+
+    for staged in [false, true] {
+        let mut staging = HostStaging::new(config.model.device);
+        let t0 = Instant::now();
+        for step_no in 0..ITERS {
+            let mut builder = BatchInfoBuilder::new(config.clone());
+            for idx in 0..BATCH_SIZE {
+                builder.push_entry(BatchEntry {
+                    seq_id: idx + 1,
+                    query_pos_token: vec![(CTX_LEN - 1, (idx % VOCAB_SIZE) as Token)],
+                    kv_slots: (idx * CTX_LEN..(idx + 1) * CTX_LEN).collect(),
+                });
+            }
+            let kv_cache = cache_engine.get_cache_iface();
+            let info = if staged {
+                builder.finish_staged(step_no as usize, kv_cache, &mut staging)
+            } else {
+                builder.finish(step_no as usize, kv_cache)
+            }
+            .unwrap();
+            assert!(info.real_batch_size == BATCH_SIZE);
+        }
+        let per_step = t0.elapsed() / ITERS;
+        println!("finish() at batch size {BATCH_SIZE}, staged={staged}: {per_step:?}/step");
+    }
+}
+
+/// Throughput with a simulated 2ms forward pass, with and without pipeline_outputs;
+/// the difference is the time spent building outputs, now hidden behind the forward pass.
+/// Run with `cargo test --release -- --ignored --nocapture bench_`.
+#[test]
+#[ignore]
+fn bench_pipeline_outputs() {
+    const NUM_REQUESTS: usize = 8;
+    const MAX_TOKENS: usize = 48;
+
+    for pipeline in [false, true] {
+        let mut config = tiny_config();
+        config.scheduler.pipeline_outputs = pipeline;
+        let model = SlowForward {
+            inner: tiny_model(&config, MODEL_SEED),
+            latency: Duration::from_millis(2),
+            ready_at: Mutex::new(Instant::now()),
+        };
+        let cache_size = CacheSize {
+            gpu: NUM_GPU_BLOCKS,
+            cpu: 8,
+        };
+        let mut engine = TestEngine::with_model(config, cache_size, 0.0, Box::new(model));
+        for idx in 0..NUM_REQUESTS {
+            engine.add_prompt(&format!("r{idx}"), &prompt(8, idx), MAX_TOKENS);
+        }
+        let t0 = Instant::now();
+        engine.run_to_completion();
+        let elapsed = t0.elapsed();
+        let tps = (NUM_REQUESTS * MAX_TOKENS) as f64 / elapsed.as_secs_f64();
+        println!("pipeline_outputs={pipeline}: {elapsed:?}, {tps:.1} tokens/s");
+    }
+}
@@ -1,37 +1,97 @@
 use super::{
+    attn::AttnBackendKind,
     config::{self, TchRllmConfig},
-    loader::{load_model_config, load_rllm_engine},
-    paged::{BatchInfo, BatchInfoBuilder, BlockSpaceManager, CacheEngine, CacheIface, TchSeqMgr},
+    loader::{load_model, load_model_config, load_rllm_engine},
+    paged::{
+        BatchInfo, BatchInfoBuilder, BlockSpaceManager, CacheEngine, CacheIface, HostStaging,
+        KvBlocks, SeqDump, TchSeqMgr,
+    },
     util::{synchronize, to_vec1},
     DType,
 };
 use aicirt::{with_timer, TimerRef};
-use anyhow::Result;
+use anyhow::{bail, Result};
 use rand::distributions::Distribution as _;
-use rllm::{config::RllmConfig, AiciBias, LogitsProcessor, ModelExec, SchedulerOutputs};
-use std::{sync::Arc, time::Instant};
+use rllm::{
+    config::{ConfigError, Pooling, RllmConfig},
+    seq::Token,
+    util::get_setting,
+    AiciBias, EngineError, LogitsProcessor, ModelExec, SchedulerOutputs, SeqId,
+};
+use std::{path::PathBuf, sync::Arc, time::Instant};
 use tch::{Device, IndexOp, Tensor};
 
 pub trait TModelInner {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor;
     fn finalize(&mut self) {}
+    /// Wait for the last forward() to finish; called before its logits are read.
+    /// Reading CUDA tensors already waits for the kernels, so this is only needed
+    /// by models that simulate asynchronous execution on the CPU.
+    fn synchronize(&self) {}
 }
 
 pub struct TModel {
     config: Arc<RllmConfig<TModel>>,
     model: Box<dyn TModelInner>,
     cache_engine: CacheEngine,
-    batch_info: Option<BatchInfo>,
-    logits: Option<Tensor>,
+    // one per micro-batch
+    batch_infos: Vec<BatchInfo>,
+    logits: Vec<Tensor>,
     t0: Instant,
     seq_mgr: Arc<TchSeqMgr>,
+    /// Host buffers for the BatchInfo tensors, reused across steps.
+    staging: HostStaging,
     pub nv_profile: bool,
+    /// Bytes of model weights, if known; see memory_bytes().
+    pub weight_bytes: usize,
+}
+
+/// Fail the sequences with NaN or Inf in their logits, and write their logits, with the
+/// batch layout, to rllm-nonfinite-step-N-seq-M.safetensors. When all logits are
+/// finite, this is a single reduction over the batch.
+fn check_logits(info: &BatchInfo, logits: &Tensor, sched_out: &mut SchedulerOutputs) {
+    if logits.isfinite().all().int64_value(&[]) == 1 {
+        return;
+    }
+    let mut seqs = info
+        .seq_id_to_idx
+        .iter()
+        .filter(|(_, idx)| **idx < info.real_batch_size)
+        .collect::<Vec<_>>();
+    seqs.sort();
+    for (seq_id, idx) in seqs {
+        let row = logits.i((*idx as i64, ..));
+        if row.isfinite().all().int64_value(&[]) == 1 {
+            continue;
+        }
+        let num_nan = row.isnan().sum(DType::Int64).int64_value(&[]);
+        let num_inf = row.isinf().sum(DType::Int64).int64_value(&[]);
+        let path = PathBuf::from(format!(
+            "rllm-nonfinite-step-{}-seq-{seq_id}.safetensors",
+            info.step_no
+        ));
+        let dump = match info.save_summary(&path, &[("logits", &row)]) {
+            Ok(()) => format!("written to {}", path.display()),
+            Err(e) => format!("failed to write {}: {e}", path.display()),
+        };
+        sched_out.failed_seqs.push(EngineError::NonFiniteLogits {
+            seq_id: SeqId(*seq_id),
+            detail: format!(
+                "{num_nan} NaN, {num_inf} Inf in step {}; {dump}",
+                info.step_no
+            ),
+        });
+    }
 }
 
 pub struct TchLoaderArgs {
     pub profile_step_no: usize,
     pub device: Device,
     pub dtype: Option<DType>,
+    pub decode_pad_buckets: Vec<usize>,
+    pub attn_backend: Option<AttnBackendKind>,
+    /// See ModelConfig::parse_layer_split().
+    pub layer_split: String,
 }
 
 impl ModelExec for TModel {
@@ -41,6 +101,7 @@ impl ModelExec for TModel {
     type ModelConfig = config::ModelConfig;
     type ModelLoaderArgs = TchLoaderArgs;
     type SequenceManager = TchSeqMgr;
+    type KvData = KvBlocks;
 
     fn load_model_config(
         args: &rllm::LoaderArgs,
@@ -50,8 +111,12 @@ impl ModelExec for TModel {
         Ok((m.meta.clone(), m))
     }
 
-    fn verify_args(args: &RllmConfig<Self>) -> Result<()> {
-        args.verify_args()
+    fn validate_config(config: &RllmConfig<Self>, errors: &mut Vec<ConfigError>) {
+        config.validate_model(errors)
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.weight_bytes + self.cache_engine.num_bytes()
     }
 
     fn load_rllm_engine(
@@ -78,10 +143,27 @@ impl ModelExec for TModel {
             self.nv_profile = true;
         }
 
-        let mut info = BatchInfoBuilder::new(self.config.clone())
-            .sched_out(sched_out, self.seq_mgr.get_gpu_allocator())
-            .finish(step_no, self.cache_iface(sched_out));
-        log::trace!("batch_info #{}: {:?}", info.step_no, info);
+        let mut builder = BatchInfoBuilder::new(self.config.clone());
+        builder.sched_out(sched_out, self.seq_mgr.get_gpu_allocator());
+        // swaps and copies have to be applied even if nothing runs
+        let mut kv_cache = Some(self.cache_iface(sched_out));
+        let num_slots = kv_cache.as_ref().unwrap().num_slots();
+        builder.drop_invalid(num_slots, &mut sched_out.failed_seqs);
+        self.batch_infos.clear();
+        self.logits.clear();
+        if builder.is_empty() {
+            // all sequences failed; the engine will report them
+            log::warn!("step #{step_no}: empty batch");
+            return Ok(());
+        }
+
+        let micro_batches = builder.split();
+        if micro_batches.len() > 1 {
+            log::debug!(
+                "step #{step_no}: split into {} micro-batches",
+                micro_batches.len()
+            );
+        }
 
         #[cfg(feature = "cuda")]
         if self.nv_profile {
@@ -90,48 +172,104 @@ impl ModelExec for TModel {
 
         self.t0 = Instant::now();
 
-        let logits = with_timer!(tim, {
-            let l = self.model.forward(&mut info);
-            if false {
-                // without this, the timing is off but we may get better perf
-                synchronize(self.config.model.device.clone());
+        for mut builder in micro_batches {
+            builder.pad_decode(self.cache_engine.scratch_slot());
+            let kv_cache = kv_cache
+                .take()
+                .unwrap_or_else(|| self.cache_engine.get_cache_iface());
+            let num_slots = kv_cache.num_slots();
+            let mut info = match builder.finish_staged(step_no, kv_cache, &mut self.staging) {
+                Ok(info) => info,
+                Err(e) => {
+                    if get_setting("dump_failures") != 0.0 {
+                        let dir = PathBuf::from(format!("rllm-failure-step-{step_no}"));
+                        let seqs = SeqDump::from_sched_out(sched_out);
+                        match builder.dump_failure(&dir, step_no, num_slots, &e, &seqs) {
+                            Ok(()) => log::error!("repro written to {}", dir.display()),
+                            Err(e2) => log::error!("failed to write repro: {e2}"),
+                        }
+                    }
+                    self.batch_infos.clear();
+                    self.logits.clear();
+                    return Err(e);
+                }
+            };
+            log::trace!("batch_info #{}: {:?}", info.step_no, info);
+
+            let logits = with_timer!(tim, {
+                let l = self.model.forward(&mut info);
+                if false {
+                    // without this, the timing is off but we may get better perf
+                    synchronize(self.config.model.device.clone());
+                }
+                l
+            });
+
+            {
+                let (num_seq, logit_vocab_size) = logits.size2()?;
+                let t_vocab = vocab_size as i64;
+                if logit_vocab_size != t_vocab {
+                    panic!("vocab size mismatch: model {logit_vocab_size} != tokenizer {t_vocab}");
+                }
+                if num_seq != info.batch_size() as i64 {
+                    self.batch_infos.clear();
+                    self.logits.clear();
+                    return Err(EngineError::KernelFailure(format!(
+                        "model returned {num_seq} rows of logits for {} sequences",
+                        info.batch_size()
+                    ))
+                    .into());
+                }
             }
-            l
-        });
-
-        {
-            let (num_seq, logit_vocab_size) = logits.size2()?;
-            let t_vocab = vocab_size as i64;
-            if logit_vocab_size != t_vocab {
-                panic!("vocab size mismatch: model {logit_vocab_size} != tokenizer {t_vocab}");
+            // drop rows of padding entries
+            let logits = logits.narrow(0, 0, info.real_batch_size as i64);
+            if get_setting("check_logits") != 0.0 {
+                check_logits(&info, &logits, sched_out);
             }
-            assert!(num_seq == info.seq_id_to_idx.len() as i64);
-        }
 
-        self.batch_info = Some(info);
-        self.logits = Some(logits);
+            self.batch_infos.push(info);
+            self.logits.push(logits);
+        }
 
         Ok(())
     }
 
+    /// Number of forward passes in the last step.
+    pub fn num_micro_batches(&self) -> usize {
+        self.batch_infos.len()
+    }
+
     fn get_logits(&self, seq_id: usize) -> Tensor {
         let _no_grad = tch::no_grad_guard();
-        let idx = self.batch_info.as_ref().unwrap().seq_id_to_idx[&seq_id];
-        self.logits.as_ref().unwrap().i((idx as i64, ..))
+        self.model.synchronize();
+        for (info, logits) in self.batch_infos.iter().zip(self.logits.iter()) {
+            if let Some(idx) = info.seq_id_to_idx.get(&seq_id) {
+                return logits.i((*idx as i64, ..));
+            }
+        }
+        panic!("no logits for seq {seq_id}");
     }
 
     fn finalize_run(&mut self) -> Result<()> {
         let _no_grad = tch::no_grad_guard();
 
         let dur = self.t0.elapsed().as_micros() as f64 / 1000.0;
-        let info = self.batch_info.as_ref().unwrap();
+        if self.batch_infos.is_empty() {
+            return Ok(());
+        }
 
+        let step_no = self.batch_infos[0].step_no;
+        let num_tokens: i64 = self
+            .batch_infos
+            .iter()
+            .map(|i| i.tokens.numel() as i64)
+            .sum();
         log::info!(
             "model forward: step #{} {:.2}ms; {} tok(s); {:.1}tps",
-            info.step_no,
+            step_no,
             dur,
-            info.tokens.numel(),
-            info.tokens.numel() as f64 / (dur / 1000.0),
+            num_tokens,
+            num_tokens as f64 / (dur / 1000.0),
         );
 
         #[cfg(feature = "cuda")]
@@ -139,8 +277,14 @@ impl ModelExec for TModel {
             cudarc::driver::safe::profiler_stop()?;
         }
 
-        info.save_log(&format!("step-{}.safetensor", info.step_no));
-        log::trace!("logits: {:?}", self.logits.as_ref().unwrap());
+        for (idx, info) in self.batch_infos.iter().enumerate() {
+            if idx == 0 {
+                info.save_log(&format!("step-{}.safetensor", step_no));
+            } else {
+                info.save_log(&format!("step-{}-{}.safetensor", step_no, idx));
+            }
+        }
+        log::trace!("logits: {:?}", self.logits);
 
         Ok(())
     }
@@ -181,8 +325,13 @@ impl ModelExec for TModel {
 
                 let top_p = state.top_p;
                 if top_p <= 0.0 || top_p >= 1.0 {
-                    // simply sample from the predicted probability distribution
-                    prs.multinomial(1, false).int64_value(&[]) as u32
+                    if state.seed.is_some() {
+                        // torch's generator is global; use the per-sequence one
+                        self.sample_multinomial(state, &to_vec1(&prs))?
+                    } else {
+                        // simply sample from the predicted probability distribution
+                        prs.multinomial(1, false).int64_value(&[]) as u32
+                    }
                 } else {
                     // top-p (nucleus) sampling, clamping the least likely tokens to zero
                     let mut prs: Vec<f32> = to_vec1(&prs);
@@ -196,6 +345,76 @@ impl ModelExec for TModel {
     fn tensor_to_vec1(tensor: &Self::Tensor) -> Vec<f32> {
         to_vec1(tensor)
     }
+
+    fn add_sparse_bias(&self, logits: &mut Tensor, bias: &[(Token, f32)]) {
+        let _no_grad = tch::no_grad_guard();
+        let idx: Vec<i64> = bias.iter().map(|(t, _)| *t as i64).collect();
+        let vals: Vec<f32> = bias.iter().map(|(_, b)| *b).collect();
+        let idx = Tensor::from_slice(&idx).to(logits.device());
+        let vals = Tensor::from_slice(&vals)
+            .to(logits.device())
+            .to_kind(logits.kind());
+        *logits = logits.index_add(0, &idx, &vals);
+    }
+
+    fn logprob(&self, logits: &Tensor, token: Token) -> f32 {
+        let _no_grad = tch::no_grad_guard();
+        logits
+            .to_kind(DType::Float)
+            .log_softmax(-1, DType::Float)
+            .double_value(&[token as i64]) as f32
+    }
+
+    fn get_continuation_logprobs(&self, seq_id: usize, continuation: &[Token]) -> Result<Vec<f32>> {
+        let _no_grad = tch::no_grad_guard();
+        let logits = self.full_output(seq_id, |info| &info.full_logits)?;
+        let (len, num) = (logits.size()[0] as usize, continuation.len());
+        if num == 0 || num != len {
+            bail!("can't score {num} tokens, with logits for {len}");
+        }
+        let logprobs = logits.to_kind(DType::Float).log_softmax(-1, DType::Float);
+        let idx = continuation.iter().map(|t| *t as i64).collect::<Vec<_>>();
+        let idx = Tensor::from_slice(&idx).to(logprobs.device()).unsqueeze(1);
+        Ok(to_vec1(&logprobs.gather(1, &idx, false).squeeze_dim(1)))
+    }
+
+    fn get_embedding(&self, seq_id: usize, pooling: Pooling) -> Result<Vec<f32>> {
+        let _no_grad = tch::no_grad_guard();
+        let hidden = self.full_output(seq_id, |info| &info.full_hidden)?;
+        let hidden = hidden.to_kind(DType::Float);
+        let pooled = match pooling {
+            Pooling::Last => hidden.select(0, -1),
+            Pooling::Mean => hidden.mean_dim(0, false, DType::Float),
+        };
+        Ok(to_vec1(&pooled))
+    }
+
+    fn save_kv(&mut self, seq_id: SeqId, num_kv: usize) -> Result<KvBlocks> {
+        let _no_grad = tch::no_grad_guard();
+        let blocks = self.seq_blocks(seq_id, num_kv)?;
+        Ok(KvBlocks {
+            num_kv,
+            block_size: self.config.model.cache.block_size,
+            layers: self.cache_engine.save_blocks(&blocks),
+        })
+    }
+
+    fn swap_weights(&mut self, filenames: Vec<PathBuf>) -> Result<()> {
+        let _no_grad = tch::no_grad_guard();
+        // the new weights are fully loaded (and checked) before the old ones are dropped
+        let (model, weight_bytes) = load_model(&self.config, filenames, None)?;
+        self.model = model;
+        self.weight_bytes = weight_bytes;
+        Ok(())
+    }
+
+    fn load_kv(&mut self, seq_id: SeqId, data: &KvBlocks) -> Result<()> {
+        let _no_grad = tch::no_grad_guard();
+        data.check_compatible(&self.config)?;
+        let blocks = self.seq_blocks(seq_id, data.num_kv)?;
+        self.cache_engine.load_blocks(&blocks, &data.layers);
+        Ok(())
+    }
 }
 
 impl TModel {
@@ -205,18 +424,55 @@ impl TModel {
         seq_mgr: Arc<TchSeqMgr>,
         model: Box<dyn TModelInner>,
     ) -> Self {
+        let staging = HostStaging::new(config.model.device);
         Self {
             config,
             cache_engine,
+            staging,
             nv_profile: false,
+            weight_bytes: 0,
             model,
-            batch_info: None,
-            logits: None,
+            batch_infos: Vec::new(),
+            logits: Vec::new(),
             seq_mgr,
             t0: Instant::now(),
         }
     }
 
+    /// The rows of `seq_id` in BatchInfo::full_logits or full_hidden (as picked by
+    /// `field`) of the last run; see BatchInfo::full_rows.
+    fn full_output(
+        &self,
+        seq_id: usize,
+        field: impl Fn(&BatchInfo) -> &Option<Tensor>,
+    ) -> Result<Tensor> {
+        self.model.synchronize();
+        for info in &self.batch_infos {
+            if let Some(rows) = info.full_rows.get(&seq_id) {
+                let (start, len) = (rows.start as i64, rows.len() as i64);
+                return match field(info) {
+                    Some(t) => Ok(t.narrow(0, start, len)),
+                    None => bail!("the model doesn't support score and embed requests"),
+                };
+            }
+        }
+        bail!("no prefill outputs for seq {seq_id}")
+    }
+
+    /// GPU blocks holding the first `len` KV entries of `seq_id`.
+    fn seq_blocks(&self, seq_id: SeqId, len: usize) -> Result<Vec<usize>> {
+        let block_size = self.config.model.cache.block_size;
+        let slots = self
+            .seq_mgr
+            .get_gpu_allocator()
+            .get_block_idxes(seq_id, len)?;
+        Ok(slots
+            .iter()
+            .step_by(block_size)
+            .map(|slot| slot / block_size)
+            .collect())
+    }
+
     fn cache_iface(&mut self, sched_out: &mut SchedulerOutputs) -> Box<dyn CacheIface> {
         self.cache_engine.new_round();
         if sched_out.blocks_to_swap_in.len() > 0 {
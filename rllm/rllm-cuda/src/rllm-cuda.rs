@@ -2,10 +2,13 @@ mod llm;
 
 use clap::Parser;
 use llm::{
+    attn::AttnBackendKind,
+    config::CacheConfig,
     tmodel::{TModel, TchLoaderArgs},
     DType,
 };
 use rllm::util::parse_with_settings;
+use std::path::Path;
 use tch::Device;
 
 /// Serve LLMs with AICI over HTTP with tch (torch) backend.
@@ -19,15 +22,31 @@ pub struct DriverArgs {
     #[arg(long, default_value = "", help_heading = "Model")]
     pub dtype: String,
 
+    /// Pad decode batches to bucket sizes: "pow2" or list like "8,16,32,64" (default: no padding)
+    #[arg(long, default_value = "", help_heading = "Model")]
+    pub decode_pad: String,
+
+    /// Split layers between devices: "cuda:0,cuda:1" (by memory size) or "cuda:0=20,cuda:1=12"
+    #[arg(long, default_value = "", help_heading = "Model")]
+    pub layer_split: String,
+
+    /// Force attention implementation: "varlen", "paged", "naive" (default: pick per batch)
+    #[arg(long, default_value = "", help_heading = "Development")]
+    pub attn_backend: String,
+
     /// Enable nvprof profiling for given engine step (if available)
     #[arg(long, default_value_t = 0, help_heading = "Development")]
     pub profile_step: usize,
+
+    /// Rebuild and run a failed step dumped with `--setting dump_failures=1`
+    /// (a rllm-failure-step-N directory) on the model, and exit
+    #[arg(long, help_heading = "Development")]
+    pub replay_failure: Option<String>,
 }
 
 #[actix_web::main]
 async fn main() -> () {
-    let args = parse_with_settings::<DriverArgs>();
-    let _ = args;
+    let mut args = parse_with_settings::<DriverArgs>();
 
     let (device, dtype) = if tch::Cuda::is_available() {
         (Device::Cuda(0), None)
@@ -48,10 +67,36 @@ async fn main() -> () {
         _ => panic!("invalid dtype; try one of bf16, f16, f32"),
     };
 
+    let decode_pad_buckets = match CacheConfig::parse_pad_buckets(&args.decode_pad) {
+        Ok(v) => v,
+        Err(e) => panic!("{e}"),
+    };
+
+    let attn_backend = match AttnBackendKind::parse(&args.attn_backend) {
+        Ok(v) => v,
+        Err(e) => panic!("{e}"),
+    };
+
     let model_args = TchLoaderArgs {
         device,
         dtype,
         profile_step_no: args.profile_step,
+        decode_pad_buckets,
+        attn_backend,
+        layer_split: args.layer_split,
     };
+
+    if let Some(dir) = &args.replay_failure {
+        let loader_args = rllm::server::init_from_cli(&mut args.args);
+        match llm::loader::replay_failure_dump(loader_args, model_args, Path::new(dir)) {
+            Ok(()) => println!("{dir}: the step builds and runs; trace.safetensors written"),
+            Err(e) => {
+                eprintln!("{dir}: reproduced: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     rllm::server::server_main::<TModel>(args.args, model_args).await;
 }
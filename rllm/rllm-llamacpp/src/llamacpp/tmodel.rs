@@ -4,8 +4,8 @@ use llama_cpp_low as cpp;
 use rand::distributions::Distribution as _;
 use rllm::{
     config::{ModelMeta, RllmConfig},
-    seq::SchedulingPhase,
-    AiciBias, HashMap, LoaderArgs, LogitsProcessor, ModelExec, SchedulerOutputs,
+    seq::{SchedulingPhase, Token},
+    AiciBias, EngineError, HashMap, LoaderArgs, LogitsProcessor, ModelExec, SchedulerOutputs,
 };
 use std::{sync::Arc, time::Instant};
 
@@ -46,6 +46,7 @@ impl ModelExec for TModel {
     type ModelConfig = ();
     type ModelLoaderArgs = CppLoaderArgs;
     type SequenceManager = CppSequenceManager;
+    type KvData = ();
 
     fn run(
         &mut self,
@@ -67,6 +68,18 @@ impl ModelExec for TModel {
                 let seq_len = seq.get_len();
                 let k_len = seq_len;
                 log::trace!("fwd seq: {seq:?}");
+                if seq_len == 0 || seq.num_kv_computed > seq_len {
+                    sched_out
+                        .failed_seqs
+                        .push(EngineError::InconsistentSequence {
+                            seq_id: seq.seq_id,
+                            detail: format!(
+                                "num_kv_computed={} but length is {}",
+                                seq.num_kv_computed, seq_len
+                            ),
+                        });
+                    continue;
+                }
                 let mut q_len = seq.get_len() - seq.num_kv_computed;
                 if q_len == 0 {
                     // just re-compute the last token
@@ -96,6 +109,11 @@ impl ModelExec for TModel {
 
         self.t0 = Instant::now();
 
+        if self.seq_id_to_idx.is_empty() {
+            // all sequences failed; the engine will report them
+            return Ok(());
+        }
+
         with_timer!(tim, { self.model.decode(&mut self.batch)? });
 
         Ok(())
@@ -145,6 +163,20 @@ impl ModelExec for TModel {
         }
     }
 
+    fn add_sparse_bias(&self, logits: &mut Tensor, bias: &[(Token, f32)]) {
+        let logits = logits.as_mut_slice();
+        for (t, b) in bias {
+            logits[*t as usize] += b;
+        }
+    }
+
+    fn logprob(&self, logits: &Tensor, token: Token) -> f32 {
+        let data = logits.as_slice();
+        let max = data.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        let sum = data.iter().map(|l| (l - max).exp()).sum::<f32>();
+        data[token as usize] - max - sum.ln()
+    }
+
     fn sample(&self, state: &mut LogitsProcessor, logits: &Tensor) -> Result<u32> {
         let next_token = match state.temperature {
             None => self.sample_argmax(&logits),
@@ -179,10 +211,6 @@ impl ModelExec for TModel {
         Ok((meta, ()))
     }
 
-    fn verify_args(_args: &RllmConfig<Self>) -> Result<()> {
-        Ok(())
-    }
-
     fn load_rllm_engine(
         args: LoaderArgs,
         model_args: Self::ModelLoaderArgs,
@@ -197,6 +197,7 @@ pub fn reshape_and_cache(
     slot_mapping: &Tensor,    // [num_tokens], int
 ) {
     // it's int64 in here, but int32 in gather*; go figure
+    // (rllm passes int64 already, which makes this a no-op)
     let slot_mapping = slot_mapping.to_kind(Kind::Int64);
     unsafe {
         check_res(